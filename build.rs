@@ -0,0 +1,34 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::process::Command;
+
+fn main() {
+    // Capture the git revision for the /version API.  Builds from a source
+    // tarball (or without git installed) just report "unknown".
+    let git_hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map_or_else(|| "unknown".to_string(), |hash| hash.trim().to_string());
+    println!("cargo:rustc-env=MOULARS_GIT_HASH={git_hash}");
+
+    // Only re-run when the checked out revision changes
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
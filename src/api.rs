@@ -14,132 +14,688 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::Infallible;
 use std::io::Write;
-use std::sync::Arc;
-use std::time::Duration;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-use data_encoding::BASE64;
-use http_body_util::Full;
-use hyper::body::{Bytes, Incoming};
-use hyper::header::CONTENT_TYPE;
+use http_body_util::{BodyExt, Either, Full, LengthLimitError, Limited};
+use hyper::body::{Body, Bytes, Frame, Incoming};
+use hyper::header::{HeaderMap, CACHE_CONTROL, CONTENT_TYPE};
 use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::{Request, Response, Method, StatusCode};
 use hyper_util::rt::TokioIo;
 use hyper_util::server::graceful::GracefulShutdown;
 use log::{warn, info};
-use num_bigint::ToBigUint;
-use serde_derive::Serialize;
-use tokio::net::TcpListener;
-use tokio::sync::broadcast;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use unicase::UniCase;
+use uuid::Uuid;
 
+use crate::auth_srv::{age_display_name, repair_player_nodes, set_player_location};
 use crate::config::ServerConfig;
+use crate::conn_limit::SessionLimiter;
 use crate::net_crypt::{CRYPT_BASE_AUTH, CRYPT_BASE_GAME, CRYPT_BASE_GATE_KEEPER};
-use crate::netcli::NetResult;
-use crate::vault::{VaultServer, VaultPlayerInfoNode};
+use crate::netcli::{NetResult, NetResultCode};
+use crate::ping_stats::{PingService, PingStats, PingSummary};
+use crate::sockets::{configure_stream, MultiListener};
+use crate::vault::{VaultServer, VaultPlayerInfoNode, VaultAgeInfoNode, AccountInfo};
+use crate::vault::messages::VaultBroadcast;
+
+// Limits for the batch online status check
+const MAX_ONLINE_CHECK: usize = 100;
+// Page sizes for the Age list
+const DEFAULT_AGE_LIST_LIMIT: usize = 50;
+const MAX_AGE_LIST_LIMIT: usize = 500;
+// Events queued for a slow /events client before the stream stops reading
+// from the vault (and eventually reports a lag)
+const EVENT_QUEUE_SIZE: usize = 64;
+
+// Caches the account for each recently used API token, so frequently polled
+// APIs don't need to look up the account on every request.  Entries are
+// dropped as soon as the vault reports a change to the account.
+struct TokenCache {
+    entries: HashMap<String, (AccountInfo, Instant)>,
+    ttl: Duration,
+    bcast_recv: broadcast::Receiver<VaultBroadcast>,
+}
+
+impl TokenCache {
+    fn get(&mut self, api_token: &str, now: Instant) -> Option<AccountInfo> {
+        self.process_updates();
+        match self.entries.get(api_token) {
+            Some((account, updated)) if now.duration_since(*updated) < self.ttl => {
+                Some(account.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, api_token: &str, account: AccountInfo, now: Instant) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, (_, updated)| now.duration_since(*updated) < ttl);
+        self.entries.insert(api_token.to_string(), (account, now));
+    }
+
+    fn process_updates(&mut self) {
+        loop {
+            match self.bcast_recv.try_recv() {
+                Ok(VaultBroadcast::AccountChanged { account_id }) => {
+                    self.entries.retain(|_, (account, _)| account.account_id != account_id);
+                }
+                Ok(_) => (),
+                // If we missed any updates, we can't know which entries are
+                // still valid
+                Err(TryRecvError::Lagged(_)) => self.entries.clear(),
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+    }
+}
 
 struct ApiInterface {
     server_config: Arc<ServerConfig>,
     shutdown_send: broadcast::Sender<()>,
     vault: Arc<VaultServer>,
+    token_cache: Mutex<TokenCache>,
+    ping_stats: PingStats,
+    session_limiter: SessionLimiter,
 }
 
 impl ApiInterface {
+    fn new(server_config: Arc<ServerConfig>, shutdown_send: broadcast::Sender<()>,
+           vault: Arc<VaultServer>, ping_stats: PingStats,
+           session_limiter: SessionLimiter) -> Self
+    {
+        let token_cache = Mutex::new(TokenCache {
+            entries: HashMap::new(),
+            ttl: server_config.api_token_cache_ttl,
+            bcast_recv: vault.subscribe(),
+        });
+        Self { server_config, shutdown_send, vault, token_cache, ping_stats, session_limiter }
+    }
+
+    fn session_status(&self) -> SessionStatus {
+        SessionStatus {
+            current: self.session_limiter.session_count(),
+            max: self.session_limiter.max_sessions(),
+        }
+    }
+
     // Returns the name of the account that matched the API token
     async fn check_api_token(&self, query: &HashMap<String, String>) -> Option<String> {
-        if let Some(api_token) = query.get("token") {
-            if let Ok(Some(account)) = self.vault.get_account_for_token(api_token).await {
-                // Currently, only Admin accounts are allowed to use privileged APIs
-                if account.is_admin() {
-                    return Some(account.account_name);
+        let api_token = query.get("token")?.to_ascii_lowercase();
+        let account = self.account_for_token(&api_token).await?;
+
+        // Currently, only Admin accounts are allowed to use privileged APIs
+        if account.is_admin() && !account.is_banned() && !account.is_disabled() {
+            Some(account.account_name)
+        } else {
+            None
+        }
+    }
+
+    async fn account_for_token(&self, api_token: &str) -> Option<AccountInfo> {
+        let now = Instant::now();
+        let cached = self.token_cache.lock().unwrap().get(api_token, now);
+        if cached.is_some() {
+            return cached;
+        }
+
+        let account = self.vault.get_account_for_token(api_token).await.ok()??;
+        if !account.is_banned() {
+            self.token_cache.lock().unwrap().insert(api_token, account.clone(), now);
+        }
+        Some(account)
+    }
+
+    // Disabling an account prevents logins without banning it, e.g. while the
+    // account is pending verification.
+    async fn set_account_disabled(&self, account_name: &str, disabled: bool) -> NetResult<()> {
+        let Some(mut account) = self.vault.get_existing_account(account_name).await? else {
+            return Err(NetResultCode::NetAccountNotFound);
+        };
+        if disabled {
+            account.account_flags |= AccountInfo::DISABLED;
+        } else {
+            account.account_flags &= !AccountInfo::DISABLED;
+        }
+        self.vault.update_account(account).await
+    }
+
+    // Issues a new login token for the account, replacing any previous one
+    async fn issue_login_token(&self, account_name: &str) -> NetResult<String> {
+        let Some(mut account) = self.vault.get_existing_account(account_name).await? else {
+            return Err(NetResultCode::NetAccountNotFound);
+        };
+        account.login_token = Uuid::new_v4().simple().to_string();
+        let login_token = account.login_token.clone();
+        self.vault.update_account(account).await?;
+        Ok(login_token)
+    }
+
+    // Formats a vault broadcast for the /events stream.  Changes to player
+    // info nodes are reported as the player's online status, since that is
+    // what most consumers are interested in.
+    async fn vault_event(&self, bcast: &VaultBroadcast) -> Bytes {
+        match bcast {
+            VaultBroadcast::NodeChanged { node_id, revision_id } => {
+                let player_info = self.vault.fetch_node(*node_id).await.ok()
+                        .and_then(|node| node.as_player_info_node());
+                if let Some(info) = player_info {
+                    sse_event("player_status", &json!({
+                        "player_id": info.player_id(),
+                        "name": info.player_name_ci(),
+                        "online": info.online() != 0,
+                        "location": info.age_instance_name(),
+                    }))
+                } else {
+                    sse_event("node_changed", &json!({
+                        "node_id": node_id,
+                        "revision_id": revision_id.to_string(),
+                    }))
                 }
             }
+            VaultBroadcast::NodeAdded { parent_id, child_id, owner_id } => {
+                sse_event("node_added", &json!({
+                    "parent_id": parent_id,
+                    "child_id": child_id,
+                    "owner_id": owner_id,
+                }))
+            }
+            VaultBroadcast::AccountChanged { account_id } => {
+                sse_event("account_changed", &json!({ "account_id": account_id.to_string() }))
+            }
+            VaultBroadcast::KickPlayer { player_id, reason } => {
+                sse_event("player_kicked", &json!({
+                    "player_id": player_id,
+                    "reason": format!("{reason:?}"),
+                }))
+            }
         }
-        None
     }
 
-    async fn query_online_players(&self) -> NetResult<Vec<OnlinePlayer>> {
+    async fn fetch_online_players(&self) -> NetResult<Vec<VaultPlayerInfoNode>> {
         let template = VaultPlayerInfoNode::new_lookup(Some(1));
         let player_list = self.vault.find_nodes(template).await?;
-        let mut players = Vec::with_capacity(player_list.len());
-        for player_info in player_list {
-            let node = self.vault.fetch_node(player_info).await?.as_player_info_node().unwrap();
-            players.push(OnlinePlayer {
-                name: node.player_name_ci().clone(),
-                location: node.age_instance_name().clone(),
-            });
+        self.vault.fetch_nodes(player_list).await?.iter()
+            .map(|node| node.as_player_info_node().ok_or(NetResultCode::NetInternalError))
+            .collect()
+    }
+
+    async fn query_online_players(&self) -> NetResult<Vec<OnlinePlayer>> {
+        Ok(self.fetch_online_players().await?.into_iter().map(|node| OnlinePlayer {
+            name: node.player_name_ci().clone(),
+            location: node.age_instance_name().clone(),
+            instance_id: node.age_instance_uuid().to_string(),
+        }).collect())
+    }
+
+    async fn check_online_players(&self, player_names: &[String], account_ids: &[Uuid])
+        -> NetResult<Vec<PlayerStatus>>
+    {
+        let online_players = self.fetch_online_players().await?;
+        let by_name: HashMap<UniCase<&str>, &VaultPlayerInfoNode> = online_players.iter()
+                .map(|node| (UniCase::new(node.player_name_ci().as_str()), node))
+                .collect();
+        let by_id: HashMap<u32, &VaultPlayerInfoNode> = online_players.iter()
+                .map(|node| (node.player_id(), node))
+                .collect();
+
+        let mut statuses = Vec::with_capacity(player_names.len() + account_ids.len());
+        for player_name in player_names {
+            let online = by_name.get(&UniCase::new(player_name.as_str()));
+            statuses.push(PlayerStatus::new(player_name, None, online.copied()));
+        }
+        for account_id in account_ids {
+            for player in self.vault.get_players(account_id).await? {
+                let online = by_id.get(&player.player_id);
+                statuses.push(PlayerStatus::new(&player.player_name, Some(account_id),
+                                                online.copied()));
+            }
+        }
+        Ok(statuses)
+    }
+
+    async fn query_age_instance(&self, instance_id: &Uuid) -> NetResult<Option<AgeInstance>> {
+        let template = VaultAgeInfoNode::new_lookup(Some(instance_id));
+        let Some(node_id) = self.vault.find_nodes(template).await?.first().copied() else {
+            return Ok(None);
+        };
+        let node = self.vault.fetch_node(node_id).await?.as_age_info_node()
+                .ok_or(NetResultCode::NetAgeNotFound)?;
+        Ok(Some(AgeInstance {
+            filename: node.age_filename().clone(),
+            display_name: age_display_name(node.age_description(),
+                    node.age_user_defined_name(), node.age_instance_name(),
+                    node.age_filename()).to_string(),
+            population: self.vault.get_member_count(instance_id).await?,
+            public: node.is_public(),
+        }))
+    }
+
+    // Lists the running instances of each Age, sorted by filename.  The
+    // offset and limit apply to the individual instances, so the instances
+    // of a popular Age may be spread over several pages.
+    async fn query_ages(&self, age_filename: Option<&str>, offset: usize, limit: usize)
+        -> NetResult<AgeList>
+    {
+        let (total, nodes) = self.vault.list_age_instances(age_filename, offset, limit).await?;
+        let mut by_filename: BTreeMap<String, Vec<VaultAgeInfoNode>> = BTreeMap::new();
+        for node in nodes {
+            let node = node.as_age_info_node().ok_or(NetResultCode::NetInternalError)?;
+            by_filename.entry(node.age_filename().clone()).or_default().push(node);
+        }
+
+        let mut ages = Vec::new();
+        for (filename, nodes) in by_filename {
+            let instance_ids = nodes.iter().map(|node| *node.age_instance_uuid()).collect();
+            let populations = self.vault.get_populations(instance_ids).await?;
+            let instances = nodes.iter().zip(populations).map(|(node, population)| {
+                AgeListInstance {
+                    instance_id: node.age_instance_uuid().to_string(),
+                    display_name: age_display_name(node.age_description(),
+                            node.age_user_defined_name(), node.age_instance_name(),
+                            node.age_filename()).to_string(),
+                    population,
+                    public: node.is_public(),
+                }
+            }).collect();
+            ages.push(AgeListEntry { filename, instances });
         }
-        Ok(players)
+        Ok(AgeList { total, offset, ages })
     }
 }
 
-fn gen_unauthorized() -> Response<Full<Bytes>> {
+// Parses an optional numeric query parameter, returning None if it is invalid
+fn query_usize(query: &HashMap<String, String>, name: &str, default: usize) -> Option<usize> {
+    query.get(name).map_or(Some(default), |value| value.parse().ok())
+}
+
+// The banner page shows the status "OK" surrounded by the operator's
+// configured banner (e.g. the shard's name and contact info).  The root
+// status check stays a bare "OK" so monitoring scripts can match it exactly.
+fn banner_page(server_config: &ServerConfig) -> Bytes {
+    if server_config.api_banner_header.is_none() && server_config.api_banner_footer.is_none() {
+        return Bytes::from_static(b"OK");
+    }
+    let lines: Vec<&str> = [
+        server_config.api_banner_header.as_deref(),
+        Some("OK"),
+        server_config.api_banner_footer.as_deref(),
+    ].into_iter().flatten().map(str::trim_end).collect();
+    Bytes::from(lines.join("\n") + "\n")
+}
+
+// Error responses include both a human readable message and a stable
+// machine-readable code derived from the NetResultCode.
+fn gen_error(status: StatusCode, result: &NetResultCode, message: &str)
+    -> Response<Full<Bytes>>
+{
+    let body = json!({ "error": message, "code": result.as_code() }).to_string();
     Response::builder()
-        .status(StatusCode::UNAUTHORIZED)
+        .status(status)
         .header(CONTENT_TYPE, "application/json")
-        .body(Full::from(Bytes::from_static(br#"{"error": "Unauthorized"}"#)))
+        .body(Full::from(body))
         .unwrap()
 }
 
-fn gen_server_error() -> Response<Full<Bytes>> {
+fn json_response(value: &impl serde::Serialize) -> Response<Full<Bytes>> {
+    match serde_json::to_string(value) {
+        Ok(json) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::from(json))
+            .unwrap(),
+        Err(err) => {
+            warn!("Failed to generate JSON: {}", err);
+            gen_server_error(&NetResultCode::NetInternalError)
+        }
+    }
+}
+
+fn gen_unauthorized() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::UNAUTHORIZED, &NetResultCode::NetAuthenticationFailed,
+              "Unauthorized")
+}
+
+fn gen_invalid_request() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::NOT_FOUND, &NetResultCode::NetNotSupported, "Invalid API Request")
+}
+
+fn gen_bad_request() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::BAD_REQUEST, &NetResultCode::NetInvalidParameter, "Bad Request")
+}
+
+fn gen_player_not_found() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::NOT_FOUND, &NetResultCode::NetPlayerNotFound, "Player Not Found")
+}
+
+fn gen_account_not_found() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::NOT_FOUND, &NetResultCode::NetAccountNotFound, "Account Not Found")
+}
+
+fn gen_age_not_found() -> Response<Full<Bytes>> {
+    gen_error(StatusCode::NOT_FOUND, &NetResultCode::NetAgeNotFound, "Age Not Found")
+}
+
+// Maps the result codes which vault and auth operations can return to the
+// closest HTTP status.  Anything else is reported as an internal error.
+fn net_error_status(result: &NetResultCode) -> StatusCode {
+    match result {
+        NetResultCode::NetInvalidParameter
+            | NetResultCode::NetPlayerNameInvalid => StatusCode::BAD_REQUEST,
+        NetResultCode::NetAuthenticationFailed => StatusCode::UNAUTHORIZED,
+        NetResultCode::NetServiceForbidden
+            | NetResultCode::NetAccountBanned
+            | NetResultCode::NetAccountNotActivated
+            | NetResultCode::NetLoginDenied => StatusCode::FORBIDDEN,
+        NetResultCode::NetAgeNotFound
+            | NetResultCode::NetFileNotFound
+            | NetResultCode::NetAccountNotFound
+            | NetResultCode::NetPlayerNotFound
+            | NetResultCode::NetVaultNodeNotFound
+            | NetResultCode::NetStateObjectNotFound => StatusCode::NOT_FOUND,
+        NetResultCode::NetAccountAlreadyExists
+            | NetResultCode::NetPlayerAlreadyExists
+            | NetResultCode::NetMaxPlayersOnAcct
+            | NetResultCode::NetCircularReference => StatusCode::CONFLICT,
+        NetResultCode::NetNotSupported => StatusCode::NOT_IMPLEMENTED,
+        NetResultCode::NetTimeout
+            | NetResultCode::NetTimeoutOdbc
+            | NetResultCode::NetServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn gen_server_error(result: &NetResultCode) -> Response<Full<Bytes>> {
+    let status = net_error_status(result);
+    gen_error(status, result, status.canonical_reason().unwrap_or("Internal Server Error"))
+}
+
+// Reads a whole request body, within the configured size and time limits.
+// Without these, a client could tie up the server by sending an endless
+// (or endlessly slow) body.
+async fn read_request_body(body: Incoming, server_config: &ServerConfig)
+        -> Result<Bytes, Response<Full<Bytes>>>
+{
+    let collect = Limited::new(body, server_config.api_max_body_size).collect();
+    match tokio::time::timeout(server_config.api_body_timeout, collect).await {
+        Ok(Ok(collected)) => Ok(collected.to_bytes()),
+        Ok(Err(err)) if err.downcast_ref::<LengthLimitError>().is_some() => {
+            Err(gen_error(StatusCode::PAYLOAD_TOO_LARGE, &NetResultCode::NetInvalidParameter,
+                          "Payload Too Large"))
+        }
+        Ok(Err(_)) => Err(gen_bad_request()),
+        Err(_) => Err(gen_error(StatusCode::REQUEST_TIMEOUT, &NetResultCode::NetTimeout,
+                                "Request Timeout")),
+    }
+}
+
+// Parses a JSON request body, which must be sent as application/json
+#[allow(clippy::result_large_err)]
+fn parse_json_body<T: DeserializeOwned>(headers: &HeaderMap, body: &Bytes)
+        -> Result<T, Response<Full<Bytes>>>
+{
+    let is_json = headers.get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+    if !is_json {
+        return Err(gen_error(StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                             &NetResultCode::NetInvalidParameter, "Unsupported Media Type"));
+    }
+    serde_json::from_slice(body).map_err(|_| gen_bad_request())
+}
+
+fn parse_query(request: &Request<Incoming>) -> HashMap<String, String> {
+    if let Some(query) = request.uri().query() {
+        form_urlencoded::parse(query.as_bytes()).into_owned().collect()
+    } else {
+        HashMap::new()
+    }
+}
+
+fn sse_event(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {event}\ndata: {data}\n\n"))
+}
+
+// Response body for a server-sent event stream, fed by stream_vault_events
+struct EventStream {
+    event_recv: mpsc::Receiver<Bytes>,
+}
+
+impl Body for EventStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>)
+        -> Poll<Option<Result<Frame<Bytes>, Infallible>>>
+    {
+        self.event_recv.poll_recv(cx).map(|event| event.map(|data| Ok(Frame::data(data))))
+    }
+}
+
+type ApiBody = Either<Full<Bytes>, EventStream>;
+
+async fn open_event_stream(query: &HashMap<String, String>, api: Arc<ApiInterface>)
+        -> Response<ApiBody>
+{
+    let Some(admin) = api.check_api_token(query).await else {
+        return gen_unauthorized().map(Either::Left);
+    };
+    info!("Event stream opened by {}", admin);
+
+    // Subscribe before responding, so no events are missed in between
+    let bcast_recv = api.vault.subscribe();
+    let (event_send, event_recv) = mpsc::channel(EVENT_QUEUE_SIZE);
+    tokio::spawn(stream_vault_events(api, bcast_recv, event_send));
+
     Response::builder()
-        .status(StatusCode::INTERNAL_SERVER_ERROR)
-        .header(CONTENT_TYPE, "application/json")
-        .body(Full::from(Bytes::from_static(br#"{"error": "Internal Server Error"}"#)))
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(Either::Right(EventStream { event_recv }))
         .unwrap()
 }
 
-async fn api_router(request: Request<Incoming>, api: Arc<ApiInterface>)
+// Forwards vault broadcasts to an /events client until it disconnects or
+// the server shuts down.  The periodic heartbeat also ensures a client that
+// silently went away is noticed even if the vault is idle.
+async fn stream_vault_events(api: Arc<ApiInterface>,
+                             mut bcast_recv: broadcast::Receiver<VaultBroadcast>,
+                             event_send: mpsc::Sender<Bytes>)
+{
+    let mut shutdown_recv = api.shutdown_send.subscribe();
+    let heartbeat_period = api.server_config.api_event_heartbeat;
+    let mut heartbeat = tokio::time::interval_at(
+                tokio::time::Instant::now() + heartbeat_period, heartbeat_period);
+
+    loop {
+        let event = tokio::select! {
+            bcast = bcast_recv.recv() => match bcast {
+                Ok(bcast) => api.vault_event(&bcast).await,
+                Err(RecvError::Lagged(count)) => sse_event("lagged", &json!({ "missed": count })),
+                Err(RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => Bytes::from_static(b": heartbeat\n\n"),
+            () = event_send.closed() => break,
+            _ = shutdown_recv.recv() => break,
+        };
+        if event_send.send(event).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Returns the route for a request path under the configured API prefix, or
+// None if the path is outside of the prefix.  The prefix itself maps to the
+// root route, with or without a trailing slash.
+fn api_route<'a>(path: &'a str, api_prefix: &str) -> Option<&'a str> {
+    let route = path.strip_prefix(api_prefix)?;
+    if route.is_empty() {
+        Some("/")
+    } else if route.starts_with('/') {
+        Some(route)
+    } else {
+        // e.g. "/apifoo" with a prefix of "/api"
+        None
+    }
+}
+
+// Event streams can't be buffered into a Full body like the other APIs,
+// so they are handled separately from api_router.
+async fn api_service(request: Request<Incoming>, api: Arc<ApiInterface>)
+        -> Result<Response<ApiBody>, Infallible>
+{
+    let Some(route) = api_route(request.uri().path(), &api.server_config.api_prefix)
+                        .map(str::to_string) else {
+        return Ok(gen_invalid_request().map(Either::Left));
+    };
+    if request.method() == Method::GET && route == "/events" {
+        let query_params = parse_query(&request);
+        return Ok(open_event_stream(&query_params, api).await);
+    }
+    let response = api_router(request, &route, api).await?;
+    Ok(response.map(Either::Left))
+}
+
+async fn api_router(request: Request<Incoming>, route: &str, api: Arc<ApiInterface>)
         -> Result<Response<Full<Bytes>>, Infallible>
 {
-    let query_params = if let Some(query) = request.uri().query() {
-        form_urlencoded::parse(query.as_bytes()).into_owned()
-                .collect::<HashMap<String, String>>()
+    let query_params = parse_query(&request);
+    let (parts, body) = request.into_parts();
+    let body = if parts.method == Method::POST {
+        match read_request_body(body, &api.server_config).await {
+            Ok(body) => body,
+            Err(response) => return Ok(response),
+        }
     } else {
-        HashMap::new()
+        Bytes::new()
     };
 
-    let response = match (request.method(), request.uri().path()) {
+    let response = match (&parts.method, route) {
         (&Method::GET, "/") => {
             // Basic status check
             Response::builder().body(Full::from(Bytes::from_static(b"OK"))).unwrap()
         }
+        (&Method::GET, "/banner") => {
+            Response::builder().body(Full::from(banner_page(&api.server_config))).unwrap()
+        }
+        (&Method::GET, "/version") => {
+            let version = ServerVersion {
+                version: env!("CARGO_PKG_VERSION"),
+                git_hash: env!("MOULARS_GIT_HASH"),
+                build_id: api.server_config.build_id,
+            };
+            json_response(&version)
+        }
         (&Method::GET, "/client_keys") => {
             let mut lines = Vec::with_capacity(6 * 105);
-            for (stype, key_g, key_k, key_n) in [
-                ("Auth", CRYPT_BASE_AUTH, &api.server_config.auth_k_key, &api.server_config.auth_n_key),
-                ("Game", CRYPT_BASE_GAME, &api.server_config.game_k_key, &api.server_config.game_n_key),
-                ("Gate", CRYPT_BASE_GATE_KEEPER, &api.server_config.gate_k_key, &api.server_config.gate_n_key)]
+            for (stype, key_g, keys) in [
+                ("Auth", CRYPT_BASE_AUTH, &api.server_config.auth_keys),
+                ("Game", CRYPT_BASE_GAME, &api.server_config.game_keys),
+                ("Gate", CRYPT_BASE_GATE_KEEPER, &api.server_config.gate_keys)]
             {
-                let key_x = key_g.to_biguint().unwrap().modpow(key_k, key_n);
-                let bytes_n = key_n.to_bytes_be();
-                let bytes_x = key_x.to_bytes_be();
-                let _ = writeln!(lines, "Server.{stype}.N \"{}\"", BASE64.encode(&bytes_n));
-                let _ = writeln!(lines, "Server.{stype}.X \"{}\"", BASE64.encode(&bytes_x));
+                for line in keys.client_ini_lines(stype, key_g) {
+                    let _ = writeln!(lines, "{line}");
+                }
             }
             Response::builder().body(Full::from(lines)).unwrap()
         }
+        (&Method::GET, "/status/ping") => {
+            // Return JSON object containing the recent round trip times for each service
+            let ping_status = PingStatus {
+                auth: api.ping_stats.summary(PingService::Auth).into(),
+                gate_keeper: api.ping_stats.summary(PingService::GateKeeper).into(),
+            };
+            json_response(&ping_status)
+        }
+        (&Method::GET, "/status/sessions") => {
+            // Return the number of logged in auth sessions and the limit
+            // (0 = unlimited)
+            json_response(&api.session_status())
+        }
         (&Method::GET, "/online") => {
             // Return JSON object containing the names and locations of online players
             let online_players = match api.query_online_players().await {
                 Ok(response) => response,
                 Err(err) => {
                     warn!("Failed to query online players: {:?}", err);
-                    return Ok(gen_server_error());
+                    return Ok(gen_server_error(&err));
                 }
             };
-            match serde_json::to_string(&online_players) {
-                Ok(json) => Response::builder()
-                    .header(CONTENT_TYPE, "application/json")
-                    .body(Full::from(json))
-                    .unwrap(),
+            json_response(&online_players)
+        }
+        (&Method::POST, "/online/check") => {
+            // Return the online status of specific players and/or accounts
+            let request = match parse_json_body::<OnlineCheckRequest>(&parts.headers, &body) {
+                Ok(request) => request,
+                Err(response) => return Ok(response),
+            };
+            if request.players.len() + request.accounts.len() > MAX_ONLINE_CHECK {
+                return Ok(gen_bad_request());
+            }
+            // Looking up players by account is restricted, since it would
+            // otherwise expose which players belong to an account
+            if !request.accounts.is_empty()
+                    && api.check_api_token(&query_params).await.is_none() {
+                return Ok(gen_unauthorized());
+            }
+            let Ok(account_ids) = request.accounts.iter()
+                    .map(|account_id| Uuid::parse_str(account_id))
+                    .collect::<Result<Vec<_>, _>>() else {
+                return Ok(gen_bad_request());
+            };
+            let statuses = match api.check_online_players(&request.players, &account_ids).await {
+                Ok(response) => response,
                 Err(err) => {
-                    warn!("Failed to generate JSON: {}", err);
-                    gen_server_error()
+                    warn!("Failed to check online players: {:?}", err);
+                    return Ok(gen_server_error(&err));
                 }
+            };
+            json_response(&statuses)
+        }
+        (&Method::GET, path) if path.starts_with("/age/") => {
+            let Ok(instance_id) = Uuid::parse_str(&path["/age/".len()..]) else {
+                return Ok(gen_bad_request());
+            };
+            let age_instance = match api.query_age_instance(&instance_id).await {
+                Ok(Some(response)) => response,
+                Ok(None) => return Ok(gen_age_not_found()),
+                Err(err) => {
+                    warn!("Failed to query Age instance {}: {:?}", instance_id, err);
+                    return Ok(gen_server_error(&err));
+                }
+            };
+            json_response(&age_instance)
+        }
+        (&Method::GET, "/ages") => {
+            if api.check_api_token(&query_params).await.is_none() {
+                return Ok(gen_unauthorized());
             }
+            let (Some(offset), Some(limit)) = (
+                query_usize(&query_params, "offset", 0),
+                query_usize(&query_params, "limit", DEFAULT_AGE_LIST_LIMIT)
+            ) else {
+                return Ok(gen_bad_request());
+            };
+            let age_filename = query_params.get("filename").map(String::as_str);
+            let age_list = match api.query_ages(age_filename, offset,
+                                                limit.min(MAX_AGE_LIST_LIMIT)).await {
+                Ok(response) => response,
+                Err(err) => {
+                    warn!("Failed to query Age list: {:?}", err);
+                    return Ok(gen_server_error(&err));
+                }
+            };
+            json_response(&age_list)
         }
         (&Method::POST, "/shutdown") => {
             if let Some(admin) = api.check_api_token(&query_params).await {
@@ -153,86 +709,774 @@ async fn api_router(request: Request<Incoming>, api: Arc<ApiInterface>)
                 gen_unauthorized()
             }
         }
-        _ => {
-            Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .header(CONTENT_TYPE, "application/json")
-                .body(Full::from(Bytes::from_static(br#"{"error": "Invalid API Request"}"#)))
-                .unwrap()
+        (&Method::POST, "/account/disabled") => {
+            let Some(admin) = api.check_api_token(&query_params).await else {
+                return Ok(gen_unauthorized());
+            };
+            let (Some(account_name), Some(disabled)) = (query_params.get("account"),
+                    query_params.get("disabled").and_then(|value| value.parse::<bool>().ok()))
+            else {
+                return Ok(gen_bad_request());
+            };
+            match api.set_account_disabled(account_name, disabled).await {
+                Ok(()) => {
+                    info!("Account {} {} by {}", account_name,
+                          if disabled { "disabled" } else { "enabled" }, admin);
+                    Response::builder()
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Full::from(Bytes::from_static(br#"{"status": "ok"}"#)))
+                        .unwrap()
+                }
+                Err(NetResultCode::NetAccountNotFound) => gen_account_not_found(),
+                Err(err) => {
+                    warn!("Failed to update account {}: {:?}", account_name, err);
+                    gen_server_error(&err)
+                }
+            }
+        }
+        (&Method::POST, "/account/login_token") => {
+            let Some(admin) = api.check_api_token(&query_params).await else {
+                return Ok(gen_unauthorized());
+            };
+            let Some(account_name) = query_params.get("account") else {
+                return Ok(gen_bad_request());
+            };
+            match api.issue_login_token(account_name).await {
+                Ok(login_token) => {
+                    info!("Login token for account {} issued by {}", account_name, admin);
+                    json_response(&json!({ "status": "ok", "login_token": login_token }))
+                }
+                Err(NetResultCode::NetAccountNotFound) => gen_account_not_found(),
+                Err(err) => {
+                    warn!("Failed to update account {}: {:?}", account_name, err);
+                    gen_server_error(&err)
+                }
+            }
+        }
+        (&Method::POST, "/player/repair") => {
+            let Some(admin) = api.check_api_token(&query_params).await else {
+                return Ok(gen_unauthorized());
+            };
+            let Some(player_id) = query_params.get("player_id")
+                                    .and_then(|value| value.parse::<u32>().ok()) else {
+                return Ok(gen_bad_request());
+            };
+            info!("Vault repair for player {} requested by {}", player_id, admin);
+            let created = match repair_player_nodes(player_id, &api.vault).await {
+                Ok(created) => created,
+                Err(NetResultCode::NetPlayerNotFound) => return Ok(gen_player_not_found()),
+                Err(err) => {
+                    warn!("Failed to repair player {}: {:?}", player_id, err);
+                    return Ok(gen_server_error(&err));
+                }
+            };
+            for (node_name, node_id) in &created {
+                info!("Created missing {} ({}) for player {}", node_name, node_id, player_id);
+            }
+            let created = created.into_iter()
+                    .map(|(node, node_id)| CreatedNode { node, node_id })
+                    .collect();
+            json_response(&RepairResult { status: "ok", created })
+        }
+        (&Method::POST, "/player/location") => {
+            let Some(admin) = api.check_api_token(&query_params).await else {
+                return Ok(gen_unauthorized());
+            };
+            let Some(player_id) = query_params.get("player_id")
+                                    .and_then(|value| value.parse::<u32>().ok()) else {
+                return Ok(gen_bad_request());
+            };
+            // Without an explicit instance, the player is sent to their Relto
+            let instance_id = match query_params.get("instance_id") {
+                Some(value) => match Uuid::parse_str(value) {
+                    Ok(instance_id) => Some(instance_id),
+                    Err(_) => return Ok(gen_bad_request()),
+                },
+                None => None,
+            };
+            info!("Location change for player {} requested by {}", player_id, admin);
+            let age_info = match set_player_location(player_id, instance_id.as_ref(),
+                                                     &api.vault).await {
+                Ok(age_info) => age_info,
+                Err(NetResultCode::NetPlayerNotFound) => return Ok(gen_player_not_found()),
+                Err(NetResultCode::NetAgeNotFound) => return Ok(gen_age_not_found()),
+                Err(err) => {
+                    warn!("Failed to set location of player {}: {:?}", player_id, err);
+                    return Ok(gen_server_error(&err));
+                }
+            };
+            let result = LocationResult {
+                status: "ok",
+                age_filename: age_info.age_filename().clone(),
+                instance_name: age_info.age_instance_name().clone(),
+                instance_id: age_info.age_instance_uuid().to_string(),
+            };
+            json_response(&result)
         }
+        _ => gen_invalid_request(),
     };
     Ok(response)
 }
 
 pub fn start_api(shutdown_send: broadcast::Sender<()>, vault: Arc<VaultServer>,
-                 server_config: Arc<ServerConfig>)
+                 server_config: Arc<ServerConfig>, ping_stats: PingStats,
+                 session_limiter: SessionLimiter)
 {
     tokio::spawn(async move {
-        let mut shutdown_recv = shutdown_send.subscribe();
-        let api = Arc::new(ApiInterface {
-            server_config,
-            shutdown_send,
-            vault,
-        });
+        let shutdown_recv = shutdown_send.subscribe();
+        let api = Arc::new(ApiInterface::new(server_config, shutdown_send, vault,
+                                             ping_stats, session_limiter));
 
-        let listener = match TcpListener::bind(&api.server_config.api_address).await {
+        let listener = match MultiListener::bind("API service",
+                                    &api.server_config.api_addresses, &api.server_config).await {
             Ok(listener) => listener,
             Err(err) => {
-                warn!("Failed to bind API service: {err}");
+                warn!("Failed to start API service: {err}");
                 return;
             }
         };
 
-        info!("Starting API service on http://{}", api.server_config.api_address);
-        let server = http1::Builder::new();
-        let graceful = GracefulShutdown::new();
+        info!("Starting API service");
+        serve_api(api, listener, shutdown_recv).await;
+    });
+}
 
-        loop {
-            tokio::select! {
-                client = listener.accept() => {
-                    let (stream, _remote_addr) = match client {
-                        Ok(accepted) => accepted,
-                        Err(err) => {
-                            warn!("Failed to accept API connection: {}", err);
-                            continue;
-                        }
-                    };
-
-                    let io = TokioIo::new(stream);
-                    let conn = {
-                        let api = api.clone();
-                        server.serve_connection(io, service_fn(move |request| {
-                            api_router(request, api.clone())
-                        }))
-                    };
-
-                    let graceful_fut = graceful.watch(conn);
-                    tokio::spawn(async move {
-                        if let Err(err) = graceful_fut.await {
-                            warn!("API service error: {err}");
-                        }
-                    });
-                }
+// Serves API requests from the listener until a shutdown is requested
+async fn serve_api(api: Arc<ApiInterface>, mut listener: MultiListener,
+                   mut shutdown_recv: broadcast::Receiver<()>)
+{
+    let server = http1::Builder::new();
+    let graceful = GracefulShutdown::new();
+    let mut connections = JoinSet::new();
 
-                _ = shutdown_recv.recv() => {
-                    drop(listener);
-                    break;
+    loop {
+        tokio::select! {
+            client = listener.accept() => {
+                let (stream, remote_addr) = match client {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Failed to accept API connection: {}", err);
+                        continue;
+                    }
+                };
+                if let Err(err) = configure_stream(&stream, &api.server_config) {
+                    warn!("{} - Failed to set socket options: {}", remote_addr, err);
                 }
+
+                let io = TokioIo::new(stream);
+                let conn = {
+                    let api = api.clone();
+                    server.serve_connection(io, service_fn(move |request| {
+                        api_service(request, api.clone())
+                    }))
+                };
+
+                let graceful_fut = graceful.watch(conn);
+                connections.spawn(async move {
+                    if let Err(err) = graceful_fut.await {
+                        warn!("API service error: {err}");
+                    }
+                });
             }
-        }
 
-        info!("Shutting down API service");
-        tokio::select! {
-            () = graceful.shutdown() => (),
-            () = tokio::time::sleep(Duration::from_secs(10)) => {
-                warn!("API service did not shut down gracefully after 10 seconds; terminating service.");
+            // Reap finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => (),
+
+            _ = shutdown_recv.recv() => {
+                drop(listener);
+                break;
             }
         }
-    });
+    }
+
+    // New connections are no longer accepted, but give in-flight
+    // requests a chance to finish before closing their connections.
+    info!("Shutting down API service");
+    let drain_timeout = api.server_config.api_drain_timeout;
+    tokio::select! {
+        () = graceful.shutdown() => (),
+        () = tokio::time::sleep(drain_timeout) => {
+            while connections.try_join_next().is_some() {}
+            warn!("API service did not shut down gracefully after {} seconds; \
+                   closing {} remaining connection(s).",
+                  drain_timeout.as_secs(), connections.len());
+            connections.abort_all();
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ServerVersion {
+    version: &'static str,
+    git_hash: &'static str,
+    build_id: u32,
+}
+
+#[derive(Serialize)]
+struct ServicePing {
+    samples: u64,
+    avg_rtt_ms: u32,
+    max_rtt_ms: u32,
+}
+
+impl From<PingSummary> for ServicePing {
+    fn from(summary: PingSummary) -> Self {
+        Self {
+            samples: summary.samples,
+            avg_rtt_ms: summary.avg_ms,
+            max_rtt_ms: summary.max_ms,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PingStatus {
+    auth: ServicePing,
+    gate_keeper: ServicePing,
+}
+
+#[derive(Serialize)]
+struct SessionStatus {
+    current: usize,
+    max: usize,
 }
 
 #[derive(Serialize)]
 struct OnlinePlayer {
     name: String,
     location: String,
+    instance_id: String,
+}
+
+#[derive(Deserialize)]
+struct OnlineCheckRequest {
+    #[serde(default)]
+    players: Vec<String>,
+    #[serde(default)]
+    accounts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PlayerStatus {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_id: Option<String>,
+    online: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<String>,
+}
+
+impl PlayerStatus {
+    fn new(player_name: &str, account_id: Option<&Uuid>,
+           online: Option<&VaultPlayerInfoNode>) -> Self
+    {
+        Self {
+            name: online.map_or_else(|| player_name.to_string(),
+                                     |node| node.player_name_ci().clone()),
+            account_id: account_id.map(Uuid::to_string),
+            online: online.is_some(),
+            location: online.map(|node| node.age_instance_name().clone()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RepairResult {
+    status: &'static str,
+    created: Vec<CreatedNode>,
+}
+
+#[derive(Serialize)]
+struct CreatedNode {
+    node: String,
+    node_id: u32,
+}
+
+#[derive(Serialize)]
+struct LocationResult {
+    status: &'static str,
+    age_filename: String,
+    instance_name: String,
+    instance_id: String,
+}
+
+#[derive(Serialize)]
+struct AgeInstance {
+    filename: String,
+    display_name: String,
+    population: u32,
+    public: bool,
+}
+
+#[derive(Serialize)]
+struct AgeList {
+    total: usize,
+    offset: usize,
+    ages: Vec<AgeListEntry>,
+}
+
+#[derive(Serialize)]
+struct AgeListEntry {
+    filename: String,
+    instances: Vec<AgeListInstance>,
+}
+
+#[derive(Serialize)]
+struct AgeListInstance {
+    instance_id: String,
+    display_name: String,
+    population: u32,
+    public: bool,
+}
+
+#[cfg(test)]
+fn test_api(server_config: ServerConfig) -> (Arc<VaultServer>, ApiInterface) {
+    let server_config = Arc::new(server_config);
+    let vault = Arc::new(VaultServer::start(server_config.clone(),
+                                            crate::sdl::DescriptorDb::empty()));
+    let (shutdown_send, _) = broadcast::channel(1);
+    let session_limiter = SessionLimiter::new(server_config.max_sessions);
+    let api = ApiInterface::new(server_config, shutdown_send, vault.clone(), PingStats::new(),
+                                session_limiter);
+    (vault, api)
+}
+
+#[tokio::test]
+async fn test_ban_invalidates_token_cache() {
+    let (vault, api) = test_api(ServerConfig::test_config());
+
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let query = HashMap::from([("token".to_string(), account.api_token.clone())]);
+    assert_eq!(api.check_api_token(&query).await.as_deref(), Some("Tester"));
+    assert!(api.token_cache.lock().unwrap().entries.contains_key(&account.api_token));
+
+    let mut banned = account.clone();
+    banned.account_flags |= AccountInfo::BANNED;
+    vault.update_account(banned).await.unwrap();
+    assert_eq!(api.check_api_token(&query).await, None);
+    assert!(api.token_cache.lock().unwrap().entries.is_empty());
+}
+
+#[tokio::test]
+async fn test_error_body() {
+    let response = gen_player_not_found();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["error"], "Player Not Found");
+    assert_eq!(json["code"], "player_not_found");
+
+    let response = gen_server_error(&NetResultCode::NetVaultNodeNotFound);
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["error"], "Not Found");
+    assert_eq!(json["code"], "vault_node_not_found");
+
+    let response = gen_error(StatusCode::BAD_REQUEST, &NetResultCode::NetInvalidParameter,
+                             "Bad \"name\"\n");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(json["error"], "Bad \"name\"\n");
+}
+
+#[test]
+fn test_net_error_status() {
+    for (result, status) in [
+        (NetResultCode::NetInvalidParameter, StatusCode::BAD_REQUEST),
+        (NetResultCode::NetPlayerNameInvalid, StatusCode::BAD_REQUEST),
+        (NetResultCode::NetAuthenticationFailed, StatusCode::UNAUTHORIZED),
+        (NetResultCode::NetServiceForbidden, StatusCode::FORBIDDEN),
+        (NetResultCode::NetAccountBanned, StatusCode::FORBIDDEN),
+        (NetResultCode::NetAccountNotActivated, StatusCode::FORBIDDEN),
+        (NetResultCode::NetLoginDenied, StatusCode::FORBIDDEN),
+        (NetResultCode::NetAgeNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetFileNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetAccountNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetPlayerNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetVaultNodeNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetStateObjectNotFound, StatusCode::NOT_FOUND),
+        (NetResultCode::NetAccountAlreadyExists, StatusCode::CONFLICT),
+        (NetResultCode::NetPlayerAlreadyExists, StatusCode::CONFLICT),
+        (NetResultCode::NetMaxPlayersOnAcct, StatusCode::CONFLICT),
+        (NetResultCode::NetCircularReference, StatusCode::CONFLICT),
+        (NetResultCode::NetNotSupported, StatusCode::NOT_IMPLEMENTED),
+        (NetResultCode::NetTimeout, StatusCode::SERVICE_UNAVAILABLE),
+        (NetResultCode::NetTimeoutOdbc, StatusCode::SERVICE_UNAVAILABLE),
+        (NetResultCode::NetServerBusy, StatusCode::SERVICE_UNAVAILABLE),
+        (NetResultCode::NetInternalError, StatusCode::INTERNAL_SERVER_ERROR),
+        (NetResultCode::NetBadServerData, StatusCode::INTERNAL_SERVER_ERROR),
+    ] {
+        assert_eq!(net_error_status(&result), status, "{result:?}");
+        assert_eq!(gen_server_error(&result).status(), status, "{result:?}");
+    }
+}
+
+#[tokio::test]
+async fn test_check_online_players() {
+    let (vault, api) = test_api(ServerConfig::test_config());
+
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let online = vault.create_player(&account.account_id, "Online Guy", "male").await.unwrap();
+    let offline = vault.create_player(&account.account_id, "Offline Guy", "male").await.unwrap();
+    for player in [&online, &offline] {
+        let info_id = vault.create_node(VaultPlayerInfoNode::new(&account.account_id,
+                                        player.player_id, &player.player_name)).await.unwrap();
+        if player.player_id == online.player_id {
+            vault.update_node(VaultPlayerInfoNode::new_update(info_id, 1, "Relto",
+                              &Uuid::new_v4())).await.unwrap();
+        }
+    }
+
+    let statuses = api.check_online_players(&["online guy".to_string(), "Nobody".to_string()],
+                                            &[account.account_id]).await.unwrap();
+    let summary: Vec<(&str, bool, Option<&str>)> = statuses.iter()
+            .map(|status| (status.name.as_str(), status.online, status.location.as_deref()))
+            .collect();
+    assert_eq!(summary[0], ("Online Guy", true, Some("Relto")));
+    assert_eq!(summary[1], ("Nobody", false, None));
+    assert_eq!(summary.len(), 4);
+    assert!(summary[2..].contains(&("Online Guy", true, Some("Relto"))));
+    assert!(summary[2..].contains(&("Offline Guy", false, None)));
+    assert!(statuses[2..].iter().all(|status| status.account_id.is_some()));
+}
+
+#[tokio::test]
+async fn test_query_ages() {
+    use crate::vault::GameServer;
+
+    let (vault, api) = test_api(ServerConfig::test_config());
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+
+    // Only running instances are listed, so the Garden is left out
+    let city_id = Uuid::new_v4();
+    for (index, (instance_id, filename, user_name, public, running)) in [
+        (city_id, "city", "", true, true),
+        (Uuid::new_v4(), "Neighborhood", "Test Hood", true, true),
+        (Uuid::new_v4(), "Neighborhood", "Other Hood", false, true),
+        (Uuid::new_v4(), "Garden", "", true, false),
+    ].into_iter().enumerate() {
+        vault.create_node(VaultAgeInfoNode::new(&instance_id, 0, 0, public, -1, &Uuid::nil(),
+                          filename, filename, user_name, "")).await.unwrap();
+        if !running {
+            continue;
+        }
+        vault.add_game_server(GameServer {
+            instance_id,
+            age_filename: filename.to_string(),
+            display_name: filename.to_string(),
+            age_id: 0,
+            sdl_id: 0,
+            temporary: false,
+        }).await.unwrap();
+        let player_name = format!("Visitor {index}");
+        let player = vault.create_player(&account.account_id, &player_name, "male")
+                .await.unwrap();
+        let info_id = vault.create_node(VaultPlayerInfoNode::new(&account.account_id,
+                                        player.player_id, &player_name)).await.unwrap();
+        vault.update_node(VaultPlayerInfoNode::new_update(info_id, 1, filename, &instance_id))
+                .await.unwrap();
+    }
+
+    let all_ages = api.query_ages(None, 0, 10).await.unwrap();
+    assert_eq!(all_ages.total, 3);
+    let filenames: Vec<&str> = all_ages.ages.iter().map(|age| age.filename.as_str()).collect();
+    assert_eq!(filenames, ["Neighborhood", "city"]);
+    assert_eq!(all_ages.ages[0].instances.len(), 2);
+    let city = &all_ages.ages[1].instances[0];
+    assert_eq!(city.instance_id, city_id.to_string());
+    assert_eq!(city.display_name, "city");
+    assert_eq!(city.population, 1);
+    assert!(city.public);
+
+    // Pages are made up of instances, not whole Ages
+    let page = api.query_ages(None, 1, 1).await.unwrap();
+    assert_eq!(page.total, 3);
+    assert_eq!(page.ages.len(), 1);
+    assert_eq!(page.ages[0].filename, "Neighborhood");
+    assert_eq!(page.ages[0].instances.len(), 1);
+    let page = api.query_ages(None, 2, 10).await.unwrap();
+    assert_eq!(page.ages.len(), 1);
+    assert_eq!(page.ages[0].filename, "city");
+
+    let hoods = api.query_ages(Some("Neighborhood"), 0, 10).await.unwrap();
+    assert_eq!(hoods.total, 2);
+    let mut hood_names: Vec<&str> = hoods.ages[0].instances.iter()
+            .map(|instance| instance.display_name.as_str()).collect();
+    hood_names.sort_unstable();
+    assert_eq!(hood_names, ["Other Hood", "Test Hood"]);
+
+    assert_eq!(api.query_ages(Some("Garden"), 0, 10).await.unwrap().total, 0);
+}
+
+// Requires an IPv6 loopback interface, which some test environments (e.g.
+// containers) don't have.  Run with `cargo test -- --ignored`.
+#[tokio::test]
+#[ignore = "requires IPv6 loopback"]
+async fn test_ipv6_listener() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let (_, api) = test_api(ServerConfig::test_config());
+    let listener = MultiListener::bind("API service", &["[::1]:0".to_string()],
+                                       &api.server_config).await.unwrap();
+    let local_addr = listener.local_addrs()[0];
+    assert!(local_addr.is_ipv6());
+
+    let shutdown_send = api.shutdown_send.clone();
+    let server = tokio::spawn(serve_api(Arc::new(api), listener, shutdown_send.subscribe()));
+
+    let mut client = TcpStream::connect(local_addr).await.unwrap();
+    client.write_all(b"GET /version HTTP/1.1\r\nHost: [::1]\r\nConnection: close\r\n\r\n")
+            .await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    assert!(response.contains(&format!("\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+
+    shutdown_send.send(()).unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_set_account_disabled() {
+    let (vault, api) = test_api(ServerConfig::test_config());
+
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let query = HashMap::from([("token".to_string(), account.api_token.clone())]);
+    assert!(api.check_api_token(&query).await.is_some());
+
+    api.set_account_disabled("Tester", true).await.unwrap();
+    let disabled = vault.get_account("Tester").await.unwrap().unwrap();
+    assert!(disabled.is_disabled());
+    assert!(!disabled.is_banned());
+    assert!(disabled.is_admin());
+    assert!(api.check_api_token(&query).await.is_none());
+
+    api.set_account_disabled("Tester", false).await.unwrap();
+    assert!(!vault.get_account("Tester").await.unwrap().unwrap().is_disabled());
+
+    assert_eq!(api.set_account_disabled("Nobody", true).await,
+               Err(NetResultCode::NetAccountNotFound));
+    assert!(vault.get_existing_account("Nobody").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_issue_login_token() {
+    let (vault, api) = test_api(ServerConfig::test_config());
+
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let first_token = api.issue_login_token("Tester").await.unwrap();
+    assert_ne!(first_token, account.api_token);
+    let by_token = vault.get_account_for_login_token(&first_token).await.unwrap().unwrap();
+    assert_eq!(by_token.account_id, account.account_id);
+
+    // Issuing a new token revokes the old one
+    let second_token = api.issue_login_token("Tester").await.unwrap();
+    assert_ne!(first_token, second_token);
+    assert!(vault.get_account_for_login_token(&first_token).await.unwrap().is_none());
+
+    assert_eq!(api.issue_login_token("Nobody").await,
+               Err(NetResultCode::NetAccountNotFound));
+}
+
+#[tokio::test]
+async fn test_request_body_limits() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    let mut server_config = ServerConfig::test_config();
+    server_config.api_max_body_size = 32;
+    server_config.api_body_timeout = Duration::from_millis(200);
+    let (_, api) = test_api(server_config);
+    let listener = MultiListener::bind("API service", &["127.0.0.1:0".to_string()],
+                                       &api.server_config).await.unwrap();
+    let local_addr = listener.local_addrs()[0];
+
+    let shutdown_send = api.shutdown_send.clone();
+    let server = tokio::spawn(serve_api(Arc::new(api), listener, shutdown_send.subscribe()));
+
+    let post = |body_size: usize, body: &'static [u8]| async move {
+        let mut client = TcpStream::connect(local_addr).await.unwrap();
+        let header = format!("POST /online/check HTTP/1.1\r\nHost: localhost\r\n\
+                              Content-Type: application/json\r\nContent-Length: {body_size}\r\n\
+                              Connection: close\r\n\r\n");
+        client.write_all(header.as_bytes()).await.unwrap();
+        client.write_all(body).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    };
+
+    // Bodies within the limit are handled as usual
+    let response = post(2, b"{}").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+
+    // Account lookups require an API token
+    let response = post(18, br#"{"accounts":["x"]}"#).await;
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{response}");
+
+    let response = post(64, &[b' '; 64]).await;
+    assert!(response.starts_with("HTTP/1.1 413 Payload Too Large\r\n"), "{response}");
+
+    // The client never sends the body it promised
+    let response = post(8, b"").await;
+    assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"), "{response}");
+
+    shutdown_send.send(()).unwrap();
+    server.await.unwrap();
+}
+
+#[test]
+fn test_json_content_type() {
+    let body = Bytes::from_static(br#"{"players": ["Somebody"]}"#);
+    let parse = |content_type: Option<&str>| {
+        let mut request = Request::builder();
+        if let Some(content_type) = content_type {
+            request = request.header(CONTENT_TYPE, content_type);
+        }
+        let request = request.body(()).unwrap();
+        parse_json_body::<OnlineCheckRequest>(request.headers(), &body)
+                .map(|request| request.players)
+                .map_err(|response| response.status())
+    };
+
+    assert_eq!(parse(Some("application/json")), Ok(vec!["Somebody".to_string()]));
+    assert_eq!(parse(Some("Application/JSON; charset=utf-8")),
+               Ok(vec!["Somebody".to_string()]));
+    assert_eq!(parse(Some("text/plain")), Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    assert_eq!(parse(Some("application/x-www-form-urlencoded")),
+               Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    assert_eq!(parse(None), Err(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+}
+
+#[tokio::test]
+async fn test_event_stream() {
+    async fn next_event(events: &mut EventStream) -> String {
+        let frame = tokio::time::timeout(Duration::from_secs(5), events.frame()).await
+                .expect("Timed out waiting for an event")
+                .expect("Event stream ended unexpectedly")
+                .unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    let mut server_config = ServerConfig::test_config();
+    server_config.api_event_heartbeat = Duration::from_millis(100);
+    let (vault, api) = test_api(server_config);
+    let shutdown_send = api.shutdown_send.clone();
+    let api = Arc::new(api);
+
+    let response = open_event_stream(&HashMap::new(), api.clone()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let query = HashMap::from([("token".to_string(), account.api_token.clone())]);
+    let response = open_event_stream(&query, api.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "text/event-stream");
+    let Either::Right(mut events) = response.into_body() else {
+        panic!("Expected an event stream body");
+    };
+
+    let player = vault.create_player(&account.account_id, "Event Guy", "male").await.unwrap();
+    let info_id = vault.create_node(VaultPlayerInfoNode::new(&account.account_id,
+                                    player.player_id, &player.player_name)).await.unwrap();
+    vault.update_node(VaultPlayerInfoNode::new_update(info_id, 1, "Relto", &Uuid::new_v4()))
+            .await.unwrap();
+    loop {
+        let event = next_event(&mut events).await;
+        if event.starts_with("event: player_status\n") {
+            assert!(event.contains(r#""name":"Event Guy""#), "{event}");
+            assert!(event.contains(r#""online":true"#), "{event}");
+            assert!(event.contains(r#""location":"Relto""#), "{event}");
+            break;
+        }
+    }
+
+    // With nothing else happening, the stream is kept alive by heartbeats
+    while next_event(&mut events).await != ": heartbeat\n\n" {}
+
+    // Dropping the body (as hyper does when the client disconnects) stops
+    // the streaming task
+    assert_eq!(shutdown_send.receiver_count(), 1);
+    drop(events);
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while shutdown_send.receiver_count() != 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("Event stream task did not stop");
+}
+
+#[test]
+fn test_api_route() {
+    // Without a prefix, paths are used as-is
+    assert_eq!(api_route("/", ""), Some("/"));
+    assert_eq!(api_route("/version", ""), Some("/version"));
+    assert_eq!(api_route("/api/version", ""), Some("/api/version"));
+
+    assert_eq!(api_route("/api", "/api"), Some("/"));
+    assert_eq!(api_route("/api/", "/api"), Some("/"));
+    assert_eq!(api_route("/api/version", "/api"), Some("/version"));
+    assert_eq!(api_route("/api/online/check", "/api"), Some("/online/check"));
+    assert_eq!(api_route("/apifoo", "/api"), None);
+    assert_eq!(api_route("/apifoo/version", "/api"), None);
+    assert_eq!(api_route("/version", "/api"), None);
+    assert_eq!(api_route("/", "/api"), None);
+
+    assert_eq!(api_route("/moulars/api", "/moulars/api"), Some("/"));
+    assert_eq!(api_route("/moulars/api/online", "/moulars/api"), Some("/online"));
+    assert_eq!(api_route("/moulars", "/moulars/api"), None);
+}
+
+#[tokio::test]
+async fn test_event_stream_closed() {
+    let (_, api) = test_api(ServerConfig::test_config());
+    let api = Arc::new(api);
+
+    // Closing the broadcast channel (e.g. when the vault shuts down) ends
+    // the stream without any events
+    let (bcast_send, bcast_recv) = broadcast::channel(4);
+    let (event_send, mut event_recv) = mpsc::channel(EVENT_QUEUE_SIZE);
+    let stream_task = tokio::spawn(stream_vault_events(api, bcast_recv, event_send));
+    drop(bcast_send);
+    tokio::time::timeout(Duration::from_secs(5), stream_task).await
+            .expect("Event stream did not stop").unwrap();
+    assert_eq!(event_recv.recv().await, None);
+}
+
+#[tokio::test]
+async fn test_session_status() {
+    let mut server_config = ServerConfig::test_config();
+    server_config.max_sessions = 2;
+    let (_, api) = test_api(server_config);
+    let session_limiter = api.session_limiter.clone();
+
+    let json = serde_json::to_value(api.session_status()).unwrap();
+    assert_eq!(json, json!({"current": 0, "max": 2}));
+
+    // Fill the server up; further sessions are rejected until one ends
+    let mut sessions: Vec<_> = (0..2).map(|_| session_limiter.try_acquire().unwrap())
+            .collect();
+    assert!(session_limiter.try_acquire().is_none());
+    let json = serde_json::to_value(api.session_status()).unwrap();
+    assert_eq!(json, json!({"current": 2, "max": 2}));
+
+    sessions.pop();
+    assert_eq!(api.session_status().current, 1);
+}
+
+#[test]
+fn test_banner_page() {
+    let mut server_config = ServerConfig::test_config();
+    assert_eq!(banner_page(&server_config), Bytes::from_static(b"OK"));
+
+    server_config.api_banner_header = Some("Welcome to Test Shard\n".to_string());
+    assert_eq!(banner_page(&server_config), "Welcome to Test Shard\nOK\n");
+
+    server_config.api_banner_footer = Some("Contact: admin@example.com".to_string());
+    assert_eq!(banner_page(&server_config),
+               "Welcome to Test Shard\nOK\nContact: admin@example.com\n");
+
+    server_config.api_banner_header = None;
+    assert_eq!(banner_page(&server_config), "OK\nContact: admin@example.com\n");
 }
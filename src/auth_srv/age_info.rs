@@ -21,7 +21,9 @@ use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use uuid::Uuid;
 
 use crate::plasma::{StreamRead, StreamWrite};
+use crate::vault::VaultAgeInfoNode;
 
+#[derive(Eq, PartialEq, Debug)]
 pub struct NetAgeInfo {
     instance_id: Uuid,
     filename: String,
@@ -34,6 +36,23 @@ pub struct NetAgeInfo {
     current_population: u32,
 }
 
+impl NetAgeInfo {
+    #[allow(clippy::cast_sign_loss)]
+    pub fn new(age_info: &VaultAgeInfoNode, population: u32) -> Self {
+        Self {
+            instance_id: *age_info.age_instance_uuid(),
+            filename: age_info.age_filename().clone(),
+            instance_name: age_info.age_instance_name().clone(),
+            user_name: age_info.age_user_defined_name().clone(),
+            description: age_info.age_description().clone(),
+            sequence: age_info.age_sequence_number() as u32,
+            language: age_info.age_language() as u32,
+            population,
+            current_population: population,
+        }
+    }
+}
+
 macro_rules! read_fixed_utf16 {
     ($stream:ident, $len:expr) => ({
         let mut buf = [0u16; $len];
@@ -42,9 +61,11 @@ macro_rules! read_fixed_utf16 {
     })
 }
 
+// The last character is always left as a nul terminator, so strings which
+// are too long for the fixed buffer will be truncated.
 macro_rules! write_fixed_utf16 {
     ($stream:ident, $len:expr, $value:expr) => {
-        for ch in $value.encode_utf16().chain(std::iter::repeat(0u16)).take($len) {
+        for ch in $value.encode_utf16().take($len - 1).chain(std::iter::repeat(0u16)).take($len) {
             $stream.write_u16::<LittleEndian>(ch)?;
         }
     }
@@ -86,3 +107,68 @@ impl StreamWrite for NetAgeInfo {
         Ok(())
     }
 }
+
+#[cfg(test)]
+fn test_age_info() -> NetAgeInfo {
+    NetAgeInfo {
+        instance_id: Uuid::parse_str("0123abcd-4567-89ef-0123-456789abcdef").unwrap(),
+        filename: "Neighborhood".to_string(),
+        instance_name: "Neighborhood".to_string(),
+        user_name: "D'ni Vött".to_string(),
+        description: "D'ni Vött Bevin".to_string(),
+        sequence: 3,
+        language: 0,
+        population: 7,
+        current_population: 5,
+    }
+}
+
+#[test]
+fn test_net_age_info_round_trip() -> Result<()> {
+    use std::io::Cursor;
+
+    let orig_info = test_age_info();
+    let mut stream = Cursor::new(Vec::new());
+    orig_info.stream_write(&mut stream)?;
+    let new_info = NetAgeInfo::stream_read(&mut Cursor::new(stream.into_inner()))?;
+    assert_eq!(orig_info, new_info);
+
+    Ok(())
+}
+
+#[test]
+fn test_net_age_info_layout() -> Result<()> {
+    use std::io::Cursor;
+
+    fn fixed_utf16(value: &str, len: usize) -> Vec<u8> {
+        let mut buffer: Vec<u8> = value.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        buffer.resize(len * 2, 0);
+        buffer
+    }
+
+    let mut expected = vec![
+        0xcd, 0xab, 0x23, 0x01, 0x67, 0x45, 0xef, 0x89,
+        0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
+    ];
+    expected.extend(fixed_utf16("Neighborhood", 64));
+    expected.extend(fixed_utf16("Neighborhood", 64));
+    expected.extend(fixed_utf16("D'ni Vött", 64));
+    expected.extend(fixed_utf16("D'ni Vött Bevin", 1024));
+    expected.extend([3, 0, 0, 0,  0, 0, 0, 0,  7, 0, 0, 0,  5, 0, 0, 0]);
+    assert_eq!(expected.len(), 2464);
+
+    let mut stream = Cursor::new(Vec::new());
+    test_age_info().stream_write(&mut stream)?;
+    assert_eq!(stream.into_inner(), expected);
+
+    // Overlong strings must be truncated, leaving room for the terminator
+    let mut long_info = test_age_info();
+    long_info.filename = "x".repeat(100);
+    let mut stream = Cursor::new(Vec::new());
+    long_info.stream_write(&mut stream)?;
+    let new_info = NetAgeInfo::stream_read(&mut Cursor::new(stream.get_ref()))?;
+    assert_eq!(new_info.filename, "x".repeat(63));
+    assert_eq!(&stream.get_ref()[16 + 126..16 + 128], &[0, 0]);
+
+    Ok(())
+}
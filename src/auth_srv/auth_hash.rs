@@ -48,6 +48,27 @@ pub fn use_email_auth(account_name: &str) -> bool {
     }
 }
 
+// Account names must fit in the client's 64-character name buffer, and may
+// not be blank or have leading/trailing whitespace.  Email-style names (see
+// use_email_auth) may not contain any whitespace, and other user names are
+// limited to letters, digits, spaces and a few punctuation characters.
+pub const MAX_ACCOUNT_NAME_LENGTH: usize = 63;
+const ACCOUNT_NAME_PUNCTUATION: &str = " _-.@+'";
+
+pub fn valid_account_name(account_name: &str) -> bool {
+    if account_name.trim().is_empty() || account_name.trim() != account_name
+            || account_name.chars().count() > MAX_ACCOUNT_NAME_LENGTH
+    {
+        return false;
+    }
+    if use_email_auth(account_name) {
+        !account_name.chars().any(|ch| ch.is_whitespace() || ch.is_control())
+    } else {
+        account_name.chars().all(|ch| ch.is_alphanumeric()
+                                      || ACCOUNT_NAME_PUNCTUATION.contains(ch))
+    }
+}
+
 pub fn create_pass_hash(account_name: &str, password: &str) -> Result<ShaDigest> {
     if use_email_auth(account_name) {
         // This is the broken hash mechanism which truncates one character
@@ -71,3 +92,31 @@ pub fn hash_password_challenge(client_challenge: u32, server_challenge: u32,
     pass_hash.stream_write(&mut buffer)?;
     Ok(ShaDigest::sha0(&buffer.into_inner()))
 }
+
+#[test]
+fn test_valid_account_name() {
+    assert!(valid_account_name("Tester"));
+    assert!(valid_account_name("Test User_01"));
+    assert!(valid_account_name("tester@example.com"));
+    assert!(valid_account_name("tester+moul@mail.example.com"));
+    assert!(valid_account_name("tester@gametap.com"));
+    assert!(valid_account_name(&"a".repeat(MAX_ACCOUNT_NAME_LENGTH)));
+
+    // Blank names
+    assert!(!valid_account_name(""));
+    assert!(!valid_account_name(" "));
+    assert!(!valid_account_name("\t \n"));
+
+    // Over-long names
+    assert!(!valid_account_name(&"a".repeat(MAX_ACCOUNT_NAME_LENGTH + 1)));
+    let long_email = format!("{}@example.com", "a".repeat(MAX_ACCOUNT_NAME_LENGTH));
+    assert!(!valid_account_name(&long_email));
+
+    // Whitespace and disallowed characters
+    assert!(!valid_account_name(" Tester"));
+    assert!(!valid_account_name("Tester\n"));
+    assert!(!valid_account_name("Test\u{7}er"));
+    assert!(!valid_account_name("Test/er"));
+    assert!(!valid_account_name("Test\"er"));
+    assert!(!valid_account_name("test er@example.com"));
+}
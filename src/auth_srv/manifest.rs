@@ -17,7 +17,7 @@
 use std::ffi::OsStr;
 use std::io::{BufRead, Write, Cursor};
 use std::mem::size_of;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
@@ -48,6 +48,18 @@ impl StreamRead for FileInfo {
     }
 }
 
+impl FileInfo {
+    pub fn new(path: String, file_size: u32) -> Self {
+        Self { path, file_size }
+    }
+
+    // Size of the encoded entry, in UTF-16 characters.  The path is
+    // nul-terminated, and the file size is encoded as 3 characters.
+    fn char_count(&self) -> usize {
+        self.path.encode_utf16().count() + 1 + 3
+    }
+}
+
 impl StreamWrite for FileInfo {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
         write_utf16z_text(stream, &self.path)?;
@@ -73,8 +85,31 @@ impl Manifest {
                     continue;
                 };
                 let client_path = format!("{}\\{}", directory, entry.file_name().to_string_lossy());
-                files.push(FileInfo { path: client_path, file_size });
+                files.push(FileInfo::new(client_path, file_size));
+            }
+        }
+        Ok(Manifest { files })
+    }
+
+    // Lists the files from several roots (see ServerConfig::auth_data_roots).
+    // Files in earlier roots take precedence over files with the same name in
+    // later ones, and roots without the directory are skipped.
+    pub fn from_dirs(data_roots: &[PathBuf], directory: &str, ext: &str) -> Result<Self> {
+        let mut files: Vec<FileInfo> = Vec::new();
+        let mut found_dir = false;
+        for data_root in data_roots {
+            if !data_root.join(directory).is_dir() {
+                continue;
             }
+            found_dir = true;
+            for file in Self::from_dir(data_root, directory, ext)?.files {
+                if !files.iter().any(|existing| existing.path.eq_ignore_ascii_case(&file.path)) {
+                    files.push(file);
+                }
+            }
+        }
+        if !found_dir {
+            return Err(anyhow!("Directory '{}' not found", directory));
         }
         Ok(Manifest { files })
     }
@@ -82,6 +117,14 @@ impl Manifest {
     pub fn files(&self) -> &Vec<FileInfo> { &self.files }
     pub fn files_mut(&mut self) -> &mut Vec<FileInfo> { &mut self.files }
     pub fn add(&mut self, file: FileInfo) { self.files.push(file); }
+
+    // Size of the encoded file list (including the terminator), in UTF-16
+    // characters.  This is computed up front so the list can be streamed
+    // without buffering it first.
+    pub fn char_count(&self) -> Result<u32> {
+        let char_count = self.files.iter().map(FileInfo::char_count).sum::<usize>() + 1;
+        u32::try_from(char_count).context("Manifest entry too large for stream")
+    }
 }
 
 impl StreamRead for Manifest {
@@ -109,17 +152,34 @@ impl StreamRead for Manifest {
 
 impl StreamWrite for Manifest {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
-        let mut file_stream = Cursor::new(Vec::new());
+        stream.write_u32::<LittleEndian>(self.char_count()?)?;
         for file in &self.files {
-            file.stream_write(&mut file_stream)?;
+            file.stream_write(stream)?;
         }
-        file_stream.write_u16::<LittleEndian>(0)?;
-
-        let file_buf = file_stream.into_inner();
-        assert_eq!(file_buf.len() % size_of::<u16>(), 0);
-        let entry_size = u32::try_from(file_buf.len() / size_of::<u16>())
-                .context("Manifest entry too large for stream")?;
-        stream.write_u32::<LittleEndian>(entry_size)?;
-        Ok(stream.write_all(file_buf.as_slice())?)
+        stream.write_u16::<LittleEndian>(0)?;
+        Ok(())
     }
 }
+
+#[test]
+fn test_large_manifest() -> Result<()> {
+    let mut manifest = Manifest::new();
+    for idx in 0..5000 {
+        manifest.add(FileInfo { path: format!("SDL\\Descriptor{idx:04}.sdl"), file_size: idx });
+    }
+    manifest.add(FileInfo { path: "SDL\\Ünicode.sdl".to_string(), file_size: 0x12345678 });
+
+    let mut stream = Cursor::new(Vec::new());
+    manifest.stream_write(&mut stream)?;
+    let buffer = stream.into_inner();
+    assert_eq!(buffer.len(), size_of::<u32>() + manifest.char_count()? as usize * size_of::<u16>());
+
+    let result = Manifest::stream_read(&mut Cursor::new(buffer))?;
+    assert_eq!(result.files().len(), manifest.files().len());
+    for (expected, actual) in manifest.files().iter().zip(result.files()) {
+        assert_eq!(expected.path, actual.path);
+        assert_eq!(expected.file_size, actual.file_size);
+    }
+
+    Ok(())
+}
@@ -20,16 +20,16 @@ use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, WriteBytesExt};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use tokio::io::{AsyncReadExt, BufReader};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use uuid::Uuid;
 
 use crate::hashes::ShaDigest;
-use crate::net_crypt::CryptTcpStream;
-use crate::netcli::NetResultCode;
+use crate::msg_trace::{display_as_debug, MsgId, MsgTrace};
+use crate::netcli::{KickReason, NetResultCode};
 use crate::plasma::{StreamWrite, net_io};
 use crate::vault::NodeRef;
 use super::age_info::NetAgeInfo;
-use super::manifest::Manifest;
+use super::manifest::{FileInfo, Manifest};
 
 pub enum CliToAuth {
     PingRequest {
@@ -499,7 +499,7 @@ pub enum AuthToCli {
 }
 
 #[repr(u16)]
-#[derive(FromPrimitive)]
+#[derive(Debug, FromPrimitive)]
 enum ClientMsgId {
     PingRequest = 0,
     ClientRegisterRequest,
@@ -562,6 +562,7 @@ enum ClientMsgId {
 }
 
 #[repr(u16)]
+#[derive(Debug, FromPrimitive)]
 enum ServerMsgId {
     PingReply = 0,
     ServerAddr,
@@ -619,13 +620,40 @@ enum ServerMsgId {
     ServerCaps,
 }
 
-const MAX_NODE_BUFFER_SIZE: u32 = 1024 * 1024;
+display_as_debug!(ClientMsgId, ServerMsgId);
+
 const MAX_PING_PAYLOAD: u32 = 64 * 1024;
-const MAX_PROPAGATE_BUFFER_SIZE: u32 = 1024 * 1024;
+
+// Length limits (in UTF-16 code units) for strings sent by the client.
+// Names are kept short; the client itself limits player names to 40
+// characters.  Log messages are allowed the full range of the u16 length.
+const MAX_NAME_LENGTH: u16 = 64;
+const MAX_STRING_LENGTH: u16 = 1024;
+const MAX_LOG_LENGTH: u16 = u16::MAX;
+
+// Size limits for the variable-sized buffers a client may send
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BufferLimits {
+    pub max_node_buffer_size: u32,
+    pub max_propagate_buffer_size: u32,
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self {
+            max_node_buffer_size: 1024 * 1024,
+            max_propagate_buffer_size: 1024 * 1024,
+        }
+    }
+}
 
 impl CliToAuth {
-    pub async fn read(stream: &mut BufReader<CryptTcpStream>) -> Result<Self> {
+    pub async fn read<S>(stream: &mut S, limits: &BufferLimits, trace: &MsgTrace)
+        -> Result<Self>
+        where S: AsyncRead + Unpin
+    {
         let msg_id = stream.read_u16_le().await?;
+        trace.recv(&MsgId { raw: msg_id.into(), name: ClientMsgId::from_u16(msg_id) });
         match ClientMsgId::from_u16(msg_id) {
             Some(ClientMsgId::PingRequest) => {
                 let ping_time = stream.read_u32_le().await?;
@@ -644,10 +672,10 @@ impl CliToAuth {
             Some(ClientMsgId::AcctLoginRequest) => {
                 let trans_id = stream.read_u32_le().await?;
                 let client_challenge = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let pass_hash = ShaDigest::read(stream).await?;
-                let auth_token = net_io::read_utf16_str(stream).await?;
-                let os = net_io::read_utf16_str(stream).await?;
+                let auth_token = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
+                let os = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::AcctLoginRequest {
                     trans_id, client_challenge, account_name, pass_hash,
                     auth_token, os
@@ -668,7 +696,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AcctCreateRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let auth_hash = ShaDigest::read(stream).await?;
                 let account_flags = stream.read_u32_le().await?;
                 let billing_type = stream.read_u32_le().await?;
@@ -679,7 +707,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AcctChangePasswordRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let auth_hash = ShaDigest::read(stream).await?;
                 Ok(CliToAuth::AcctChangePasswordRequest {
                     trans_id, account_name, auth_hash
@@ -687,7 +715,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AcctSetRolesRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let account_flags = stream.read_u32_le().await?;
                 Ok(CliToAuth::AcctSetRolesRequest {
                     trans_id, account_name, account_flags
@@ -695,7 +723,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AcctSetBillingTypeRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let billing_type = stream.read_u32_le().await?;
                 Ok(CliToAuth::AcctSetBillingTypeRequest {
                     trans_id, account_name, billing_type
@@ -708,7 +736,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AcctCreateFromKeyRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let auth_hash = ShaDigest::read(stream).await?;
                 let key = net_io::read_uuid(stream).await?;
                 let billing_type = stream.read_u32_le().await?;
@@ -735,9 +763,9 @@ impl CliToAuth {
             }
             Some(ClientMsgId::PlayerCreateRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let player_name = net_io::read_utf16_str(stream).await?;
-                let avatar_shape = net_io::read_utf16_str(stream).await?;
-                let friend_invite = net_io::read_utf16_str(stream).await?;
+                let player_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
+                let avatar_shape = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
+                let friend_invite = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
                 Ok(CliToAuth::PlayerCreateRequest {
                     trans_id, player_name, avatar_shape, friend_invite
                 })
@@ -770,7 +798,7 @@ impl CliToAuth {
             Some(ClientMsgId::ChangePlayerNameRequest) => {
                 let trans_id = stream.read_u32_le().await?;
                 let player_id = stream.read_u32_le().await?;
-                let new_name = net_io::read_utf16_str(stream).await?;
+                let new_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::ChangePlayerNameRequest {
                     trans_id, player_id, new_name
                 })
@@ -778,15 +806,15 @@ impl CliToAuth {
             Some(ClientMsgId::SendFriendInviteRequest) => {
                 let trans_id = stream.read_u32_le().await?;
                 let invite_id = net_io::read_uuid(stream).await?;
-                let email_address = net_io::read_utf16_str(stream).await?;
-                let to_player = net_io::read_utf16_str(stream).await?;
+                let email_address = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
+                let to_player = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::SendFriendInviteRequest {
                     trans_id, invite_id, email_address, to_player
                 })
             }
             Some(ClientMsgId::VaultNodeCreate) => {
                 let trans_id = stream.read_u32_le().await?;
-                let node_buffer = net_io::read_sized_buffer(stream, MAX_NODE_BUFFER_SIZE).await?;
+                let node_buffer = net_io::read_sized_buffer(stream, limits.max_node_buffer_size).await?;
                 Ok(CliToAuth::VaultNodeCreate { trans_id, node_buffer })
             }
             Some(ClientMsgId::VaultNodeFetch) => {
@@ -798,7 +826,7 @@ impl CliToAuth {
                 let trans_id = stream.read_u32_le().await?;
                 let node_id = stream.read_u32_le().await?;
                 let revision = net_io::read_uuid(stream).await?;
-                let node_buffer = net_io::read_sized_buffer(stream, MAX_NODE_BUFFER_SIZE).await?;
+                let node_buffer = net_io::read_sized_buffer(stream, limits.max_node_buffer_size).await?;
                 Ok(CliToAuth::VaultNodeSave {
                     trans_id, node_id, revision, node_buffer
                 })
@@ -831,10 +859,10 @@ impl CliToAuth {
                 let trans_id = stream.read_u32_le().await?;
                 let age_instance_id = net_io::read_uuid(stream).await?;
                 let parent_age_instance_id = net_io::read_uuid(stream).await?;
-                let age_filename = net_io::read_utf16_str(stream).await?;
-                let age_instance_name = net_io::read_utf16_str(stream).await?;
-                let age_user_name = net_io::read_utf16_str(stream).await?;
-                let age_description = net_io::read_utf16_str(stream).await?;
+                let age_filename = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
+                let age_instance_name = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
+                let age_user_name = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
+                let age_description = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
                 let age_sequence = stream.read_i32_le().await?;
                 let age_language = stream.read_i32_le().await?;
                 Ok(CliToAuth::VaultInitAgeRequest {
@@ -845,7 +873,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::VaultNodeFind) => {
                 let trans_id = stream.read_u32_le().await?;
-                let node_buffer = net_io::read_sized_buffer(stream, MAX_NODE_BUFFER_SIZE).await?;
+                let node_buffer = net_io::read_sized_buffer(stream, limits.max_node_buffer_size).await?;
                 Ok(CliToAuth::VaultNodeFind { trans_id, node_buffer })
             }
             Some(ClientMsgId::VaultSetSeen) => {
@@ -861,19 +889,19 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AgeRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let age_name = net_io::read_utf16_str(stream).await?;
+                let age_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let age_instance_id = net_io::read_uuid(stream).await?;
                 Ok(CliToAuth::AgeRequest { trans_id, age_name, age_instance_id })
             }
             Some(ClientMsgId::FileListRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let directory = net_io::read_utf16_str(stream).await?;
-                let ext = net_io::read_utf16_str(stream).await?;
+                let directory = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
+                let ext = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::FileListRequest { trans_id, directory, ext })
             }
             Some(ClientMsgId::FileDownloadRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let filename = net_io::read_utf16_str(stream).await?;
+                let filename = net_io::read_utf16_str(stream, MAX_STRING_LENGTH).await?;
                 Ok(CliToAuth::FileDownloadRequest { trans_id, filename })
             }
             Some(ClientMsgId::FileDownloadChunkAck) => {
@@ -882,12 +910,13 @@ impl CliToAuth {
             }
             Some(ClientMsgId::PropagateBuffer) => {
                 let type_id = stream.read_u32_le().await?;
-                let buffer = net_io::read_sized_buffer(stream, MAX_PROPAGATE_BUFFER_SIZE).await?;
+                let buffer = net_io::read_sized_buffer(stream,
+                                            limits.max_propagate_buffer_size).await?;
                 Ok(CliToAuth::PropagateBuffer { type_id, buffer })
             }
             Some(ClientMsgId::GetPublicAgeList) => {
                 let trans_id = stream.read_u32_le().await?;
-                let age_filename = net_io::read_utf16_str(stream).await?;
+                let age_filename = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::GetPublicAgeList { trans_id, age_filename })
             }
             Some(ClientMsgId::SetAgePublic) => {
@@ -896,11 +925,11 @@ impl CliToAuth {
                 Ok(CliToAuth::SetAgePublic { age_info_id, public })
             }
             Some(ClientMsgId::LogPythonTraceback) => {
-                let traceback = net_io::read_utf16_str(stream).await?;
+                let traceback = net_io::read_utf16_str(stream, MAX_LOG_LENGTH).await?;
                 Ok(CliToAuth::LogPythonTraceback { traceback })
             }
             Some(ClientMsgId::LogStackDump) => {
-                let stackdump = net_io::read_utf16_str(stream).await?;
+                let stackdump = net_io::read_utf16_str(stream, MAX_LOG_LENGTH).await?;
                 Ok(CliToAuth::LogStackDump { stackdump })
             }
             Some(ClientMsgId::LogClientDebuggerConnect) => {
@@ -910,7 +939,7 @@ impl CliToAuth {
             Some(ClientMsgId::ScoreCreate) => {
                 let trans_id = stream.read_u32_le().await?;
                 let owner_id = stream.read_u32_le().await?;
-                let game_name = net_io::read_utf16_str(stream).await?;
+                let game_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let game_type = stream.read_u32_le().await?;
                 let value = stream.read_u32_le().await?;
                 Ok(CliToAuth::ScoreCreate {
@@ -925,7 +954,7 @@ impl CliToAuth {
             Some(ClientMsgId::ScoreGetScores) => {
                 let trans_id = stream.read_u32_le().await?;
                 let owner_id = stream.read_u32_le().await?;
-                let game_name = net_io::read_utf16_str(stream).await?;
+                let game_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::ScoreGetScores { trans_id, owner_id, game_name })
             }
             Some(ClientMsgId::ScoreAddPoints) => {
@@ -954,7 +983,7 @@ impl CliToAuth {
                 let owner_id = stream.read_u32_le().await?;
                 let score_group = stream.read_u32_le().await?;
                 let parent_folder_id = stream.read_u32_le().await?;
-                let game_name = net_io::read_utf16_str(stream).await?;
+                let game_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 let time_period = stream.read_u32_le().await?;
                 let num_results = stream.read_u32_le().await?;
                 let page_number = stream.read_u32_le().await?;
@@ -967,7 +996,7 @@ impl CliToAuth {
             }
             Some(ClientMsgId::AccountExistsRequest) => {
                 let trans_id = stream.read_u32_le().await?;
-                let account_name = net_io::read_utf16_str(stream).await?;
+                let account_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::AccountExistsRequest { trans_id, account_name })
             }
             Some(ClientMsgId::AgeRequestEx) => {
@@ -978,7 +1007,7 @@ impl CliToAuth {
                 let trans_id = stream.read_u32_le().await?;
                 let age_id = stream.read_u32_le().await?;
                 let max_scores = stream.read_u32_le().await?;
-                let game_name = net_io::read_utf16_str(stream).await?;
+                let game_name = net_io::read_utf16_str(stream, MAX_NAME_LENGTH).await?;
                 Ok(CliToAuth::ScoreGetHighScores {
                     trans_id, age_id, max_scores, game_name
                 })
@@ -988,7 +1017,44 @@ impl CliToAuth {
     }
 }
 
+// File lists are flushed to the client whenever this much data is buffered
+const FILE_LIST_CHUNK_SIZE: usize = 64 * 1024;
+
+// Encodes a successful FileListReply one chunk at a time, so the entire
+// (potentially very large) manifest never needs to be buffered at once.
+pub struct FileListChunks<'a> {
+    files: std::slice::Iter<'a, FileInfo>,
+    buffer: Vec<u8>,
+    done: bool,
+}
+
+impl FileListChunks<'_> {
+    pub fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.done {
+            return Ok(None);
+        }
+        for file in self.files.by_ref() {
+            file.stream_write(&mut self.buffer)?;
+            if self.buffer.len() >= FILE_LIST_CHUNK_SIZE {
+                return Ok(Some(std::mem::take(&mut self.buffer)));
+            }
+        }
+        self.buffer.write_u16::<LittleEndian>(0)?;
+        self.done = true;
+        Ok(Some(std::mem::take(&mut self.buffer)))
+    }
+}
+
 impl AuthToCli {
+    pub fn file_list_chunks(trans_id: u32, manifest: &Manifest) -> Result<FileListChunks<'_>> {
+        let mut buffer = Vec::with_capacity(FILE_LIST_CHUNK_SIZE);
+        buffer.write_u16::<LittleEndian>(ServerMsgId::FileListReply as u16)?;
+        buffer.write_u32::<LittleEndian>(trans_id)?;
+        buffer.write_i32::<LittleEndian>(NetResultCode::NetSuccess as i32)?;
+        buffer.write_u32::<LittleEndian>(manifest.char_count()?)?;
+        Ok(FileListChunks { files: manifest.files().iter(), buffer, done: false })
+    }
+
     pub fn download_error(trans_id: u32, result: NetResultCode) -> Self {
         Self::FileDownloadChunk {
             trans_id,
@@ -999,6 +1065,10 @@ impl AuthToCli {
         }
     }
 
+    pub fn kicked_off(reason: KickReason) -> Self {
+        Self::KickedOff { reason: reason.result_code() as i32 }
+    }
+
     pub fn login_error(trans_id: u32, result: NetResultCode) -> Self {
         Self::AcctLoginReply {
             trans_id,
@@ -1022,6 +1092,16 @@ impl AuthToCli {
     }
 }
 
+impl AuthToCli {
+    // Trace the message ID of an already serialized reply
+    pub fn trace_sent(msg_buf: &[u8], trace: &MsgTrace) {
+        if let Some(&[lo, hi]) = msg_buf.get(0..2) {
+            let msg_id = u16::from_le_bytes([lo, hi]);
+            trace.send(&MsgId { raw: msg_id.into(), name: ServerMsgId::from_u16(msg_id) });
+        }
+    }
+}
+
 impl StreamWrite for AuthToCli {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
         match self {
@@ -1323,3 +1403,66 @@ impl StreamWrite for AuthToCli {
         Ok(())
     }
 }
+
+#[test]
+fn test_file_list_chunks() -> Result<()> {
+    let mut manifest = Manifest::new();
+    for idx in 0..5000 {
+        manifest.add(FileInfo::new(format!("SDL\\Descriptor{idx:04}.sdl"), idx));
+    }
+
+    // No chunk holds more than one entry past the flush size, no matter how
+    // large the whole list is
+    let mut max_entry_size = 0;
+    for file in manifest.files() {
+        let mut entry = Vec::new();
+        file.stream_write(&mut entry)?;
+        max_entry_size = max_entry_size.max(entry.len());
+    }
+    let mut streamed = Vec::new();
+    let mut chunks = AuthToCli::file_list_chunks(42, &manifest)?;
+    let mut chunk_count = 0;
+    while let Some(chunk) = chunks.next_chunk()? {
+        assert!(chunk.len() < FILE_LIST_CHUNK_SIZE + max_entry_size,
+                "Chunk of {} bytes exceeds the buffer bound", chunk.len());
+        streamed.extend_from_slice(&chunk);
+        chunk_count += 1;
+    }
+    assert!(chunk_count > 1);
+    assert!(streamed.len() > 2 * FILE_LIST_CHUNK_SIZE);
+
+    let mut buffered = std::io::Cursor::new(Vec::new());
+    AuthToCli::FileListReply {
+        trans_id: 42,
+        result: NetResultCode::NetSuccess as i32,
+        manifest,
+    }.stream_write(&mut buffered)?;
+    assert_eq!(streamed, buffered.into_inner());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_node_buffer_limits() {
+    let limits = BufferLimits::default();
+    let admin_limits = BufferLimits {
+        max_node_buffer_size: 4 * 1024 * 1024,
+        ..limits
+    };
+
+    let node_buffer = vec![0x55; 2 * 1024 * 1024];
+    let mut message = Vec::new();
+    message.write_u16::<LittleEndian>(ClientMsgId::VaultNodeCreate as u16).unwrap();
+    message.write_u32::<LittleEndian>(1).unwrap();
+    net_io::write_sized_buffer(&mut message, &node_buffer).unwrap();
+
+    let trace = MsgTrace::disabled();
+    assert!(CliToAuth::read(&mut message.as_slice(), &limits, &trace).await.is_err());
+    match CliToAuth::read(&mut message.as_slice(), &admin_limits, &trace).await {
+        Ok(CliToAuth::VaultNodeCreate { trans_id, node_buffer: buffer }) => {
+            assert_eq!(trans_id, 1);
+            assert_eq!(buffer, node_buffer);
+        }
+        _ => panic!("Failed to read node buffer with admin limits"),
+    }
+}
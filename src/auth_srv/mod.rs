@@ -22,8 +22,15 @@ mod manifest;
 pub use manifest::{FileInfo, Manifest};
 
 mod messages;
+pub use messages::BufferLimits;
+#[cfg(feature = "fuzzing")]
+pub(crate) use messages::CliToAuth;
+
+mod name_filter;
+pub use name_filter::NameFilter;
 
 mod server;
 pub use server::AuthServer;
 
 mod vault_helpers;
+pub use vault_helpers::{age_display_name, repair_player_nodes, set_player_location};
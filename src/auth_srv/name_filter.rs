@@ -0,0 +1,81 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Checks player-chosen names against the server's configured rules.
+// Banned words are matched as case-insensitive substrings, so e.g. "Bad"
+// will also reject "xXbAdXx".
+pub struct NameFilter {
+    banned_words: Vec<String>,
+    max_length: usize,
+}
+
+impl NameFilter {
+    pub fn new(banned_words: &[String], max_length: usize) -> Self {
+        Self {
+            banned_words: banned_words.iter()
+                    .map(|word| word.trim().to_lowercase())
+                    .filter(|word| !word.is_empty())
+                    .collect(),
+            max_length,
+        }
+    }
+
+    // Returns a description of why the name was rejected, or None if the
+    // name is acceptable.
+    pub fn check(&self, name: &str) -> Option<&'static str> {
+        let length = name.chars().count();
+        if name.trim().is_empty() {
+            return Some("empty name");
+        } else if length > self.max_length {
+            return Some("name too long");
+        } else if name.chars().any(char::is_control) {
+            return Some("control characters");
+        }
+
+        let name = name.to_lowercase();
+        if self.banned_words.iter().any(|word| name.contains(word.as_str())) {
+            Some("banned word")
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_name_filter() {
+    let filter = NameFilter::new(&["Badword".to_string(), "ÄRGER".to_string(),
+                                   "  ".to_string()], 20);
+
+    // Allowed names
+    assert_eq!(filter.check("Atrus"), None);
+    assert_eq!(filter.check("Yeesha of D'ni"), None);
+    assert_eq!(filter.check("Arger the Explorer"), None);
+    assert_eq!(filter.check("12345678901234567890"), None);
+    assert_eq!(filter.check("Ünïcödé Nämé"), None);
+
+    // Banned words, regardless of case
+    assert_eq!(filter.check("badword"), Some("banned word"));
+    assert_eq!(filter.check("xXBaDwOrDXx"), Some("banned word"));
+    assert_eq!(filter.check("Großer Ärger"), Some("banned word"));
+    assert_eq!(filter.check("großer ärger"), Some("banned word"));
+
+    // Structural checks
+    assert_eq!(filter.check(""), Some("empty name"));
+    assert_eq!(filter.check("   "), Some("empty name"));
+    assert_eq!(filter.check("123456789012345678901"), Some("name too long"));
+    assert_eq!(filter.check("Bell\x07"), Some("control characters"));
+    assert_eq!(filter.check("Line\nBreak"), Some("control characters"));
+}
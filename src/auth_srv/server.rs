@@ -15,33 +15,48 @@
  */
 
 use std::io::{self, BufRead, Cursor};
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use log::{error, warn, info, debug};
 use tokio::io::{AsyncReadExt, BufReader};
-use tokio::sync::{mpsc, broadcast};
+use tokio::sync::{mpsc, broadcast, broadcast::error::RecvError};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
+use unicase::UniCase;
 use uuid::Uuid;
 
 use crate::config::ServerConfig;
+use crate::conn_limit::{ConnectionGuard, SessionGuard, SessionLimiter};
 use crate::hashes::ShaDigest;
+use crate::msg_trace::MsgTrace;
 use crate::net_crypt::CryptTcpStream;
-use crate::netcli::NetResultCode;
+use crate::netcli::{KickReason, NetResult, NetResultCode};
 use crate::path_utils;
+use crate::ping_stats::{PingService, PingStats, PingTracker};
 use crate::plasma::{StreamRead, StreamWrite, BitVector};
-use crate::vault::{VaultServer, VaultNode, VaultPlayerInfoNode};
+use crate::sdl::SdlLimits;
+use crate::vault::{
+    NodeType, VaultServer, VaultNode, VaultPlayerInfoNode, VaultAgeInfoNode, AccountInfo,
+    VaultSdlNode, PlayerInfo
+};
 use crate::vault::messages::VaultBroadcast;
-use super::auth_hash::{hash_password_challenge, use_email_auth};
+use super::age_info::NetAgeInfo;
+use super::auth_hash::{hash_password_challenge, use_email_auth, valid_account_name};
 use super::manifest::Manifest;
 use super::messages::{CliToAuth, AuthToCli};
-use super::vault_helpers::{create_player_nodes, find_age_instance};
+use super::vault_helpers::{
+    add_ccr_players, bootstrap_first_player, can_modify_node,
+    check_client_sdl, check_node_create, create_new_player, fetch_client_refs,
+    find_age_instance, find_game_server
+};
 
 pub struct AuthServer {
-    incoming_send: mpsc::Sender<TcpStream>,
+    incoming_send: mpsc::Sender<(TcpStream, ConnectionGuard)>,
 }
 
 struct AuthServerWorker {
@@ -50,12 +65,45 @@ struct AuthServerWorker {
     vault: Arc<VaultServer>,
     vault_bcast: broadcast::Receiver<VaultBroadcast>,
     server_challenge: u32,
+    // The build ID from ClientRegisterRequest, which selects the build's
+    // Python and SDL files when build_data_dirs is enabled
+    build_id: u32,
     account_id: Option<Uuid>,
+    billing_type: u32,
+    is_admin: bool,
+    is_ccr: bool,
+    ccr_level: u32,
+    player: ActivePlayer,
+    ping_tracker: PingTracker,
+    trace: MsgTrace,
+    session_limiter: SessionLimiter,
+    session_guard: Option<SessionGuard>,
+}
+
+// Holds the currently active player for a client.  If the worker is dropped
+// without a clean disconnect (e.g. because a message handler panicked), the
+// player is still marked offline.
+struct ActivePlayer {
+    vault: Arc<VaultServer>,
     player_id: Option<u32>,
 }
 
+impl Drop for ActivePlayer {
+    fn drop(&mut self) {
+        if let Some(player_id) = self.player_id.take() {
+            let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                warn!("Cannot set player {} offline: No runtime available", player_id);
+                return;
+            };
+            let vault = self.vault.clone();
+            runtime.spawn(async move {
+                set_player_offline(&vault, player_id).await;
+            });
+        }
+    }
+}
+
 const CONN_HEADER_SIZE: u32 = 20;
-const FILE_CHUNK_SIZE: usize = 64 * 1024;
 
 enum ServerCaps {
     ScoreLeaderBoards,
@@ -82,8 +130,7 @@ async fn init_client(mut sock: TcpStream, server_config: &ServerConfig)
     sock.read_exact(&mut header).await?;
     read_conn_header(&mut Cursor::new(header))?;
 
-    crate::net_crypt::init_crypt(sock, &server_config.auth_n_key,
-                                 &server_config.auth_k_key).await
+    crate::net_crypt::init_crypt(sock, &server_config.auth_keys).await
 }
 
 fn check_file_request(dir_name: &str, ext: &str) -> bool {
@@ -91,13 +138,16 @@ fn check_file_request(dir_name: &str, ext: &str) -> bool {
         || (dir_name == "SDL" && ext == "sdl")
 }
 
-fn fetch_list(dir_name: &str, ext: &str, data_root: &Path) -> Option<Manifest> {
+fn fetch_list(dir_name: &str, ext: &str, server_config: &ServerConfig, build_id: u32)
+    -> Option<Manifest>
+{
     // Whitelist what the client is allowed to request.
     if !check_file_request(dir_name, ext) {
         return None;
     }
 
-    match Manifest::from_dir(data_root, dir_name, ext) {
+    let data_roots = server_config.auth_data_roots(dir_name, build_id);
+    match Manifest::from_dirs(&data_roots, dir_name, ext) {
         Ok(manifest) => Some(manifest),
         Err(err) => {
             warn!("Failed to fetch directory list for {}\\*.{}: {}", dir_name, ext, err);
@@ -106,18 +156,16 @@ fn fetch_list(dir_name: &str, ext: &str, data_root: &Path) -> Option<Manifest> {
     }
 }
 
-async fn open_server_file(filename: &str, data_root: &Path)
+async fn open_server_file(filename: &str, server_config: &ServerConfig, build_id: u32)
     -> Option<(tokio::fs::File, std::fs::Metadata, PathBuf)>
 {
     let path_parts: Vec<&str> = filename.split('\\').collect();
-    if path_parts.len() != 2 {
+    if path_parts.len() != 2 || path_parts[1].contains(['/', ':']) {
         // The requested path should be exactly "<dir>\<file>.<ext>"
         return None;
     }
     let native_path = path_utils::to_native(filename);
-    let download_path = data_root.join(native_path);
-
-    let ext = download_path.extension().unwrap_or_default();
+    let ext = Path::new(&native_path).extension().unwrap_or_default();
     if !check_file_request(path_parts[0], &ext.to_string_lossy())
         || path_parts[1].starts_with('.')
     {
@@ -125,6 +173,13 @@ async fn open_server_file(filename: &str, data_root: &Path)
         return None;
     }
 
+    // Build-specific files take precedence over the shared ones, and each
+    // file must stay within the configured root for its directory
+    let download_path = server_config.auth_data_roots(path_parts[0], build_id).iter()
+            .map(|root| (root.join(&native_path), root))
+            .find(|(path, root)| path.starts_with(root) && path.exists())
+            .map(|(path, _)| path)?;
+
     let file = match tokio::fs::File::open(&download_path).await {
         Ok(file) => file,
         Err(err) => {
@@ -145,31 +200,35 @@ async fn open_server_file(filename: &str, data_root: &Path)
 }
 
 impl AuthServer {
-    pub fn start(server_config: Arc<ServerConfig>, vault: Arc<VaultServer>)
-        -> AuthServer
+    pub fn start(server_config: Arc<ServerConfig>, vault: Arc<VaultServer>,
+                 ping_stats: PingStats, session_limiter: SessionLimiter) -> AuthServer
     {
         let (incoming_send, mut incoming_recv) = mpsc::channel(5);
 
         tokio::spawn(async move {
-            while let Some(sock) = incoming_recv.recv().await {
-                AuthServerWorker::start(sock, server_config.clone(), vault.clone());
+            while let Some((sock, conn_guard)) = incoming_recv.recv().await {
+                AuthServerWorker::start(sock, conn_guard, server_config.clone(), vault.clone(),
+                                        &ping_stats, session_limiter.clone());
             }
         });
         AuthServer { incoming_send }
     }
 
-    pub async fn add(&mut self, sock: TcpStream) {
-        if let Err(err) = self.incoming_send.send(sock).await {
+    pub async fn add(&mut self, sock: TcpStream, conn_guard: ConnectionGuard) {
+        if let Err(err) = self.incoming_send.send((sock, conn_guard)).await {
             error!("Failed to add client: {}", err);
         }
     }
 }
 
 impl AuthServerWorker {
-    pub fn start(sock: TcpStream, server_config: Arc<ServerConfig>,
-                 vault: Arc<VaultServer>)
+    pub fn start(sock: TcpStream, conn_guard: ConnectionGuard,
+                 server_config: Arc<ServerConfig>, vault: Arc<VaultServer>,
+                 ping_stats: &PingStats, session_limiter: SessionLimiter)
     {
+        let ping_tracker = ping_stats.tracker(PingService::Auth);
         tokio::spawn(async move {
+            let _conn_guard = conn_guard;
             let stream = match init_client(sock, &server_config).await {
                 Ok(cipher) => cipher,
                 Err(err) => {
@@ -178,21 +237,40 @@ impl AuthServerWorker {
                 }
             };
 
-            let vault_bcast = vault.subscribe();
-            let mut worker = AuthServerWorker {
-                stream,
-                server_config,
-                vault,
-                vault_bcast,
-                server_challenge: rand::random::<u32>(),
-                account_id: None,
-                player_id: None,
-            };
+            let mut worker = AuthServerWorker::new(stream, server_config, vault, ping_tracker,
+                                                   session_limiter);
             worker.run().await;
             worker.handle_disconnect().await;
         });
     }
 
+    fn new(stream: BufReader<CryptTcpStream>, server_config: Arc<ServerConfig>,
+           vault: Arc<VaultServer>, ping_tracker: PingTracker,
+           session_limiter: SessionLimiter) -> Self
+    {
+        let vault_bcast = vault.subscribe();
+        let player = ActivePlayer { vault: vault.clone(), player_id: None };
+        let trace = MsgTrace::new("Auth", stream.get_ref().peer_addr().ok(), &server_config);
+        AuthServerWorker {
+            stream,
+            server_config,
+            vault,
+            vault_bcast,
+            server_challenge: rand::random::<u32>(),
+            build_id: 0,
+            account_id: None,
+            billing_type: 0,
+            is_admin: false,
+            is_ccr: false,
+            ccr_level: 0,
+            player,
+            ping_tracker,
+            trace,
+            session_limiter,
+            session_guard: None,
+        }
+    }
+
     fn peer_addr(&self) -> io::Result<SocketAddr> { self.stream.get_ref().peer_addr() }
 
     async fn send_caps(&mut self) -> Result<()> {
@@ -215,7 +293,22 @@ impl AuthServerWorker {
             warn!("Failed to send ServerCaps message: {}", err);
         }
 
+        // Connections which have gone quiet (e.g. because the client's network
+        // dropped out) are otherwise held open forever by TCP, leaving the
+        // player marked online.  Clients ping regularly, so any silence
+        // longer than the idle timeout means the connection is dead.
+        let idle_timeout = self.server_config.client_idle_timeout;
+        let mut idle_deadline = Instant::now() + idle_timeout;
+
         loop {
+            // Admins may be allowed larger buffers once they've logged in
+            let buffer_limits = if self.is_admin {
+                self.server_config.admin_buffer_limits
+            } else {
+                self.server_config.buffer_limits
+            };
+            let trace = self.trace;
+
             tokio::select! {
                 // Drain any broadcast messages first, to avoid the broadcast
                 // queue from filling up and starving.
@@ -227,26 +320,43 @@ impl AuthServerWorker {
                             break;
                         }
                     }
-                    Err(err) => warn!("Failed to receive broadcast message: {}", err),
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("Client {} missed {} broadcast message(s)",
+                              self.peer_addr().unwrap(), count);
+                    }
+                    // The vault is shutting down, so this client can't be
+                    // served any longer.  Continuing would just spin on the
+                    // closed channel.
+                    Err(RecvError::Closed) => break,
                 },
 
-                client_msg = CliToAuth::read(&mut self.stream) => match client_msg {
-                    Ok(message) => {
-                        if !self.handle_message(message).await {
-                            break;
+                client_msg = CliToAuth::read(&mut self.stream, &buffer_limits, &trace) => {
+                    match client_msg {
+                        Ok(message) => {
+                            idle_deadline = Instant::now() + idle_timeout;
+                            if !self.handle_message(message).await {
+                                break;
+                            }
                         }
-                    }
-                    Err(err) => {
-                        match err.downcast_ref::<io::Error>() {
-                            Some(io_err) if matches!(io_err.kind(), io::ErrorKind::ConnectionReset
-                                                                    | io::ErrorKind::UnexpectedEof) => {
-                                debug!("Client {} disconnected", self.peer_addr().unwrap());
+                        Err(err) => {
+                            match err.downcast_ref::<io::Error>() {
+                                Some(io_err) if matches!(io_err.kind(),
+                                        io::ErrorKind::ConnectionReset
+                                        | io::ErrorKind::UnexpectedEof) => {
+                                    debug!("Client {} disconnected", self.peer_addr().unwrap());
+                                }
+                                _ => warn!("Error reading message from client: {}", err),
                             }
-                            _ => warn!("Error reading message from client: {}", err),
+                            return;
                         }
-                        return;
                     }
-                },
+                }
+
+                () = tokio::time::sleep_until(idle_deadline) => {
+                    info!("Client {} timed out after {} seconds of inactivity",
+                          self.peer_addr().unwrap(), idle_timeout.as_secs());
+                    return;
+                }
             }
         }
         warn!("Dropping client {}", self.peer_addr().unwrap());
@@ -266,18 +376,55 @@ impl AuthServerWorker {
                     parent_id, child_id, owner_id
                 }).await
             }
+            VaultBroadcast::AccountChanged { account_id } => {
+                // Not sent to clients, but a newly banned account is kicked
+                if self.account_id != Some(account_id) {
+                    return true;
+                }
+                match self.vault.get_account_by_id(&account_id).await {
+                    Ok(Some(account)) if account.is_banned() => {
+                        self.kick(KickReason::AccountBanned).await
+                    }
+                    Ok(_) => true,
+                    Err(err) => {
+                        warn!("Failed to check account {}: {:?}", account_id, err);
+                        true
+                    }
+                }
+            }
+            VaultBroadcast::KickPlayer { player_id, reason } => {
+                if self.player.player_id != Some(player_id) {
+                    return true;
+                }
+                self.kick(reason).await
+            }
+        }
+    }
+
+    // Always returns false, since the client is disconnected after the kick
+    async fn kick(&mut self, reason: KickReason) -> bool {
+        info!("Kicking {} ({:?})", self.peer_addr().unwrap(), reason);
+        // Mark the player offline before disconnecting, so a session
+        // waiting to take over the player doesn't see a stale state
+        if let Some(player_id) = self.player.player_id.take() {
+            set_player_offline(&self.vault, player_id).await;
         }
+        self.send_message(AuthToCli::kicked_off(reason)).await;
+        false
     }
 
     async fn handle_message(&mut self, message: CliToAuth) -> bool {
         match message {
             CliToAuth::PingRequest { trans_id, ping_time, payload } => {
+                if let Some(rtt) = self.stream.get_ref().tcp_rtt() {
+                    self.ping_tracker.record(rtt);
+                }
                 self.send_message(AuthToCli::PingReply {
                     trans_id, ping_time, payload
                 }).await
             }
             CliToAuth::ClientRegisterRequest { build_id } => {
-                if build_id != 0 && build_id != self.server_config.build_id {
+                if !self.server_config.accepts_build_id(build_id) {
                     warn!("Client {} has an unexpected build ID {}",
                           self.peer_addr().unwrap(), build_id);
                     // The client isn't listening for anything other than a
@@ -285,12 +432,20 @@ impl AuthServerWorker {
                     // so we can't notify them that their build is invalid...
                     return false;
                 }
+                self.build_id = build_id;
                 self.send_message(AuthToCli::ClientRegisterReply {
                     server_challenge: self.server_challenge,
                 }).await
             }
-            CliToAuth::ClientSetCCRLevel { .. } => {
-                warn!("Ignoring CCR level set request from {}", self.peer_addr().unwrap());
+            CliToAuth::ClientSetCCRLevel { ccr_level } => {
+                // CCR-only operations should check the level stored here
+                if self.is_ccr {
+                    info!("{}: CCR level changed from {} to {}", self.peer_addr().unwrap(),
+                          self.ccr_level, ccr_level);
+                    self.ccr_level = ccr_level;
+                } else {
+                    warn!("Ignoring CCR level set request from {}", self.peer_addr().unwrap());
+                }
                 true
             }
             CliToAuth::AcctLoginRequest { trans_id, client_challenge, account_name,
@@ -298,7 +453,7 @@ impl AuthServerWorker {
                 debug!("Login Request U:{} P:{} T:{} O:{}", account_name,
                        pass_hash.as_hex(), auth_token, os);
                 self.do_login_request(trans_id, client_challenge, &account_name,
-                                      pass_hash).await
+                                      pass_hash, &auth_token).await
             }
             CliToAuth::AcctSetPlayerRequest { trans_id, player_id } => {
                 if player_id == 0 {
@@ -310,12 +465,8 @@ impl AuthServerWorker {
                 }
                 self.do_set_player(trans_id, player_id).await
             }
-            CliToAuth::AcctCreateRequest { trans_id, .. } => {
-                self.send_message(AuthToCli::AcctCreateReply {
-                    trans_id,
-                    result: NetResultCode::NetNotSupported as i32,
-                    account_id: Uuid::nil(),
-                }).await
+            CliToAuth::AcctCreateRequest { trans_id, account_name, auth_hash, .. } => {
+                self.do_account_create(trans_id, &account_name, auth_hash).await
             }
             CliToAuth::AcctChangePasswordRequest { trans_id, .. } => {
                 self.send_message(AuthToCli::AcctChangePasswordReply {
@@ -372,14 +523,47 @@ impl AuthServerWorker {
                 warn!("Ignoring kick player request from {}", self.peer_addr().unwrap());
                 true
             }
-            CliToAuth::ChangePlayerNameRequest { .. } => {
-                todo!()
+            CliToAuth::ChangePlayerNameRequest { trans_id, player_id, new_name } => {
+                let result = if let Some(reason) = self.server_config.name_filter.check(&new_name) {
+                    warn!("{} attempted to rename player {} to '{}': {}",
+                          self.peer_addr().unwrap(), player_id, new_name, reason);
+                    NetResultCode::NetInvalidParameter
+                } else {
+                    // TODO: Renaming players is not yet supported
+                    NetResultCode::NetNotSupported
+                };
+                self.send_message(AuthToCli::ChangePlayerNameReply {
+                    trans_id,
+                    result: result as i32,
+                }).await
             }
             CliToAuth::SendFriendInviteRequest { .. } => {
                 todo!()
             }
             CliToAuth::VaultNodeCreate { trans_id, node_buffer } => {
-                let reply = match VaultNode::from_blob(&node_buffer) {
+                let node = VaultNode::from_blob(&node_buffer).map_err(|err| {
+                    warn!("Failed to read vault node from blob: {}", err);
+                    NetResultCode::NetInternalError
+                }).and_then(|node| {
+                    match check_node_create(&node, self.is_admin, &self.server_config)
+                            .and_then(|()| check_client_sdl(&node, self.vault.sdl_db(),
+                                                            &self.server_config.sdl_limits)) {
+                        Ok(()) => Ok(node),
+                        Err(err) => {
+                            warn!("{} attempted to create a {} node: {:?}",
+                                  self.peer_addr().unwrap(),
+                                  NodeType::describe(node.node_type()), err);
+                            Err(err)
+                        }
+                    }
+                }).and_then(|mut node| {
+                    VaultSdlNode::escape_client_blob(&mut node).map_err(|err| {
+                        warn!("Failed to store SDL blob: {}", err);
+                        NetResultCode::NetInternalError
+                    })?;
+                    Ok(node)
+                });
+                let reply = match node {
                     Ok(node) => match self.vault.create_node(node).await {
                         Ok(node_id) => AuthToCli::VaultNodeCreated {
                             trans_id,
@@ -392,19 +576,17 @@ impl AuthServerWorker {
                             node_id: 0
                         },
                     }
-                    Err(err) => {
-                        warn!("Failed to read vault node from blob: {}", err);
-                        AuthToCli::VaultNodeCreated {
-                            trans_id,
-                            result: NetResultCode::NetInternalError as i32,
-                            node_id: 0
-                        }
-                    }
+                    Err(err) => AuthToCli::VaultNodeCreated {
+                        trans_id,
+                        result: err as i32,
+                        node_id: 0
+                    },
                 };
                 self.send_message(reply).await
             }
             CliToAuth::VaultNodeFetch { trans_id, node_id } => {
-                let reply = match self.vault.fetch_node(node_id).await {
+                let reply = match self.vault.fetch_node(node_id).await
+                    .and_then(|node| client_node(node, &self.server_config.sdl_limits)) {
                     Ok(node) => match node.to_blob() {
                         Ok(node_buffer) => AuthToCli::VaultNodeFetched {
                             trans_id,
@@ -435,6 +617,12 @@ impl AuthServerWorker {
                 todo!()
             }
             CliToAuth::VaultNodeAdd { trans_id, parent_id, child_id, owner_id } => {
+                if let Err(err) = self.check_node_access(parent_id).await {
+                    return self.send_message(AuthToCli::VaultAddNodeReply {
+                        trans_id,
+                        result: err as i32
+                    }).await;
+                }
                 let reply = match self.vault.ref_node(parent_id, child_id, owner_id, true).await {
                     Ok(()) => AuthToCli::VaultAddNodeReply {
                         trans_id,
@@ -451,7 +639,27 @@ impl AuthServerWorker {
                 todo!()
             }
             CliToAuth::VaultFetchNodeRefs { trans_id, node_id } => {
-                let reply = match self.vault.fetch_refs(node_id, true).await {
+                // Fetching the CCRPlayersFolder requires the same access as
+                // modifying it (i.e. a CCR or admin).  Any other node's refs
+                // may be fetched.
+                let access = match self.vault.get_ccr_players_node().await {
+                    Ok(ccr_players) if ccr_players == node_id => {
+                        self.check_node_access(node_id).await
+                    }
+                    Ok(_) => Ok(()),
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = access {
+                    return self.send_message(AuthToCli::VaultNodeRefsFetched {
+                        trans_id,
+                        result: err as i32,
+                        refs: Vec::new()
+                    }).await;
+                }
+                // Oversized ref trees are rejected with NetInvalidParameter,
+                // since the protocol has no more specific result for them
+                let max_refs = self.server_config.max_fetched_refs;
+                let reply = match fetch_client_refs(node_id, max_refs, &self.vault).await {
                     Ok(refs) => AuthToCli::VaultNodeRefsFetched {
                         trans_id,
                         result: NetResultCode::NetSuccess as i32,
@@ -468,6 +676,17 @@ impl AuthServerWorker {
             CliToAuth::VaultInitAgeRequest { trans_id, age_instance_id, parent_age_instance_id,
                                              age_filename, age_instance_name, age_user_name,
                                              age_description, age_sequence, age_language } => {
+                let entitlements = &self.server_config.entitlements;
+                if !entitlements.can_access_age(self.billing_type, &age_filename) {
+                    info!("{}: Billing type {} may not access Age {}",
+                          self.peer_addr().unwrap(), self.billing_type, age_filename);
+                    return self.send_message(AuthToCli::VaultInitAgeReply {
+                        trans_id,
+                        result: NetResultCode::NetServiceForbidden as i32,
+                        age_vault_id: 0,
+                        age_info_vault_id: 0,
+                    }).await;
+                }
                 let reply = match find_age_instance(&age_instance_id, &parent_age_instance_id,
                                         &age_filename, &age_instance_name, &age_user_name,
                                         &age_description, age_sequence, age_language,
@@ -519,8 +738,8 @@ impl AuthServerWorker {
             CliToAuth::VaultSendNode { .. } => {
                 todo!()
             }
-            CliToAuth::AgeRequest { .. } => {
-                todo!()
+            CliToAuth::AgeRequest { trans_id, age_name, age_instance_id } => {
+                self.do_age_request(trans_id, &age_name, &age_instance_id).await
             }
             CliToAuth::FileListRequest { trans_id, directory, ext } => {
                 self.do_manifest(trans_id, &directory, &ext).await
@@ -532,8 +751,8 @@ impl AuthServerWorker {
                 warn!("Ignoring propagate buffer from {}", self.peer_addr().unwrap());
                 true
             }
-            CliToAuth::GetPublicAgeList { .. } => {
-                todo!()
+            CliToAuth::GetPublicAgeList { trans_id, age_filename } => {
+                self.do_public_age_list(trans_id, &age_filename).await
             }
             CliToAuth::SetAgePublic { .. } => {
                 todo!()
@@ -584,6 +803,7 @@ impl AuthServerWorker {
             warn!("Failed to write reply stream: {}", err);
             return false;
         }
+        AuthToCli::trace_sent(reply_buf.get_ref(), &self.trace);
         if let Err(err) = self.stream.get_mut().write_all(reply_buf.get_ref()).await {
             warn!("Failed to send reply: {}", err);
             false
@@ -593,33 +813,55 @@ impl AuthServerWorker {
     }
 
     async fn do_manifest(&mut self, trans_id: u32, dir_name: &str, ext: &str) -> bool {
-        let reply = if let Some(manifest)
-                            = fetch_list(dir_name, ext, &self.server_config.data_root)
-        {
-            debug!("Client {} requested list '{}\\*.{}'",
-                   self.peer_addr().unwrap(), dir_name, ext);
-
-            AuthToCli::FileListReply {
-                trans_id,
-                result: NetResultCode::NetSuccess as i32,
-                manifest
-            }
-        } else {
+        let Some(manifest) = fetch_list(dir_name, ext, &self.server_config,
+                                        self.build_id) else {
             warn!("Client {} requested invalid list '{}\\*.{}'",
                   self.peer_addr().unwrap(), dir_name, ext);
-            AuthToCli::FileListReply {
+            return self.send_message(AuthToCli::FileListReply {
                 trans_id,
                 result: NetResultCode::NetFileNotFound as i32,
                 manifest: Manifest::new()
-            }
+            }).await;
         };
 
-        self.send_message(reply).await
+        debug!("Client {} requested list '{}\\*.{}'",
+               self.peer_addr().unwrap(), dir_name, ext);
+
+        // Lists can be large, so they are streamed rather than going through
+        // send_message.
+        let mut chunks = match AuthToCli::file_list_chunks(trans_id, &manifest) {
+            Ok(chunks) => chunks,
+            Err(err) => {
+                warn!("Failed to write reply stream: {}", err);
+                return false;
+            }
+        };
+        let mut first_chunk = true;
+        loop {
+            match chunks.next_chunk() {
+                Ok(Some(chunk)) => {
+                    // Only the first chunk starts with the message ID
+                    if first_chunk {
+                        AuthToCli::trace_sent(&chunk, &self.trace);
+                        first_chunk = false;
+                    }
+                    if let Err(err) = self.stream.get_mut().write_all(&chunk).await {
+                        warn!("Failed to send reply: {}", err);
+                        return false;
+                    }
+                }
+                Ok(None) => return true,
+                Err(err) => {
+                    warn!("Failed to write reply stream: {}", err);
+                    return false;
+                }
+            }
+        }
     }
 
     async fn do_download(&mut self, trans_id: u32, filename: &str) -> bool {
         if let Some((mut file, metadata, download_path))
-                    = open_server_file(filename, &self.server_config.data_root).await
+                    = open_server_file(filename, &self.server_config, self.build_id).await
         {
             debug!("Client {} requested file '{}'", self.peer_addr().unwrap(), filename);
 
@@ -629,7 +871,7 @@ impl AuthServerWorker {
                                             NetResultCode::NetInternalError)).await;
             };
 
-            let mut buffer = [0u8; FILE_CHUNK_SIZE];
+            let mut buffer = vec![0u8; self.server_config.file_chunk_size];
             let mut offset = 0;
             loop {
                 match file.read(&mut buffer).await {
@@ -669,11 +911,28 @@ impl AuthServerWorker {
     }
 
     async fn do_login_request(&mut self, trans_id: u32, client_challenge: u32,
-                              account_name: &str, pass_hash: ShaDigest) -> bool
+                              account_name: &str, pass_hash: ShaDigest,
+                              auth_token: &str) -> bool
     {
-        let account = match self.vault.get_account(account_name).await {
+        // Names which could never have been created are rejected the same
+        // way as any other unknown account, without touching the vault.
+        if !valid_account_name(account_name) {
+            info!("{}: Invalid account name {:?}", self.peer_addr().unwrap(), account_name);
+            return self.send_message(AuthToCli::login_error(trans_id,
+                                        NetResultCode::NetAuthenticationFailed)).await;
+        }
+
+        // External (e.g. SSO) integrations may authenticate with a token
+        // instead of a password, if enabled.
+        let use_token_auth = self.server_config.allow_token_login && !auth_token.is_empty();
+        let account = if use_token_auth {
+            token_login_account(&self.vault, account_name, auth_token).await
+        } else {
+            self.vault.get_account(account_name).await
+        };
+        let account = match account {
             Ok(Some(account)) => account,
-            Ok(None) => {
+            Ok(_) => {
                 info!("{}: Account {} was not found", self.peer_addr().unwrap(),
                       account_name);
 
@@ -686,49 +945,31 @@ impl AuthServerWorker {
             }
         };
 
-        // NOTE: Neither of these is good or secure, but they are what the
-        // client expects.  To fix these, we'd have to break compatibility
-        // with older clients.
-        if use_email_auth(account_name) {
-            // Use broken LE Sha0 hash mechanism
-            let challenge_hash = match hash_password_challenge(
-                        client_challenge, self.server_challenge,
-                        account.pass_hash)
-            {
-                Ok(digest) => digest,
-                Err(err) => {
-                    warn!("Failed to generate challenge hash: {}", err);
-                    return self.send_message(AuthToCli::login_error(trans_id,
-                                                NetResultCode::NetInternalError)).await;
-                }
-            };
-            if challenge_hash != pass_hash {
-                info!("{}: Login failure for account {}", self.peer_addr().unwrap(),
-                      account_name);
-                return self.send_message(AuthToCli::login_error(trans_id,
-                                            NetResultCode::NetAuthenticationFailed)).await;
-            }
-        } else {
-            // Directly compare the BE Sha1 hash
-            // NOTE: The client sends its hash as Little Endian...
-            if account.pass_hash != pass_hash.endian_swap() {
-                info!("{}: Login failure for account {}", self.peer_addr().unwrap(),
-                      account_name);
-                return self.send_message(AuthToCli::login_error(trans_id,
-                                            NetResultCode::NetAuthenticationFailed)).await;
-            }
+        if !use_token_auth && !self.check_password(client_challenge, account_name,
+                                                   &account, pass_hash)
+        {
+            info!("{}: Login failure for account {}", self.peer_addr().unwrap(),
+                  account_name);
+            return self.send_message(AuthToCli::login_error(trans_id,
+                                        NetResultCode::NetAuthenticationFailed)).await;
         }
 
-        if account.is_banned() {
-            info!("{}: Account {} is banned", self.peer_addr().unwrap(), account_name);
-            return self.send_message(AuthToCli::login_error(trans_id,
-                                        NetResultCode::NetAccountBanned)).await;
+        if let Some((result, status)) = account_status_error(&account) {
+            info!("{}: Account {} is {}", self.peer_addr().unwrap(), account_name, status);
+            return self.send_message(AuthToCli::login_error(trans_id, result)).await;
         }
-        if self.server_config.restrict_logins && !account.can_login_restricted() {
-            info!("{}: Account {} login is restricted", self.peer_addr().unwrap(),
-                  account_name);
-            return self.send_message(AuthToCli::login_error(trans_id,
-                                        NetResultCode::NetLoginDenied)).await;
+        match self.server_config.restrict_logins.check(&account) {
+            Some("unrestricted") => (),
+            Some(rule) => {
+                info!("{}: Account {} admitted to restricted login by {}",
+                      self.peer_addr().unwrap(), account_name, rule);
+            }
+            None => {
+                info!("{}: Account {} login is restricted", self.peer_addr().unwrap(),
+                      account_name);
+                return self.send_message(AuthToCli::login_error(trans_id,
+                                            NetResultCode::NetLoginDenied)).await;
+            }
         }
 
         let ntd_key = match self.server_config.get_ntd_key() {
@@ -740,9 +981,30 @@ impl AuthServerWorker {
             }
         };
 
+        // The session slot is kept for the rest of the connection, even if
+        // the client logs in again.
+        if self.session_guard.is_none() {
+            let Some(session_guard) = self.session_limiter.try_acquire() else {
+                info!("{}: Server is full ({} sessions); rejecting login for {}",
+                      self.peer_addr().unwrap(), self.session_limiter.max_sessions(),
+                      account_name);
+                return self.send_message(AuthToCli::login_error(trans_id,
+                                            NetResultCode::NetServerBusy)).await;
+            };
+            self.session_guard = Some(session_guard);
+        }
+
         info!("{}: Logged in as {} {}", self.peer_addr().unwrap(),
               account_name, account.account_id);
         self.account_id = Some(account.account_id);
+        self.billing_type = account.billing_type;
+        self.is_admin = account.is_admin();
+        self.is_ccr = account.is_ccr();
+        if self.is_ccr {
+            if let Err(err) = add_ccr_players(&account.account_id, &self.vault).await {
+                warn!("Failed to add CCR players for {}: {:?}", account_name, err);
+            }
+        }
 
         match self.fetch_account_players(trans_id, &account.account_id).await {
             Some(NetResultCode::NetSuccess) => (),
@@ -763,6 +1025,67 @@ impl AuthServerWorker {
         }).await
     }
 
+    async fn do_account_create(&mut self, trans_id: u32, account_name: &str,
+                               auth_hash: ShaDigest) -> bool
+    {
+        let result = if !self.server_config.allow_account_creation {
+            Err(NetResultCode::NetNotSupported)
+        } else if !valid_account_name(account_name) {
+            Err(NetResultCode::NetInvalidParameter)
+        } else {
+            // Store the hash the same way that check_password expects it
+            let pass_hash = if use_email_auth(account_name) {
+                auth_hash
+            } else {
+                auth_hash.endian_swap()
+            };
+            self.vault.create_account(account_name, pass_hash,
+                                      self.server_config.new_account_flags).await
+        };
+
+        match result {
+            Ok(account) => {
+                info!("{}: Created account {}", self.peer_addr().unwrap(), account_name);
+                self.send_message(AuthToCli::AcctCreateReply {
+                    trans_id,
+                    result: NetResultCode::NetSuccess as i32,
+                    account_id: account.account_id,
+                }).await
+            }
+            Err(err) => {
+                self.send_message(AuthToCli::AcctCreateReply {
+                    trans_id,
+                    result: err as i32,
+                    account_id: Uuid::nil(),
+                }).await
+            }
+        }
+    }
+
+    fn check_password(&self, client_challenge: u32, account_name: &str,
+                      account: &AccountInfo, pass_hash: ShaDigest) -> bool
+    {
+        // NOTE: Neither of these is good or secure, but they are what the
+        // client expects.  To fix these, we'd have to break compatibility
+        // with older clients.
+        if use_email_auth(account_name) {
+            // Use broken LE Sha0 hash mechanism
+            match hash_password_challenge(client_challenge, self.server_challenge,
+                                          account.pass_hash)
+            {
+                Ok(challenge_hash) => challenge_hash == pass_hash,
+                Err(err) => {
+                    warn!("Failed to generate challenge hash: {}", err);
+                    false
+                }
+            }
+        } else {
+            // Directly compare the BE Sha1 hash
+            // NOTE: The client sends its hash as Little Endian...
+            account.pass_hash == pass_hash.endian_swap()
+        }
+    }
+
     async fn fetch_account_players(&mut self, trans_id: u32, account_id: &Uuid)
         -> Option<NetResultCode>
     {
@@ -785,6 +1108,30 @@ impl AuthServerWorker {
         Some(NetResultCode::NetSuccess)
     }
 
+    // Links an account's first player to the standard starting Ages
+    async fn bootstrap_player(&self, account_id: &Uuid, player_info: &PlayerInfo,
+                              created: &[(String, u32)])
+    {
+        match self.vault.get_players(account_id).await {
+            Ok(players) if players.len() == 1 => (),
+            Ok(_) => return,
+            Err(err) => {
+                warn!("Failed to get players for {}: {:?}", account_id, err);
+                return;
+            }
+        }
+        match bootstrap_first_player(created, &self.vault).await {
+            Ok(linked) => {
+                for age_filename in linked {
+                    info!("Linked new player {} to {}", player_info.player_name, age_filename);
+                }
+            }
+            Err(err) => {
+                warn!("Failed to bootstrap player {}: {:?}", player_info.player_name, err);
+            }
+        }
+    }
+
     async fn player_create(&mut self, trans_id: u32, player_name: &str,
                            avatar_shape: &str) -> bool
     {
@@ -803,17 +1150,41 @@ impl AuthServerWorker {
                                         NetResultCode::NetInvalidParameter)).await;
         }
 
-        let player_info = match self.vault.create_player(&account_id, player_name,
-                                                         avatar_shape).await
+        if let Some(reason) = self.server_config.name_filter.check(player_name) {
+            warn!("Client {} attempted to use player name '{}': {}",
+                  self.peer_addr().unwrap(), player_name, reason);
+            return self.send_message(AuthToCli::player_create_error(trans_id,
+                                        NetResultCode::NetInvalidParameter)).await;
+        }
+
+        if let Some(max_players) = self.server_config.entitlements.max_players(self.billing_type) {
+            match self.vault.get_players(&account_id).await {
+                Ok(players) if players.len() as u64 >= max_players => {
+                    return self.send_message(AuthToCli::player_create_error(trans_id,
+                                                NetResultCode::NetMaxPlayersOnAcct)).await;
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    return self.send_message(AuthToCli::player_create_error(trans_id, err)).await;
+                }
+            }
+        }
+
+        let (player_info, created) = match create_new_player(&account_id, player_name,
+                                                             avatar_shape, &self.vault).await
         {
-            Ok(player_info) => player_info,
-            Err(result) => {
-                return self.send_message(AuthToCli::player_create_error(trans_id, result)).await;
+            Ok(result) => result,
+            Err(err) => {
+                return self.send_message(AuthToCli::player_create_error(trans_id, err)).await;
             }
         };
-
-        if let Err(err) = create_player_nodes(&account_id, &player_info, &self.vault).await {
-            return self.send_message(AuthToCli::player_create_error(trans_id, err)).await;
+        if self.is_ccr {
+            if let Err(err) = add_ccr_players(&account_id, &self.vault).await {
+                warn!("Failed to add CCR player {}: {:?}", player_info.player_name, err);
+            }
+        }
+        if self.server_config.bootstrap_first_player {
+            self.bootstrap_player(&account_id, &player_info, &created).await;
         }
 
         info!("{} created new player {} ({})", self.peer_addr().unwrap(),
@@ -829,6 +1200,63 @@ impl AuthServerWorker {
         }).await
     }
 
+    async fn do_age_request(&mut self, trans_id: u32, age_name: &str, age_instance_id: &Uuid)
+        -> bool
+    {
+        if self.account_id.is_none() || self.player.player_id.is_none() {
+            warn!("{} cannot request Age {}: No active player", self.peer_addr().unwrap(),
+                  age_name);
+            return self.send_message(AuthToCli::AgeReply {
+                trans_id,
+                result: NetResultCode::NetAuthenticationFailed as i32,
+                age_mcp_id: 0,
+                age_instance_id: Uuid::nil(),
+                age_vault_id: 0,
+                game_server_node: 0,
+            }).await;
+        }
+
+        let reply = match find_game_server(age_instance_id, &self.server_config.game_serv_ip,
+                                           &self.vault).await
+        {
+            Ok((game_server, _)) if !self.server_config.entitlements
+                    .can_access_age(self.billing_type, &game_server.age_filename) => {
+                info!("{}: Billing type {} may not access Age {}",
+                      self.peer_addr().unwrap(), self.billing_type, game_server.age_filename);
+                AuthToCli::AgeReply {
+                    trans_id,
+                    result: NetResultCode::NetServiceForbidden as i32,
+                    age_mcp_id: 0,
+                    age_instance_id: Uuid::nil(),
+                    age_vault_id: 0,
+                    game_server_node: 0,
+                }
+            }
+            Ok((game_server, game_server_node)) => {
+                debug!("{} requested Age {} ({}) on game server {}",
+                       self.peer_addr().unwrap(), age_name, age_instance_id,
+                       Ipv4Addr::from(game_server_node));
+                AuthToCli::AgeReply {
+                    trans_id,
+                    result: NetResultCode::NetSuccess as i32,
+                    age_mcp_id: game_server.age_id,
+                    age_instance_id: game_server.instance_id,
+                    age_vault_id: game_server.age_id,
+                    game_server_node,
+                }
+            }
+            Err(err) => AuthToCli::AgeReply {
+                trans_id,
+                result: err as i32,
+                age_mcp_id: 0,
+                age_instance_id: Uuid::nil(),
+                age_vault_id: 0,
+                game_server_node: 0,
+            },
+        };
+        self.send_message(reply).await
+    }
+
     async fn do_set_player(&mut self, trans_id: u32, player_id: u32) -> bool {
         let Some(account_id) = self.account_id else {
             warn!("{} cannot set player: Not logged in", self.peer_addr().unwrap());
@@ -869,8 +1297,10 @@ impl AuthServerWorker {
             }).await;
         }
 
-        let player_info = match self.vault.get_player_info_node(player_id).await {
-            Ok(node) => node.as_player_info_node().unwrap(),
+        let player_info = match self.vault.get_player_info_node(player_id).await
+                                    .and_then(|node| expect_player_info(&node, player_id))
+        {
+            Ok(node) => node,
             Err(err) => {
                 warn!("Failed to get Player Info node for Player {}", player_id);
                 return self.send_message(AuthToCli::AcctSetPlayerReply {
@@ -883,10 +1313,17 @@ impl AuthServerWorker {
         if player_info.online() != 0 {
             warn!("{} requested already-online player {}", self.peer_addr().unwrap(),
                   player_id);
-            return self.send_message(AuthToCli::AcctSetPlayerReply {
-                trans_id,
-                result: NetResultCode::NetLoggedInElsewhere as i32
-            }).await;
+            // If enabled, disconnect the other session so this client can
+            // take over the player
+            if !self.server_config.evict_duplicate_logins
+                    || self.player.player_id == Some(player_id)
+                    || !evict_player(&self.vault, &player_info).await
+            {
+                return self.send_message(AuthToCli::AcctSetPlayerReply {
+                    trans_id,
+                    result: NetResultCode::NetLoggedInElsewhere as i32
+                }).await;
+            }
         }
 
         let update = VaultPlayerInfoNode::new_update(player_info.node_id(), 1,
@@ -901,7 +1338,7 @@ impl AuthServerWorker {
 
         info!("{} signed in as {} ({})", self.peer_addr().unwrap(),
               player_node.player_name_ci(), player_id);
-        self.player_id = Some(player_id);
+        self.player.player_id = Some(player_id);
 
         self.send_message(AuthToCli::AcctSetPlayerReply {
             trans_id,
@@ -909,29 +1346,453 @@ impl AuthServerWorker {
         }).await
     }
 
-    async fn set_player_offline(&mut self, player_id: u32) {
-        let player_info = match self.vault.get_player_info_node(player_id).await {
-            Ok(node) => node.as_player_info_node().unwrap(),
+    // Ensures the current player is allowed to modify the refs of the
+    // specified node.  Admins may modify any node.
+    async fn check_node_access(&self, node_id: u32) -> NetResult<()> {
+        if self.is_admin {
+            return Ok(());
+        }
+        let (Some(account_id), Some(player_id)) = (self.account_id, self.player.player_id) else {
+            warn!("{} cannot modify node {}: No active player",
+                  self.peer_addr().unwrap(), node_id);
+            return Err(NetResultCode::NetServiceForbidden);
+        };
+        if can_modify_node(node_id, player_id, &account_id, self.is_ccr, &self.vault).await? {
+            Ok(())
+        } else {
+            warn!("{}: Player {} may not modify node {}", self.peer_addr().unwrap(),
+                  player_id, node_id);
+            Err(NetResultCode::NetServiceForbidden)
+        }
+    }
+
+    async fn fetch_public_ages(&self, age_filename: &str) -> NetResult<Vec<NetAgeInfo>> {
+        let template = VaultAgeInfoNode::new_public_lookup(age_filename);
+        let mut age_infos = Vec::new();
+        for node_id in self.vault.find_nodes(template).await? {
+            if let Some(age_info) = self.vault.fetch_node(node_id).await?.as_age_info_node() {
+                age_infos.push(age_info);
+            }
+        }
+
+        let instance_ids = age_infos.iter()
+                .map(|age_info| *age_info.age_instance_uuid()).collect();
+        let populations = self.vault.get_populations(instance_ids).await?;
+        Ok(age_infos.iter().zip(populations)
+                .map(|(age_info, population)| NetAgeInfo::new(age_info, population))
+                .collect())
+    }
+
+    async fn do_public_age_list(&mut self, trans_id: u32, age_filename: &str) -> bool {
+        let reply = match self.fetch_public_ages(age_filename).await {
+            Ok(ages) => AuthToCli::PublicAgeList {
+                trans_id,
+                result: NetResultCode::NetSuccess as i32,
+                ages,
+            },
             Err(err) => {
-                warn!("Failed to get Player Info node for Player {}: {:?}",
-                      player_id, err);
-                return;
+                warn!("Failed to fetch public Age list for {}: {:?}", age_filename, err);
+                AuthToCli::PublicAgeList {
+                    trans_id,
+                    result: err as i32,
+                    ages: Vec::new(),
+                }
             }
         };
+        self.send_message(reply).await
+    }
 
-        let update = VaultPlayerInfoNode::new_update(player_info.node_id(), 0, "", &Uuid::nil());
-        if let Err(err) = self.vault.update_node(update).await {
-            warn!("Failed to set player {} offline: {:?}", player_id, err);
+    async fn handle_disconnect(&mut self) {
+        // Make sure any final reply (e.g. KickedOff) reaches the client
+        // before the connection is closed.
+        if let Err(err) = self.stream.get_mut().shutdown().await {
+            if err.kind() != io::ErrorKind::NotConnected {
+                debug!("Failed to shut down connection: {}", err);
+            }
+        }
+
+        if let Some(player_id) = self.player.player_id.take() {
+            set_player_offline(&self.vault, player_id).await;
+        }
+    }
+}
+
+// Compressed SDL blobs are an implementation detail of the vault storage,
+// so nodes are always sent to the client with the uncompressed blob.
+fn client_node(node: Arc<VaultNode>, sdl_limits: &SdlLimits) -> NetResult<Arc<VaultNode>> {
+    match node.as_sdl_node() {
+        Some(sdl_node) => sdl_node.to_uncompressed(sdl_limits.max_blob_size).map_err(|err| {
+            warn!("Failed to decompress SDL blob for node {}: {}", node.node_id(), err);
+            NetResultCode::NetInternalError
+        }),
+        None => Ok(node),
+    }
+}
+
+// Guards against malformed vaults, where the node found for a player's
+// Player Info is actually some other node type.
+fn expect_player_info(node: &Arc<VaultNode>, player_id: u32) -> NetResult<VaultPlayerInfoNode> {
+    node.as_player_info_node().ok_or_else(|| {
+        warn!("Node {} for Player {} is not a Player Info node", node.node_id(), player_id);
+        NetResultCode::NetInternalError
+    })
+}
+
+// Kicks the session which has the player, and waits for it to mark the
+// player offline.  Returns false if the player is still online after
+// EVICT_TIMEOUT.
+async fn evict_player(vault: &VaultServer, player_info: &VaultPlayerInfoNode) -> bool {
+    const EVICT_TIMEOUT: Duration = Duration::from_secs(5);
+
+    let player_id = player_info.player_id();
+    // Subscribe before kicking, so the offline update can't be missed
+    let mut bcast_recv = vault.subscribe();
+    vault.kick_player(player_id, KickReason::LoggedInElsewhere);
+
+    let wait_offline = async {
+        loop {
+            match vault.get_player_info_node(player_id).await
+                       .and_then(|node| expect_player_info(&node, player_id))
+            {
+                Ok(player_info) if player_info.online() == 0 => return true,
+                Ok(_) => (),
+                Err(err) => {
+                    warn!("Failed to get Player Info node for Player {}: {:?}",
+                          player_id, err);
+                    return false;
+                }
+            }
+            loop {
+                match bcast_recv.recv().await {
+                    Ok(VaultBroadcast::NodeChanged { node_id, .. })
+                            if node_id == player_info.node_id() => break,
+                    Ok(_) => (),
+                    // We may have missed the update, so check again
+                    Err(RecvError::Lagged(_)) => break,
+                    Err(RecvError::Closed) => return false,
+                }
+            }
+        }
+    };
+    if let Ok(released) = tokio::time::timeout(EVICT_TIMEOUT, wait_offline).await {
+        released
+    } else {
+        warn!("Player {} was not released by its previous session", player_id);
+        false
+    }
+}
+
+async fn set_player_offline(vault: &VaultServer, player_id: u32) {
+    let player_info = match vault.get_player_info_node(player_id).await
+                                .and_then(|node| expect_player_info(&node, player_id))
+    {
+        Ok(node) => node,
+        Err(err) => {
+            warn!("Failed to get Player Info node for Player {}: {:?}",
+                  player_id, err);
             return;
         }
+    };
 
-        info!("Player {} ({}) is now offline", player_info.player_name_ci(),
-              player_id);
+    let update = VaultPlayerInfoNode::new_update(player_info.node_id(), 0, "", &Uuid::nil());
+    if let Err(err) = vault.update_node(update).await {
+        warn!("Failed to set player {} offline: {:?}", player_id, err);
+        return;
     }
 
-    async fn handle_disconnect(&mut self) {
-        if let Some(player_id) = self.player_id {
-            self.set_player_offline(player_id).await;
+    info!("Player {} ({}) is now offline", player_info.player_name_ci(),
+          player_id);
+}
+
+// Looks up the account for a token login.  The token is only accepted for
+// the account it was issued to.
+async fn token_login_account(vault: &VaultServer, account_name: &str, login_token: &str)
+    -> NetResult<Option<AccountInfo>>
+{
+    let account = vault.get_account_for_login_token(login_token).await?;
+    Ok(account.filter(|account| {
+        UniCase::new(account.account_name.as_str()) == UniCase::new(account_name)
+    }))
+}
+
+// Banned and disabled (e.g. pending verification) accounts may not log in,
+// regardless of any login restrictions.
+fn account_status_error(account: &AccountInfo) -> Option<(NetResultCode, &'static str)> {
+    if account.is_banned() {
+        Some((NetResultCode::NetAccountBanned, "banned"))
+    } else if account.is_disabled() {
+        Some((NetResultCode::NetAccountNotActivated, "disabled"))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_expect_player_info() {
+    use crate::vault::VaultPlayerNode;
+
+    let account_id = Uuid::new_v4();
+    let node = VaultPlayerInfoNode::new(&account_id, 1001, "Test Player");
+    assert!(expect_player_info(&Arc::new(node), 1001).is_ok());
+
+    let node = VaultPlayerNode::new(&account_id, "Test Player", "male", 1);
+    assert_eq!(expect_player_info(&Arc::new(node), 1001).err(),
+               Some(NetResultCode::NetInternalError));
+}
+
+#[tokio::test]
+async fn test_offline_after_panic() {
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = Arc::new(VaultServer::start(server_config, DescriptorDb::empty()));
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let (player, _) = create_new_player(&account.account_id, "Panic Test", "male", &vault)
+            .await.unwrap();
+
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap();
+    let update = VaultPlayerInfoNode::new_update(player_info.node_id(), 1, "Lobby", &Uuid::nil());
+    vault.update_node(update).await.unwrap();
+
+    let worker_vault = vault.clone();
+    let result = tokio::spawn(async move {
+        let _player = ActivePlayer { vault: worker_vault, player_id: Some(player.player_id) };
+        panic!("Simulated message handler panic");
+    }).await;
+    assert!(result.unwrap_err().is_panic());
+
+    // The offline update is processed asynchronously
+    for _ in 0..100 {
+        let player_info = vault.get_player_info_node(player.player_id).await.unwrap()
+                .as_player_info_node().unwrap();
+        if player_info.online() == 0 {
+            return;
         }
+        tokio::time::sleep(Duration::from_millis(10)).await;
     }
+    panic!("Player was not set offline");
+}
+
+#[tokio::test]
+async fn test_evict_player() {
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = Arc::new(VaultServer::start(server_config, DescriptorDb::empty()));
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    let (player, _) = create_new_player(&account.account_id, "Evict Test", "male", &vault)
+            .await.unwrap();
+
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap();
+    let update = VaultPlayerInfoNode::new_update(player_info.node_id(), 1, "Lobby", &Uuid::nil());
+    vault.update_node(update).await.unwrap();
+
+    // Simulate the session which has the player releasing it when kicked
+    let worker_vault = vault.clone();
+    let mut bcast_recv = vault.subscribe();
+    tokio::spawn(async move {
+        while let Ok(bcast) = bcast_recv.recv().await {
+            if let VaultBroadcast::KickPlayer { player_id, .. } = bcast {
+                set_player_offline(&worker_vault, player_id).await;
+                break;
+            }
+        }
+    });
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap()
+            .as_player_info_node().unwrap();
+    assert!(evict_player(&vault, &player_info).await);
+}
+
+#[tokio::test]
+async fn test_split_file_roots() {
+    let python_root = tempfile::tempdir().unwrap();
+    let sdl_root = tempfile::tempdir().unwrap();
+    std::fs::create_dir(python_root.path().join("Python")).unwrap();
+    std::fs::write(python_root.path().join("Python").join("test.pak"), b"pak").unwrap();
+    std::fs::create_dir(sdl_root.path().join("SDL")).unwrap();
+    std::fs::write(sdl_root.path().join("SDL").join("test.sdl"), b"sdl").unwrap();
+
+    let mut server_config = ServerConfig::test_config();
+    server_config.auth_file_roots.insert(UniCase::new("Python".to_string()),
+                                         python_root.path().to_path_buf());
+    server_config.auth_file_roots.insert(UniCase::new("SDL".to_string()),
+                                         sdl_root.path().to_path_buf());
+
+    let config = &server_config;
+    let (_, _, path) = open_server_file("Python\\test.pak", config, 0).await.unwrap();
+    assert!(path.starts_with(python_root.path()));
+    let (_, _, path) = open_server_file("SDL\\test.sdl", config, 0).await.unwrap();
+    assert!(path.starts_with(sdl_root.path()));
+    assert_eq!(fetch_list("SDL", "sdl", config, 0).unwrap().files().len(), 1);
+
+    // Files must not be found outside of their own root
+    assert!(open_server_file("SDL\\test.pak", config, 0).await.is_none());
+    assert!(open_server_file("Python\\..\\SDL\\test.sdl", config, 0).await.is_none());
+    assert!(open_server_file("Python\\x/../../SDL/test.pak", config, 0).await.is_none());
+}
+
+#[tokio::test]
+async fn test_build_file_roots() {
+    let sdl_root = tempfile::tempdir().unwrap();
+    for (dir, contents) in [("SDL", "shared"), ("919/SDL", "build 919")] {
+        std::fs::create_dir_all(sdl_root.path().join(dir)).unwrap();
+        std::fs::write(sdl_root.path().join(dir).join("test.sdl"), contents).unwrap();
+    }
+    std::fs::write(sdl_root.path().join("SDL").join("shared.sdl"), "shared").unwrap();
+
+    let mut server_config = ServerConfig::test_config();
+    server_config.auth_file_roots.insert(UniCase::new("SDL".to_string()),
+                                         sdl_root.path().to_path_buf());
+    server_config.extra_build_ids = vec![919];
+    server_config.build_data_dirs = true;
+
+    for (filename, build_id, expected) in [
+        ("SDL\\test.sdl", 919, "build 919"),
+        ("SDL\\shared.sdl", 919, "shared"),
+        ("SDL\\test.sdl", 0, "shared"),
+    ] {
+        let (_, _, path) = open_server_file(filename, &server_config, build_id).await.unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap(), expected);
+    }
+
+    // Build-specific files replace the shared files with the same name
+    let manifest = fetch_list("SDL", "sdl", &server_config, 919).unwrap();
+    assert_eq!(manifest.files().len(), 2);
+    assert_eq!(fetch_list("SDL", "sdl", &server_config, 0).unwrap().files().len(), 2);
+}
+
+#[tokio::test]
+async fn test_disabled_account_login() {
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let mut account = vault.get_account("Tester").await.unwrap().unwrap();
+    assert_eq!(account_status_error(&account), None);
+
+    account.account_flags |= AccountInfo::DISABLED;
+    vault.update_account(account).await.unwrap();
+    let account = vault.get_account("Tester").await.unwrap().unwrap();
+    assert!(account.is_disabled());
+    assert!(!account.is_banned());
+    assert_eq!(account_status_error(&account).map(|(result, _)| result),
+               Some(NetResultCode::NetAccountNotActivated));
+
+    // Banned accounts are reported as banned, even if also disabled
+    let mut banned = account.clone();
+    banned.account_flags |= AccountInfo::BANNED;
+    assert_eq!(account_status_error(&banned).map(|(result, _)| result),
+               Some(NetResultCode::NetAccountBanned));
+}
+
+#[tokio::test]
+async fn test_token_login() {
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let mut account = vault.get_account("Tester").await.unwrap().unwrap();
+
+    // No token has been issued yet
+    assert!(token_login_account(&vault, "Tester", "").await.unwrap().is_none());
+    assert!(token_login_account(&vault, "Tester", &account.api_token).await
+                .unwrap().is_none());
+
+    account.login_token = "0123456789abcdef".to_string();
+    vault.update_account(account.clone()).await.unwrap();
+    let logged_in = token_login_account(&vault, "tester", "0123456789ABCDEF").await
+            .unwrap().unwrap();
+    assert_eq!(logged_in.account_id, account.account_id);
+
+    // The admin API token is not a login token
+    assert!(token_login_account(&vault, "Tester", &account.api_token).await
+                .unwrap().is_none());
+    assert!(token_login_account(&vault, "Tester", "fedcba9876543210").await
+                .unwrap().is_none());
+
+    // The token must match the account name being logged into
+    vault.get_account("Other").await.unwrap().unwrap();
+    assert!(token_login_account(&vault, "Other", &account.login_token).await
+                .unwrap().is_none());
+}
+
+// Creates a worker for the server side of a local connection.  The returned
+// client socket is not decrypted, so replies must be passed through
+// decrypt_test_reply.
+#[cfg(test)]
+async fn test_worker(server_config: Arc<ServerConfig>, vault: Arc<VaultServer>,
+                     session_limiter: SessionLimiter) -> (AuthServerWorker, TcpStream)
+{
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let stream = BufReader::new(CryptTcpStream::new(sock, &TEST_CRYPT_KEY));
+    let ping_tracker = PingStats::new().tracker(PingService::Auth);
+    let worker = AuthServerWorker::new(stream, server_config, vault, ping_tracker,
+                                       session_limiter);
+    (worker, client)
+}
+
+#[cfg(test)]
+const TEST_CRYPT_KEY: [u8; 7] = [1, 2, 3, 4, 5, 6, 7];
+
+#[cfg(test)]
+fn decrypt_test_reply(reply: &mut [u8]) {
+    use rc4::{Key, KeyInit, StreamCipher};
+
+    rc4::Rc4::<rc4::consts::U7>::new(Key::from_slice(&TEST_CRYPT_KEY)).apply_keystream(reply);
+}
+
+#[tokio::test]
+async fn test_login_server_busy() {
+    use crate::sdl::DescriptorDb;
+
+    let data_dir = tempfile::tempdir().unwrap();
+    let mut server_config = ServerConfig::test_config();
+    server_config.data_root = data_dir.path().to_path_buf();
+    let server_config = Arc::new(server_config);
+    let vault = Arc::new(VaultServer::start(server_config.clone(), DescriptorDb::empty()));
+    let pass_hash = ShaDigest::sha1(b"password");
+    vault.create_account("BusyTester", pass_hash, 0).await.unwrap();
+
+    // Another client already holds the only session
+    let session_limiter = SessionLimiter::new(1);
+    let other_session = session_limiter.try_acquire().unwrap();
+    let (mut worker, mut client) = test_worker(server_config, vault,
+                                               session_limiter.clone()).await;
+    assert!(worker.do_login_request(7, 0, "BusyTester", pass_hash.endian_swap(), "").await);
+    assert!(worker.session_guard.is_none());
+    assert!(worker.account_id.is_none());
+
+    let mut expected = Vec::new();
+    AuthToCli::login_error(7, NetResultCode::NetServerBusy).stream_write(&mut expected).unwrap();
+    let mut reply = vec![0; expected.len()];
+    client.read_exact(&mut reply).await.unwrap();
+    decrypt_test_reply(&mut reply);
+    assert_eq!(reply, expected);
+
+    // Once the other session ends, the same login is accepted
+    drop(other_session);
+    assert!(worker.do_login_request(8, 0, "BusyTester", pass_hash.endian_swap(), "").await);
+    assert!(worker.session_guard.is_some());
+    assert_eq!(session_limiter.session_count(), 1);
+}
+
+#[tokio::test]
+async fn test_worker_exits_on_closed_vault() {
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = Arc::new(VaultServer::start(server_config.clone(), DescriptorDb::empty()));
+    let (mut worker, _client) = test_worker(server_config, vault, SessionLimiter::new(0)).await;
+
+    // The client stays connected and within the idle timeout, so only the
+    // closed broadcast channel can end the worker's loop
+    let (bcast_send, bcast_recv) = broadcast::channel(1);
+    drop(bcast_send);
+    worker.vault_bcast = bcast_recv;
+    tokio::time::timeout(Duration::from_secs(5), worker.run()).await
+            .expect("Worker did not exit after the vault broadcast closed");
 }
@@ -14,117 +14,465 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use anyhow::anyhow;
 use log::{warn, debug};
+use num_traits::FromPrimitive;
 use uuid::Uuid;
 
-use crate::netcli::{NetResult, NetResultCode};
+use crate::config::ServerConfig;
+use crate::netcli::{KickReason, NetResult, NetResultCode};
 use crate::sdl;
 use crate::vault::{
-    VaultServer, VaultPlayerInfoNode, VaultAgeNode, VaultFolderNode,
+    NodeType, VaultServer, VaultNode, VaultPlayerInfoNode, VaultAgeNode, VaultFolderNode,
     VaultSdlNode, VaultAgeLinkNode, VaultPlayerInfoListNode, VaultAgeInfoNode,
-    VaultAgeInfoListNode, StandardNode, PlayerInfo, GameServer
+    VaultAgeInfoListNode, StandardNode, PlayerInfo, GameServer, SpawnPointInfo, NodeRef,
+    NodeOwner
 };
 
-pub async fn create_player_nodes(account_id: &Uuid, player: &PlayerInfo,
-                                 vault: &VaultServer) -> NetResult<()>
-{
-    let node = VaultPlayerInfoNode::new(account_id, player.player_id, &player.player_name);
-    let player_info = vault.create_node(node).await?;
+// Standard folders which are referenced directly by every Player node
+type FolderCtor = fn(&Uuid, u32, StandardNode) -> VaultNode;
+const PLAYER_FOLDERS: [(StandardNode, FolderCtor); 11] = [
+    (StandardNode::BuddyListFolder, VaultPlayerInfoListNode::new),
+    (StandardNode::IgnoreListFolder, VaultPlayerInfoListNode::new),
+    (StandardNode::PlayerInviteFolder, VaultFolderNode::new),
+    (StandardNode::AgesIOwnFolder, VaultAgeInfoListNode::new),
+    (StandardNode::AgeJournalsFolder, VaultFolderNode::new),
+    (StandardNode::ChronicleFolder, VaultFolderNode::new),
+    (StandardNode::AgesICanVisitFolder, VaultAgeInfoListNode::new),
+    (StandardNode::AvatarOutfitFolder, VaultFolderNode::new),
+    (StandardNode::AvatarClosetFolder, VaultFolderNode::new),
+    (StandardNode::InboxFolder, VaultFolderNode::new),
+    (StandardNode::PeopleIKnowAboutFolder, VaultPlayerInfoListNode::new),
+];
 
-    let node = VaultPlayerInfoListNode::new(account_id, player.player_id,
-                                            StandardNode::BuddyListFolder);
-    let buddy_list = vault.create_node(node).await?;
+// Folders which are managed by the server, and may not be created by clients
+const SERVER_FOLDERS: [StandardNode; 7] = [
+    StandardNode::VaultMgrGlobalDataFolder, StandardNode::AllPlayersFolder,
+    StandardNode::AllAgeGlobalSDLNodesFolder, StandardNode::PublicAgesFolder,
+    StandardNode::SystemNode, StandardNode::CCRPlayersFolder,
+    StandardNode::GlobalInboxFolder,
+];
 
-    let node = VaultPlayerInfoListNode::new(account_id, player.player_id,
-                                            StandardNode::IgnoreListFolder);
-    let ignore_list = vault.create_node(node).await?;
+const DEFAULT_LINK_SPAWN: &[u8] = b"Default:LinkInPointDefault:;";
+const CITY_LINK_SPAWN: &[u8] = b"Ferry Terminal:LinkInPointFerry:;";
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::PlayerInviteFolder);
-    let invite_folder = vault.create_node(node).await?;
+// Descriptions of the placeholder 'Hood and City links, as reported by
+// create_player_nodes
+const HOOD_LINK_NAME: &str = "Neighborhood AgeLink";
+const CITY_LINK_NAME: &str = "City AgeLink";
 
-    let node = VaultAgeInfoListNode::new(account_id, player.player_id,
-                                         StandardNode::AgesIOwnFolder);
-    let owned_ages = vault.create_node(node).await?;
+// Shared Ages which a new account's first player is linked to, along with
+// the instance name to use if the shared instance doesn't exist yet.
+const STARTING_AGES: [(&str, &str, &str); 2] = [
+    (HOOD_LINK_NAME, "Neighborhood", "Neighborhood"),
+    (CITY_LINK_NAME, "city", "Ae'gura"),
+];
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::AgeJournalsFolder);
-    let journals_folder = vault.create_node(node).await?;
+// Like VaultServer::fetch_node, but treats a missing node as a dangling ref
+// rather than an error.
+async fn fetch_ref_child(node_id: u32, vault: &VaultServer)
+    -> NetResult<Option<Arc<VaultNode>>>
+{
+    match vault.fetch_node(node_id).await {
+        Ok(node) => Ok(Some(node)),
+        Err(NetResultCode::NetVaultNodeNotFound) => {
+            warn!("Skipping reference to missing node {}", node_id);
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::ChronicleFolder);
-    let chronicle_folder = vault.create_node(node).await?;
+// Creates any of the player's standard vault nodes which don't already exist.
+// This is used both for initializing new players and for repairing players
+// whose vault tree is missing some of its nodes, so it is safe to call more
+// than once.  Returns a description and node ID for each node that was
+// created.  The nodes are created in a single transaction, so a failure
+// doesn't leave any of them behind.
+pub async fn create_player_nodes(account_id: &Uuid, player: &PlayerInfo,
+                                 vault: &VaultServer) -> NetResult<Vec<(String, u32)>>
+{
+    let txn = vault.begin_transaction().await?;
+    let result = init_player_nodes(account_id, player, txn.vault()).await;
+    txn.finish(result).await
+}
+
+// Creates a new player along with all of its standard vault nodes.  The
+// player itself is created in the same transaction as its nodes, so a
+// failure doesn't leave a half-built player in the account's player list.
+pub async fn create_new_player(account_id: &Uuid, player_name: &str, avatar_shape: &str,
+                               vault: &VaultServer)
+    -> NetResult<(PlayerInfo, Vec<(String, u32)>)>
+{
+    let txn = vault.begin_transaction().await?;
+    let result = match txn.vault().create_player(account_id, player_name, avatar_shape).await {
+        Ok(player) => init_player_nodes(account_id, &player, txn.vault()).await
+                            .map(|created| (player, created)),
+        Err(err) => Err(err),
+    };
+    txn.finish(result).await
+}
 
-    let node = VaultAgeInfoListNode::new(account_id, player.player_id,
-                                         StandardNode::AgesICanVisitFolder);
-    let visit_ages = vault.create_node(node).await?;
+async fn init_player_nodes(account_id: &Uuid, player: &PlayerInfo, vault: &VaultServer)
+    -> NetResult<Vec<(String, u32)>>
+{
+    let mut created = Vec::new();
+    let system_node = vault.get_system_node().await?;
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::AvatarOutfitFolder);
-    let outfit_folder = vault.create_node(node).await?;
+    let mut has_system = false;
+    let mut player_info = None;
+    let mut folders = HashMap::new();
+    for node_ref in vault.fetch_refs(player.player_id, false).await? {
+        if node_ref.child() == system_node {
+            has_system = true;
+            continue;
+        }
+        let Some(node) = fetch_ref_child(node_ref.child(), vault).await? else {
+            continue;
+        };
+        if let Some(info_node) = node.as_player_info_node() {
+            player_info = Some(info_node.node_id());
+        } else if let Some(folder) = node.as_folder_node() {
+            folders.entry(folder.folder_type()).or_insert(folder.node_id());
+        }
+    }
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::AvatarClosetFolder);
-    let closet_folder = vault.create_node(node).await?;
+    if !has_system {
+        vault.ref_node(player.player_id, system_node, 0, false).await?;
+    }
 
-    let node = VaultFolderNode::new(account_id, player.player_id,
-                                    StandardNode::InboxFolder);
-    let inbox = vault.create_node(node).await?;
+    let player_info = if let Some(node_id) = player_info {
+        node_id
+    } else {
+        let node = VaultPlayerInfoNode::new(account_id, player.player_id,
+                                            &player.player_name);
+        let node_id = vault.create_node(node).await?;
+        vault.ref_node(player.player_id, node_id, 0, false).await?;
 
-    let node = VaultPlayerInfoListNode::new(account_id, player.player_id,
-                                            StandardNode::PeopleIKnowAboutFolder);
-    let people_list = vault.create_node(node).await?;
+        // Add the player to the All Players folder
+        let all_players = vault.get_all_players_node().await?;
+        vault.ref_node(all_players, node_id, 0, true).await?;
+        created.push((format!("{:?}", StandardNode::PlayerInfoNode), node_id));
+        node_id
+    };
 
-    let node = VaultAgeLinkNode::new(account_id, player.player_id,
-                                     b"Default:LinkInPointDefault:;");
-    let relto_link = vault.create_node(node).await?;
+    for (folder_type, new_folder) in PLAYER_FOLDERS {
+        if folders.contains_key(&(folder_type as i32)) {
+            continue;
+        }
+        let node = new_folder(account_id, player.player_id, folder_type);
+        let node_id = vault.create_node(node).await?;
+        vault.ref_node(player.player_id, node_id, 0, false).await?;
+        folders.insert(folder_type as i32, node_id);
+        created.push((format!("{folder_type:?}"), node_id));
+    }
 
-    let node = VaultAgeLinkNode::new(account_id, player.player_id,
-                                     b"Default:LinkInPointDefault:;");
-    let hood_link = vault.create_node(node).await?;
+    // The standard links are identified by the Age they point to.  The
+    // 'Hood and City links don't have an Age yet, so those are identified
+    // by their spawn point instead.
+    let owned_ages = folders[&(StandardNode::AgesIOwnFolder as i32)];
+    let (mut has_relto, mut has_hood, mut has_city) = (false, false, false);
+    for node_ref in vault.fetch_refs(owned_ages, false).await? {
+        let Some(link) = fetch_ref_child(node_ref.child(), vault).await?
+                            .and_then(|node| node.as_age_link_node()) else {
+            continue;
+        };
+        let mut age_filename = None;
+        for link_ref in vault.fetch_refs(link.node_id(), false).await? {
+            if let Some(age_info) = fetch_ref_child(link_ref.child(), vault).await?
+                                        .and_then(|node| node.as_age_info_node())
+            {
+                age_filename = Some(age_info.age_filename().clone());
+            }
+        }
+        match age_filename.as_deref() {
+            Some("Personal") => has_relto = true,
+            Some("Neighborhood") => has_hood = true,
+            Some("city") => has_city = true,
+            None if link.spawn_points().as_slice() == CITY_LINK_SPAWN => has_city = true,
+            None if link.spawn_points().as_slice() == DEFAULT_LINK_SPAWN => has_hood = true,
+            Some(_) | None => (),
+        }
+    }
 
-    let node = VaultAgeLinkNode::new(account_id, player.player_id,
-                                     b"Ferry Terminal:LinkInPointFerry:;");
-    let city_link = vault.create_node(node).await?;
+    if !has_relto {
+        let user_name = format!("{}'s", player.player_name);
+        let description = format!("{}'s Relto", player.player_name);
+        let (relto_id, relto_info) = create_age_nodes(&Uuid::new_v4(), &Uuid::nil(),
+                "Personal", "Relto", &user_name, &description, 0, -1,
+                Some((player.player_id, player_info)), false, vault).await?;
 
-    let user_name = format!("{}'s", player.player_name);
-    let description = format!("{}'s Relto", player.player_name);
-    let (relto_id, relto_info) = create_age_nodes(&Uuid::new_v4(), &Uuid::nil(),
-            "Personal", "Relto", &user_name, &description, 0, -1,
-            Some((player.player_id, player_info)), false, vault).await?;
+        let spawn_point = SpawnPointInfo::new("Default", "LinkInPointDefault");
+        let relto_link = create_age_link(owned_ages, account_id, player.player_id,
+                                         relto_info, &spawn_point, vault).await?;
+        vault.ref_node(relto_id, owned_ages, 0, false).await?;
+        created.push(("Relto AgeLink".to_string(), relto_link));
+    }
 
     // TODO: Add the new player to a 'Hood
+    if !has_hood {
+        let node = VaultAgeLinkNode::new(account_id, player.player_id, DEFAULT_LINK_SPAWN);
+        let hood_link = vault.create_node(node).await?;
+        vault.ref_node(owned_ages, hood_link, 0, false).await?;
+        /* TODO vault.ref_node(hood_link, hood_info, 0, false).await?; */
+        created.push((HOOD_LINK_NAME.to_string(), hood_link));
+    }
+
     // TODO: Get the public city age
+    if !has_city {
+        let node = VaultAgeLinkNode::new(account_id, player.player_id, CITY_LINK_SPAWN);
+        let city_link = vault.create_node(node).await?;
+        vault.ref_node(owned_ages, city_link, 0, false).await?;
+        /* TODO vault.ref_node(city_link, city_info, 0, false).await?; */
+        created.push((CITY_LINK_NAME.to_string(), city_link));
+    }
 
-    let system_node = vault.get_system_node().await?;
-    vault.ref_node(player.player_id, system_node, 0, false).await?;
-    vault.ref_node(player.player_id, player_info, 0, false).await?;
-    vault.ref_node(player.player_id, buddy_list, 0, false).await?;
-    vault.ref_node(player.player_id, ignore_list, 0, false).await?;
-    vault.ref_node(player.player_id, invite_folder, 0, false).await?;
-    vault.ref_node(player.player_id, owned_ages, 0, false).await?;
-    vault.ref_node(player.player_id, journals_folder, 0, false).await?;
-    vault.ref_node(player.player_id, chronicle_folder, 0, false).await?;
-    vault.ref_node(player.player_id, visit_ages, 0, false).await?;
-    vault.ref_node(player.player_id, outfit_folder, 0, false).await?;
-    vault.ref_node(player.player_id, closet_folder, 0, false).await?;
-    vault.ref_node(player.player_id, inbox, 0, false).await?;
-    vault.ref_node(player.player_id, people_list, 0, false).await?;
-    vault.ref_node(owned_ages, relto_link, 0, false).await?;
-    vault.ref_node(owned_ages, hood_link, 0, false).await?;
-    vault.ref_node(owned_ages, city_link, 0, false).await?;
-    vault.ref_node(relto_link, relto_info, 0, false).await?;
-    /* TODO vault.ref_node(hood_link, hood_info, 0, false).await?; */
-    /* TODO vault.ref_node(city_link, city_info, 0, false).await?; */
-    vault.ref_node(relto_id, owned_ages, 0, false).await?;
-
-    // Add the player to the All Players folder
-    let all_players = vault.get_all_players_node().await?;
-    vault.ref_node(all_players, player_info, 0, true).await?;
+    Ok(created)
+}
+
+// Creates an AgeLink node in the parent folder, which links to the Age
+// described by the age_info node at the given spawn point.
+pub async fn create_age_link(parent_id: u32, creator_uuid: &Uuid, creator_id: u32,
+        age_info_id: u32, spawn_point: &SpawnPointInfo, vault: &VaultServer)
+        -> NetResult<u32>
+{
+    let node = VaultAgeLinkNode::with_spawn_point(creator_uuid, creator_id, spawn_point);
+    let link_id = vault.create_node(node).await?;
+    vault.ref_node(parent_id, link_id, 0, false).await?;
+    vault.ref_node(link_id, age_info_id, 0, false).await?;
+    Ok(link_id)
+}
+
+// Finds the Age Info node of one of the player's own Ages (e.g. their Relto,
+// which has the "Personal" Age filename).
+async fn find_owned_age(player_id: u32, age_filename: &str, vault: &VaultServer)
+    -> NetResult<Option<VaultAgeInfoNode>>
+{
+    Ok(vault.player_ages(player_id).await?.into_iter()
+        .map(|(_, age_info)| age_info)
+        .find(|age_info| age_info.age_filename().eq_ignore_ascii_case(age_filename)))
+}
+
+// Moves a player to another Age instance (by default, their Relto), e.g. to
+// rescue a player who is stuck in a broken Age.  Updating the PlayerInfo node
+// also notifies anyone with the player on their buddy list.  If the player is
+// online, their session is kicked so the client logs in and links again.
+pub async fn set_player_location(player_id: u32, instance_id: Option<&Uuid>,
+                                 vault: &VaultServer) -> NetResult<VaultAgeInfoNode>
+{
+    let player_info = match vault.get_player_info_node(player_id).await
+                                .map(|node| node.as_player_info_node())
+    {
+        Ok(Some(node)) => node,
+        Ok(None) | Err(NetResultCode::NetVaultNodeNotFound) => {
+            return Err(NetResultCode::NetPlayerNotFound);
+        }
+        Err(err) => return Err(err),
+    };
+
+    let age_info = if let Some(instance_id) = instance_id {
+        let template = VaultAgeInfoNode::new_lookup(Some(instance_id));
+        match vault.find_nodes(template).await?.first() {
+            Some(node_id) => vault.fetch_node(*node_id).await?.as_age_info_node(),
+            None => None,
+        }
+    } else {
+        find_owned_age(player_id, "Personal", vault).await?
+    };
+    let Some(age_info) = age_info else {
+        return Err(NetResultCode::NetAgeNotFound);
+    };
+
+    let node = VaultPlayerInfoNode::new_update(player_info.node_id(), player_info.online(),
+                                               age_info.age_instance_name(),
+                                               age_info.age_instance_uuid());
+    vault.update_node(node).await?;
+    if player_info.online() != 0 {
+        vault.kick_player(player_id, KickReason::KickedByCCR);
+    }
+    Ok(age_info)
+}
+
+// Ensures that an existing player has all of its standard vault nodes.
+pub async fn repair_player_nodes(player_id: u32, vault: &VaultServer)
+    -> NetResult<Vec<(String, u32)>>
+{
+    let player_node = match vault.fetch_node(player_id).await
+                                .map(|node| node.as_player_node())
+    {
+        Ok(Some(node)) => node,
+        Ok(None) | Err(NetResultCode::NetVaultNodeNotFound) => {
+            return Err(NetResultCode::NetPlayerNotFound);
+        }
+        Err(err) => return Err(err),
+    };
+
+    let player = PlayerInfo {
+        player_id,
+        player_name: player_node.player_name_ci().clone(),
+        avatar_shape: player_node.avatar_shape().clone(),
+        explorer: player_node.explorer(),
+    };
+    create_player_nodes(player_node.account_id(), &player, vault).await
+}
+
+// Checks whether a client may create the specified node.  Every node needs
+// a valid node type, and non-admin clients are further restricted to the
+// configured node types, excluding server-managed folders.
+pub fn check_node_create(node: &VaultNode, is_admin: bool, server_config: &ServerConfig)
+    -> NetResult<()>
+{
+    if !node.has_node_type() {
+        return Err(NetResultCode::NetInvalidParameter);
+    }
+    let node_type = match NodeType::from_i32(node.node_type()) {
+        None | Some(NodeType::_Invalid | NodeType::_VNodeMgrLow | NodeType::_VNodeMgrHigh
+                    | NodeType::_Unused01 | NodeType::_Unused02) => {
+            return Err(NetResultCode::NetInvalidParameter);
+        }
+        Some(node_type) => node_type,
+    };
+    if is_admin {
+        return Ok(());
+    }
+
+    if !server_config.client_node_types.contains(&node_type) {
+        return Err(NetResultCode::NetServiceForbidden);
+    }
+    let is_folder = matches!(node_type,
+            NodeType::Folder | NodeType::PlayerInfoList | NodeType::AgeInfoList);
+    if is_folder && node.has_int32_1()
+            && SERVER_FOLDERS.iter().any(|&folder| folder as i32 == node.int32_1())
+    {
+        return Err(NetResultCode::NetServiceForbidden);
+    }
+    Ok(())
+}
+
+// Client SDL blobs are read with the configured limits before they are
+// stored, so a malformed or oversized state is never saved to the vault.
+// Blobs for descriptors the server doesn't have are only checked for size.
+pub fn check_client_sdl(node: &VaultNode, sdl_db: &sdl::DescriptorDb, limits: &sdl::SdlLimits)
+    -> NetResult<()>
+{
+    if node.node_type() != NodeType::Sdl as i32 || node.blob_1().is_empty() {
+        return Ok(());
+    }
+    let sdl_blob = node.blob_1();
+    let result = if sdl_db.get_latest(node.string64_1()).is_some() {
+        sdl::State::from_blob_limited(sdl_blob, sdl_db, limits).map(|_| ())
+    } else if sdl_blob.len() > limits.max_blob_size {
+        Err(anyhow!("SDL blob is too large ({} > {} bytes)",
+                    sdl_blob.len(), limits.max_blob_size))
+    } else {
+        Ok(())
+    };
+    result.map_err(|err| {
+        warn!("Rejecting SDL blob for {}: {:#}", node.string64_1(), err);
+        NetResultCode::NetInvalidParameter
+    })
+}
+
+// Checks whether a player may add or remove refs under the specified node.
+// Players may modify their own nodes, as well as nodes belonging to any Age
+// instance which is linked from their vault (i.e. Ages they own or can visit).
+// The CCRPlayersFolder reveals the presence of CCRs, so only CCRs may modify
+// or even fetch its contents.
+pub async fn can_modify_node(node_id: u32, player_id: u32, account_id: &Uuid, is_ccr: bool,
+                             vault: &VaultServer) -> NetResult<bool>
+{
+    if node_id == player_id {
+        return Ok(true);
+    }
+    if node_id == vault.get_ccr_players_node().await? {
+        return Ok(is_ccr);
+    }
+    let node = vault.fetch_node(node_id).await?;
+    if node.creator_id() == player_id || node.creator_uuid() == account_id {
+        return Ok(true);
+    }
+    let is_owner = |owners: Vec<NodeOwner>| owners.iter().any(|owner| owner.player_id == player_id);
+    if is_owner(vault.node_owner(node_id).await?) {
+        return Ok(true);
+    }
+
+    // Nodes owned by an Age are created with the Age instance's UUID, and
+    // the Age Info node is in the vault tree of each player linked to it
+    let template = VaultAgeInfoNode::new_lookup(Some(node.creator_uuid()));
+    let Some(age_info) = vault.find_nodes(template).await?.first().copied() else {
+        return Ok(false);
+    };
+    Ok(is_owner(vault.node_owner(age_info).await?))
+}
+
+// Links the placeholder 'Hood and City links created by create_player_nodes
+// (given the nodes it reported) to the shared public instances of those Ages,
+// creating the instances if necessary.  Returns a list describing the Ages
+// that were linked.
+pub async fn bootstrap_first_player(created: &[(String, u32)], vault: &VaultServer)
+    -> NetResult<Vec<String>>
+{
+    let mut linked = Vec::new();
+    for (node_name, link_id) in created {
+        let Some((_, age_filename, instance_name)) = STARTING_AGES.iter()
+                .find(|(link_name, _, _)| node_name == link_name) else {
+            continue;
+        };
+        if !vault.fetch_refs(*link_id, false).await?.is_empty() {
+            // Already linked to an Age
+            continue;
+        }
+
+        let template = VaultAgeInfoNode::new_public_lookup(age_filename);
+        let age_info = if let Some(node_id) = vault.find_nodes(template).await?.first() {
+            *node_id
+        } else {
+            let (_, age_info) = create_age_nodes(&Uuid::new_v4(), &Uuid::nil(), age_filename,
+                    instance_name, "", "", 0, -1, None, true, vault).await?;
+            age_info
+        };
+        vault.ref_node(*link_id, age_info, 0, false).await?;
+        linked.push((*age_filename).to_string());
+    }
+
+    Ok(linked)
+}
+
+// Fetches the whole ref tree under a node for a client.  Trees with more
+// than max_refs refs are rejected with NetInvalidParameter, since they would
+// otherwise be sent as a single, enormous VaultNodeRefsFetched reply.
+pub async fn fetch_client_refs(node_id: u32, max_refs: usize, vault: &VaultServer)
+    -> NetResult<Vec<NodeRef>>
+{
+    let refs = vault.fetch_ref_tree(node_id, max_refs).await?;
+    if refs.len() > max_refs {
+        warn!("Ref tree for node {} has more than {} refs; rejecting", node_id, max_refs);
+        return Err(NetResultCode::NetInvalidParameter);
+    }
+    Ok(refs)
+}
 
+// Adds all of a CCR account's players to the CCRPlayersFolder
+pub async fn add_ccr_players(account_id: &Uuid, vault: &VaultServer) -> NetResult<()> {
+    let ccr_players = vault.get_ccr_players_node().await?;
+    let existing = vault.fetch_refs(ccr_players, false).await?;
+    for player in vault.get_players(account_id).await? {
+        let player_info = vault.get_player_info_node(player.player_id).await?;
+        if !existing.iter().any(|node_ref| node_ref.child() == player_info.node_id()) {
+            vault.ref_node(ccr_players, player_info.node_id(), 0, true).await?;
+        }
+    }
     Ok(())
 }
 
+// Finds the Age and Age Info nodes of an Age instance, creating the whole
+// Age node tree in a single transaction if the instance doesn't exist yet.
+// Most requests are for existing instances, so those are looked up without
+// holding up the vault for a transaction.
 #[allow(clippy::too_many_arguments)]
 pub async fn find_age_instance(age_uuid: &Uuid, parent_uuid: &Uuid,
         age_filename: &str, instance_name: &str, user_name: &str, description: &str,
@@ -132,22 +480,46 @@ pub async fn find_age_instance(age_uuid: &Uuid, parent_uuid: &Uuid,
         -> NetResult<(u32, u32)>
 {
     let template = VaultAgeNode::new_lookup(Some(age_uuid));
-    let age_id = match vault.find_nodes(template).await?.first() {
-        Some(node_id) => *node_id,
-        None => return create_age_nodes(age_uuid, parent_uuid, age_filename,
+    if let Some(age_id) = vault.find_nodes(template).await?.first().copied() {
+        return Ok((age_id, find_age_info(age_id, age_uuid, vault).await?));
+    }
+
+    // Another client may still create the instance before the transaction
+    // starts, so find_or_init_age checks for it again.
+    let txn = vault.begin_transaction().await?;
+    let result = find_or_init_age(age_uuid, parent_uuid, age_filename, instance_name,
+                        user_name, description, sequence_number, language,
+                        txn.vault()).await;
+    txn.finish(result).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn find_or_init_age(age_uuid: &Uuid, parent_uuid: &Uuid,
+        age_filename: &str, instance_name: &str, user_name: &str, description: &str,
+        sequence_number: i32, language: i32, vault: &VaultServer)
+        -> NetResult<(u32, u32)>
+{
+    let template = VaultAgeNode::new_lookup(Some(age_uuid));
+    let node = VaultAgeNode::new(age_uuid, parent_uuid, age_filename);
+    let (age_id, created) = vault.find_or_create_node(template, node).await?;
+    if created {
+        let age_info = init_age_nodes(age_id, age_uuid, parent_uuid, age_filename,
                             instance_name, user_name, description,
-                            sequence_number, language, None, false, vault).await,
-    };
+                            sequence_number, language, None, false, vault).await?;
+        return Ok((age_id, age_info));
+    }
+
+    Ok((age_id, find_age_info(age_id, age_uuid, vault).await?))
+}
 
+async fn find_age_info(age_id: u32, age_uuid: &Uuid, vault: &VaultServer) -> NetResult<u32> {
     let template = VaultAgeInfoNode::new_lookup(Some(age_uuid));
-    let age_info = if let Some(node_id) = vault.find_nodes(template).await?.first() {
-        *node_id
+    if let Some(node_id) = vault.find_nodes(template).await?.first() {
+        Ok(*node_id)
     } else {
         warn!("Got Age node {}, but no Age Info node for {}", age_id, age_uuid);
-        return Err(NetResultCode::NetInternalError);
-    };
-
-    Ok((age_id, age_info))
+        Err(NetResultCode::NetInternalError)
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -158,7 +530,20 @@ pub async fn create_age_nodes(age_uuid: &Uuid, parent_uuid: &Uuid,
 {
     let node = VaultAgeNode::new(age_uuid, parent_uuid, age_filename);
     let age_id = vault.create_node(node).await?;
+    let age_info = init_age_nodes(age_id, age_uuid, parent_uuid, age_filename,
+                        instance_name, user_name, description, sequence_number,
+                        language, add_owner, public, vault).await?;
+    Ok((age_id, age_info))
+}
 
+// Creates the rest of the standard node tree under a new Age node, and
+// returns the Age Info node ID.
+#[allow(clippy::too_many_arguments)]
+async fn init_age_nodes(age_id: u32, age_uuid: &Uuid, parent_uuid: &Uuid,
+        age_filename: &str, instance_name: &str, user_name: &str, description: &str,
+        sequence_number: i32, language: i32, add_owner: Option<(u32, u32)>, public: bool,
+        vault: &VaultServer) -> NetResult<u32>
+{
     let node = VaultFolderNode::new(age_uuid, age_id, StandardNode::ChronicleFolder);
     let chronicle_folder = vault.create_node(node).await?;
 
@@ -182,7 +567,8 @@ pub async fn create_age_nodes(age_uuid: &Uuid, parent_uuid: &Uuid,
     let can_visit = vault.create_node(node).await?;
 
     let sdl_blob = if let Some(descriptor) = vault.sdl_db().get_latest(age_filename) {
-        match sdl::State::from_defaults(descriptor, vault.sdl_db()).to_blob() {
+        match sdl::State::from_defaults(descriptor, vault.sdl_db())
+                .and_then(|state| state.to_blob()) {
             Ok(blob) => blob,
             Err(err) => {
                 warn!("Failed to generate default SDL for {}: {}", age_filename, err);
@@ -193,7 +579,11 @@ pub async fn create_age_nodes(age_uuid: &Uuid, parent_uuid: &Uuid,
         debug!("Could not find SDL descriptor for {}", age_filename);
         Vec::new()
     };
-    let node = VaultSdlNode::new(age_uuid, age_id, age_filename, &sdl_blob);
+    let node = VaultSdlNode::new(age_uuid, age_id, age_filename, &sdl_blob,
+                                 vault.compress_sdl()).map_err(|err| {
+        warn!("Failed to compress SDL blob for {}: {}", age_filename, err);
+        NetResultCode::NetInternalError
+    })?;
     let sdl_node = vault.create_node(node).await?;
 
     let node = VaultPlayerInfoListNode::new(age_uuid, age_id,
@@ -220,22 +610,526 @@ pub async fn create_age_nodes(age_uuid: &Uuid, parent_uuid: &Uuid,
         vault.ref_node(age_owners, owner_info, owner_id, true).await?;
     }
 
-    let display_name = if !description.is_empty() {
+    let game_server = GameServer {
+        instance_id: *age_uuid,
+        age_filename: age_filename.to_string(),
+        display_name: age_display_name(description, user_name, instance_name,
+                                       age_filename).to_string(),
+        age_id,
+        sdl_id: sdl_node,
+        temporary: false
+    };
+    vault.add_game_server(game_server).await?;
+
+    Ok(age_info)
+}
+
+// Picks the most specific name available for an Age instance
+pub fn age_display_name<'a>(description: &'a str, user_name: &'a str,
+                            instance_name: &'a str, age_filename: &'a str) -> &'a str
+{
+    if !description.is_empty() {
         description
+    } else if !user_name.is_empty() {
+        user_name
     } else if !instance_name.is_empty() {
         instance_name
     } else {
         age_filename
+    }
+}
+
+// Game server records for temporary Age instances are removed once the
+// instance is empty, but the instance's vault nodes are kept.  This creates
+// a new record for an Age instance which still exists in the vault.
+async fn register_game_server(instance_id: &Uuid, vault: &VaultServer)
+    -> NetResult<Option<GameServer>>
+{
+    let template = VaultAgeNode::new_lookup(Some(instance_id));
+    let Some(age_id) = vault.find_nodes(template).await?.first().copied() else {
+        return Ok(None);
+    };
+    let template = VaultAgeInfoNode::new_lookup(Some(instance_id));
+    let Some(age_info) = vault.find_nodes(template).await?.first().copied() else {
+        warn!("Got Age node {}, but no Age Info node for {}", age_id, instance_id);
+        return Err(NetResultCode::NetInternalError);
+    };
+    let Some(age_info) = vault.fetch_node(age_info).await?.as_age_info_node() else {
+        return Err(NetResultCode::NetInternalError);
     };
+
+    let mut sdl_id = 0;
+    for node_ref in vault.fetch_refs(age_info.node_id(), false).await? {
+        if let Some(sdl_node) = fetch_ref_child(node_ref.child(), vault).await?
+                                    .and_then(|node| node.as_sdl_node())
+        {
+            sdl_id = sdl_node.node_id();
+            break;
+        }
+    }
+
     let game_server = GameServer {
-        instance_id: *age_uuid,
-        age_filename: age_filename.to_string(),
-        display_name: display_name.to_string(),
+        instance_id: *instance_id,
+        age_filename: age_info.age_filename().clone(),
+        display_name: age_display_name(age_info.age_description(),
+                                       age_info.age_user_defined_name(),
+                                       age_info.age_instance_name(),
+                                       age_info.age_filename()).to_string(),
         age_id,
-        sdl_id: sdl_node,
-        temporary: false
+        sdl_id,
+        temporary: true,
     };
-    vault.add_game_server(game_server).await?;
+    vault.add_game_server(game_server.clone()).await?;
+    Ok(Some(game_server))
+}
 
-    Ok((age_id, age_info))
+// Look up the game server record for an Age instance, along with the
+// address the client should connect to for it.  The address is sent to the
+// client as an IPv4 address packed into a u32.
+pub async fn find_game_server(instance_id: &Uuid, game_serv_ip: &str, vault: &VaultServer)
+    -> NetResult<(GameServer, u32)>
+{
+    let game_server = if let Some(game_server) = vault.get_game_server(instance_id).await? {
+        game_server
+    } else if let Some(game_server) = register_game_server(instance_id, vault).await? {
+        debug!("Registered new game server for Age instance {}", instance_id);
+        game_server
+    } else {
+        warn!("Requested unknown Age instance {}", instance_id);
+        return Err(NetResultCode::NetAgeNotFound);
+    };
+
+    let game_server_node = if let Ok(addr) = game_serv_ip.parse::<Ipv4Addr>() {
+        Some(u32::from(addr))
+    } else {
+        tokio::net::lookup_host((game_serv_ip, 0)).await.ok().and_then(|mut addrs| {
+            addrs.find_map(|addr| match addr.ip() {
+                IpAddr::V4(addr) => Some(u32::from(addr)),
+                IpAddr::V6(_) => None,
+            })
+        })
+    };
+    let Some(game_server_node) = game_server_node else {
+        warn!("Could not resolve game server address '{}' to an IPv4 address", game_serv_ip);
+        return Err(NetResultCode::NetInternalError);
+    };
+
+    Ok((game_server, game_server_node))
+}
+
+#[tokio::test]
+async fn test_create_age_link() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let node = VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::AgesIOwnFolder);
+    let folder_id = vault.create_node(node).await.unwrap();
+    let (_, age_info_id) = create_age_nodes(&Uuid::new_v4(), &Uuid::nil(), "Teledahn",
+            "Teledahn", "", "", 0, -1, None, false, &vault).await.unwrap();
+
+    let spawn_point = SpawnPointInfo::new("Dock", "LinkInPointDock");
+    let link_id = create_age_link(folder_id, &Uuid::nil(), 0, age_info_id, &spawn_point,
+                                  &vault).await.unwrap();
+
+    let link = vault.fetch_node(link_id).await.unwrap().as_age_link_node().unwrap();
+    assert_eq!(link.spawn_point_list(), vec![spawn_point]);
+    let folder_refs = vault.fetch_refs(folder_id, false).await.unwrap();
+    assert!(folder_refs.iter().any(|node_ref| node_ref.child() == link_id));
+    let link_refs = vault.fetch_refs(link_id, false).await.unwrap();
+    assert!(link_refs.iter().any(|node_ref| node_ref.child() == age_info_id));
+}
+
+#[tokio::test]
+async fn test_ccr_players_folder() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_id = Uuid::new_v4();
+    let player = vault.create_player(&account_id, "Friendly CCR", "male").await.unwrap();
+    create_player_nodes(&account_id, &player, &vault).await.unwrap();
+
+    // Adding the players again must not duplicate their refs
+    add_ccr_players(&account_id, &vault).await.unwrap();
+    add_ccr_players(&account_id, &vault).await.unwrap();
+    let ccr_players = vault.get_ccr_players_node().await.unwrap();
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap();
+    let refs = vault.fetch_refs(ccr_players, false).await.unwrap();
+    assert_eq!(refs.len(), 1);
+    assert_eq!(refs[0].child(), player_info.node_id());
+
+    let player_id = player.player_id;
+    assert_eq!(can_modify_node(ccr_players, player_id, &account_id, true, &vault).await,
+               Ok(true));
+    assert_eq!(can_modify_node(ccr_players, player_id, &account_id, false, &vault).await,
+               Ok(false));
+
+    // Other nodes are unaffected
+    let all_players = vault.get_all_players_node().await.unwrap();
+    assert_eq!(can_modify_node(all_players, player_id, &account_id, true, &vault).await,
+               Ok(false));
+    assert_eq!(can_modify_node(player_id, player_id, &account_id, false, &vault).await,
+               Ok(true));
+}
+
+#[tokio::test]
+async fn test_bootstrap_first_player() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let mut hood_infos = Vec::new();
+    for player_name in ["First Explorer", "Second Explorer"] {
+        let account_id = Uuid::new_v4();
+        let player = vault.create_player(&account_id, player_name, "female").await.unwrap();
+        let created = create_player_nodes(&account_id, &player, &vault).await.unwrap();
+
+        let mut linked = bootstrap_first_player(&created, &vault).await.unwrap();
+        linked.sort();
+        assert_eq!(linked, ["Neighborhood", "city"]);
+
+        // Running it again finds nothing left to link
+        assert!(bootstrap_first_player(&created, &vault).await.unwrap().is_empty());
+
+        // The Relto link shares the 'Hood link's spawn point, but must not
+        // be mistaken for it
+        let mut owned_ages = vault.player_ages(player.player_id).await.unwrap().into_iter()
+                .map(|(_, age_info)| age_info.age_filename().clone())
+                .collect::<Vec<_>>();
+        owned_ages.sort();
+        assert_eq!(owned_ages, ["Neighborhood", "Personal", "city"]);
+
+        let template = VaultAgeInfoNode::new_public_lookup("Neighborhood");
+        hood_infos.push(vault.find_nodes(template).await.unwrap());
+    }
+
+    // Both players share the same public 'Hood instance
+    assert_eq!(hood_infos[0].len(), 1);
+    assert_eq!(hood_infos[0], hood_infos[1]);
+}
+
+#[tokio::test]
+async fn test_find_game_server() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let instance_id = Uuid::new_v4();
+    let (age_id, _) = find_age_instance(&instance_id, &Uuid::nil(), "Teledahn", "Teledahn",
+                                        "", "", 0, -1, &vault).await.unwrap();
+
+    let (game_server, game_server_node) = find_game_server(&instance_id, "127.0.0.1", &vault)
+            .await.unwrap();
+    assert_eq!(game_server.instance_id, instance_id);
+    assert_eq!(game_server.age_id, age_id);
+    assert_eq!(game_server.age_filename, "Teledahn");
+    assert_eq!(game_server_node, 0x7F000001);
+
+    assert_eq!(find_game_server(&Uuid::new_v4(), "127.0.0.1", &vault).await.err(),
+               Some(NetResultCode::NetAgeNotFound));
+
+    // A game server record is created for an Age instance which exists in
+    // the vault without one (e.g. after a temporary instance was emptied)
+    let instance_id = Uuid::new_v4();
+    let node = VaultAgeNode::new(&instance_id, &Uuid::nil(), "Teledahn");
+    let age_id = vault.create_node(node).await.unwrap();
+    let node = VaultAgeInfoNode::new(&instance_id, age_id, 0, false, -1, &Uuid::nil(),
+                                     "Teledahn", "Teledahn", "", "");
+    vault.create_node(node).await.unwrap();
+    assert!(vault.get_game_server(&instance_id).await.unwrap().is_none());
+
+    let (game_server, _) = find_game_server(&instance_id, "127.0.0.1", &vault).await.unwrap();
+    assert_eq!(game_server.age_id, age_id);
+    assert_eq!(game_server.display_name, "Teledahn");
+    assert!(game_server.temporary);
+    let registered = vault.get_game_server(&instance_id).await.unwrap().unwrap();
+    assert_eq!(registered.age_id, age_id);
+}
+
+#[tokio::test]
+async fn test_set_player_location() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+    use crate::vault::messages::VaultBroadcast;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_id = Uuid::new_v4();
+    let player = vault.create_player(&account_id, "Stuck Explorer", "male").await.unwrap();
+    create_player_nodes(&account_id, &player, &vault).await.unwrap();
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap();
+    let node = VaultPlayerInfoNode::new_update(player_info.node_id(), 1, "Broken",
+                                               &Uuid::new_v4());
+    vault.update_node(node).await.unwrap();
+
+    // By default, the player is sent to their Relto
+    let mut bcast_recv = vault.subscribe();
+    let relto = set_player_location(player.player_id, None, &vault).await.unwrap();
+    assert_eq!(relto.age_filename(), "Personal");
+    let player_info = vault.get_player_info_node(player.player_id).await.unwrap()
+                           .as_player_info_node().unwrap();
+    assert_eq!(player_info.age_instance_name(), "Relto");
+    assert_eq!(player_info.age_instance_uuid(), relto.age_instance_uuid());
+    assert_eq!(player_info.online(), 1);
+    assert!(matches!(bcast_recv.try_recv(), Ok(VaultBroadcast::NodeChanged { node_id, .. })
+                     if node_id == player_info.node_id()));
+    assert!(matches!(bcast_recv.try_recv(), Ok(VaultBroadcast::KickPlayer { player_id, .. })
+                     if player_id == player.player_id));
+
+    // ... or to a specific Age instance, which must exist
+    let instance_id = Uuid::new_v4();
+    find_age_instance(&instance_id, &Uuid::nil(), "Teledahn", "Teledahn", "", "", 0, -1,
+                      &vault).await.unwrap();
+    let age_info = set_player_location(player.player_id, Some(&instance_id), &vault)
+            .await.unwrap();
+    assert_eq!(age_info.age_instance_uuid(), &instance_id);
+    assert_eq!(set_player_location(player.player_id, Some(&Uuid::new_v4()), &vault)
+                    .await.err(), Some(NetResultCode::NetAgeNotFound));
+    assert_eq!(set_player_location(0xDEAD_BEEF, None, &vault).await.err(),
+               Some(NetResultCode::NetPlayerNotFound));
+}
+
+#[tokio::test]
+async fn test_create_player_nodes_twice() {
+    use std::collections::HashSet;
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_id = Uuid::new_v4();
+    let player = vault.create_player(&account_id, "Repeat Player", "female").await.unwrap();
+    let created = create_player_nodes(&account_id, &player, &vault).await.unwrap();
+    assert_eq!(created.len(), 1 + PLAYER_FOLDERS.len() + 3);
+    let created_ids: HashSet<u32> = created.iter().map(|(_, node_id)| *node_id).collect();
+    assert_eq!(created_ids.len(), created.len());
+    for (_, node_id) in &created {
+        assert!(vault.fetch_node(*node_id).await.is_ok());
+    }
+
+    let player_refs = vault.fetch_refs(player.player_id, true).await.unwrap();
+    assert!(create_player_nodes(&account_id, &player, &vault).await.unwrap().is_empty());
+    assert_eq!(vault.fetch_refs(player.player_id, true).await.unwrap().len(),
+               player_refs.len());
+}
+
+#[tokio::test]
+async fn test_can_modify_node() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let mut players = Vec::new();
+    for player_name in ["Owner", "Stranger"] {
+        let account_id = Uuid::new_v4();
+        let player = vault.create_player(&account_id, player_name, "male").await.unwrap();
+        create_player_nodes(&account_id, &player, &vault).await.unwrap();
+        players.push((account_id, player.player_id));
+    }
+    let [(owner_account, owner_id), (stranger_account, stranger_id)] = players[..] else {
+        unreachable!();
+    };
+
+    // Nodes in the player's own vault tree, regardless of who created them
+    let folder = VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::InboxFolder);
+    let folder = vault.create_node(folder).await.unwrap();
+    vault.ref_node(owner_id, folder, 0, false).await.unwrap();
+    assert_eq!(can_modify_node(folder, owner_id, &owner_account, false, &vault).await,
+               Ok(true));
+    assert_eq!(can_modify_node(folder, stranger_id, &stranger_account, false, &vault).await,
+               Ok(false));
+
+    // Nodes created by an Age the player is linked to
+    let relto = find_owned_age(owner_id, "Personal", &vault).await.unwrap().unwrap();
+    let age_folder = VaultFolderNode::new(relto.age_instance_uuid(), 0,
+                                          StandardNode::ChronicleFolder);
+    let age_folder = vault.create_node(age_folder).await.unwrap();
+    assert_eq!(can_modify_node(age_folder, owner_id, &owner_account, false, &vault).await,
+               Ok(true));
+    assert_eq!(can_modify_node(age_folder, stranger_id, &stranger_account, false,
+                               &vault).await, Ok(false));
+}
+
+#[tokio::test]
+async fn test_create_player_nodes_unknown_link() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    // A link without an Age Info node is neither the 'Hood nor the City
+    // placeholder unless it has their spawn point
+    let account_id = Uuid::new_v4();
+    let player = vault.create_player(&account_id, "Link Player", "male").await.unwrap();
+    let owned_ages = vault.create_node(VaultFolderNode::new(&account_id, player.player_id,
+                                       StandardNode::AgesIOwnFolder)).await.unwrap();
+    vault.ref_node(player.player_id, owned_ages, 0, false).await.unwrap();
+    let link = VaultAgeLinkNode::new(&account_id, player.player_id, b"Other:LinkInPointOther:;");
+    let link_id = vault.create_node(link).await.unwrap();
+    vault.ref_node(owned_ages, link_id, 0, false).await.unwrap();
+
+    let created = create_player_nodes(&account_id, &player, &vault).await.unwrap();
+    let created_names: Vec<&str> = created.iter().map(|(name, _)| name.as_str()).collect();
+    assert!(created_names.contains(&HOOD_LINK_NAME));
+    assert!(created_names.contains(&CITY_LINK_NAME));
+}
+
+#[test]
+fn test_check_node_create() {
+    let server_config = ServerConfig::test_config();
+    let new_node = |node_type: NodeType| {
+        let mut node = VaultNode::default();
+        node.set_node_type(node_type as i32);
+        node
+    };
+
+    let text_note = new_node(NodeType::TextNote);
+    assert_eq!(check_node_create(&text_note, false, &server_config), Ok(()));
+    let journal = VaultFolderNode::new(&Uuid::nil(), 1, StandardNode::AgeJournalsFolder);
+    assert_eq!(check_node_create(&journal, false, &server_config), Ok(()));
+
+    let system = new_node(NodeType::System);
+    assert_eq!(check_node_create(&system, false, &server_config),
+               Err(NetResultCode::NetServiceForbidden));
+    assert_eq!(check_node_create(&system, true, &server_config), Ok(()));
+    let all_players = VaultFolderNode::new(&Uuid::nil(), 1, StandardNode::AllPlayersFolder);
+    assert_eq!(check_node_create(&all_players, false, &server_config),
+               Err(NetResultCode::NetServiceForbidden));
+
+    // The node type is required, even for admins
+    assert_eq!(check_node_create(&VaultNode::default(), true, &server_config),
+               Err(NetResultCode::NetInvalidParameter));
+    let mut bogus = VaultNode::default();
+    bogus.set_node_type(99);
+    assert_eq!(check_node_create(&bogus, true, &server_config),
+               Err(NetResultCode::NetInvalidParameter));
+
+    // Allowed types are configurable
+    let mut server_config = server_config;
+    server_config.client_node_types.remove(&NodeType::TextNote);
+    assert_eq!(check_node_create(&text_note, false, &server_config),
+               Err(NetResultCode::NetServiceForbidden));
+}
+
+#[test]
+fn test_check_client_sdl() {
+    use crate::sdl::{DescriptorDb, SdlLimits};
+
+    const NESTED_DESCRIPTORS: &str = r"
+        STATEDESC Outer
+        {
+            VERSION 1
+            VAR $Inner      inner[1]
+        }
+
+        STATEDESC Inner
+        {
+            VERSION 1
+            VAR INT         value[1]    DEFAULT=0
+        }
+    ";
+
+    let sdl_db = DescriptorDb::from_string(NESTED_DESCRIPTORS).unwrap();
+    let descriptor = sdl_db.get_latest("Outer").unwrap();
+    let mut state = sdl::State::from_defaults(descriptor, &sdl_db).unwrap();
+    state.mark_dirty();
+    let sdl_blob = state.to_blob().unwrap();
+    let node = VaultSdlNode::new(&Uuid::nil(), 1, "Outer", &sdl_blob, false).unwrap();
+
+    let limits = SdlLimits::default();
+    assert_eq!(check_client_sdl(&node, &sdl_db, &limits), Ok(()));
+    let too_deep = SdlLimits { max_depth: 0, ..limits };
+    assert_eq!(check_client_sdl(&node, &sdl_db, &too_deep),
+               Err(NetResultCode::NetInvalidParameter));
+
+    // Unknown descriptors are only checked for size
+    let node = VaultSdlNode::new(&Uuid::nil(), 1, "Unknown", &sdl_blob, false).unwrap();
+    assert_eq!(check_client_sdl(&node, &sdl_db, &limits), Ok(()));
+    let too_large = SdlLimits { max_blob_size: sdl_blob.len() - 1, ..limits };
+    assert_eq!(check_client_sdl(&node, &sdl_db, &too_large),
+               Err(NetResultCode::NetInvalidParameter));
+
+    // Other node types are not affected
+    let mut text_note = VaultNode::default();
+    text_note.set_node_type(NodeType::TextNote as i32);
+    text_note.set_blob_1(b"\xFF\xFF");
+    assert_eq!(check_client_sdl(&text_note, &sdl_db, &too_large), Ok(()));
+}
+
+#[tokio::test]
+async fn test_create_player_nodes_rollback() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    // Remove the All Players folder, so creating the player's nodes fails
+    // after the PlayerInfo node has already been created
+    let mut backup = vault.export(true).await.unwrap();
+    let all_players = vault.get_all_players_node().await.unwrap();
+    backup.nodes.retain(|node| node.node_id() != all_players);
+    vault.import(backup).await.unwrap();
+
+    let account_id = Uuid::new_v4();
+    let before = vault.export(true).await.unwrap();
+    assert_eq!(create_new_player(&account_id, "Unlucky Player", "male", &vault).await.err(),
+               Some(NetResultCode::NetVaultNodeNotFound));
+
+    // The player itself is rolled back along with its nodes
+    let after = vault.export(true).await.unwrap();
+    assert_eq!(after.nodes.len(), before.nodes.len());
+    assert_eq!(after.refs, before.refs);
+    assert!(vault.get_players(&account_id).await.unwrap().is_empty());
+
+    // The vault is still usable after the rollback, and the name is free
+    assert!(vault.create_player(&account_id, "Unlucky Player", "male").await.is_ok());
+}
+
+#[tokio::test]
+async fn test_fetch_client_refs_limit() {
+    use crate::config::ServerConfig;
+    use crate::sdl::DescriptorDb;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    // A two-level tree with 4 + 4 * 4 = 20 refs
+    let new_folder = || VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::InboxFolder);
+    let root_id = vault.create_node(new_folder()).await.unwrap();
+    for _ in 0..4 {
+        let child_id = vault.create_node(new_folder()).await.unwrap();
+        vault.ref_node(root_id, child_id, 0, false).await.unwrap();
+        for _ in 0..4 {
+            let leaf_id = vault.create_node(new_folder()).await.unwrap();
+            vault.ref_node(child_id, leaf_id, 0, false).await.unwrap();
+        }
+    }
+
+    assert_eq!(fetch_client_refs(root_id, 20, &vault).await.map(|refs| refs.len()), Ok(20));
+    assert_eq!(fetch_client_refs(root_id, 19, &vault).await,
+               Err(NetResultCode::NetInvalidParameter));
+
+    // The traversal stops as soon as the limit is exceeded
+    assert_eq!(vault.fetch_ref_tree(root_id, 5).await.map(|refs| refs.len()), Ok(6));
+    assert_eq!(vault.fetch_ref_tree(root_id, 20).await.unwrap(),
+               vault.fetch_refs(root_id, true).await.unwrap());
+
+    // Smaller subtrees are still within the limit
+    let subtree = vault.fetch_refs(root_id, false).await.unwrap()[0].child();
+    assert_eq!(fetch_client_refs(subtree, 19, &vault).await.map(|refs| refs.len()), Ok(4));
 }
@@ -30,10 +30,10 @@ use std::process::ExitCode;
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use data_encoding::HEXLOWER_PERMISSIVE;
-use log::{error, warn};
+use log::{error, warn, info};
 
 use moulars::file_srv::Manifest;
-use moulars::file_srv::data_cache::cache_clients;
+use moulars::file_srv::data_cache::{cache_clients, verify_manifests};
 use moulars::plasma::{StreamRead, PakFile};
 use moulars::plasma::file_crypt::EncryptedReader;
 
@@ -89,6 +89,12 @@ enum Command {
         #[arg(required = true)]
         data_root: PathBuf,
     },
+
+    #[command(about = "Verify that the files on disk match the cached manifests")]
+    Verify {
+        #[arg(required = true)]
+        data_root: PathBuf,
+    },
 }
 
 fn main() -> ExitCode {
@@ -135,6 +141,19 @@ fn main() -> ExitCode {
                 warn!("Failed to update file server cache: {}", err);
             }
         }
+        Command::Verify { data_root } => {
+            match verify_manifests(&data_root) {
+                Ok(0) => info!("All manifests verified successfully"),
+                Ok(problems) => {
+                    error!("Verification failed with {} problem(s)", problems);
+                    return ExitCode::FAILURE;
+                }
+                Err(err) => {
+                    error!("Failed to verify manifests: {}", err);
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
     }
     ExitCode::SUCCESS
 }
@@ -25,12 +25,12 @@ use std::process::ExitCode;
 use clap::Parser;
 use data_encoding::BASE64;
 use log::error;
-use num_bigint::{BigUint, ToBigUint};
 use num_prime::RandPrime;
 
-use moulars::config::ServerConfig;
-use moulars::lobby::LobbyServer;
+use moulars::config::{CryptKeyPair, ServerConfig};
+use moulars::lobby::{LobbyServer, load_sdl};
 use moulars::net_crypt::{CRYPT_BASE_AUTH, CRYPT_BASE_GAME, CRYPT_BASE_GATE_KEEPER};
+use moulars::sdl::State;
 
 #[cfg(debug_assertions)]
 const DEFAULT_LOG_LEVEL: &str = "debug";
@@ -49,6 +49,10 @@ struct Args {
     #[arg(long, exclusive = true,
           help = "Show the client Rc4 keys associated with the configured server keys")]
     show_keys: bool,
+
+    #[arg(long, exclusive = true, value_name = "BLOB_FILE",
+          help = "Dump the contents of an SDL state blob (e.g. from a vault SDL node) as JSON")]
+    dump_sdl: Option<PathBuf>,
 }
 
 fn main() -> ExitCode {
@@ -78,19 +82,23 @@ fn main() -> ExitCode {
         println!("\n----------------------------");
         println!("Client keys: (server.ini)");
         println!("----------------------------");
-        for (stype, key_g, key_k, key_n) in [
-            ("Auth", CRYPT_BASE_AUTH, &config.auth_k_key, &config.auth_n_key),
-            ("Game", CRYPT_BASE_GAME, &config.game_k_key, &config.game_n_key),
-            ("Gate", CRYPT_BASE_GATE_KEEPER, &config.gate_k_key, &config.gate_n_key)]
+        for (stype, key_g, keys) in [
+            ("Auth", CRYPT_BASE_AUTH, &config.auth_keys),
+            ("Game", CRYPT_BASE_GAME, &config.game_keys),
+            ("Gate", CRYPT_BASE_GATE_KEEPER, &config.gate_keys)]
         {
-            let key_x = key_g.to_biguint().unwrap().modpow(key_k, key_n);
-            let bytes_n = key_n.to_bytes_be();
-            let bytes_x = key_x.to_bytes_be();
-            println!("Server.{stype}.N \"{}\"", BASE64.encode(&bytes_n));
-            println!("Server.{stype}.X \"{}\"", BASE64.encode(&bytes_x));
+            for line in keys.client_ini_lines(stype, key_g) {
+                println!("{line}");
+            }
         }
 
         return ExitCode::SUCCESS;
+    } else if let Some(blob_path) = &args.dump_sdl {
+        let config = match load_config() {
+            Ok(config) => config,
+            Err(exit_code) => return exit_code,
+        };
+        return dump_sdl(blob_path, &config);
     }
 
     let server_config = match load_config() {
@@ -157,6 +165,34 @@ fn load_config() -> Result<ServerConfig, ExitCode> {
     Err(ExitCode::FAILURE)
 }
 
+fn dump_sdl(blob_path: &Path, config: &ServerConfig) -> ExitCode {
+    let blob = match std::fs::read(blob_path) {
+        Ok(blob) => blob,
+        Err(err) => {
+            error!("Failed to read {}: {err}", blob_path.display());
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let sdl_db = load_sdl(config);
+    let state = match State::from_blob_limited(&blob, &sdl_db, &config.sdl_limits) {
+        Ok(state) => state,
+        Err(err) => {
+            error!("Failed to parse SDL blob: {err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match serde_json::to_string_pretty(&state.to_json()) {
+        Ok(json) => println!("{json}"),
+        Err(err) => {
+            error!("Failed to generate JSON: {err}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
 fn write_progress_pip(out: &mut io::Stdout) {
     let _ = out.write(b".");
     let _ = out.flush();
@@ -177,31 +213,34 @@ fn generate_keys() {
             let mut rng = rand::thread_rng();
             let mut stdout = io::stdout();
             loop {
-                let key_n: BigUint = rng.gen_safe_prime(512);
-                write_progress_pip(&mut stdout);
-                let key_k: BigUint = rng.gen_safe_prime(512);
-                write_progress_pip(&mut stdout);
-                let key_x = key_g.to_biguint().unwrap().modpow(&key_k, &key_n);
+                let keys = CryptKeyPair {
+                    n: rng.gen_safe_prime(512),
+                    k: rng.gen_safe_prime(512),
+                };
                 write_progress_pip(&mut stdout);
 
                 // For best compatibility with H-uru/Plasma and DirtSand, the keys
                 // are stored in Big Endian byte order
-                let bytes_n = key_n.to_bytes_be();
-                let bytes_k = key_k.to_bytes_be();
-                let bytes_x = key_x.to_bytes_be();
+                let bytes_n = keys.n.to_bytes_be();
+                let bytes_k = keys.k.to_bytes_be();
+                let bytes_x = keys.client_key_x(key_g).to_bytes_be();
+                write_progress_pip(&mut stdout);
 
-                if bytes_n.len() != 64 || bytes_k.len() != 64 || bytes_x.len() != 64 {
+                if [&bytes_n, &bytes_k, &bytes_x].iter()
+                        .any(|bytes| bytes.len() != CryptKeyPair::KEY_SIZE)
+                {
                     // We generated a bad length key, so now we need to
                     // start over :(
                     continue;
                 }
 
                 let stype_lower = stype.to_ascii_lowercase();
+                let [cli_n, cli_x] = keys.client_ini_lines(stype, key_g);
                 return (
                     format!("{stype_lower}.n = \"{}\"", BASE64.encode(&bytes_n)),
                     format!("{stype_lower}.k = \"{}\"", BASE64.encode(&bytes_k)),
-                    format!("Server.{stype}.N \"{}\"", BASE64.encode(&bytes_n)),
-                    format!("Server.{stype}.X \"{}\"", BASE64.encode(&bytes_x)),
+                    cli_n,
+                    cli_x,
                 );
             }
         }));
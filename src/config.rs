@@ -14,10 +14,13 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use data_encoding::BASE64;
@@ -25,6 +28,23 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use num_bigint::BigUint;
 use rand::Rng;
 use serde_derive::Deserialize;
+use unicase::UniCase;
+
+use crate::auth_srv::{BufferLimits, NameFilter};
+use crate::sdl::SdlLimits;
+use crate::vault::{AccountInfo, NodeType};
+
+const DEFAULT_FILE_CHUNK_SIZE: usize = 64 * 1024;
+const MIN_FILE_CHUNK_SIZE: usize = 4 * 1024;
+const MAX_FILE_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Node types which the client normally creates on its own.  Player, Age and
+// System nodes (among others) are only ever created by the server.
+const DEFAULT_CLIENT_NODE_TYPES: [NodeType; 9] = [
+    NodeType::Folder, NodeType::Image, NodeType::TextNote, NodeType::Sdl,
+    NodeType::AgeLink, NodeType::Chronicle, NodeType::PlayerInfoList,
+    NodeType::AgeInfoList, NodeType::MarkerGame,
+];
 
 pub enum VaultDbBackend {
     None,
@@ -32,23 +52,133 @@ pub enum VaultDbBackend {
     Postgres,
 }
 
+// The services which are run by this server process.  By default, all
+// services are enabled.
+#[allow(clippy::struct_excessive_bools)]
+pub struct EnabledServices {
+    pub auth: bool,
+    pub file: bool,
+    pub gate_keeper: bool,
+    pub api: bool,
+}
+
+impl EnabledServices {
+    fn from_config(services: Option<Vec<String>>) -> Result<Self> {
+        let Some(services) = services else {
+            return Ok(Self { auth: true, file: true, gate_keeper: true, api: true });
+        };
+
+        let mut enabled = Self {
+            auth: false, file: false, gate_keeper: false, api: false
+        };
+        for service in services {
+            match service.as_str() {
+                "auth" => enabled.auth = true,
+                "file" => enabled.file = true,
+                "game" => return Err(anyhow!("The game service is not implemented yet")),
+                "gate_keeper" => enabled.gate_keeper = true,
+                "api" => enabled.api = true,
+                _ => return Err(anyhow!("Unknown service type: {}", service)),
+            }
+        }
+        Ok(enabled)
+    }
+
+    // Only some services need access to the vault database
+    pub fn needs_vault(&self) -> bool {
+        self.auth || self.api
+    }
+}
+
+pub struct LoginRestriction {
+    pub enabled: bool,
+    pub allow_flags: u32,
+    pub allow_names: HashSet<UniCase<String>>,
+}
+
+impl LoginRestriction {
+    // Returns the name of the rule which allowed the account to log in, or
+    // None if the login should be denied.
+    pub fn check(&self, account: &AccountInfo) -> Option<&'static str> {
+        if !self.enabled {
+            Some("unrestricted")
+        } else if (account.account_flags & self.allow_flags) != 0 {
+            Some("account flags")
+        } else if self.allow_names.contains(&UniCase::new(account.account_name.clone())) {
+            Some("allow list")
+        } else {
+            None
+        }
+    }
+}
+
+// Features which are restricted based on an account's billing type.  Any
+// billing type which isn't configured has no restrictions.
+#[derive(Default)]
+pub struct BillingEntitlements {
+    types: HashMap<u32, Entitlements>,
+}
+
+struct Entitlements {
+    max_players: Option<u64>,
+    blocked_ages: HashSet<UniCase<String>>,
+}
+
+impl BillingEntitlements {
+    fn from_config(config: HashMap<String, BillingConfig>) -> Result<Self> {
+        let mut types = HashMap::with_capacity(config.len());
+        for (billing_type, section) in config {
+            let billing_type = billing_type.parse::<u32>()
+                    .with_context(|| format!("Invalid billing type '{billing_type}'"))?;
+            types.insert(billing_type, Entitlements {
+                max_players: section.max_players,
+                blocked_ages: section.blocked_ages.unwrap_or_default()
+                        .into_iter().map(UniCase::new).collect(),
+            });
+        }
+        Ok(Self { types })
+    }
+
+    pub fn max_players(&self, billing_type: u32) -> Option<u64> {
+        self.types.get(&billing_type).and_then(|ent| ent.max_players)
+    }
+
+    pub fn can_access_age(&self, billing_type: u32, age_filename: &str) -> bool {
+        self.types.get(&billing_type).map_or(true, |ent| {
+            !ent.blocked_ages.contains(&UniCase::new(age_filename.to_string()))
+        })
+    }
+}
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct ServerConfig {
-    /* Listen address for the lobby server */
-    pub listen_address: String,
+    /* Listen addresses for the lobby server */
+    pub listen_addresses: Vec<String>,
+
+    /* Services to run in this process */
+    pub services: EnabledServices,
 
-    /* Listen address for the API service */
-    pub api_address: String,
+    /* Listen addresses for the API service */
+    pub api_addresses: Vec<String>,
+
+    /* Path prefix for all API routes, without a trailing slash (or empty) */
+    pub api_prefix: String,
+    /* Extra text shown before and after the API's root status page */
+    pub api_banner_header: Option<String>,
+    pub api_banner_footer: Option<String>,
 
     /* Product configuration */
     pub build_id: u32,
+    /* Other build IDs accepted from clients, for serving multiple builds */
+    pub extra_build_ids: Vec<u32>,
+    /* Build types and branch IDs accepted from clients (empty = any) */
+    pub allowed_build_types: Vec<u32>,
+    pub allowed_branch_ids: Vec<u32>,
 
     /* Rc4 Encryption keys */
-    pub auth_n_key: BigUint,
-    pub auth_k_key: BigUint,
-    pub game_n_key: BigUint,
-    pub game_k_key: BigUint,
-    pub gate_n_key: BigUint,
-    pub gate_k_key: BigUint,
+    pub auth_keys: CryptKeyPair,
+    pub game_keys: CryptKeyPair,
+    pub gate_keys: CryptKeyPair,
 
     /* GateKeeper server addresses */
     pub file_serv_ip: String,
@@ -57,30 +187,164 @@ pub struct ServerConfig {
 
     /* File server data path */
     pub data_root: PathBuf,
+    /* Serve each build's files from a data_root/<build_id> subdirectory
+       when they exist there */
+    pub build_data_dirs: bool,
+
+    /* Optional per-directory base paths for files served by the auth server */
+    pub auth_file_roots: HashMap<UniCase<String>, PathBuf>,
 
     /* Vault backend */
     pub db_type: VaultDbBackend,
+    pub compress_sdl_blobs: bool,
+    /* Where to save the in-memory vault on shutdown and restore it at startup */
+    pub snapshot_file: Option<PathBuf>,
+    /* Limits for reading SDL blobs stored in the vault */
+    pub sdl_limits: SdlLimits,
+
+    /* Link an account's first player to the standard starting Ages */
+    pub bootstrap_first_player: bool,
+
+    /* Kick an already-online player's session instead of rejecting a new login */
+    pub evict_duplicate_logins: bool,
+
+    /* Allow logins authenticated by an API token instead of a password */
+    pub allow_token_login: bool,
+
+    /* Allow clients to create new accounts from the login screen */
+    pub allow_account_creation: bool,
+
+    /* Account flags given to accounts created by clients (never ADMIN) */
+    pub new_account_flags: u32,
+
+    /* Restrict logins to just Admins + Beta Testers (or other allowed accounts) */
+    pub restrict_logins: LoginRestriction,
+
+    /* Node types which non-admin clients are allowed to create */
+    pub client_node_types: HashSet<NodeType>,
+
+    /* How long an empty temporary Age instance is kept around */
+    pub temp_instance_timeout: Duration,
+
+    /* How long to cache Age population counts for the public Age list */
+    pub age_population_ttl: Duration,
 
-    /* Restrict logins to just Admins + Beta Testers */
-    pub restrict_logins: bool,
+    /* How long to cache the account for an API token */
+    pub api_token_cache_ttl: Duration,
+
+    /* How long to let in-flight API requests finish when shutting down */
+    pub api_drain_timeout: Duration,
+
+    /* Maximum size of an API request body, and how long to wait for it */
+    pub api_max_body_size: usize,
+    pub api_body_timeout: Duration,
+
+    /* How often to send a heartbeat on idle API event streams */
+    pub api_event_heartbeat: Duration,
+
+    /* Rules for acceptable player names */
+    pub name_filter: NameFilter,
+
+    /* Per-billing type restrictions */
+    pub entitlements: BillingEntitlements,
+
+    /* Drop auth clients which haven't sent anything for this long */
+    pub client_idle_timeout: Duration,
+
+    /* Maximum simultaneous client connections from a single IP (0 = unlimited) */
+    pub max_connections_per_ip: usize,
+
+    /* Maximum logged in auth sessions across the whole server (0 = unlimited) */
+    pub max_sessions: usize,
+
+    /* TCP keepalive settings for client and API connections (None = disabled) */
+    pub tcp_keepalive_time: Option<Duration>,
+    pub tcp_keepalive_interval: Duration,
+
+    /* Maximum pending connections on the lobby and API listeners */
+    pub listen_backlog: u32,
+
+    /* Size of each chunk sent for auth and file server downloads */
+    pub file_chunk_size: usize,
+
+    /* Maximum vault node and propagate buffer sizes accepted from clients */
+    pub buffer_limits: BufferLimits,
+
+    /* Buffer limits for logged in admin accounts */
+    pub admin_buffer_limits: BufferLimits,
+
+    /* Maximum number of refs returned for a single client ref fetch */
+    pub max_fetched_refs: usize,
+
+    /* Log the ID of every client message sent and received at trace level */
+    pub trace_messages: bool,
 }
 
-fn decode_crypt_key(value: &str) -> Result<BigUint> {
-    let bytes = BASE64.decode(value.as_bytes())
-            .with_context(|| format!("Could not parse Base64 key '{value}'"))?;
-    if bytes.len() == 64 {
-        Ok(BigUint::from_bytes_be(&bytes))
-    } else {
-        Err(anyhow!("Invalid key length for key '{value}'"))
+// The private key pair used by one service for the Rc4 connection handshake.
+// Both values are 512-bit integers, stored in the config file as Base64
+// encoded big endian bytes.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct CryptKeyPair {
+    pub n: BigUint,
+    pub k: BigUint,
+}
+
+impl CryptKeyPair {
+    pub const KEY_SIZE: usize = 64;
+
+    pub fn from_base64(n: &str, k: &str) -> Result<Self> {
+        Ok(Self {
+            n: Self::decode_key(n).context("Invalid N key")?,
+            k: Self::decode_key(k).context("Invalid K key")?,
+        })
+    }
+
+    fn decode_key(value: &str) -> Result<BigUint> {
+        let bytes = BASE64.decode(value.trim().as_bytes())
+                .with_context(|| format!("Could not parse Base64 key '{value}'"))?;
+        if bytes.len() == Self::KEY_SIZE {
+            Ok(BigUint::from_bytes_be(&bytes))
+        } else {
+            Err(anyhow!("Key must be {} bits, but '{value}' is {} bits",
+                        Self::KEY_SIZE * 8, bytes.len() * 8))
+        }
+    }
+
+    // The public key (X = g^k mod n) which clients need for this key pair
+    pub fn client_key_x(&self, key_g: u32) -> BigUint {
+        BigUint::from(key_g).modpow(&self.k, &self.n)
+    }
+
+    // The client's server.ini lines for this key pair, e.g.
+    //   Server.Auth.N "..."
+    //   Server.Auth.X "..."
+    pub fn client_ini_lines(&self, service: &str, key_g: u32) -> [String; 2] {
+        let bytes_n = self.n.to_bytes_be();
+        let bytes_x = self.client_key_x(key_g).to_bytes_be();
+        [
+            format!("Server.{service}.N \"{}\"", BASE64.encode(&bytes_n)),
+            format!("Server.{service}.X \"{}\"", BASE64.encode(&bytes_x)),
+        ]
+    }
+}
+
+impl ConfigKeyPair {
+    fn decode(&self, service: &str) -> Result<CryptKeyPair> {
+        CryptKeyPair::from_base64(&self.n, &self.k)
+                .with_context(|| format!("Invalid keys in [crypt_keys.{service}]"))
     }
 }
 
 impl ServerConfig {
     pub fn from_file(path: &Path) -> Result<ServerConfig> {
+        let config_file = std::fs::read_to_string(path)?;
+        Self::parse(&config_file)
+    }
+
+    pub fn parse(config_file: &str) -> Result<ServerConfig> {
         #![allow(clippy::similar_names)]
 
-        let config_file = std::fs::read_to_string(path)?;
-        let config: StructuredConfig = toml::from_str(&config_file)
+        let config: StructuredConfig = toml::from_str(config_file)
                 .context("Failed to parse config file")?;
 
         let server_section = config.server.unwrap_or_default();
@@ -88,9 +352,9 @@ impl ServerConfig {
         // The default is to listen on 127.0.0.1, which means that ONLY
         // connections from localhost are allowed.  To listen on any IPv4
         // address, you should set listen_address = "0.0.0.0"
-        let listen_address = format!("{}:{}",
-                server_section.listen_address.as_deref().unwrap_or("127.0.0.1"),
-                server_section.listen_port.unwrap_or(14617));
+        let listen_addresses = bind_addresses("listen_address",
+                server_section.listen_address, server_section.listen_port.unwrap_or(14617))?;
+        let services = EnabledServices::from_config(server_section.services)?;
         let build_id = config.build_id.unwrap_or(918);
         let data_root =
             if let Some(data_root) = config.data_root {
@@ -100,13 +364,13 @@ impl ServerConfig {
                     .context("Failed to determine current working directory")?
                     .join("data")
             };
+        let auth_file_roots = config.auth_file_roots.unwrap_or_default().into_iter()
+                .map(|(dir_name, root)| (UniCase::new(dir_name), PathBuf::from(root)))
+                .collect();
 
-        let auth_n_key = decode_crypt_key(&config.crypt_keys.auth.n)?;
-        let auth_k_key = decode_crypt_key(&config.crypt_keys.auth.k)?;
-        let game_n_key = decode_crypt_key(&config.crypt_keys.game.n)?;
-        let game_k_key = decode_crypt_key(&config.crypt_keys.game.k)?;
-        let gate_n_key = decode_crypt_key(&config.crypt_keys.gate.n)?;
-        let gate_k_key = decode_crypt_key(&config.crypt_keys.gate.k)?;
+        let auth_keys = config.crypt_keys.auth.decode("auth")?;
+        let game_keys = config.crypt_keys.game.decode("game")?;
+        let gate_keys = config.crypt_keys.gate.decode("gate")?;
 
         // Again, the defaults are only useful when connecting from localhost.
         // These should be configured to an EXTERNAL IP address, since they
@@ -119,9 +383,13 @@ impl ServerConfig {
         let game_serv_ip = server_section.game_server_ip.as_deref()
                                 .unwrap_or("127.0.0.1").to_string();
 
-        let api_address = format!("{}:{}",
-                server_section.api_address.as_deref().unwrap_or("127.0.0.1"),
-                server_section.api_port.unwrap_or(14615));
+        let api_addresses = bind_addresses("api_address",
+                server_section.api_address, server_section.api_port.unwrap_or(14615))?;
+        let api_prefix = normalize_api_prefix(server_section.api_prefix.as_deref())?;
+        let api_banner_header = server_section.api_banner_header
+                .filter(|text| !text.trim().is_empty());
+        let api_banner_footer = server_section.api_banner_footer
+                .filter(|text| !text.trim().is_empty());
 
         let vault_db_section = config.vault_db.unwrap_or_default();
         let db_type = if let Some(type_str) = vault_db_section.db_type {
@@ -135,27 +403,214 @@ impl ServerConfig {
             VaultDbBackend::None
         };
 
-        let restrict_logins = config.restrict_logins.unwrap_or(false);
+        let default_sdl_limits = SdlLimits::default();
+        let sdl_limits = SdlLimits {
+            max_depth: vault_db_section.max_sdl_depth.unwrap_or(default_sdl_limits.max_depth),
+            max_blob_size: vault_db_section.max_sdl_blob_size
+                    .unwrap_or(default_sdl_limits.max_blob_size),
+        };
+        if sdl_limits.max_blob_size == 0 {
+            return Err(anyhow!("max_sdl_blob_size must be greater than 0"));
+        }
+
+        let restrict_logins = LoginRestriction {
+            enabled: config.restrict_logins.unwrap_or(false),
+            allow_flags: config.restrict_logins_flags
+                    .unwrap_or(AccountInfo::ADMIN | AccountInfo::BETA_TESTER),
+            allow_names: config.restrict_logins_allow.unwrap_or_default()
+                    .into_iter().map(UniCase::new).collect(),
+        };
+        let client_node_types = match config.client_node_types {
+            Some(names) => names.iter().map(|name| {
+                NodeType::from_name(name).ok_or_else(|| {
+                    anyhow!("Unknown node type in client_node_types: '{}'", name)
+                })
+            }).collect::<Result<HashSet<_>>>()?,
+            None => DEFAULT_CLIENT_NODE_TYPES.into_iter().collect(),
+        };
+        let new_account_flags = config.new_account_flags.unwrap_or(0);
+        if (new_account_flags & AccountInfo::ADMIN) != 0 {
+            return Err(anyhow!("new_account_flags must not include the Admin flag"));
+        }
+        let temp_instance_timeout =
+                Duration::from_secs(config.temp_instance_timeout.unwrap_or(300));
+        let age_population_ttl =
+                Duration::from_secs(config.age_population_ttl.unwrap_or(30));
+        let api_token_cache_ttl =
+                Duration::from_secs(config.api_token_cache_ttl.unwrap_or(30));
+        let api_drain_timeout =
+                Duration::from_secs(config.api_drain_timeout.unwrap_or(10));
+        let api_max_body_size = config.api_max_body_size.unwrap_or(64 * 1024);
+        if api_max_body_size == 0 {
+            return Err(anyhow!("api_max_body_size must be greater than 0"));
+        }
+        let api_body_timeout = match config.api_body_timeout.unwrap_or(10) {
+            0 => return Err(anyhow!("api_body_timeout must be greater than 0")),
+            secs => Duration::from_secs(secs),
+        };
+        let api_event_heartbeat = match config.api_event_heartbeat.unwrap_or(15) {
+            0 => return Err(anyhow!("api_event_heartbeat must be greater than 0")),
+            secs => Duration::from_secs(secs),
+        };
+        let name_filter = NameFilter::new(&config.banned_name_words.unwrap_or_default(),
+                                          config.max_name_length.unwrap_or(40));
+        let client_idle_timeout =
+                Duration::from_secs(config.client_idle_timeout.unwrap_or(120));
+        let tcp_keepalive_time = match config.tcp_keepalive_time.unwrap_or(60) {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let tcp_keepalive_interval =
+                Duration::from_secs(config.tcp_keepalive_interval.unwrap_or(10));
+        let entitlements = BillingEntitlements::from_config(config.billing.unwrap_or_default())?;
+        let file_chunk_size = config.file_chunk_size.unwrap_or(DEFAULT_FILE_CHUNK_SIZE);
+        if !(MIN_FILE_CHUNK_SIZE..=MAX_FILE_CHUNK_SIZE).contains(&file_chunk_size) {
+            return Err(anyhow!("file_chunk_size must be between {} and {} bytes",
+                               MIN_FILE_CHUNK_SIZE, MAX_FILE_CHUNK_SIZE));
+        }
+
+        let default_limits = BufferLimits::default();
+        let buffer_limits = BufferLimits {
+            max_node_buffer_size: config.max_node_buffer_size
+                    .unwrap_or(default_limits.max_node_buffer_size),
+            max_propagate_buffer_size: config.max_propagate_buffer_size
+                    .unwrap_or(default_limits.max_propagate_buffer_size),
+        };
+        let admin_buffer_limits = BufferLimits {
+            max_node_buffer_size: config.admin_max_node_buffer_size
+                    .unwrap_or(buffer_limits.max_node_buffer_size),
+            max_propagate_buffer_size: config.admin_max_propagate_buffer_size
+                    .unwrap_or(buffer_limits.max_propagate_buffer_size),
+        };
+
+        let max_fetched_refs = match config.max_fetched_refs.unwrap_or(50_000) {
+            0 => return Err(anyhow!("max_fetched_refs must be greater than 0")),
+            count => count,
+        };
 
         Ok(ServerConfig {
-            listen_address,
-            api_address,
+            listen_addresses,
+            services,
+            api_addresses,
+            api_prefix,
+            api_banner_header,
+            api_banner_footer,
             build_id,
-            auth_n_key,
-            auth_k_key,
-            game_n_key,
-            game_k_key,
-            gate_n_key,
-            gate_k_key,
+            extra_build_ids: config.extra_build_ids.unwrap_or_default(),
+            allowed_build_types: config.allowed_build_types.unwrap_or_default(),
+            allowed_branch_ids: config.allowed_branch_ids.unwrap_or_default(),
+            auth_keys,
+            game_keys,
+            gate_keys,
             file_serv_ip,
             auth_serv_ip,
             game_serv_ip,
             data_root,
+            build_data_dirs: config.build_data_dirs.unwrap_or(false),
+            auth_file_roots,
             db_type,
+            compress_sdl_blobs: vault_db_section.compress_sdl_blobs.unwrap_or(false),
+            snapshot_file: vault_db_section.snapshot_file.map(PathBuf::from),
+            sdl_limits,
+            bootstrap_first_player: config.bootstrap_first_player.unwrap_or(false),
+            evict_duplicate_logins: config.evict_duplicate_logins.unwrap_or(true),
+            allow_token_login: config.allow_token_login.unwrap_or(false),
+            allow_account_creation: config.allow_account_creation.unwrap_or(false),
+            new_account_flags,
             restrict_logins,
+            client_node_types,
+            temp_instance_timeout,
+            age_population_ttl,
+            api_token_cache_ttl,
+            api_drain_timeout,
+            api_max_body_size,
+            api_body_timeout,
+            api_event_heartbeat,
+            name_filter,
+            entitlements,
+            client_idle_timeout,
+            max_connections_per_ip: config.max_connections_per_ip.unwrap_or(0),
+            max_sessions: config.max_sessions.unwrap_or(0),
+            tcp_keepalive_time,
+            tcp_keepalive_interval,
+            listen_backlog: config.listen_backlog.unwrap_or(1024),
+            file_chunk_size,
+            buffer_limits,
+            admin_buffer_limits,
+            max_fetched_refs,
+            trace_messages: config.trace_messages.unwrap_or(false),
         })
     }
 
+    // A minimal configuration for tests, with dummy crypt keys
+    #[cfg(test)]
+    pub fn test_config() -> ServerConfig {
+        Self::parse_test_config("").expect("Failed to parse test config")
+    }
+
+    // Parses the top-level settings in extra_config along with dummy crypt keys
+    #[cfg(test)]
+    fn parse_test_config(extra_config: &str) -> Result<ServerConfig> {
+        let dummy_key = BASE64.encode(&[0x55; 64]);
+        Self::parse(&format!(r#"
+            {extra_config}
+            [crypt_keys.auth]
+            n = "{dummy_key}"
+            k = "{dummy_key}"
+            [crypt_keys.game]
+            n = "{dummy_key}"
+            k = "{dummy_key}"
+            [crypt_keys.gate]
+            n = "{dummy_key}"
+            k = "{dummy_key}"
+        "#))
+    }
+
+    // Returns the base path containing the named auth server directory
+    // (e.g. "Python" or "SDL"), which defaults to the data_root.
+    pub fn auth_file_root(&self, dir_name: &str) -> &Path {
+        self.auth_file_roots.get(&UniCase::new(dir_name.to_string()))
+                .map_or(self.data_root.as_path(), PathBuf::as_path)
+    }
+
+    // A build ID of 0 is sent by clients which don't care about the build,
+    // so it is always accepted.
+    pub fn accepts_build_id(&self, build_id: u32) -> bool {
+        build_id == 0 || build_id == self.build_id || self.extra_build_ids.contains(&build_id)
+    }
+
+    // Returns the directories to search (in order) for a build's files and
+    // manifests.  When build_data_dirs is enabled, files in the build's own
+    // subdirectory take precedence over the shared ones in data_root.
+    pub fn build_data_roots(&self, build_id: u32) -> Vec<PathBuf> {
+        self.build_roots_in(&self.data_root, build_id)
+    }
+
+    // Like build_data_roots, but for the named auth server directory, which
+    // is searched relative to its auth_file_root.
+    pub fn auth_data_roots(&self, dir_name: &str, build_id: u32) -> Vec<PathBuf> {
+        self.build_roots_in(self.auth_file_root(dir_name), build_id)
+    }
+
+    fn build_roots_in(&self, base_root: &Path, build_id: u32) -> Vec<PathBuf> {
+        let mut roots = Vec::with_capacity(2);
+        if self.build_data_dirs && build_id != 0 {
+            let build_root = base_root.join(build_id.to_string());
+            if build_root.is_dir() {
+                roots.push(build_root);
+            }
+        }
+        roots.push(base_root.to_path_buf());
+        roots
+    }
+
+    // Checks the build type and branch ID from a client's connection header
+    pub fn accepts_build_branch(&self, build_type: u32, branch_id: u32) -> bool {
+        (self.allowed_build_types.is_empty() || self.allowed_build_types.contains(&build_type))
+            && (self.allowed_branch_ids.is_empty()
+                || self.allowed_branch_ids.contains(&branch_id))
+    }
+
     pub fn get_ntd_key(&self) -> io::Result<[u32; 4]> {
         load_or_create_ntd_key(&self.data_root)
     }
@@ -164,22 +619,124 @@ impl ServerConfig {
 #[derive(Deserialize)]
 struct StructuredConfig {
     data_root: Option<String>,
+    build_data_dirs: Option<bool>,
+    auth_file_roots: Option<HashMap<String, String>>,
     build_id: Option<u32>,
+    extra_build_ids: Option<Vec<u32>>,
+    allowed_build_types: Option<Vec<u32>>,
+    allowed_branch_ids: Option<Vec<u32>>,
     restrict_logins: Option<bool>,
+    allow_token_login: Option<bool>,
+    allow_account_creation: Option<bool>,
+    new_account_flags: Option<u32>,
+    bootstrap_first_player: Option<bool>,
+    evict_duplicate_logins: Option<bool>,
+    restrict_logins_flags: Option<u32>,
+    restrict_logins_allow: Option<Vec<String>>,
+    client_node_types: Option<Vec<String>>,
+    temp_instance_timeout: Option<u64>,
+    age_population_ttl: Option<u64>,
+    api_token_cache_ttl: Option<u64>,
+    api_drain_timeout: Option<u64>,
+    api_max_body_size: Option<usize>,
+    api_body_timeout: Option<u64>,
+    api_event_heartbeat: Option<u64>,
+    client_idle_timeout: Option<u64>,
+    max_connections_per_ip: Option<usize>,
+    max_sessions: Option<usize>,
+    tcp_keepalive_time: Option<u64>,
+    tcp_keepalive_interval: Option<u64>,
+    listen_backlog: Option<u32>,
+    file_chunk_size: Option<usize>,
+    max_node_buffer_size: Option<u32>,
+    max_propagate_buffer_size: Option<u32>,
+    admin_max_node_buffer_size: Option<u32>,
+    admin_max_propagate_buffer_size: Option<u32>,
+    max_fetched_refs: Option<usize>,
+    trace_messages: Option<bool>,
+    banned_name_words: Option<Vec<String>>,
+    max_name_length: Option<usize>,
     server: Option<ServerAddrConfig>,
     crypt_keys: ConfigKeys,
     vault_db: Option<VaultDbConfig>,
+    billing: Option<HashMap<String, BillingConfig>>,
+}
+
+#[derive(Deserialize)]
+struct BillingConfig {
+    max_players: Option<u64>,
+    blocked_ages: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Default)]
 struct ServerAddrConfig {
-    listen_address: Option<String>,
+    services: Option<Vec<String>>,
+    listen_address: Option<AddressList>,
     listen_port: Option<u16>,
     file_server_ip: Option<String>,
     auth_server_ip: Option<String>,
     game_server_ip: Option<String>,
-    api_address: Option<String>,
+    api_address: Option<AddressList>,
     api_port: Option<u16>,
+    api_prefix: Option<String>,
+    api_banner_header: Option<String>,
+    api_banner_footer: Option<String>,
+}
+
+// A single address, or a list of addresses to listen on
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AddressList {
+    One(String),
+    Many(Vec<String>),
+}
+
+// Resolves the configured listen addresses for a service into "host:port"
+// strings.  Addresses may be IP addresses or host names, which use the
+// service's default port, or IP addresses with an explicit port.
+fn bind_addresses(setting: &str, addresses: Option<AddressList>, port: u16)
+    -> Result<Vec<String>>
+{
+    let addresses = match addresses {
+        None => vec!["127.0.0.1".to_string()],
+        Some(AddressList::One(address)) => vec![address],
+        Some(AddressList::Many(addresses)) => addresses,
+    };
+    if addresses.is_empty() {
+        return Err(anyhow!("{} must contain at least one address", setting));
+    }
+
+    let mut bind_addrs: Vec<String> = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let bind_addr = if let Ok(sock_addr) = address.parse::<SocketAddr>() {
+            sock_addr.to_string()
+        } else if let Ok(ip_addr) = address.parse::<IpAddr>() {
+            SocketAddr::new(ip_addr, port).to_string()
+        } else if !address.is_empty() && address.chars()
+                    .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '.') {
+            format!("{address}:{port}")
+        } else {
+            return Err(anyhow!("Invalid address in {}: '{}'", setting, address));
+        };
+        if bind_addrs.contains(&bind_addr) {
+            return Err(anyhow!("Duplicate address in {}: '{}'", setting, address));
+        }
+        bind_addrs.push(bind_addr);
+    }
+    Ok(bind_addrs)
+}
+
+// Trailing slashes are dropped, so both "/api" and "/api/" are stored as
+// "/api", and a prefix of "/" is the same as no prefix at all.
+fn normalize_api_prefix(prefix: Option<&str>) -> Result<String> {
+    let prefix = prefix.unwrap_or_default();
+    if !prefix.is_empty() && !prefix.starts_with('/') {
+        return Err(anyhow!("api_prefix must start with a '/': '{}'", prefix));
+    }
+    if prefix.contains(['?', '#']) {
+        return Err(anyhow!("Invalid api_prefix: '{}'", prefix));
+    }
+    Ok(prefix.trim_end_matches('/').to_string())
 }
 
 #[derive(Deserialize)]
@@ -198,6 +755,10 @@ struct ConfigKeyPair {
 #[derive(Deserialize, Default)]
 struct VaultDbConfig {
     db_type: Option<String>,
+    compress_sdl_blobs: Option<bool>,
+    snapshot_file: Option<String>,
+    max_sdl_depth: Option<usize>,
+    max_sdl_blob_size: Option<usize>,
 }
 
 // NOTE: This file stores the keys in Big Endian format for easier debugging
@@ -234,3 +795,245 @@ pub fn load_or_create_ntd_key(data_root: &Path) -> io::Result<[u32; 4]> {
     NTD_KEY.set(key).expect("Tried to set NTD key twice");
     Ok(key)
 }
+
+#[test]
+fn test_login_restriction() {
+    use crate::hashes::ShaDigest;
+
+    let make_account = |account_name: &str, account_flags: u32| AccountInfo {
+        account_name: account_name.to_string(),
+        pass_hash: ShaDigest::sha1(b""),
+        account_id: uuid::Uuid::new_v4(),
+        account_flags,
+        billing_type: 1,
+        api_token: String::new(),
+        login_token: String::new(),
+    };
+
+    let mut restriction = LoginRestriction {
+        enabled: false,
+        allow_flags: AccountInfo::ADMIN | AccountInfo::BETA_TESTER,
+        allow_names: HashSet::from([UniCase::new("Tester@example.com".to_string())]),
+    };
+    assert_eq!(restriction.check(&make_account("Player", 0)), Some("unrestricted"));
+
+    restriction.enabled = true;
+    assert_eq!(restriction.check(&make_account("Player", 0)), None);
+    assert_eq!(restriction.check(&make_account("Player", AccountInfo::BETA_TESTER)),
+               Some("account flags"));
+    assert_eq!(restriction.check(&make_account("tester@EXAMPLE.com", 0)), Some("allow list"));
+    assert_eq!(restriction.check(&make_account("other@example.com", 0)), None);
+
+    // Allowed names still work when flag-based access is disabled
+    restriction.allow_flags = 0;
+    assert_eq!(restriction.check(&make_account("Admin", AccountInfo::ADMIN)), None);
+    assert_eq!(restriction.check(&make_account("Tester@example.com", 0)), Some("allow list"));
+}
+
+#[test]
+fn test_new_account_flags() {
+    let parse_flags = |flags: u32| {
+        ServerConfig::parse_test_config(&format!("new_account_flags = {flags}"))
+                .map(|config| config.new_account_flags)
+    };
+
+    assert_eq!(ServerConfig::test_config().new_account_flags, 0);
+    assert_eq!(parse_flags(AccountInfo::BETA_TESTER).unwrap(), AccountInfo::BETA_TESTER);
+    assert!(parse_flags(AccountInfo::ADMIN).is_err());
+    assert!(parse_flags(AccountInfo::ADMIN | AccountInfo::BETA_TESTER).is_err());
+}
+
+#[test]
+fn test_accepts_build_branch() {
+    let mut config = ServerConfig::test_config();
+    assert!(config.accepts_build_branch(50, 1));
+    assert!(config.accepts_build_branch(0, 0));
+
+    config.allowed_build_types = vec![50];
+    assert!(config.accepts_build_branch(50, 7));
+    assert!(!config.accepts_build_branch(10, 7));
+
+    config.allowed_branch_ids = vec![1, 2];
+    assert!(config.accepts_build_branch(50, 2));
+    assert!(!config.accepts_build_branch(50, 7));
+    assert!(!config.accepts_build_branch(10, 1));
+}
+
+#[test]
+fn test_file_chunk_size() {
+    let parse_chunk_size = |size: usize| {
+        ServerConfig::parse_test_config(&format!("file_chunk_size = {size}"))
+                .map(|config| config.file_chunk_size)
+    };
+
+    assert_eq!(ServerConfig::test_config().file_chunk_size, DEFAULT_FILE_CHUNK_SIZE);
+    assert_eq!(parse_chunk_size(256 * 1024).unwrap(), 256 * 1024);
+    assert_eq!(parse_chunk_size(MIN_FILE_CHUNK_SIZE).unwrap(), MIN_FILE_CHUNK_SIZE);
+    assert_eq!(parse_chunk_size(MAX_FILE_CHUNK_SIZE).unwrap(), MAX_FILE_CHUNK_SIZE);
+    assert!(parse_chunk_size(MIN_FILE_CHUNK_SIZE - 1).is_err());
+    assert!(parse_chunk_size(MAX_FILE_CHUNK_SIZE + 1).is_err());
+}
+
+#[test]
+fn test_bind_addresses() {
+    let parse_addresses = |server_section: &str| {
+        ServerConfig::parse_test_config(&format!("[server]\n{server_section}"))
+                .map(|config| (config.listen_addresses, config.api_addresses))
+    };
+
+    let (listen, api) = parse_addresses("").unwrap();
+    assert_eq!(listen, ["127.0.0.1:14617"]);
+    assert_eq!(api, ["127.0.0.1:14615"]);
+
+    let (listen, api) = parse_addresses(r#"
+        listen_address = ["0.0.0.0", "::", "[::1]:5000", "localhost"]
+        listen_port = 4000
+        api_address = "::1"
+    "#).unwrap();
+    assert_eq!(listen, ["0.0.0.0:4000", "[::]:4000", "[::1]:5000", "localhost:4000"]);
+    assert_eq!(api, ["[::1]:14615"]);
+
+    assert!(parse_addresses("listen_address = []").is_err());
+    assert!(parse_addresses("listen_address = [\"\"]").is_err());
+    assert!(parse_addresses("listen_address = \"not an address\"").is_err());
+    assert!(parse_addresses("api_address = [\"::1\", \"[::1]:14615\"]").is_err());
+}
+
+#[test]
+fn test_client_node_types() {
+    let default_types = ServerConfig::test_config().client_node_types;
+    assert!(default_types.contains(&NodeType::TextNote));
+    assert!(!default_types.contains(&NodeType::System));
+    assert!(!default_types.contains(&NodeType::PlayerInfo));
+
+    let config = ServerConfig::parse_test_config(
+            r#"client_node_types = ["textnote", "Chronicle"]"#).unwrap();
+    assert_eq!(config.client_node_types,
+               HashSet::from([NodeType::TextNote, NodeType::Chronicle]));
+    assert!(ServerConfig::parse_test_config(r#"client_node_types = ["Bogus"]"#).is_err());
+    assert!(ServerConfig::parse_test_config(r#"client_node_types = ["_Invalid"]"#).is_err());
+}
+
+#[test]
+fn test_api_prefix() {
+    let parse_prefix = |prefix: &str| {
+        ServerConfig::parse_test_config(&format!("[server]\napi_prefix = \"{prefix}\""))
+                .map(|config| config.api_prefix)
+    };
+
+    assert_eq!(ServerConfig::test_config().api_prefix, "");
+    assert_eq!(parse_prefix("").unwrap(), "");
+    assert_eq!(parse_prefix("/").unwrap(), "");
+    assert_eq!(parse_prefix("/api").unwrap(), "/api");
+    assert_eq!(parse_prefix("/api/").unwrap(), "/api");
+    assert_eq!(parse_prefix("/moulars/api//").unwrap(), "/moulars/api");
+    assert!(parse_prefix("api").is_err());
+    assert!(parse_prefix("/api?x=1").is_err());
+}
+
+#[test]
+fn test_max_fetched_refs() {
+    assert_eq!(ServerConfig::test_config().max_fetched_refs, 50_000);
+    let config = ServerConfig::parse_test_config("max_fetched_refs = 100").unwrap();
+    assert_eq!(config.max_fetched_refs, 100);
+    assert!(ServerConfig::parse_test_config("max_fetched_refs = 0").is_err());
+}
+
+#[test]
+fn test_api_banner() {
+    let config = ServerConfig::test_config();
+    assert_eq!(config.api_banner_header, None);
+    assert_eq!(config.api_banner_footer, None);
+
+    let config = ServerConfig::parse_test_config(
+            "[server]\napi_banner_header = \"My Shard\"\napi_banner_footer = \"  \"").unwrap();
+    assert_eq!(config.api_banner_header.as_deref(), Some("My Shard"));
+    assert_eq!(config.api_banner_footer, None);
+}
+
+#[test]
+fn test_billing_entitlements() {
+    let config: HashMap<String, BillingConfig> = toml::from_str(r#"
+        [0]
+        max_players = 1
+        blocked_ages = ["Personal", "Neighborhood"]
+
+        [1]
+        blocked_ages = []
+    "#).unwrap();
+    let entitlements = BillingEntitlements::from_config(config).unwrap();
+
+    assert!(!entitlements.can_access_age(0, "Personal"));
+    assert!(!entitlements.can_access_age(0, "neighborhood"));
+    assert!(entitlements.can_access_age(0, "city"));
+    assert!(entitlements.can_access_age(1, "Personal"));
+    assert_eq!(entitlements.max_players(0), Some(1));
+    assert_eq!(entitlements.max_players(1), None);
+
+    // Unconfigured billing types are not restricted
+    assert!(entitlements.can_access_age(2, "Personal"));
+    assert!(BillingEntitlements::default().can_access_age(0, "Personal"));
+    assert_eq!(BillingEntitlements::default().max_players(0), None);
+
+    let config: HashMap<String, BillingConfig> = toml::from_str(r"
+        [visitor]
+        max_players = 1
+    ").unwrap();
+    assert!(BillingEntitlements::from_config(config).is_err());
+}
+
+#[test]
+fn test_enabled_services() {
+    let all = EnabledServices::from_config(None).unwrap();
+    assert!(all.auth && all.file && all.gate_keeper && all.api);
+    assert!(all.needs_vault());
+
+    let file_only = EnabledServices::from_config(Some(vec![
+        "gate_keeper".to_string(), "file".to_string()
+    ])).unwrap();
+    assert!(file_only.file && file_only.gate_keeper);
+    assert!(!file_only.auth && !file_only.api);
+    assert!(!file_only.needs_vault());
+
+    assert!(EnabledServices::from_config(Some(vec!["lobby".to_string()])).is_err());
+    assert!(EnabledServices::from_config(Some(vec!["game".to_string()])).is_err());
+}
+
+#[test]
+fn test_crypt_key_pair() {
+    let key_n = BASE64.encode(&[0xAA; 64]);
+    let key_k = BASE64.encode(&[0x55; 64]);
+    let keys = CryptKeyPair::from_base64(&key_n, &key_k).unwrap();
+    assert_eq!(keys.n, BigUint::from_bytes_be(&[0xAA; 64]));
+    assert_eq!(keys.k, BigUint::from_bytes_be(&[0x55; 64]));
+    assert_eq!(keys.client_key_x(2), BigUint::from(2_u32).modpow(&keys.k, &keys.n));
+    let [cli_n, cli_x] = keys.client_ini_lines("Auth", 2);
+    assert_eq!(cli_n, format!("Server.Auth.N \"{key_n}\""));
+    assert_eq!(cli_x, format!("Server.Auth.X \"{}\"",
+                              BASE64.encode(&keys.client_key_x(2).to_bytes_be())));
+
+    // Malformed Base64
+    assert!(CryptKeyPair::from_base64("not base64!", &key_k).is_err());
+    assert!(CryptKeyPair::from_base64(&key_n, "").is_err());
+
+    // Wrong key sizes
+    let short_key = BASE64.encode(&[0x55; 32]);
+    let long_key = BASE64.encode(&[0x55; 65]);
+    let err = CryptKeyPair::from_base64(&short_key, &key_k).unwrap_err();
+    assert!(format!("{err:#}").contains("must be 512 bits"));
+    assert!(CryptKeyPair::from_base64(&key_n, &long_key).is_err());
+
+    // Errors identify the service whose keys are invalid
+    let err = ServerConfig::parse(&format!(r#"
+        [crypt_keys.auth]
+        n = "{key_n}"
+        k = "{key_k}"
+        [crypt_keys.game]
+        n = "{key_n}"
+        k = "{short_key}"
+        [crypt_keys.gate]
+        n = "{key_n}"
+        k = "{key_k}"
+    "#)).err().unwrap();
+    assert!(format!("{err:#}").contains("[crypt_keys.game]"));
+}
@@ -0,0 +1,169 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+// Tracks the number of open client connections from each source IP.
+// A limit of 0 means connections are not limited.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+// Holds one connection slot for an IP address, which is released when
+// this is dropped.  Each service moves the guard into its client worker
+// task (as `_conn_guard`), so the slot stays taken until the client
+// disconnects and the task exits.
+pub struct ConnectionGuard {
+    addr: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self { max_per_ip, counts: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    // Returns None if the address already has the maximum number of
+    // connections open.
+    pub fn try_acquire(&self, addr: IpAddr) -> Option<ConnectionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(addr).or_insert(0);
+        if self.max_per_ip != 0 && *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard { addr, counts: self.counts.clone() })
+    }
+
+    pub fn connection_count(&self, addr: IpAddr) -> usize {
+        self.counts.lock().unwrap().get(&addr).copied().unwrap_or(0)
+    }
+}
+
+// Tracks the number of logged in auth sessions across the whole server.
+// A limit of 0 means sessions are not limited.
+#[derive(Clone)]
+pub struct SessionLimiter {
+    max_sessions: usize,
+    count: Arc<Mutex<usize>>,
+}
+
+// Holds one session slot, which is released when this is dropped.
+pub struct SessionGuard {
+    count: Arc<Mutex<usize>>,
+}
+
+impl SessionLimiter {
+    pub fn new(max_sessions: usize) -> Self {
+        Self { max_sessions, count: Arc::new(Mutex::new(0)) }
+    }
+
+    // Returns None if the server already has the maximum number of
+    // sessions open.
+    pub fn try_acquire(&self) -> Option<SessionGuard> {
+        let mut count = self.count.lock().unwrap();
+        if self.max_sessions != 0 && *count >= self.max_sessions {
+            return None;
+        }
+        *count += 1;
+        Some(SessionGuard { count: self.count.clone() })
+    }
+
+    pub fn session_count(&self) -> usize { *self.count.lock().unwrap() }
+    pub fn max_sessions(&self) -> usize { self.max_sessions }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        *self.count.lock().unwrap() -= 1;
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.addr) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.addr);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_connection_limit() {
+    use std::net::Ipv4Addr;
+
+    let limiter = ConnectionLimiter::new(4);
+    let client_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3));
+    let other_addr = IpAddr::V4(Ipv4Addr::new(10, 1, 2, 4));
+
+    let mut guards: Vec<_> = (0..4).map(|_| limiter.try_acquire(client_addr).unwrap())
+            .collect();
+    for _ in 0..20 {
+        assert!(limiter.try_acquire(client_addr).is_none());
+    }
+    assert_eq!(limiter.connection_count(client_addr), 4);
+
+    // Other addresses are tracked separately
+    assert!(limiter.try_acquire(other_addr).is_some());
+
+    // Disconnecting a client frees up a slot
+    guards.pop();
+    assert_eq!(limiter.connection_count(client_addr), 3);
+    guards.push(limiter.try_acquire(client_addr).unwrap());
+    assert!(limiter.try_acquire(client_addr).is_none());
+
+    guards.clear();
+    assert_eq!(limiter.connection_count(client_addr), 0);
+
+    // A limit of 0 doesn't restrict connections
+    let unlimited = ConnectionLimiter::new(0);
+    let guards: Vec<_> = (0..100).map(|_| unlimited.try_acquire(client_addr).unwrap())
+            .collect();
+    assert_eq!(unlimited.connection_count(client_addr), guards.len());
+}
+
+#[test]
+fn test_session_limit() {
+    let limiter = SessionLimiter::new(3);
+    let mut guards: Vec<_> = (0..3).map(|_| limiter.try_acquire().unwrap()).collect();
+    assert!(limiter.try_acquire().is_none());
+    assert_eq!(limiter.session_count(), 3);
+
+    // Clones share the same count
+    let other = limiter.clone();
+    assert!(other.try_acquire().is_none());
+
+    // Ending a session frees up a slot
+    guards.pop();
+    assert_eq!(other.session_count(), 2);
+    guards.push(other.try_acquire().unwrap());
+    assert!(limiter.try_acquire().is_none());
+
+    guards.clear();
+    assert_eq!(limiter.session_count(), 0);
+
+    // A limit of 0 doesn't restrict sessions
+    let unlimited = SessionLimiter::new(0);
+    let guards: Vec<_> = (0..100).map(|_| unlimited.try_acquire().unwrap()).collect();
+    assert_eq!(unlimited.session_count(), guards.len());
+}
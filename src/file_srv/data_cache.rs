@@ -277,6 +277,52 @@ pub fn cache_clients(data_root: &Path, python_exe: Option<&Path>) -> Result<()>
     Ok(())
 }
 
+// Checks every file referenced by the cached manifests in data_root against
+// the files on disk.  Returns the number of problems found.
+pub fn verify_manifests(data_root: &Path) -> Result<usize> {
+    let mut manifest_paths = Vec::new();
+    for entry in data_root.read_dir()? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new("mfs_cache")) {
+            manifest_paths.push(path);
+        }
+    }
+    manifest_paths.sort();
+
+    // Many files are shared between manifests, so only check them once
+    let mut verified: HashMap<String, usize> = HashMap::new();
+    let mut total_problems = 0;
+    for manifest_path in manifest_paths {
+        let manifest = match Manifest::from_cache(&manifest_path) {
+            Ok(manifest) => manifest,
+            Err(err) => {
+                warn!("Failed to load manifest {}: {}", manifest_path.display(), err);
+                total_problems += 1;
+                continue;
+            }
+        };
+
+        let mut problems = 0;
+        for file in manifest.files() {
+            problems += *verified.entry(file.as_ds_mfs()).or_insert_with(|| {
+                let file_problems = file.verify(data_root);
+                for problem in &file_problems {
+                    warn!("{}", problem);
+                }
+                file_problems.len()
+            });
+        }
+        if problems == 0 {
+            info!("Verified {} ({} files)", manifest_path.display(), manifest.files().len());
+        } else {
+            warn!("Found {} problem(s) in {}", problems, manifest_path.display());
+        }
+        total_problems += problems;
+    }
+
+    Ok(total_problems)
+}
+
 fn load_or_create_manifest(path: &Path) -> Result<Manifest> {
     if path.exists() {
         info!("Updating manifest {}", path.display());
@@ -56,6 +56,30 @@ fn md5_hash_file(path: &Path) -> Result<[u8; 16]> {
     Ok(hash.finalize().into())
 }
 
+fn verify_file(path: &Path, expected_size: u32, expected_hash: &[u8; 16])
+    -> Option<String>
+{
+    if !path.exists() {
+        return Some(format!("{} is missing", path.display()));
+    }
+    let file_size = match path.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return Some(format!("{}: {}", path.display(), err)),
+    };
+    if file_size != u64::from(expected_size) {
+        return Some(format!("{}: Size mismatch (expected {}, found {})",
+                            path.display(), expected_size, file_size));
+    }
+    match md5_hash_file(path) {
+        Ok(file_hash) if &file_hash != expected_hash => {
+            Some(format!("{}: Hash mismatch (expected {}, found {})", path.display(),
+                         HEXLOWER.encode(expected_hash), HEXLOWER.encode(&file_hash)))
+        }
+        Ok(_) => None,
+        Err(err) => Some(format!("{}: {}", path.display(), err)),
+    }
+}
+
 impl FileInfo {
     // Flags for FileInfo
     const OGG_SPLIT_CHANNELS: u32 = 1 << 0;
@@ -176,6 +200,25 @@ impl FileInfo {
         Ok(())
     }
 
+    // Checks that the files on disk still match the hashes and sizes stored
+    // in this entry.  Returns a description of each problem found.
+    pub fn verify(&self, data_root: &Path) -> Vec<String> {
+        let mut problems = Vec::new();
+        let src_path = self.source_path(data_root);
+        if let Some(problem) = verify_file(&src_path, self.file_size, &self.file_hash) {
+            problems.push(problem);
+        }
+        if self.is_compressed() {
+            let download_path = data_root.join(path_utils::to_native(&self.download_path));
+            if let Some(problem) = verify_file(&download_path, self.download_size,
+                                               &self.download_hash)
+            {
+                problems.push(problem);
+            }
+        }
+        problems
+    }
+
     // Use this to indicate that the source file was deleted
     pub fn mark_deleted(&mut self) {
         self.deleted = true;
@@ -366,3 +409,34 @@ impl StreamWrite for Manifest {
         Ok(stream.write_all(file_buf.as_slice())?)
     }
 }
+
+#[test]
+fn test_verify_file_info() -> Result<()> {
+    let data_root = tempfile::TempDir::new()?;
+    let dat_dir = data_root.path().join("dat");
+    std::fs::create_dir(&dat_dir)?;
+
+    // Compressible, so the manifest should reference a .gz download
+    let src_path = dat_dir.join("Test.prp");
+    std::fs::write(&src_path, [0x55u8; 4096])?;
+    let mut file = FileInfo::new("dat\\Test.prp".to_string(), "dat\\Test.prp");
+    file.update(data_root.path())?;
+    assert!(file.is_compressed());
+    assert!(file.verify(data_root.path()).is_empty());
+
+    std::fs::write(&src_path, [0xAAu8; 4096])?;
+    let problems = file.verify(data_root.path());
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].contains("Hash mismatch"));
+
+    std::fs::write(&src_path, [0x55u8; 100])?;
+    assert!(file.verify(data_root.path())[0].contains("Size mismatch"));
+
+    std::fs::remove_file(src_path.with_extension("prp.gz"))?;
+    std::fs::remove_file(&src_path)?;
+    let problems = file.verify(data_root.path());
+    assert_eq!(problems.len(), 2);
+    assert!(problems.iter().all(|problem| problem.ends_with("is missing")));
+
+    Ok(())
+}
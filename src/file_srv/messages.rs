@@ -14,18 +14,20 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::io::{BufRead, Write, Cursor};
+use std::fmt::Display;
+use std::io::Cursor;
 use std::mem::size_of;
 
 use anyhow::{anyhow, Context, Result};
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use tokio::io::BufReader;
+use tokio::io::AsyncRead;
 use tokio::net::TcpStream;
 
+use crate::msg_trace::{display_as_debug, MsgId, MsgTrace};
 use crate::netcli::NetResultCode;
-use crate::plasma::{StreamRead, StreamWrite, net_io};
+use crate::plasma::{StreamRead, StreamWrite};
+use crate::plasma::net_io::{net_msg_read, net_msg_write};
 use super::manifest::Manifest;
 
 pub enum CliToFile {
@@ -76,7 +78,7 @@ pub enum FileToCli {
 }
 
 #[repr(u32)]
-#[derive(FromPrimitive)]
+#[derive(Debug, FromPrimitive)]
 enum ClientMsgId {
     PingRequest = 0,
     BuildIdRequest = 10,
@@ -87,6 +89,7 @@ enum ClientMsgId {
 }
 
 #[repr(u32)]
+#[derive(Debug, FromPrimitive)]
 enum ServerMsgId {
     PingReply = 0,
     BuildIdReply = 10,
@@ -95,16 +98,18 @@ enum ServerMsgId {
     FileDownloadReply = 21,
 }
 
-macro_rules! read_fixed_utf16 {
-    ($stream:ident, $len:expr) => ({
-        let mut buf = [0u16; $len];
-        $stream.read_u16_into::<LittleEndian>(&mut buf)?;
-        String::from_utf16_lossy(&buf.split(|ch| ch == &0).next().unwrap())
-    })
+display_as_debug!(ClientMsgId, ServerMsgId);
+
+// Extract the message ID from the start of a message body
+fn peek_msg_id<T: FromPrimitive + Display>(msg_buf: &[u8]) -> Option<MsgId<T>> {
+    let raw = u32::from_le_bytes(msg_buf.get(0..4)?.try_into().ok()?);
+    Some(MsgId { raw, name: T::from_u32(raw) })
 }
 
 impl CliToFile {
-    pub async fn read(stream: &mut BufReader<TcpStream>) -> Result<Self> {
+    pub async fn read<S>(stream: &mut S, trace: &MsgTrace) -> Result<Self>
+        where S: AsyncRead + Unpin
+    {
         use tokio::io::AsyncReadExt;
 
         let msg_size = stream.read_u32_le().await?;
@@ -113,54 +118,31 @@ impl CliToFile {
         }
         let mut msg_buf = vec![0u8; (msg_size as usize) - size_of::<u32>()];
         stream.read_exact(&mut msg_buf).await?;
+        if let Some(msg_id) = peek_msg_id::<ClientMsgId>(&msg_buf) {
+            trace.recv(&msg_id);
+        }
+
+        // A truncated message body also produces an UnexpectedEof error, which
+        // must not be confused with the client closing its connection.
         CliToFile::stream_read(&mut Cursor::new(msg_buf))
+                .map_err(|err| anyhow!("Malformed message: {}", err))
     }
 }
 
-impl StreamRead for CliToFile {
-    fn stream_read<S>(stream: &mut S) -> Result<Self>
-        where S: BufRead
-    {
-        let msg_id = stream.read_u32::<LittleEndian>()?;
-        match ClientMsgId::from_u32(msg_id) {
-            Some(ClientMsgId::PingRequest) => {
-                let ping_time = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::PingRequest { ping_time })
-            }
-            Some(ClientMsgId::BuildIdRequest) => {
-                let trans_id = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::BuildIdRequest { trans_id })
-            }
-            Some(ClientMsgId::ManifestRequest) => {
-                let trans_id = stream.read_u32::<LittleEndian>()?;
-                let manifest_name = read_fixed_utf16!(stream, 260);
-                let build_id = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::ManifestRequest { trans_id, manifest_name, build_id })
-            }
-            Some(ClientMsgId::ManifestEntryAck) => {
-                let trans_id = stream.read_u32::<LittleEndian>()?;
-                let reader_id = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::ManifestEntryAck { trans_id, reader_id })
-            }
-            Some(ClientMsgId::DownloadRequest) => {
-                let trans_id = stream.read_u32::<LittleEndian>()?;
-                let filename = read_fixed_utf16!(stream, 260);
-                let build_id = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::DownloadRequest { trans_id, filename, build_id })
-            }
-            Some(ClientMsgId::DownloadChunkAck) => {
-                let trans_id = stream.read_u32::<LittleEndian>()?;
-                let reader_id = stream.read_u32::<LittleEndian>()?;
-                Ok(CliToFile::DownloadChunkAck { trans_id, reader_id })
-            }
-            None => Err(anyhow!("Bad message ID {}", msg_id))
-        }
+net_msg_read! {
+    CliToFile(ClientMsgId) {
+        PingRequest { ping_time: u32 },
+        BuildIdRequest { trans_id: u32 },
+        ManifestRequest { trans_id: u32, manifest_name: utf16[260], build_id: u32 },
+        DownloadRequest { trans_id: u32, filename: utf16[260], build_id: u32 },
+        ManifestEntryAck { trans_id: u32, reader_id: u32 },
+        DownloadChunkAck { trans_id: u32, reader_id: u32 },
     }
 }
 
 impl FileToCli {
     // Requires special buffering to write the output size correctly
-    pub async fn write(&self, stream: &mut TcpStream) -> Result<()> {
+    pub async fn write(&self, stream: &mut TcpStream, trace: &MsgTrace) -> Result<()> {
         use tokio::io::AsyncWriteExt;
 
         let buffer = {
@@ -168,6 +150,9 @@ impl FileToCli {
             self.stream_write(&mut buffer)?;
             buffer.into_inner()
         };
+        if let Some(msg_id) = peek_msg_id::<ServerMsgId>(&buffer) {
+            trace.send(&msg_id);
+        }
 
         let msg_size = u32::try_from(size_of::<u32>() + buffer.len())
                 .context("Message too large for stream")?;
@@ -195,41 +180,63 @@ impl FileToCli {
     }
 }
 
-impl StreamWrite for FileToCli {
-    fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
-        match self {
-            FileToCli::PingReply { ping_time } => {
-                stream.write_u32::<LittleEndian>(ServerMsgId::PingReply as u32)?;
-                stream.write_u32::<LittleEndian>(*ping_time)?;
-            }
-            FileToCli::BuildIdReply { trans_id, result, build_id } => {
-                stream.write_u32::<LittleEndian>(ServerMsgId::BuildIdReply as u32)?;
-                stream.write_u32::<LittleEndian>(*trans_id)?;
-                stream.write_i32::<LittleEndian>(*result)?;
-                stream.write_u32::<LittleEndian>(*build_id)?;
-            }
-            FileToCli::BuildIdUpdate { build_id } => {
-                stream.write_u32::<LittleEndian>(ServerMsgId::BuildIdUpdate as u32)?;
-                stream.write_u32::<LittleEndian>(*build_id)?;
-            }
-            FileToCli::ManifestReply { trans_id, result, reader_id, manifest } => {
-                stream.write_u32::<LittleEndian>(ServerMsgId::ManifestReply as u32)?;
-                stream.write_u32::<LittleEndian>(*trans_id)?;
-                stream.write_i32::<LittleEndian>(*result)?;
-                stream.write_u32::<LittleEndian>(*reader_id)?;
-                manifest.stream_write(stream)?;
-            }
-            FileToCli::FileDownloadReply { trans_id, result, reader_id,
-                                           total_size, file_data } => {
-                stream.write_u32::<LittleEndian>(ServerMsgId::FileDownloadReply as u32)?;
-                stream.write_u32::<LittleEndian>(*trans_id)?;
-                stream.write_i32::<LittleEndian>(*result)?;
-                stream.write_u32::<LittleEndian>(*reader_id)?;
-                stream.write_u32::<LittleEndian>(*total_size)?;
-                net_io::write_sized_buffer(stream, file_data)?;
-            }
-        }
+net_msg_write! {
+    FileToCli(ServerMsgId) {
+        PingReply { ping_time: u32 },
+        BuildIdReply { trans_id: u32, result: i32, build_id: u32 },
+        BuildIdUpdate { build_id: u32 },
+        ManifestReply { trans_id: u32, result: i32, reader_id: u32, manifest: stream },
+        FileDownloadReply {
+            trans_id: u32,
+            result: i32,
+            reader_id: u32,
+            total_size: u32,
+            file_data: buffer,
+        },
+    }
+}
 
-        Ok(())
+#[test]
+fn test_file_messages() -> Result<()> {
+    // These byte streams match the hand-written encoding used before the
+    // message tables were introduced.
+    let mut request = Vec::new();
+    request.extend_from_slice(&20u32.to_le_bytes());
+    request.extend_from_slice(&7u32.to_le_bytes());
+    let mut name = [0u16; 260];
+    for (dest, ch) in name.iter_mut().zip("External".encode_utf16()) {
+        *dest = ch;
     }
+    for ch in name {
+        request.extend_from_slice(&ch.to_le_bytes());
+    }
+    request.extend_from_slice(&918u32.to_le_bytes());
+    match CliToFile::stream_read(&mut Cursor::new(&request))? {
+        CliToFile::ManifestRequest { trans_id, manifest_name, build_id } => {
+            assert_eq!(trans_id, 7);
+            assert_eq!(manifest_name, "External");
+            assert_eq!(build_id, 918);
+        }
+        _ => panic!("Wrong message type"),
+    }
+    let mut bad_request = Cursor::new(99u32.to_le_bytes().to_vec());
+    assert!(CliToFile::stream_read(&mut bad_request).is_err());
+
+    let reply = FileToCli::FileDownloadReply {
+        trans_id: 7,
+        result: NetResultCode::NetSuccess as i32,
+        reader_id: 3,
+        total_size: 1000,
+        file_data: vec![1, 2, 3, 4],
+    };
+    let mut stream = Cursor::new(Vec::new());
+    reply.stream_write(&mut stream)?;
+    let mut expected = Vec::new();
+    for value in [21u32, 7, 0, 3, 1000, 4] {
+        expected.extend_from_slice(&value.to_le_bytes());
+    }
+    expected.extend_from_slice(&[1, 2, 3, 4]);
+    assert_eq!(stream.into_inner(), expected);
+
+    Ok(())
 }
@@ -20,6 +20,8 @@ pub mod manifest;
 pub use manifest::{FileInfo, Manifest};
 
 mod messages;
+#[cfg(feature = "fuzzing")]
+pub(crate) use messages::CliToFile;
 
 mod server;
 pub use server::FileServer;
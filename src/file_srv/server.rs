@@ -28,65 +28,65 @@ use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 
 use crate::config::ServerConfig;
+use crate::conn_limit::ConnectionGuard;
+use crate::msg_trace::MsgTrace;
 use crate::netcli::NetResultCode;
 use crate::path_utils;
 use super::messages::{CliToFile, FileToCli};
 use super::manifest::Manifest;
 
 pub struct FileServer {
-    incoming_send: mpsc::Sender<TcpStream>,
+    incoming_send: mpsc::Sender<(TcpStream, ConnectionGuard)>,
 }
 
 struct FileServerWorker {
     stream: BufReader<TcpStream>,
     server_config: Arc<ServerConfig>,
     client_reader_id: u32,
+    conn_build_id: u32,
+    trace: MsgTrace,
 }
 
 const CONN_HEADER_SIZE: u32 = 12;
-const FILE_CHUNK_SIZE: usize = 64 * 1024;
 
-fn read_conn_header<S>(stream: &mut S) -> Result<()>
+// Returns the Build ID from the connection header
+fn read_conn_header<S>(stream: &mut S) -> Result<u32>
     where S: BufRead
 {
-    // Everything here is discarded...
     let header_size = stream.read_u32::<LittleEndian>()?;
     if header_size != CONN_HEADER_SIZE {
         return Err(anyhow!("Invalid connection header size {}", header_size));
     }
-    // Build ID
-    let _ = stream.read_u32::<LittleEndian>()?;
-    // Server Type
+    let build_id = stream.read_u32::<LittleEndian>()?;
+    // Server Type -- discarded
     let _ = stream.read_u32::<LittleEndian>()?;
 
-    Ok(())
+    Ok(build_id)
 }
 
-async fn init_client(mut sock: TcpStream) -> Result<BufReader<TcpStream>> {
+async fn init_client(mut sock: TcpStream) -> Result<(BufReader<TcpStream>, u32)> {
     let mut buffer = [0u8; CONN_HEADER_SIZE as usize];
     sock.read_exact(&mut buffer).await?;
-    read_conn_header(&mut Cursor::new(buffer))?;
+    let build_id = read_conn_header(&mut Cursor::new(buffer))?;
 
-    Ok(BufReader::new(sock))
+    Ok((BufReader::new(sock), build_id))
 }
 
-fn fetch_manifest(manifest_name: &str, data_path: &Path) -> Option<Manifest> {
+fn fetch_manifest(manifest_name: &str, data_roots: &[PathBuf]) -> Option<Manifest> {
     if manifest_name.contains(['/', '\\', ':', '.']) {
         // Reject anything that looks like a path
         return None;
     }
 
-    let manifest_path = data_path.join(manifest_name.to_owned() + ".mfs_cache");
-    if manifest_path.exists() {
-        match Manifest::from_cache(&manifest_path) {
-            Ok(manifest) => Some(manifest),
-            Err(err) => {
-                warn!("Failed to load manifest '{}': {}", manifest_name, err);
-                None
-            }
+    let manifest_path = data_roots.iter()
+            .map(|data_root| data_root.join(manifest_name.to_owned() + ".mfs_cache"))
+            .find(|manifest_path| manifest_path.exists())?;
+    match Manifest::from_cache(&manifest_path) {
+        Ok(manifest) => Some(manifest),
+        Err(err) => {
+            warn!("Failed to load manifest '{}': {}", manifest_name, err);
+            None
         }
-    } else {
-        None
     }
 }
 
@@ -109,21 +109,24 @@ pub fn ignore_file(path: &Path, allow_compressed: bool) -> bool {
     false
 }
 
-async fn open_server_file(filename: &str, data_root: &Path)
-    -> Option<(tokio::fs::File, std::fs::Metadata, PathBuf)>
-{
-    let native_path = path_utils::to_native(filename);
-    let download_path = data_root.join(native_path);
-
-    // Reject path traversal attempts, hidden/ignored files,
-    // and check if the file exists.
-    if filename.contains("..")
-        || !download_path.starts_with(data_root)
-        || !download_path.exists()
-        || ignore_file(&download_path, true)
-    {
+// Finds the first of the data roots which contains the requested file
+fn resolve_server_file(filename: &str, data_roots: &[PathBuf]) -> Option<PathBuf> {
+    // Reject path traversal attempts and hidden/ignored files
+    if filename.contains("..") {
         return None;
     }
+    let native_path = path_utils::to_native(filename);
+    data_roots.iter()
+        .map(|data_root| (data_root, data_root.join(&native_path)))
+        .find(|(data_root, path)| path.starts_with(data_root) && path.exists())
+        .map(|(_, path)| path)
+        .filter(|path| !ignore_file(path, true))
+}
+
+async fn open_server_file(filename: &str, data_roots: &[PathBuf])
+    -> Option<(tokio::fs::File, std::fs::Metadata, PathBuf)>
+{
+    let download_path = resolve_server_file(filename, data_roots)?;
 
     let file = match tokio::fs::File::open(&download_path).await {
         Ok(file) => file,
@@ -149,36 +152,43 @@ impl FileServer {
         let (incoming_send, mut incoming_recv) = mpsc::channel(5);
 
         tokio::spawn(async move {
-            while let Some(sock) = incoming_recv.recv().await {
-                FileServerWorker::start(sock, server_config.clone());
+            while let Some((sock, conn_guard)) = incoming_recv.recv().await {
+                FileServerWorker::start(sock, conn_guard, server_config.clone());
             }
         });
         FileServer { incoming_send }
     }
 
-    pub async fn add(&mut self, sock: TcpStream) {
-        if let Err(err) = self.incoming_send.send(sock).await {
+    pub async fn add(&mut self, sock: TcpStream, conn_guard: ConnectionGuard) {
+        if let Err(err) = self.incoming_send.send((sock, conn_guard)).await {
             error!("Failed to add client: {}", err);
         }
     }
 }
 
 impl FileServerWorker {
-    pub fn start(sock: TcpStream, server_config: Arc<ServerConfig>) {
+    pub fn start(sock: TcpStream, conn_guard: ConnectionGuard,
+                 server_config: Arc<ServerConfig>)
+    {
         tokio::spawn(async move {
-            let stream = match init_client(sock).await {
-                Ok(stream) => stream,
+            let _conn_guard = conn_guard;
+            let (stream, conn_build_id) = match init_client(sock).await {
+                Ok(result) => result,
                 Err(err) => {
                     warn!("Failed to initialize client: {}", err);
                     return;
                 }
             };
 
+            let trace = MsgTrace::new("File", stream.get_ref().peer_addr().ok(),
+                                      &server_config);
             let mut worker = FileServerWorker {
                 stream,
                 server_config,
                 // This monotonic ID is unique for each client, so we always start at 0
                 client_reader_id: 0,
+                conn_build_id,
+                trace,
             };
             worker.run().await;
         });
@@ -188,15 +198,19 @@ impl FileServerWorker {
 
     async fn run(&mut self) {
         loop {
-            match CliToFile::read(&mut self.stream).await {
+            match CliToFile::read(&mut self.stream, &self.trace).await {
                 Ok(message) => {
                     if !self.handle_message(message).await {
                         break;
                     }
                 }
                 Err(err) => {
+                    // Clients commonly just drop the connection when they're
+                    // done, so that is not worth warning about.
                     match err.downcast_ref::<io::Error>() {
                         Some(io_err) if matches!(io_err.kind(), io::ErrorKind::ConnectionReset
+                                                                | io::ErrorKind::ConnectionAborted
+                                                                | io::ErrorKind::BrokenPipe
                                                                 | io::ErrorKind::UnexpectedEof) => {
                             debug!("Client {} disconnected", self.peer_addr().unwrap());
                         }
@@ -222,30 +236,39 @@ impl FileServerWorker {
                 }).await
             }
             CliToFile::ManifestRequest { trans_id, manifest_name, build_id } => {
-                if build_id != 0 && build_id != self.server_config.build_id {
-                    warn!("Client {} has an unexpected build ID {}",
-                          self.peer_addr().unwrap(), build_id);
+                if !self.check_build_id(build_id) {
                     return self.send_message(FileToCli::manifest_error(trans_id,
                                                 NetResultCode::NetOldBuildId)).await;
                 }
-                self.do_manifest(trans_id, &manifest_name).await
+                self.do_manifest(trans_id, &manifest_name, build_id).await
             }
             CliToFile::DownloadRequest { trans_id, filename, build_id } => {
-                if build_id != 0 && build_id != self.server_config.build_id {
-                    warn!("Client {} has an unexpected build ID {}",
-                          self.peer_addr().unwrap(), build_id);
+                if !self.check_build_id(build_id) {
                     return self.send_message(FileToCli::download_error(trans_id,
                                                 NetResultCode::NetOldBuildId)).await;
                 }
-                Box::pin(self.do_download(trans_id, &filename)).await
+                Box::pin(self.do_download(trans_id, &filename, build_id)).await
             }
             CliToFile::ManifestEntryAck { .. }
                 | CliToFile::DownloadChunkAck { .. } => true, // Ignored
         }
     }
 
+    // Both the build ID sent in the connection header and the one sent with
+    // each request must match the server's build.
+    fn check_build_id(&self, request_build_id: u32) -> bool {
+        for build_id in [self.conn_build_id, request_build_id] {
+            if !self.server_config.accepts_build_id(build_id) {
+                warn!("Client {} has an unexpected build ID {}",
+                      self.peer_addr().unwrap(), build_id);
+                return false;
+            }
+        }
+        true
+    }
+
     async fn send_message(&mut self, reply: FileToCli) -> bool {
-        if let Err(err) = reply.write(self.stream.get_mut()).await {
+        if let Err(err) = reply.write(self.stream.get_mut(), &self.trace).await {
             warn!("Failed to send reply message: {}", err);
             false
         } else {
@@ -253,10 +276,18 @@ impl FileServerWorker {
         }
     }
 
-    async fn do_manifest(&mut self, trans_id: u32, manifest_name: &str) -> bool {
-        let reply = if let Some(manifest)
-                            = fetch_manifest(manifest_name, &self.server_config.data_root)
-        {
+    // Clients which send a build ID of 0 with a request get the files for
+    // the build they connected with.
+    fn data_roots(&self, request_build_id: u32) -> Vec<PathBuf> {
+        let build_id = if request_build_id != 0 { request_build_id } else { self.conn_build_id };
+        self.server_config.build_data_roots(build_id)
+    }
+
+    async fn do_manifest(&mut self, trans_id: u32, manifest_name: &str, build_id: u32)
+        -> bool
+    {
+        let data_roots = self.data_roots(build_id);
+        let reply = if let Some(manifest) = fetch_manifest(manifest_name, &data_roots) {
             debug!("Client {} requested manifest '{}'", self.peer_addr().unwrap(),
                    manifest_name);
 
@@ -276,9 +307,10 @@ impl FileServerWorker {
         self.send_message(reply).await
     }
 
-    async fn do_download(&mut self, trans_id: u32, filename: &str) -> bool {
+    async fn do_download(&mut self, trans_id: u32, filename: &str, build_id: u32) -> bool {
+        let data_roots = self.data_roots(build_id);
         if let Some((mut file, metadata, download_path))
-                    = open_server_file(filename, &self.server_config.data_root).await
+                    = open_server_file(filename, &data_roots).await
         {
             debug!("Client {} requested file '{}'", self.peer_addr().unwrap(), filename);
 
@@ -289,7 +321,7 @@ impl FileServerWorker {
             };
 
             self.client_reader_id += 1;
-            let mut buffer = [0u8; FILE_CHUNK_SIZE];
+            let mut buffer = vec![0u8; self.server_config.file_chunk_size];
             loop {
                 match file.read(&mut buffer).await {
                     Ok(count) => {
@@ -323,3 +355,43 @@ impl FileServerWorker {
         }
     }
 }
+
+#[test]
+fn test_build_data_roots() {
+    use crate::config::ServerConfig;
+
+    fn read_file(server_config: &ServerConfig, filename: &str, build_id: u32)
+        -> Option<String>
+    {
+        let data_roots = server_config.build_data_roots(build_id);
+        resolve_server_file(filename, &data_roots)
+                .map(|path| std::fs::read_to_string(path).unwrap())
+    }
+
+    let data_root = tempfile::tempdir().unwrap();
+    for (dir, contents) in [("client", "shared"), ("919/client", "build 919")] {
+        std::fs::create_dir_all(data_root.path().join(dir)).unwrap();
+        std::fs::write(data_root.path().join(dir).join("version.txt"), contents).unwrap();
+    }
+    std::fs::write(data_root.path().join("client/shared.txt"), "shared").unwrap();
+
+    let mut server_config = ServerConfig::test_config();
+    server_config.data_root = data_root.path().to_path_buf();
+    server_config.extra_build_ids = vec![919];
+    server_config.build_data_dirs = true;
+    assert!(server_config.accepts_build_id(919));
+    assert!(!server_config.accepts_build_id(920));
+
+    // Build-specific files take precedence, with a fallback to the shared root
+    let config = &server_config;
+    assert_eq!(read_file(config, "client\\version.txt", 919).as_deref(), Some("build 919"));
+    assert_eq!(read_file(config, "client\\shared.txt", 919).as_deref(), Some("shared"));
+    assert_eq!(read_file(config, "client\\missing.txt", 919), None);
+    assert_eq!(read_file(config, "client\\version.txt", config.build_id).as_deref(),
+               Some("shared"));
+    assert_eq!(read_file(config, "..\\client\\version.txt", 919), None);
+
+    server_config.build_data_dirs = false;
+    assert_eq!(read_file(&server_config, "client\\version.txt", 919).as_deref(),
+               Some("shared"));
+}
@@ -0,0 +1,70 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// Entry points for the cargo-fuzz targets in fuzz/.  Each one feeds an
+// arbitrary byte buffer to one of the parsers that handles client-supplied
+// data.  Parse errors are expected and ignored; only panics (or hangs) are
+// interesting to the fuzzer.
+
+use std::sync::OnceLock;
+
+use crate::auth_srv::{BufferLimits, CliToAuth};
+use crate::file_srv::CliToFile;
+use crate::msg_trace::MsgTrace;
+use crate::sdl::{DescriptorDb, State};
+use crate::vault::VaultNode;
+
+thread_local! {
+    static RUNTIME: tokio::runtime::Runtime =
+        tokio::runtime::Builder::new_current_thread().build()
+            .expect("Failed to create tokio runtime");
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    RUNTIME.with(|rt| rt.block_on(future))
+}
+
+fn sdl_db() -> &'static DescriptorDb {
+    static SDL_DB: OnceLock<DescriptorDb> = OnceLock::new();
+    SDL_DB.get_or_init(|| {
+        let sdl_source = [
+            include_str!("../tests/fixtures/sdl/Garden.sdl"),
+            include_str!("../tests/fixtures/sdl/Test.sdl"),
+            include_str!("../tests/fixtures/sdl/Types.sdl"),
+        ].join("\n");
+        DescriptorDb::from_string(&sdl_source).expect("Failed to parse SDL fixtures")
+    })
+}
+
+pub fn auth_message(data: &[u8]) {
+    let mut stream = data;
+    let _ = block_on(CliToAuth::read(&mut stream, &BufferLimits::default(),
+                                     &MsgTrace::disabled()));
+}
+
+pub fn file_message(data: &[u8]) {
+    let mut stream = data;
+    let _ = block_on(CliToFile::read(&mut stream, &MsgTrace::disabled()));
+}
+
+pub fn vault_node(data: &[u8]) {
+    let _ = VaultNode::from_blob(data);
+    let _ = VaultNode::from_import_blob(data);
+}
+
+pub fn sdl_state(data: &[u8]) {
+    let _ = State::from_blob(&data.to_vec(), sdl_db());
+}
@@ -22,6 +22,7 @@ use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use tokio::io::{AsyncReadExt, BufReader};
 
+use crate::msg_trace::{display_as_debug, MsgId, MsgTrace};
 use crate::net_crypt::CryptTcpStream;
 use crate::plasma::{StreamWrite, net_io};
 
@@ -59,7 +60,7 @@ pub enum GateKeeperToCli {
 }
 
 #[repr(u16)]
-#[derive(FromPrimitive)]
+#[derive(Debug, FromPrimitive)]
 #[allow(clippy::enum_variant_names)]
 enum ClientMsgId {
     PingRequest = 0,
@@ -68,6 +69,7 @@ enum ClientMsgId {
 }
 
 #[repr(u16)]
+#[derive(Debug, FromPrimitive)]
 #[allow(clippy::enum_variant_names)]
 enum ServerMsgId {
     PingReply = 0,
@@ -75,11 +77,16 @@ enum ServerMsgId {
     AuthServIpAddressReply,
 }
 
+display_as_debug!(ClientMsgId, ServerMsgId);
+
 const MAX_PING_PAYLOAD: u32 = 64 * 1024;
 
 impl CliToGateKeeper {
-    pub async fn read(stream: &mut BufReader<CryptTcpStream>) -> Result<Self> {
+    pub async fn read(stream: &mut BufReader<CryptTcpStream>, trace: &MsgTrace)
+        -> Result<Self>
+    {
         let msg_id = stream.read_u16_le().await?;
+        trace.recv(&MsgId { raw: msg_id.into(), name: ClientMsgId::from_u16(msg_id) });
         match ClientMsgId::from_u16(msg_id) {
             Some(ClientMsgId::PingRequest) => {
                 let trans_id = stream.read_u32_le().await?;
@@ -101,6 +108,16 @@ impl CliToGateKeeper {
     }
 }
 
+impl GateKeeperToCli {
+    // Trace the message ID of an already serialized reply
+    pub fn trace_sent(msg_buf: &[u8], trace: &MsgTrace) {
+        if let Some(&[lo, hi]) = msg_buf.get(0..2) {
+            let msg_id = u16::from_le_bytes([lo, hi]);
+            trace.send(&MsgId { raw: msg_id.into(), name: ServerMsgId::from_u16(msg_id) });
+        }
+    }
+}
+
 impl StreamWrite for GateKeeperToCli {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
         match self {
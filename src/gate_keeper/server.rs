@@ -27,17 +27,22 @@ use tokio::net::TcpStream;
 use uuid::Uuid;
 
 use crate::config::ServerConfig;
+use crate::conn_limit::ConnectionGuard;
+use crate::msg_trace::MsgTrace;
 use crate::net_crypt::CryptTcpStream;
+use crate::ping_stats::{PingService, PingStats, PingTracker};
 use crate::plasma::{StreamRead, StreamWrite};
 use super::messages::{CliToGateKeeper, GateKeeperToCli};
 
 pub struct GateKeeper {
-    incoming_send: mpsc::Sender<TcpStream>,
+    incoming_send: mpsc::Sender<(TcpStream, ConnectionGuard)>,
 }
 
 struct GateKeeperWorker {
     stream: BufReader<CryptTcpStream>,
     server_config: Arc<ServerConfig>,
+    ping_tracker: PingTracker,
+    trace: MsgTrace,
 }
 
 const CONN_HEADER_SIZE: u32 = 20;
@@ -63,32 +68,36 @@ async fn init_client(mut sock: TcpStream, server_config: &ServerConfig)
     sock.read_exact(&mut header).await?;
     read_conn_header(&mut Cursor::new(header))?;
 
-    crate::net_crypt::init_crypt(sock, &server_config.gate_n_key,
-                                 &server_config.gate_k_key).await
+    crate::net_crypt::init_crypt(sock, &server_config.gate_keys).await
 }
 
 impl GateKeeper {
-    pub fn start(server_config: Arc<ServerConfig>) -> GateKeeper {
+    pub fn start(server_config: Arc<ServerConfig>, ping_stats: PingStats) -> GateKeeper {
         let (incoming_send, mut incoming_recv) = mpsc::channel(5);
 
         tokio::spawn(async move {
-            while let Some(sock) = incoming_recv.recv().await {
-                GateKeeperWorker::start(sock, server_config.clone());
+            while let Some((sock, conn_guard)) = incoming_recv.recv().await {
+                GateKeeperWorker::start(sock, conn_guard, server_config.clone(),
+                                        &ping_stats);
             }
         });
         GateKeeper { incoming_send }
     }
 
-    pub async fn add(&mut self, sock: TcpStream) {
-        if let Err(err) = self.incoming_send.send(sock).await {
+    pub async fn add(&mut self, sock: TcpStream, conn_guard: ConnectionGuard) {
+        if let Err(err) = self.incoming_send.send((sock, conn_guard)).await {
             error!("Failed to add client: {}", err);
         }
     }
 }
 
 impl GateKeeperWorker {
-    pub fn start(sock: TcpStream, server_config: Arc<ServerConfig>) {
+    pub fn start(sock: TcpStream, conn_guard: ConnectionGuard,
+                 server_config: Arc<ServerConfig>, ping_stats: &PingStats)
+    {
+        let ping_tracker = ping_stats.tracker(PingService::GateKeeper);
         tokio::spawn(async move {
+            let _conn_guard = conn_guard;
             let stream = match init_client(sock, &server_config).await {
                 Ok(cipher) => cipher,
                 Err(err) => {
@@ -97,7 +106,9 @@ impl GateKeeperWorker {
                 }
             };
 
-            let mut worker = GateKeeperWorker { stream, server_config };
+            let trace = MsgTrace::new("GateKeeper", stream.get_ref().peer_addr().ok(),
+                                      &server_config);
+            let mut worker = GateKeeperWorker { stream, server_config, ping_tracker, trace };
             worker.run().await;
         });
     }
@@ -106,7 +117,7 @@ impl GateKeeperWorker {
 
     async fn run(&mut self) {
         loop {
-            match CliToGateKeeper::read(&mut self.stream).await {
+            match CliToGateKeeper::read(&mut self.stream, &self.trace).await {
                 Ok(message) => {
                     if !self.handle_message(message).await {
                         break;
@@ -130,6 +141,9 @@ impl GateKeeperWorker {
     async fn handle_message(&mut self, message: CliToGateKeeper) -> bool {
         match message {
             CliToGateKeeper::PingRequest { trans_id, ping_time, payload } => {
+                if let Some(rtt) = self.stream.get_ref().tcp_rtt() {
+                    self.ping_tracker.record(rtt);
+                }
                 self.send_message(GateKeeperToCli::PingReply {
                     trans_id, ping_time, payload
                 }).await
@@ -158,6 +172,7 @@ impl GateKeeperWorker {
             warn!("Failed to write reply stream: {}", err);
             return false;
         }
+        GateKeeperToCli::trace_sent(reply_buf.get_ref(), &self.trace);
         if let Err(err) = self.stream.get_mut().write_all(reply_buf.get_ref()).await {
             warn!("Failed to send reply: {}", err);
             false
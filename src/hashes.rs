@@ -14,14 +14,14 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::io::Write;
+use std::io::{BufRead, Write};
 use std::mem::size_of;
 
 use anyhow::Result;
 use data_encoding::HEXLOWER;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::plasma::StreamWrite;
+use crate::plasma::{StreamRead, StreamWrite};
 
 // This is used for both Sha0 and Sha1 digests
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -156,6 +156,16 @@ impl ShaDigest {
     }
 }
 
+impl StreamRead for ShaDigest {
+    fn stream_read<S>(stream: &mut S) -> Result<Self>
+        where S: BufRead
+    {
+        let mut data = [0; 20];
+        stream.read_exact(&mut data)?;
+        Ok(Self { data })
+    }
+}
+
 impl StreamWrite for ShaDigest {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
         Ok(stream.write_all(&self.data)?)
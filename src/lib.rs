@@ -35,8 +35,15 @@ pub mod vault;
 
 pub mod api;
 pub mod config;
+pub mod conn_limit;
 pub mod hashes;
 pub mod lobby;
+pub mod msg_trace;
 pub mod net_crypt;
 pub mod netcli;
 pub mod path_utils;
+pub mod ping_stats;
+pub mod sockets;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
@@ -21,16 +21,20 @@ use std::sync::Arc;
 use anyhow::{anyhow, Result};
 use byteorder::{LittleEndian, ReadBytesExt};
 use log::{warn, info};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpStream;
 use tokio::sync::broadcast;
 use uuid::Uuid;
 
 use crate::config::ServerConfig;
+use crate::conn_limit::{ConnectionLimiter, SessionLimiter};
 use crate::auth_srv::AuthServer;
 use crate::gate_keeper::GateKeeper;
+use crate::netcli::KickReason;
 use crate::file_srv::FileServer;
+use crate::ping_stats::PingStats;
 use crate::plasma::StreamRead;
 use crate::sdl::DescriptorDb;
+use crate::sockets::{configure_stream, MultiListener};
 use crate::vault::VaultServer;
 
 struct ConnectionHeader {
@@ -95,9 +99,11 @@ fn connection_type_name(conn_type: u8) -> String {
 }
 
 pub struct LobbyServer {
-    auth_server: AuthServer,
-    file_server: FileServer,
-    gate_keeper: GateKeeper,
+    auth_server: Option<AuthServer>,
+    file_server: Option<FileServer>,
+    gate_keeper: Option<GateKeeper>,
+    conn_limiter: ConnectionLimiter,
+    server_config: Arc<ServerConfig>,
 }
 
 impl LobbyServer {
@@ -113,38 +119,42 @@ impl LobbyServer {
             let _ = ctrl_c_send.send(());
         });
 
-        let listener = match TcpListener::bind(&server_config.listen_address).await {
+        let mut listener = match MultiListener::bind("Lobby server",
+                                    &server_config.listen_addresses, &server_config).await {
             Ok(listener) => listener,
-            Err(err) => panic!("Failed to bind on address {}: {}",
-                               server_config.listen_address, err),
+            Err(err) => panic!("{err}"),
         };
 
-        let ntd_key = server_config.get_ntd_key().unwrap_or_else(|err| {
-            // This is not a fatal error, because the SDL files can still
-            // be loaded successfully if they are not encrypted.
-            warn!("Failed to get encryption key: {}", err);
-            [0; 4]
+        let server_config = Arc::new(server_config);
+        let services = &server_config.services;
+        let vault = if services.needs_vault() {
+            Some(Arc::new(VaultServer::start(server_config.clone(), load_sdl(&server_config))))
+        } else {
+            None
+        };
+        let ping_stats = PingStats::new();
+        let session_limiter = SessionLimiter::new(server_config.max_sessions);
+        let auth_server = vault.as_ref().filter(|_| services.auth)
+                .map(|vault| AuthServer::start(server_config.clone(), vault.clone(),
+                                               ping_stats.clone(), session_limiter.clone()));
+        let file_server = services.file.then(|| FileServer::start(server_config.clone()));
+        let gate_keeper = services.gate_keeper.then(|| {
+            GateKeeper::start(server_config.clone(), ping_stats.clone())
         });
-
-        let sdl_path = server_config.data_root.join("SDL");
-        let sdl_db = match DescriptorDb::from_dir(&sdl_path, &ntd_key) {
-            Ok(database) => database,
-            Err(err) => {
-                warn!("Failed to load SDL descriptors from {}: {}", sdl_path.display(), err);
-                DescriptorDb::empty()
-            }
+        let mut lobby = Self {
+            auth_server,
+            file_server,
+            gate_keeper,
+            conn_limiter: ConnectionLimiter::new(server_config.max_connections_per_ip),
+            server_config: server_config.clone(),
         };
 
-        let server_config = Arc::new(server_config);
-        let vault = Arc::new(VaultServer::start(server_config.clone(), sdl_db));
-        let auth_server = AuthServer::start(server_config.clone(), vault.clone());
-        let file_server = FileServer::start(server_config.clone());
-        let gate_keeper = GateKeeper::start(server_config.clone());
-        let mut lobby = Self { auth_server, file_server, gate_keeper };
-
-        crate::api::start_api(shutdown_send.clone(), vault, server_config.clone());
+        if let Some(vault) = vault.clone().filter(|_| services.api) {
+            crate::api::start_api(shutdown_send.clone(), vault, server_config.clone(),
+                                  ping_stats, session_limiter);
+        }
 
-        info!("Starting lobby server on {}", server_config.listen_address);
+        info!("Starting lobby server");
         loop {
             tokio::select! {
                 () = async {
@@ -160,10 +170,24 @@ impl LobbyServer {
         }
 
         info!("Shutting down...");
+        if let Some(vault) = vault {
+            if let Err(err) = vault.kick_online_players(KickReason::ServerShutdown).await {
+                warn!("Failed to kick online players: {:?}", err);
+            }
+            vault.shutdown().await;
+        }
     }
 
     pub async fn accept_client(&mut self, mut sock: TcpStream, sock_addr: SocketAddr)
     {
+        let Some(conn_guard) = self.conn_limiter.try_acquire(sock_addr.ip()) else {
+            warn!("{} - Too many connections from this address; rejecting", sock_addr);
+            return;
+        };
+        if let Err(err) = configure_stream(&sock, &self.server_config) {
+            warn!("{} - Failed to set socket options: {}", sock_addr, err);
+        }
+
         let header = match ConnectionHeader::read(&mut sock).await {
             Ok(header) => header,
             Err(err) => {
@@ -177,18 +201,58 @@ impl LobbyServer {
               header.build_id, header.build_type, header.branch_id,
               header.product_id);
 
+        if !self.server_config.accepts_build_branch(header.build_type, header.branch_id) {
+            warn!("{} - Build type {} / branch {} is not allowed; rejecting",
+                  sock_addr, header.build_type, header.branch_id);
+            return;
+        }
+
         match header.conn_type {
-            CONN_CLI_TO_GATE_KEEPER => self.gate_keeper.add(sock).await,
-            CONN_CLI_TO_FILE => self.file_server.add(sock).await,
-            CONN_CLI_TO_AUTH => self.auth_server.add(sock).await,
-            CONN_CLI_TO_GAME => todo!(),
+            CONN_CLI_TO_GATE_KEEPER => {
+                if let Some(server) = &mut self.gate_keeper {
+                    return server.add(sock, conn_guard).await;
+                }
+            }
+            CONN_CLI_TO_FILE => {
+                if let Some(server) = &mut self.file_server {
+                    return server.add(sock, conn_guard).await;
+                }
+            }
+            CONN_CLI_TO_AUTH => {
+                if let Some(server) = &mut self.auth_server {
+                    return server.add(sock, conn_guard).await;
+                }
+            }
+            CONN_CLI_TO_GAME => (),
             CONN_CLI_TO_CSR => {
                 warn!("{} - Got CSR client; rejecting", sock_addr);
+                return;
             }
             _ => {
                 warn!("{} - Unknown connection type {}; rejecting",
                       sock_addr, header.conn_type);
+                return;
             }
         }
+        warn!("{} - {} service is not enabled on this server; rejecting",
+              sock_addr, connection_type_name(header.conn_type));
+    }
+}
+
+pub fn load_sdl(server_config: &ServerConfig) -> DescriptorDb {
+    let ntd_key = server_config.get_ntd_key().unwrap_or_else(|err| {
+        // This is not a fatal error, because the SDL files can still
+        // be loaded successfully if they are not encrypted.
+        warn!("Failed to get encryption key: {}", err);
+        [0; 4]
+    });
+
+    let sdl_path = server_config.auth_file_root("SDL").join("SDL");
+    match DescriptorDb::from_dir(&sdl_path, &ntd_key) {
+        Ok(database) => database,
+        Err(err) => {
+            warn!("Failed to load SDL descriptors from {}: {:#}", sdl_path.display(), err);
+            DescriptorDb::empty()
+        }
     }
 }
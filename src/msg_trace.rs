@@ -0,0 +1,95 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+
+use log::trace;
+
+use crate::config::ServerConfig;
+
+// Logs the ID of every message sent or received on a client connection at
+// trace level, for diagnosing client compatibility issues.  Message payloads
+// are never logged.
+#[derive(Copy, Clone)]
+pub struct MsgTrace {
+    enabled: bool,
+    service: &'static str,
+    peer: Option<SocketAddr>,
+}
+
+// A raw message ID, along with its name if it is known
+pub struct MsgId<T: Display> {
+    pub raw: u32,
+    pub name: Option<T>,
+}
+
+impl<T: Display> Display for MsgId<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.raw),
+            None => write!(f, "Unknown ({})", self.raw),
+        }
+    }
+}
+
+// Implements Display using the type's Debug output, e.g. for the message ID
+// enums which are logged by name.
+macro_rules! display_as_debug {
+    ($($type:ty),+ $(,)?) => {
+        $(impl std::fmt::Display for $type {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                std::fmt::Debug::fmt(self, f)
+            }
+        })+
+    };
+}
+pub(crate) use display_as_debug;
+
+impl MsgTrace {
+    pub fn new(service: &'static str, peer: Option<SocketAddr>,
+               server_config: &ServerConfig) -> Self
+    {
+        Self { enabled: server_config.trace_messages, service, peer }
+    }
+
+    pub fn disabled() -> Self {
+        Self { enabled: false, service: "", peer: None }
+    }
+
+    fn peer_name(&self) -> String {
+        self.peer.map_or_else(|| "(unknown peer)".to_string(), |peer| peer.to_string())
+    }
+
+    pub fn recv<T: Display>(&self, msg_id: &MsgId<T>) {
+        if self.enabled {
+            trace!("{} -> {}: {}", self.peer_name(), self.service, msg_id);
+        }
+    }
+
+    pub fn send<T: Display>(&self, msg_id: &MsgId<T>) {
+        if self.enabled {
+            trace!("{} <- {}: {}", self.peer_name(), self.service, msg_id);
+        }
+    }
+}
+
+#[test]
+fn test_msg_id_display() {
+    assert_eq!(MsgId { raw: 3, name: Some("AcctLoginRequest") }.to_string(),
+               "AcctLoginRequest (3)");
+    assert_eq!(MsgId::<&str> { raw: 0x1234, name: None }.to_string(), "Unknown (4660)");
+}
@@ -18,6 +18,7 @@ use std::io::{self, BufRead, Write, Cursor};
 use std::net::SocketAddr;
 use std::task::{Context, Poll};
 use std::pin::Pin;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use byteorder::{ReadBytesExt, WriteBytesExt};
@@ -25,7 +26,9 @@ use num_bigint::{BigUint, RandBigInt};
 use tokio::net::TcpStream;
 use tokio::io::{AsyncRead, BufReader, ReadBuf};
 
+use crate::config::CryptKeyPair;
 use crate::plasma::StreamRead;
+use crate::sockets;
 
 pub const CRYPT_BASE_AUTH: u32 = 41;
 pub const CRYPT_BASE_GAME: u32 = 73;
@@ -62,9 +65,23 @@ impl CryptTcpStream {
         self.stream.write_all(crypt_buf.as_slice()).await
     }
 
+    // Flushes anything still pending and gracefully closes the write half
+    // of the connection, so the client receives everything we've sent
+    // before the socket is closed.
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.stream.flush().await?;
+        self.stream.shutdown().await
+    }
+
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         self.stream.peer_addr()
     }
+
+    pub fn tcp_rtt(&self) -> Option<Duration> {
+        sockets::tcp_rtt(&self.stream)
+    }
 }
 
 impl AsyncRead for CryptTcpStream {
@@ -73,9 +90,14 @@ impl AsyncRead for CryptTcpStream {
     {
         use rc4::StreamCipher;
 
+        // RC4 is a stream cipher, so any number of bytes can be decrypted
+        // as they arrive.  However, the caller may pass in a partially
+        // filled buffer (e.g. from read_exact), so we must only decrypt the
+        // newly read bytes.
+        let start = buf.filled().len();
         match Pin::new(&mut self.stream).poll_read(cx, buf) {
             Poll::Ready(Ok(())) => {
-                self.cipher_read.apply_keystream(buf.filled_mut());
+                self.cipher_read.apply_keystream(&mut buf.filled_mut()[start..]);
                 Poll::Ready(Ok(()))
             }
             result => result
@@ -156,7 +178,7 @@ fn crypt_key_create(key_n: &BigUint, key_k: &BigUint, key_y: &BigUint)
     (server_seed, key)
 }
 
-pub async fn init_crypt(mut sock: TcpStream, key_n: &BigUint, key_k: &BigUint)
+pub async fn init_crypt(mut sock: TcpStream, keys: &CryptKeyPair)
     -> Result<BufReader<CryptTcpStream>>
 {
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -184,9 +206,75 @@ pub async fn init_crypt(mut sock: TcpStream, key_n: &BigUint, key_k: &BigUint)
     }
 
     let key_y = BigUint::from_bytes_le(&crypt_header.key_seed);
-    let (server_seed, crypt_key) = crypt_key_create(key_n, key_k, &key_y);
+    let (server_seed, crypt_key) = crypt_key_create(&keys.n, &keys.k, &key_y);
     let reply = create_crypt_reply(&server_seed)?;
     sock.write_all(&reply).await?;
 
     Ok(BufReader::new(CryptTcpStream::new(sock, &crypt_key)))
 }
+
+#[tokio::test]
+async fn test_final_message_delivered() {
+    use rc4::{Key, KeyInit, StreamCipher};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    const KEY_DATA: [u8; 7] = [1, 2, 3, 4, 5, 6, 7];
+    const MESSAGE: &[u8] = b"Last message before disconnect";
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+
+    let mut server = CryptTcpStream::new(sock, &KEY_DATA);
+    server.write_all(MESSAGE).await.unwrap();
+    server.shutdown().await.unwrap();
+    drop(server);
+
+    let mut received = Vec::new();
+    client.read_to_end(&mut received).await.unwrap();
+    CryptCipher::new(Key::from_slice(&KEY_DATA)).apply_keystream(&mut received);
+    assert_eq!(received, MESSAGE);
+}
+
+#[tokio::test]
+async fn test_partial_reads() {
+    use rc4::{Key, KeyInit, StreamCipher};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const KEY_DATA: [u8; 7] = [7, 6, 5, 4, 3, 2, 1];
+    let message: Vec<u8> = (0..=255).collect();
+    let mut encrypted = message.clone();
+    CryptCipher::new(Key::from_slice(&KEY_DATA)).apply_keystream(&mut encrypted);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+
+    // Send the data one byte at a time, followed by the same data in bulk
+    let mut client = TcpStream::connect(local_addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    let client_data = encrypted.clone();
+    let sender = tokio::spawn(async move {
+        for byte in &client_data[..128] {
+            client.write_all(std::slice::from_ref(byte)).await.unwrap();
+            client.flush().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        client.write_all(&client_data[128..]).await.unwrap();
+    });
+
+    let mut server = CryptTcpStream::new(sock, &KEY_DATA);
+    let mut trickled = vec![0; message.len()];
+    server.read_exact(&mut trickled).await.unwrap();
+    sender.await.unwrap();
+    assert_eq!(trickled, message);
+
+    let mut client = TcpStream::connect(local_addr).await.unwrap();
+    let (sock, _) = listener.accept().await.unwrap();
+    client.write_all(&encrypted).await.unwrap();
+    let mut server = BufReader::new(CryptTcpStream::new(sock, &KEY_DATA));
+    let mut bulk = vec![0; message.len()];
+    server.read_exact(&mut bulk).await.unwrap();
+    assert_eq!(bulk, trickled);
+}
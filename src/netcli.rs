@@ -70,3 +70,82 @@ pub enum NetResultCode
     NetNeedToPay,
     NetServerBusy,
 }
+
+impl NetResultCode {
+    // A stable, machine-readable name for the result code, e.g. for use in
+    // API responses.
+    pub fn as_code(&self) -> &'static str {
+        match self {
+            NetResultCode::NetPending => "pending",
+            NetResultCode::NetSuccess => "success",
+            NetResultCode::NetInternalError => "internal_error",
+            NetResultCode::NetTimeout => "timeout",
+            NetResultCode::NetBadServerData => "bad_server_data",
+            NetResultCode::NetAgeNotFound => "age_not_found",
+            NetResultCode::NetConnectFailed => "connect_failed",
+            NetResultCode::NetDisconnected => "disconnected",
+            NetResultCode::NetFileNotFound => "file_not_found",
+            NetResultCode::NetOldBuildId => "old_build_id",
+            NetResultCode::NetRemoteShutdown => "remote_shutdown",
+            NetResultCode::NetTimeoutOdbc => "timeout_odbc",
+            NetResultCode::NetAccountAlreadyExists => "account_already_exists",
+            NetResultCode::NetPlayerAlreadyExists => "player_already_exists",
+            NetResultCode::NetAccountNotFound => "account_not_found",
+            NetResultCode::NetPlayerNotFound => "player_not_found",
+            NetResultCode::NetInvalidParameter => "invalid_parameter",
+            NetResultCode::NetNameLookupFailed => "name_lookup_failed",
+            NetResultCode::NetLoggedInElsewhere => "logged_in_elsewhere",
+            NetResultCode::NetVaultNodeNotFound => "vault_node_not_found",
+            NetResultCode::NetMaxPlayersOnAcct => "max_players_on_account",
+            NetResultCode::NetAuthenticationFailed => "authentication_failed",
+            NetResultCode::NetStateObjectNotFound => "state_object_not_found",
+            NetResultCode::NetLoginDenied => "login_denied",
+            NetResultCode::NetCircularReference => "circular_reference",
+            NetResultCode::NetAccountNotActivated => "account_not_activated",
+            NetResultCode::NetKeyAlreadyUsed => "key_already_used",
+            NetResultCode::NetKeyNotFound => "key_not_found",
+            NetResultCode::NetActivationCodeNotFound => "activation_code_not_found",
+            NetResultCode::NetPlayerNameInvalid => "player_name_invalid",
+            NetResultCode::NetNotSupported => "not_supported",
+            NetResultCode::NetServiceForbidden => "service_forbidden",
+            NetResultCode::NetAuthTokenTooOld => "auth_token_too_old",
+            NetResultCode::NetMustUseGameTapClient => "must_use_gametap_client",
+            NetResultCode::NetTooManyFailedLogins => "too_many_failed_logins",
+            NetResultCode::NetGameTapConnectionFailed => "gametap_connection_failed",
+            NetResultCode::NetGTTooManyAuthOptions => "gametap_too_many_auth_options",
+            NetResultCode::NetGTMissingParameter => "gametap_missing_parameter",
+            NetResultCode::NetGTServerError => "gametap_server_error",
+            NetResultCode::NetAccountBanned => "account_banned",
+            NetResultCode::NetKickedByCCR => "kicked_by_ccr",
+            NetResultCode::NetScoreWrongType => "score_wrong_type",
+            NetResultCode::NetScoreNotEnoughPoints => "score_not_enough_points",
+            NetResultCode::NetScoreAlreadyExists => "score_already_exists",
+            NetResultCode::NetScoreNoDataFound => "score_no_data_found",
+            NetResultCode::NetInviteNoMatchingPlayer => "invite_no_matching_player",
+            NetResultCode::NetInviteTooManyHoods => "invite_too_many_hoods",
+            NetResultCode::NetNeedToPay => "need_to_pay",
+            NetResultCode::NetServerBusy => "server_busy",
+        }
+    }
+}
+
+// Reasons for disconnecting a client with a KickedOff message.  The client
+// shows a message based on the corresponding NetResultCode.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum KickReason {
+    LoggedInElsewhere,
+    AccountBanned,
+    ServerShutdown,
+    KickedByCCR,
+}
+
+impl KickReason {
+    pub fn result_code(self) -> NetResultCode {
+        match self {
+            KickReason::LoggedInElsewhere => NetResultCode::NetLoggedInElsewhere,
+            KickReason::AccountBanned => NetResultCode::NetAccountBanned,
+            KickReason::ServerShutdown => NetResultCode::NetRemoteShutdown,
+            KickReason::KickedByCCR => NetResultCode::NetKickedByCCR,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+// Number of recent ping samples kept for each connection
+const PING_SAMPLES: usize = 16;
+
+// Pings are sent by the client, and the server only echoes them back, so the
+// round trip time can't be measured from the pings themselves.  Instead,
+// each ping samples the connection's round trip time as estimated by the
+// TCP stack (see sockets::tcp_rtt).
+#[derive(Default)]
+struct ServiceStats {
+    sample_count: AtomicU64,
+    sample_total: AtomicU64,
+    max_sample: AtomicU32,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PingService {
+    Auth,
+    GateKeeper,
+}
+
+// Aggregated ping statistics for each service, shared by all connections.
+#[derive(Clone, Default)]
+pub struct PingStats {
+    auth: Arc<ServiceStats>,
+    gate_keeper: Arc<ServiceStats>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct PingSummary {
+    // Number of samples currently held by open connections
+    pub samples: u64,
+    // Average of the samples currently held by open connections
+    pub avg_ms: u32,
+    // Largest sample seen since the server started
+    pub max_ms: u32,
+}
+
+// Tracks the recent ping samples for one connection.  Samples are removed
+// from the service's aggregate when the connection is dropped.
+pub struct PingTracker {
+    stats: Arc<ServiceStats>,
+    samples: [u32; PING_SAMPLES],
+    next: usize,
+    len: usize,
+}
+
+impl PingStats {
+    pub fn new() -> Self { Self::default() }
+
+    fn service(&self, service: PingService) -> &Arc<ServiceStats> {
+        match service {
+            PingService::Auth => &self.auth,
+            PingService::GateKeeper => &self.gate_keeper,
+        }
+    }
+
+    pub fn tracker(&self, service: PingService) -> PingTracker {
+        PingTracker {
+            stats: self.service(service).clone(),
+            samples: [0; PING_SAMPLES],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn summary(&self, service: PingService) -> PingSummary {
+        let stats = self.service(service);
+        let samples = stats.sample_count.load(Ordering::Relaxed);
+        let total = stats.sample_total.load(Ordering::Relaxed);
+        let avg_ms = total.checked_div(samples)
+                .map_or(0, |avg| u32::try_from(avg).unwrap_or(u32::MAX));
+        PingSummary { samples, avg_ms, max_ms: stats.max_sample.load(Ordering::Relaxed) }
+    }
+}
+
+impl PingTracker {
+    // Record the connection's round trip time when a ping is received
+    pub fn record(&mut self, rtt: Duration) {
+        self.push(u32::try_from(rtt.as_millis()).unwrap_or(u32::MAX));
+    }
+
+    fn push(&mut self, sample: u32) {
+        if self.len == PING_SAMPLES {
+            let old = self.samples[self.next];
+            self.stats.sample_total.fetch_sub(u64::from(old), Ordering::Relaxed);
+        } else {
+            self.len += 1;
+            self.stats.sample_count.fetch_add(1, Ordering::Relaxed);
+        }
+        self.samples[self.next] = sample;
+        self.next = (self.next + 1) % PING_SAMPLES;
+        self.stats.sample_total.fetch_add(u64::from(sample), Ordering::Relaxed);
+        self.stats.max_sample.fetch_max(sample, Ordering::Relaxed);
+    }
+}
+
+impl Drop for PingTracker {
+    fn drop(&mut self) {
+        let total: u64 = self.samples[..self.len].iter().copied().map(u64::from).sum();
+        self.stats.sample_total.fetch_sub(total, Ordering::Relaxed);
+        self.stats.sample_count.fetch_sub(self.len as u64, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn test_ping_tracker() {
+    let num_samples = u32::try_from(PING_SAMPLES).unwrap();
+    let stats = PingStats::new();
+    let mut tracker = stats.tracker(PingService::Auth);
+    assert_eq!(stats.summary(PingService::Auth),
+               PingSummary { samples: 0, avg_ms: 0, max_ms: 0 });
+
+    tracker.record(Duration::from_millis(40));
+    tracker.record(Duration::from_micros(60_900));
+    assert_eq!(stats.summary(PingService::Auth),
+               PingSummary { samples: 2, avg_ms: 50, max_ms: 60 });
+    assert_eq!(stats.summary(PingService::GateKeeper).samples, 0);
+
+    // Only the most recent samples are kept for each connection
+    for _ in 0..(num_samples * 2) {
+        tracker.record(Duration::from_millis(20));
+    }
+    assert_eq!(stats.summary(PingService::Auth),
+               PingSummary { samples: u64::from(num_samples), avg_ms: 20, max_ms: 60 });
+
+    let mut other = stats.tracker(PingService::Auth);
+    other.record(Duration::from_millis(u64::from(20 + num_samples + 1)));
+    assert_eq!(stats.summary(PingService::Auth).samples, u64::from(num_samples) + 1);
+    assert_eq!(stats.summary(PingService::Auth).avg_ms, 21);
+
+    // Closed connections no longer count towards the average
+    drop(tracker);
+    assert_eq!(stats.summary(PingService::Auth),
+               PingSummary { samples: 1, avg_ms: 20 + num_samples + 1, max_ms: 60 });
+    drop(other);
+    assert_eq!(stats.summary(PingService::Auth).samples, 0);
+}
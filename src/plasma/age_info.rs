@@ -114,5 +114,7 @@ impl AgeInfo {
         Ok(info)
     }
 
+    pub fn start_date_time(&self) -> &UnifiedTime { &self.start_date_time }
+    pub fn day_length(&self) -> f32 { self.day_length }
     pub fn pages(&self) -> &Vec<PageInfo> { &self.pages }
 }
@@ -83,7 +83,11 @@ impl Uoid {
         }
     }
 
+    pub fn location(&self) -> &Location { &self.location }
     pub fn obj_type(&self) -> u16 { self.obj_type }
+    pub fn obj_name(&self) -> &String { &self.obj_name }
+    pub fn clone_id(&self) -> u32 { self.clone_id }
+    pub fn clone_player_id(&self) -> u32 { self.clone_player_id }
 }
 
 impl StreamRead for Uoid {
@@ -154,6 +158,9 @@ impl Location {
         Self { sequence: 0xFFFFFFFF, flags: 0}
     }
 
+    pub fn sequence(self) -> u32 { self.sequence }
+    pub fn flags(self) -> u16 { self.flags }
+
     pub fn make(prefix: i32, page: i32, flags: u16) -> Self {
         if prefix < 0 {
             #[allow(clippy::cast_sign_loss)]
@@ -14,18 +14,24 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::io::Write;
+use std::io::{BufRead, Read, Write};
 use std::mem::size_of;
 
 use anyhow::{anyhow, Context, Result};
-use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use uuid::Uuid;
 
-pub async fn read_utf16_str<S>(stream: &mut S) -> Result<String>
+// The length is checked against max_length (in UTF-16 code units) before
+// any of the string data is allocated or read.
+pub async fn read_utf16_str<S>(stream: &mut S, max_length: u16) -> Result<String>
     where S: AsyncRead + Unpin
 {
     let length = stream.read_u16_le().await?;
+    if length > max_length {
+        return Err(anyhow!("String too long ({} characters, limit {})",
+                           length, max_length));
+    }
     let mut read_buf = vec![0; (length as usize) * size_of::<u16>()];
     stream.read_exact(&mut read_buf).await?;
 
@@ -75,3 +81,167 @@ pub fn write_sized_buffer(stream: &mut dyn Write, buffer: &Vec<u8>) -> Result<()
     stream.write_u32::<LittleEndian>(buffer_size)?;
     Ok(stream.write_all(buffer.as_slice())?)
 }
+
+// Synchronous versions of the above, for messages which are buffered
+// before they are parsed.
+pub fn read_utf16_str_sync<S>(stream: &mut S) -> Result<String>
+    where S: BufRead
+{
+    let length = stream.read_u16::<LittleEndian>()?;
+    let mut utf16_buf = vec![0; length as usize];
+    stream.read_u16_into::<LittleEndian>(&mut utf16_buf)?;
+    Ok(String::from_utf16_lossy(utf16_buf.as_slice()))
+}
+
+pub fn read_sized_buffer_sync<S>(stream: &mut S) -> Result<Vec<u8>>
+    where S: BufRead
+{
+    let data_size = stream.read_u32::<LittleEndian>()?;
+    let mut buffer = Vec::new();
+    stream.take(u64::from(data_size)).read_to_end(&mut buffer)?;
+    if buffer.len() != data_size as usize {
+        return Err(anyhow!("Unexpected end of stream reading {} byte buffer", data_size));
+    }
+    Ok(buffer)
+}
+
+// Fixed-length UTF-16 buffers are nul-padded.  When writing, the last
+// character is always left as a nul terminator.
+pub fn read_fixed_utf16<S>(stream: &mut S, length: usize) -> Result<String>
+    where S: BufRead
+{
+    let mut buf = vec![0u16; length];
+    stream.read_u16_into::<LittleEndian>(&mut buf)?;
+    Ok(String::from_utf16_lossy(buf.split(|ch| ch == &0).next().unwrap()))
+}
+
+pub fn write_fixed_utf16(stream: &mut dyn Write, value: &str, length: usize) -> Result<()> {
+    let mut buf = vec![0u16; length];
+    for (dest, ch) in buf.iter_mut().take(length.saturating_sub(1)).zip(value.encode_utf16()) {
+        *dest = ch;
+    }
+    for ch in buf {
+        stream.write_u16::<LittleEndian>(ch)?;
+    }
+    Ok(())
+}
+
+// Generates the StreamRead implementation for a message enum from a table
+// of variants and their fields.  Each variant must have a matching entry
+// in the message ID enum, which must implement FromPrimitive.
+//
+// Supported field kinds are:
+//   u8, u32, i32       - Little-endian integers
+//   uuid               - A 16-byte UUID
+//   utf16              - A u16 length-prefixed UTF-16 string
+//   utf16[N]           - A fixed-length, nul-padded UTF-16 string
+//   buffer             - A u32 size-prefixed byte buffer
+//   stream(Type)       - Any type implementing StreamRead / StreamWrite
+macro_rules! net_msg_read {
+    ($msg_type:ident($id_type:ident) {
+        $($variant:ident {
+            $($field:ident: $kind:ident $([$len:literal])? $(($ty:ty))?),* $(,)?
+        }),* $(,)?
+    }) => {
+        impl $crate::plasma::StreamRead for $msg_type {
+            fn stream_read<S>(stream: &mut S) -> ::anyhow::Result<Self>
+                where S: ::std::io::BufRead
+            {
+                use ::byteorder::ReadBytesExt;
+
+                let msg_id = stream.read_u32::<::byteorder::LittleEndian>()?;
+                match <$id_type as ::num_traits::FromPrimitive>::from_u32(msg_id) {
+                    $(Some($id_type::$variant) => {
+                        $(let $field = $crate::plasma::net_io::read_msg_field!(
+                                stream, $kind $([$len])? $(($ty))?);)*
+                        Ok($msg_type::$variant { $($field),* })
+                    })*
+                    None => Err(::anyhow::anyhow!("Bad message ID {}", msg_id))
+                }
+            }
+        }
+    };
+}
+pub(crate) use net_msg_read;
+
+// Generates the StreamWrite implementation for a message enum.  See
+// `net_msg_read` for the supported field kinds.
+macro_rules! net_msg_write {
+    ($msg_type:ident($id_type:ident) {
+        $($variant:ident {
+            $($field:ident: $kind:ident $([$len:literal])? $(($ty:ty))?),* $(,)?
+        }),* $(,)?
+    }) => {
+        impl $crate::plasma::StreamWrite for $msg_type {
+            fn stream_write(&self, stream: &mut dyn ::std::io::Write) -> ::anyhow::Result<()> {
+                use ::byteorder::WriteBytesExt;
+
+                match self {
+                    $($msg_type::$variant { $($field),* } => {
+                        stream.write_u32::<::byteorder::LittleEndian>($id_type::$variant as u32)?;
+                        $($crate::plasma::net_io::write_msg_field!(
+                                stream, $kind $([$len])? $(($ty))?, $field);)*
+                    })*
+                }
+                Ok(())
+            }
+        }
+    };
+}
+pub(crate) use net_msg_write;
+
+macro_rules! read_msg_field {
+    ($stream:ident, u8) => { ::byteorder::ReadBytesExt::read_u8($stream)? };
+    ($stream:ident, u32) => {
+        ::byteorder::ReadBytesExt::read_u32::<::byteorder::LittleEndian>($stream)?
+    };
+    ($stream:ident, i32) => {
+        ::byteorder::ReadBytesExt::read_i32::<::byteorder::LittleEndian>($stream)?
+    };
+    ($stream:ident, uuid) => {
+        <::uuid::Uuid as $crate::plasma::StreamRead>::stream_read($stream)?
+    };
+    ($stream:ident, utf16) => { $crate::plasma::net_io::read_utf16_str_sync($stream)? };
+    ($stream:ident, utf16[$len:literal]) => {
+        $crate::plasma::net_io::read_fixed_utf16($stream, $len)?
+    };
+    ($stream:ident, buffer) => { $crate::plasma::net_io::read_sized_buffer_sync($stream)? };
+    ($stream:ident, stream($ty:ty)) => { <$ty as $crate::plasma::StreamRead>::stream_read($stream)? };
+}
+pub(crate) use read_msg_field;
+
+macro_rules! write_msg_field {
+    ($stream:ident, u8, $value:ident) => { ::byteorder::WriteBytesExt::write_u8($stream, *$value)? };
+    ($stream:ident, u32, $value:ident) => {
+        ::byteorder::WriteBytesExt::write_u32::<::byteorder::LittleEndian>($stream, *$value)?
+    };
+    ($stream:ident, i32, $value:ident) => {
+        ::byteorder::WriteBytesExt::write_i32::<::byteorder::LittleEndian>($stream, *$value)?
+    };
+    ($stream:ident, uuid, $value:ident) => { $crate::plasma::StreamWrite::stream_write($value, $stream)? };
+    ($stream:ident, utf16, $value:ident) => { $crate::plasma::net_io::write_utf16_str($stream, $value)? };
+    ($stream:ident, utf16[$len:literal], $value:ident) => {
+        $crate::plasma::net_io::write_fixed_utf16($stream, $value, $len)?
+    };
+    ($stream:ident, buffer, $value:ident) => { $crate::plasma::net_io::write_sized_buffer($stream, $value)? };
+    ($stream:ident, stream $(($ty:ty))?, $value:ident) => {
+        $crate::plasma::StreamWrite::stream_write($value, $stream)?
+    };
+}
+pub(crate) use write_msg_field;
+
+#[tokio::test]
+async fn test_utf16_str_limit() {
+    let mut buffer = Vec::new();
+    write_utf16_str(&mut buffer, "Relto").unwrap();
+
+    let result = read_utf16_str(&mut buffer.as_slice(), 5).await.unwrap();
+    assert_eq!(result, "Relto");
+
+    let err = read_utf16_str(&mut buffer.as_slice(), 4).await.unwrap_err();
+    assert_eq!(err.to_string(), "String too long (5 characters, limit 4)");
+
+    // The claimed length is rejected before the (missing) data is read
+    let err = read_utf16_str(&mut [0xff_u8, 0xff].as_slice(), 64).await.unwrap_err();
+    assert_eq!(err.to_string(), "String too long (65535 characters, limit 64)");
+}
@@ -0,0 +1,211 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::io::{BufRead, Cursor, Read, Write};
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::ZlibDecoder;
+use log::warn;
+use num_traits::FromPrimitive;
+
+use crate::plasma::{Creatable, Factory, StreamRead, StreamWrite, UnifiedTime};
+use crate::plasma::creatable::ClassID;
+use super::NetMessage;
+
+// A sized (and optionally compressed) buffer containing a single creatable.
+// The raw buffer is kept so the message can be relayed to other clients
+// as-is, even if we can't parse the creatable it contains.
+pub struct NetMsgStream {
+    uncompressed_size: u32,
+    compression_type: u8,
+    buffer: Vec<u8>,
+}
+
+impl NetMsgStream {
+    // Compression types
+    pub const COMPRESSION_NONE: u8 = 0;
+    pub const COMPRESSION_FAILED: u8 = 1;
+    pub const COMPRESSION_ZLIB: u8 = 2;
+    pub const COMPRESSION_DONT: u8 = 3;
+
+    pub fn new(buffer: Vec<u8>) -> Result<Self> {
+        let uncompressed_size = u32::try_from(buffer.len())
+                .context("Buffer too large for stream")?;
+        Ok(Self {
+            uncompressed_size,
+            compression_type: Self::COMPRESSION_NONE,
+            buffer,
+        })
+    }
+
+    pub fn uncompressed_buffer(&self) -> Result<Vec<u8>> {
+        if self.compression_type != Self::COMPRESSION_ZLIB {
+            return Ok(self.buffer.clone());
+        }
+
+        // The first two bytes (the creatable's class ID) are never compressed
+        if self.buffer.len() < 2 {
+            return Err(anyhow!("Compressed stream is too short"));
+        }
+        // The uncompressed size comes from the client, so it only limits how
+        // much we decompress.  One extra byte is allowed in order to detect
+        // streams which are larger than they claim to be.
+        let mut buffer = self.buffer[..2].to_vec();
+        ZlibDecoder::new(&self.buffer[2..])
+                .take(u64::from(self.uncompressed_size.saturating_sub(1)))
+                .read_to_end(&mut buffer)?;
+        if buffer.len() != self.uncompressed_size as usize {
+            return Err(anyhow!("Stream decompressed size mismatch"));
+        }
+        Ok(buffer)
+    }
+
+    // Reads the contained creatable.  Unknown creatable types are logged
+    // and skipped (returning None), since the stream's size is known.
+    pub fn read_creatable(&self) -> Result<Option<Box<dyn Creatable>>> {
+        let buffer = self.uncompressed_buffer()?;
+        let mut stream = Cursor::new(&buffer[..]);
+        let class_id = stream.read_u16::<LittleEndian>()?;
+        if ClassID::from_u16(class_id).is_none() {
+            warn!("Skipping unknown creatable type 0x{:04x} ({} bytes)",
+                  class_id, buffer.len() - 2);
+            return Ok(None);
+        }
+        Factory::read_creatable_as(&mut stream, class_id)
+    }
+}
+
+impl StreamRead for NetMsgStream {
+    fn stream_read<S>(stream: &mut S) -> Result<Self>
+        where S: BufRead
+    {
+        let uncompressed_size = stream.read_u32::<LittleEndian>()?;
+        let compression_type = stream.read_u8()?;
+        let buffer_size = stream.read_u32::<LittleEndian>()? as usize;
+        let mut buffer = Vec::new();
+        let read_size = stream.take(buffer_size as u64).read_to_end(&mut buffer)?;
+        if read_size != buffer_size {
+            return Err(anyhow!("Stream read size mismatch"));
+        }
+
+        Ok(Self { uncompressed_size, compression_type, buffer })
+    }
+}
+
+impl StreamWrite for NetMsgStream {
+    fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
+        let buffer_size = u32::try_from(self.buffer.len())
+                .context("Buffer too large for stream")?;
+        stream.write_u32::<LittleEndian>(self.uncompressed_size)?;
+        stream.write_u8(self.compression_type)?;
+        stream.write_u32::<LittleEndian>(buffer_size)?;
+        stream.write_all(&self.buffer)?;
+        Ok(())
+    }
+}
+
+pub struct NetMsgGameMessage {
+    base: NetMessage,
+    stream: NetMsgStream,
+    delivery_time: Option<UnifiedTime>,
+}
+
+impl NetMsgGameMessage {
+    pub fn stream(&self) -> &NetMsgStream { &self.stream }
+    pub fn delivery_time(&self) -> Option<&UnifiedTime> { self.delivery_time.as_ref() }
+}
+
+impl StreamRead for NetMsgGameMessage {
+    fn stream_read<S>(stream: &mut S) -> Result<Self>
+        where S: BufRead
+    {
+        let base = NetMessage::stream_read(stream)?;
+        let msg_stream = NetMsgStream::stream_read(stream)?;
+        let delivery_time = if stream.read_u8()? != 0 {
+            Some(UnifiedTime::stream_read(stream)?)
+        } else {
+            None
+        };
+
+        Ok(Self { base, stream: msg_stream, delivery_time })
+    }
+}
+
+impl StreamWrite for NetMsgGameMessage {
+    fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
+        self.base.stream_write(stream)?;
+        self.stream.stream_write(stream)?;
+        if let Some(delivery_time) = &self.delivery_time {
+            stream.write_u8(1)?;
+            delivery_time.stream_write(stream)?;
+        } else {
+            stream.write_u8(0)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_unknown_creatable() {
+    let mut creatable = Vec::new();
+    creatable.write_u16::<LittleEndian>(0x7ABC).unwrap();
+    creatable.extend_from_slice(b"Some unknown creatable data");
+
+    let mut buffer = Vec::new();
+    buffer.write_u32::<LittleEndian>(NetMessage::HAS_PLAYER_ID).unwrap();
+    buffer.write_u32::<LittleEndian>(1001).unwrap();
+    NetMsgStream::new(creatable).unwrap().stream_write(&mut buffer).unwrap();
+    buffer.write_u8(0).unwrap();
+
+    // The outer message should still parse, skipping the unknown creatable
+    let mut stream = Cursor::new(&buffer[..]);
+    let message = NetMsgGameMessage::stream_read(&mut stream).unwrap();
+    assert_eq!(stream.position(), buffer.len() as u64);
+    assert!(message.delivery_time().is_none());
+    assert!(message.stream().read_creatable().unwrap().is_none());
+
+    // ...and be relayed unmodified
+    let mut relayed = Vec::new();
+    message.stream_write(&mut relayed).unwrap();
+    assert_eq!(relayed, buffer);
+}
+
+#[test]
+fn test_compressed_size_mismatch() {
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+
+    let compressed_stream = |uncompressed_size: u32| {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0; 4096]).unwrap();
+        let mut buffer = vec![0x7A, 0xBC];
+        buffer.extend_from_slice(&encoder.finish().unwrap());
+        NetMsgStream {
+            uncompressed_size,
+            compression_type: NetMsgStream::COMPRESSION_ZLIB,
+            buffer,
+        }
+    };
+
+    let expected = compressed_stream(4098).uncompressed_buffer().unwrap();
+    assert_eq!(expected.len(), 4098);
+    assert_eq!(&expected[..2], &[0x7A, 0xBC]);
+
+    assert!(compressed_stream(64).uncompressed_buffer().is_err());
+    assert!(compressed_stream(0).uncompressed_buffer().is_err());
+    assert!(compressed_stream(8192).uncompressed_buffer().is_err());
+}
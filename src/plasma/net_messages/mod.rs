@@ -14,5 +14,8 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod game_message;
+pub use game_message::{NetMsgGameMessage, NetMsgStream};
+
 mod net_message;
 pub use net_message::NetMessage;
@@ -47,6 +47,13 @@ impl UnifiedTime {
             micros: now.subsec_micros()
         })
     }
+
+    pub fn secs(&self) -> u32 { self.secs }
+    pub fn micros(&self) -> u32 { self.micros }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        f64::from(self.secs) + f64::from(self.micros) / 1_000_000.0
+    }
 }
 
 impl StreamRead for UnifiedTime {
@@ -0,0 +1,82 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use crate::plasma::{AgeInfo, UnifiedTime};
+
+// Plasma's default DayLength (in hours), for Ages which don't set one
+const DEFAULT_DAY_LENGTH: f64 = 24.0;
+const SECS_PER_HOUR: f64 = 3600.0;
+
+// AgeTimeOfDay variables have no stored value.  Instead, the client expects
+// the fraction of the current Age day which has elapsed, where 0.0 is the
+// start of the day (midnight) and 0.5 is noon.  Age days are counted from
+// the .age file's StartDateTime, and last DayLength hours.
+pub fn age_time_of_day(age_info: &AgeInfo, now: &UnifiedTime) -> f32 {
+    let day_length = match f64::from(age_info.day_length()) {
+        hours if hours.is_finite() && hours > 0.0 => hours * SECS_PER_HOUR,
+        _ => DEFAULT_DAY_LENGTH * SECS_PER_HOUR,
+    };
+    let elapsed = now.as_secs_f64() - age_info.start_date_time().as_secs_f64();
+    let fraction = elapsed.rem_euclid(day_length) / day_length;
+
+    // Rounding can land exactly on the end of the day
+    #[allow(clippy::cast_possible_truncation)]
+    let fraction = fraction as f32;
+    if fraction >= 1.0 { 0.0 } else { fraction }
+}
+
+#[cfg(test)]
+fn test_age_info(contents: &str) -> AgeInfo {
+    use std::io::Write;
+
+    let mut age_file = tempfile::NamedTempFile::new().unwrap();
+    age_file.write_all(contents.as_bytes()).unwrap();
+    AgeInfo::from_file(age_file.path()).unwrap()
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn test_age_time_of_day() {
+    const START: u32 = 1_000_000_000;
+    const DAY: u32 = 10 * 3600;
+
+    let age_info = test_age_info(&format!("StartDateTime={START}\nDayLength=10.0\n"));
+    let time_at = |secs: u32| age_time_of_day(&age_info, &UnifiedTime::from_secs(secs));
+
+    assert_eq!(time_at(START), 0.0);
+    assert_eq!(time_at(START + DAY / 4), 0.25);
+    assert_eq!(time_at(START + DAY / 2), 0.5);
+    assert_eq!(time_at(START + DAY * 3 / 4), 0.75);
+    assert_eq!(time_at(START + DAY), 0.0);
+    assert_eq!(time_at(START + DAY * 7 + DAY / 2), 0.5);
+
+    // Times before the start of the Age wrap around to the previous day
+    assert_eq!(time_at(START - DAY / 4), 0.75);
+
+    // Microseconds count towards the time of day
+    let now = UnifiedTime::new(START + DAY / 2, 500_000);
+    let time = age_time_of_day(&age_info, &now);
+    assert!(time > 0.5 && time < 0.500_02, "{time}");
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn test_default_day_length() {
+    // Ages without a DayLength use Plasma's default 24-hour day
+    let age_info = test_age_info("StartDateTime=0\n");
+    assert_eq!(age_time_of_day(&age_info, &UnifiedTime::from_secs(6 * 3600)), 0.25);
+    assert_eq!(age_time_of_day(&age_info, &UnifiedTime::from_secs(36 * 3600)), 0.5);
+}
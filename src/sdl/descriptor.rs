@@ -14,6 +14,7 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 
 use crate::plasma::UnifiedTime;
@@ -27,6 +28,33 @@ pub enum VarType {
     StateDesc(String),
 }
 
+impl Display for VarType {
+    // Formats the type the same way it is written in a .sdl file
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            VarType::AgeTimeOfDay => write!(f, "AGETIMEOFDAY"),
+            VarType::Bool => write!(f, "BOOL"),
+            VarType::Byte => write!(f, "BYTE"),
+            VarType::Creatable => write!(f, "CREATABLE"),
+            VarType::Double => write!(f, "DOUBLE"),
+            VarType::Float => write!(f, "FLOAT"),
+            VarType::Int => write!(f, "INT"),
+            VarType::Key => write!(f, "PLKEY"),
+            VarType::Point3 => write!(f, "POINT3"),
+            VarType::Quat => write!(f, "QUATERNION"),
+            VarType::Rgb => write!(f, "RGB"),
+            VarType::Rgb8 => write!(f, "RGB8"),
+            VarType::Rgba => write!(f, "RGBA"),
+            VarType::Rgba8 => write!(f, "RGBA8"),
+            VarType::Short => write!(f, "SHORT"),
+            VarType::String32 => write!(f, "STRING32"),
+            VarType::Time => write!(f, "TIME"),
+            VarType::Vector3 => write!(f, "VECTOR3"),
+            VarType::StateDesc(name) => write!(f, "${name}"),
+        }
+    }
+}
+
 #[derive(PartialEq, Debug)]
 pub enum VarDefault {
     Bool(bool),
@@ -43,7 +71,7 @@ pub enum VarDefault {
     Rgba8(Color32),
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Debug)]
 pub struct VarDescriptor {
     name: String,
     var_type: VarType,
@@ -51,7 +79,7 @@ pub struct VarDescriptor {
     default: Option<VarDefault>,
 }
 
-#[derive(Debug)]
+#[derive(PartialEq, Debug)]
 pub struct StateDescriptor {
     name: String,
     version: u16,
@@ -88,3 +116,62 @@ impl StateDescriptor {
         self.vars.iter().find(|var| var.name == name).cloned()
     }
 }
+
+// Writes the variable as a VAR line in .sdl syntax, which can be read back
+// with the sdl::Parser.
+impl Display for VarDescriptor {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "VAR {:<15} {}[", self.var_type.to_string(), self.name)?;
+        if let Some(count) = self.count {
+            write!(f, "{count}")?;
+        }
+        write!(f, "]")?;
+
+        let Some(default) = &self.default else {
+            return Ok(());
+        };
+        write!(f, "    DEFAULT=")?;
+        match (&self.var_type, default) {
+            (_, VarDefault::Bool(value)) => write!(f, "{}", u8::from(*value)),
+            (_, VarDefault::Byte(value)) => write!(f, "{value}"),
+            (_, VarDefault::Short(value)) => write!(f, "{value}"),
+            (_, VarDefault::Int(value)) => write!(f, "{value}"),
+            (_, VarDefault::Float(value)) => write!(f, "{value}"),
+            (_, VarDefault::Double(value)) => write!(f, "{value}"),
+            (_, VarDefault::String32(value)) => write!(f, "\"{value}\""),
+            (_, VarDefault::Time(value)) => write!(f, "{}", value.secs()),
+            (_, VarDefault::Quat(value)) => {
+                write!(f, "({},{},{},{})", value.x, value.y, value.z, value.w)
+            }
+            (_, VarDefault::Vector3(value)) => write!(f, "({},{},{})", value.x, value.y, value.z),
+            (VarType::Rgb, VarDefault::Rgba(value)) => {
+                write!(f, "({},{},{})", value.r, value.g, value.b)
+            }
+            (_, VarDefault::Rgba(value)) => {
+                write!(f, "({},{},{},{})", value.r, value.g, value.b, value.a)
+            }
+            (VarType::Rgb8, VarDefault::Rgba8(value)) => {
+                write!(f, "({},{},{})", value.r, value.g, value.b)
+            }
+            (_, VarDefault::Rgba8(value)) => {
+                write!(f, "({},{},{},{})", value.r, value.g, value.b, value.a)
+            }
+        }
+    }
+}
+
+// Writes the full STATEDESC block in .sdl syntax
+impl Display for StateDescriptor {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        writeln!(f, "STATEDESC {}", self.name)?;
+        writeln!(f, "{{")?;
+        writeln!(f, "    VERSION {}", self.version)?;
+        if !self.vars.is_empty() {
+            writeln!(f)?;
+        }
+        for var in &self.vars {
+            writeln!(f, "    {var}")?;
+        }
+        writeln!(f, "}}")
+    }
+}
@@ -14,19 +14,19 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use log::warn;
 use unicase::UniCase;
 
 use crate::plasma::file_crypt::EncryptedReader;
-use super::{StateDescriptor, Parser};
+use super::{StateDescriptor, Parser, VarType};
 
 type DescriptorMap = HashMap<UniCase<String>, BTreeMap<u16, Arc<StateDescriptor>>>;
 
@@ -34,14 +34,22 @@ pub struct DescriptorDb {
     descriptors: DescriptorMap,
 }
 
-fn merge_descriptors(db: &mut DescriptorMap, descriptors: Vec<StateDescriptor>) {
+// Duplicate versions can't be represented in the database, and there's no
+// way to tell which of them the client expects, so they are rejected.
+fn merge_descriptors(db: &mut DescriptorMap, descriptors: Vec<StateDescriptor>)
+    -> Result<()>
+{
     for desc in descriptors {
-        db.entry(UniCase::new(desc.name().clone())).or_default()
-            .entry(desc.version())
-            .and_modify(|_| warn!("Duplicate descriptor found for {} version {}",
-                                  desc.name(), desc.version()))
-            .or_insert_with(|| Arc::new(desc));
+        let versions = db.entry(UniCase::new(desc.name().clone())).or_default();
+        match versions.entry(desc.version()) {
+            btree_map::Entry::Occupied(_) => {
+                return Err(anyhow!("Duplicate STATEDESC {} version {}",
+                                   desc.name(), desc.version()));
+            }
+            btree_map::Entry::Vacant(entry) => { entry.insert(Arc::new(desc)); }
+        }
     }
+    Ok(())
 }
 
 impl DescriptorDb {
@@ -58,20 +66,28 @@ impl DescriptorDb {
                 let file_reader = BufReader::new(File::open(entry.path())?);
                 let stream = BufReader::new(EncryptedReader::new(file_reader, key)?);
                 let mut parser = Parser::new(stream);
-                merge_descriptors(&mut descriptors, parser.parse()?);
+                parser.parse()
+                        .and_then(|file_descriptors| {
+                            merge_descriptors(&mut descriptors, file_descriptors)
+                        })
+                        .with_context(|| format!("Failed to load {}", entry.path().display()))?;
             }
         }
 
-        Ok(Self { descriptors })
+        let db = Self { descriptors };
+        db.validate()?;
+        Ok(db)
     }
 
-    #[cfg(test)]
+    #[cfg(any(test, feature = "fuzzing"))]
     pub fn from_string(input: &str) -> Result<Self> {
         let mut descriptors = DescriptorMap::new();
         let stream = std::io::Cursor::new(input);
         let mut parser = Parser::new(stream);
-        merge_descriptors(&mut descriptors, parser.parse()?);
-        Ok(Self { descriptors })
+        merge_descriptors(&mut descriptors, parser.parse()?)?;
+        let db = Self { descriptors };
+        db.validate()?;
+        Ok(db)
     }
 
     pub fn get_version(&self, name: &str, version: u16) -> Option<Arc<StateDescriptor>> {
@@ -93,37 +109,80 @@ impl DescriptorDb {
     pub fn descriptor_names(&self) -> Vec<&str> {
         self.descriptors.keys().map(|k| k.as_str()).collect()
     }
-}
-
-#[cfg(test)]
-pub(super) const TEST_DESCRIPTORS: &str = r"
-    STATEDESC Test
-    {
-        VERSION 1
 
-        VAR BOOL    bTestVar1[1]    DEFAULT=0
-        VAR BOOL    bTestVar2[1]    DEFAULT=1    DEFAULTOPTION=VAULT
-        VAR INT     iTestVar3[1]    DEFAULT=0    DEFAULTOPTION=VAULT
-        VAR INT     iTestVar4[1]    DEFAULT=100
+    // Version gaps are only logged, since the versions which are present
+    // can still be used.  Descriptors which reference each other (directly
+    // or indirectly) via $name variables would recurse forever when creating
+    // default states, so those are rejected.
+    fn validate(&self) -> Result<()> {
+        for (name, prev_version, version) in self.version_gaps() {
+            warn!("STATEDESC {} skips from version {} to {}", name, prev_version, version);
+        }
+        if let Some(path) = self.find_circular_reference() {
+            return Err(anyhow!("Circular STATEDESC reference: {}", path.join(" -> ")));
+        }
+        Ok(())
     }
 
-    STATEDESC Test
-    {
-        VERSION 2
-
-        VAR BOOL    bTestVar1[1]    DEFAULT=0
-        VAR BOOL    bTestVar2[1]    DEFAULT=1    DEFAULTOPTION=VAULT
-        VAR INT     iTestVar3[1]    DEFAULT=0    DEFAULTOPTION=VAULT
-        VAR INT     iTestVar4[1]    DEFAULT=100
+    // A gap in the version sequence usually means an SDL file is missing,
+    // and states saved with the missing versions won't be readable.
+    fn version_gaps(&self) -> Vec<(String, u16, u16)> {
+        let mut gaps = Vec::new();
+        for versions in self.descriptors.values() {
+            let mut iter = versions.values();
+            let Some(mut prev) = iter.next() else { continue };
+            for desc in iter {
+                if desc.version() != prev.version() + 1 {
+                    gaps.push((desc.name().clone(), prev.version(), desc.version()));
+                }
+                prev = desc;
+            }
+        }
+        gaps
+    }
 
-        VAR BYTE    bTestVar5[1]    DEFAULT=50
+    fn find_circular_reference(&self) -> Option<Vec<String>> {
+        let mut checked = HashSet::new();
+        for name in self.descriptors.keys() {
+            let mut path = Vec::new();
+            if self.find_circular_reference_from(name, &mut path, &mut checked) {
+                return Some(path);
+            }
+        }
+        None
     }
 
-    STATEDESC Barney
+    // Returns true if a circular reference was found, leaving the reference
+    // chain in path.
+    fn find_circular_reference_from(&self, name: &str, path: &mut Vec<String>,
+                                    checked: &mut HashSet<UniCase<String>>) -> bool
     {
-        VERSION 1
+        let key = UniCase::new(name.to_string());
+        if checked.contains(&key) {
+            return false;
+        }
+        path.push(name.to_string());
+        if path[..path.len() - 1].iter().any(|parent| UniCase::new(parent.as_str()) == key) {
+            return true;
+        }
+
+        // Unknown descriptors are reported when the state is created
+        if let Some(versions) = self.descriptors.get(&key) {
+            for desc in versions.values() {
+                for var in desc.vars() {
+                    if let VarType::StateDesc(child) = var.var_type() {
+                        if self.find_circular_reference_from(child, path, checked) {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        path.pop();
+        checked.insert(key);
+        false
     }
-";
+}
 
 #[cfg(test)]
 macro_rules! check_var_descriptor {
@@ -137,13 +196,10 @@ macro_rules! check_var_descriptor {
 }
 
 #[test]
-fn test_descriptors() -> Result<()> {
+fn test_descriptors() {
     use super::{VarType, VarDefault};
 
-    let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug)
-                .format_timestamp(None).format_target(false).try_init();
-
-    let db = DescriptorDb::from_string(TEST_DESCRIPTORS)?;
+    let db = super::test_fixtures::load_fixtures();
     let v1 = db.get_version("Test", 1).expect("Could not get StateDesc Test v1");
     let v2 = db.get_version("Test", 2).expect("Could not get StateDesc Test v2");
 
@@ -163,6 +219,71 @@ fn test_descriptors() -> Result<()> {
     check_var_descriptor!(v2, "iTestVar3", VarType::Int, VarDefault::Int(0));
     check_var_descriptor!(v2, "iTestVar4", VarType::Int, VarDefault::Int(100));
     check_var_descriptor!(v2, "bTestVar5", VarType::Byte, VarDefault::Byte(50));
+}
 
-    Ok(())
+#[test]
+fn test_circular_references() {
+    use super::State;
+
+    const CIRCULAR_DESCRIPTORS: &str = r"
+        STATEDESC Chicken
+        {
+            VERSION 1
+            VAR $Egg    egg[1]
+        }
+
+        STATEDESC Egg
+        {
+            VERSION 1
+            VAR $Chicken    chicken[1]
+        }
+    ";
+
+    // The error names the whole cycle
+    let err = DescriptorDb::from_string(CIRCULAR_DESCRIPTORS).err().unwrap().to_string();
+    assert!(err == "Circular STATEDESC reference: Chicken -> Egg -> Chicken"
+            || err == "Circular STATEDESC reference: Egg -> Chicken -> Egg", "{err}");
+
+    // Even without validation, creating a default state must not recurse
+    // forever.
+    let mut descriptors = DescriptorMap::new();
+    let mut parser = Parser::new(std::io::Cursor::new(CIRCULAR_DESCRIPTORS));
+    merge_descriptors(&mut descriptors, parser.parse().unwrap()).unwrap();
+    let db = DescriptorDb { descriptors };
+    let chicken = db.get_latest("Chicken").unwrap();
+    assert!(State::from_defaults(chicken, &db).is_err());
+}
+
+#[test]
+fn test_duplicate_versions() {
+    const DUPLICATE_DESCRIPTORS: &str = r"
+        STATEDESC Twin { VERSION 1 VAR INT a[1] }
+        STATEDESC Twin { VERSION 2 VAR INT a[1] }
+        STATEDESC Twin { VERSION 1 VAR BOOL b[1] }
+        STATEDESC Single { VERSION 1 VAR INT c[1] }
+    ";
+
+    let err = DescriptorDb::from_string(DUPLICATE_DESCRIPTORS).err().unwrap();
+    assert_eq!(err.to_string(), "Duplicate STATEDESC Twin version 1");
+
+    // Case differences in the name don't make a different descriptor
+    let mut parser = Parser::new(std::io::Cursor::new(r"
+        STATEDESC Twin { VERSION 1 }
+        STATEDESC twin { VERSION 1 }
+    "));
+    let err = merge_descriptors(&mut DescriptorMap::new(), parser.parse().unwrap()).unwrap_err();
+    assert_eq!(err.to_string(), "Duplicate STATEDESC twin version 1");
+}
+
+#[test]
+fn test_version_gaps() {
+    // Gaps are only warned about, since the versions are still usable
+    let db = DescriptorDb::from_string(r"
+        STATEDESC Gappy { VERSION 1 VAR INT a[1] }
+        STATEDESC Gappy { VERSION 3 VAR INT a[1] VAR INT b[1] }
+    ").expect("Version gaps should not be rejected");
+    assert!(db.get_version("Gappy", 1).is_some());
+    assert!(db.get_version("Gappy", 2).is_none());
+    assert_eq!(db.get_latest("Gappy").map(|desc| desc.version()), Some(3));
+    assert_eq!(db.version_gaps(), vec![("Gappy".to_string(), 1, 3)]);
 }
@@ -14,6 +14,9 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod age_time;
+pub use age_time::age_time_of_day;
+
 mod descriptor;
 pub use descriptor::{VarType, VarDefault, VarDescriptor, StateDescriptor};
 
@@ -24,11 +27,14 @@ mod parser;
 pub use parser::Parser;
 
 mod state;
-pub use state::State;
+pub use state::{State, SdlLimits};
 
 mod state_variable;
 pub use state_variable::Variable;
 
+#[cfg(test)]
+mod test_fixtures;
+
 // Read/Write flags
 const HAS_UOID: u16             = 1 << 0;
 const VAR_LENGTH_IO: u16        = 1 << 15;
@@ -614,6 +614,49 @@ fn test_tokenizer() {
     }
 }
 
+#[test]
+fn test_tokenizer_eof() {
+    use std::io::Cursor;
+
+    // The last line is still tokenized without a trailing newline
+    {
+        let mid_token = b"VAR\n  BOOL  bVar[1]";
+        let mut parser = Parser::new(Cursor::new(mid_token));
+        check_token!(parser, Token::Identifier("VAR".to_string()), @(1, 1));
+        check_token!(parser, Token::Identifier("BOOL".to_string()), @(2, 3));
+        check_token!(parser, Token::Identifier("bVar".to_string()), @(2, 9));
+        check_token!(parser, Token::Char('['), @(2, 13));
+        check_token!(parser, Token::Number("1".to_string()), @(2, 14));
+        check_token!(parser, Token::Char(']'), @(2, 15));
+        check_token!(parser, None);
+    }
+
+    {
+        let trailing_comment = b"}\r\n# Comment with no newline";
+        let mut parser = Parser::new(Cursor::new(trailing_comment));
+        check_token!(parser, Token::Char('}'), @(1, 1));
+        check_token!(parser, None);
+    }
+
+    {
+        let token_then_comment = b"\n\nDEFAULT=-5# Comment";
+        let mut parser = Parser::new(Cursor::new(token_then_comment));
+        check_token!(parser, Token::Identifier("DEFAULT".to_string()), @(3, 1));
+        check_token!(parser, Token::Char('='), @(3, 8));
+        check_token!(parser, Token::Number("-5".to_string()), @(3, 9));
+        check_token!(parser, None);
+    }
+
+    {
+        let incomplete_string = b"DEFAULT=\"No end";
+        let mut parser = Parser::new(Cursor::new(incomplete_string));
+        check_token!(parser, Token::Identifier("DEFAULT".to_string()), @(1, 1));
+        check_token!(parser, Token::Char('='), @(1, 8));
+        check_token!(parser, Token::IncompleteString, @(1, 9));
+        check_token!(parser, None);
+    }
+}
+
 #[test]
 fn test_parser() {
     use std::io::Cursor;
@@ -833,3 +876,57 @@ fn test_parser() {
         assert!(result.unwrap_err().to_string().contains("Unexpected IncompleteString"));
     }
 }
+
+#[test]
+fn test_parser_eof() {
+    use std::io::Cursor;
+
+    for sdl_text in [
+        &b"STATEDESC eof\n{\n    VERSION 2\n    VAR INT iVar[1] DEFAULT=7\n}"[..],
+        &b"STATEDESC eof\n{\n    VERSION 2\n    VAR INT iVar[1] DEFAULT=7\n}\n# End"[..],
+        &b"STATEDESC eof { VERSION 2 VAR INT iVar[1] DEFAULT=7 }   "[..],
+    ] {
+        let descs = Parser::new(Cursor::new(sdl_text)).parse().unwrap();
+        assert_eq!(descs.len(), 1);
+        assert_eq!(descs[0].name().as_str(), "eof");
+        assert_eq!(descs[0].version(), 2);
+        assert_eq!(descs[0].vars().len(), 1);
+        assert_eq!(descs[0].vars()[0].default(), Some(&VarDefault::Int(7)));
+    }
+
+    // A descriptor cut off at the end of the file is still an error
+    for sdl_text in [
+        &b"STATEDESC eof { VERSION 2 VAR INT iVar[1] DEFAULT=7"[..],
+        &b"STATEDESC eof { VERSION 2 VAR STRING32 sVar[1] DEFAULT=\"7 }"[..],
+        &b"STATEDESC eof { VERSION 2 # }"[..],
+    ] {
+        assert!(Parser::new(Cursor::new(sdl_text)).parse().is_err());
+    }
+}
+
+#[test]
+fn test_write_round_trip() {
+    use std::io::{BufReader, Cursor};
+
+    let fixtures_dir = super::test_fixtures::fixtures_dir();
+    let mut entries: Vec<_> = std::fs::read_dir(&fixtures_dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "sdl"))
+            .collect();
+    entries.sort();
+    assert!(!entries.is_empty());
+
+    for path in entries {
+        let file = std::fs::File::open(&path).unwrap();
+        let descriptors = Parser::new(BufReader::new(file)).parse()
+                .unwrap_or_else(|err| panic!("Failed to parse {}: {err}", path.display()));
+        assert!(!descriptors.is_empty());
+
+        let sdl_text = descriptors.iter().map(ToString::to_string)
+                .collect::<Vec<_>>().join("\n");
+        let reparsed = Parser::new(Cursor::new(sdl_text.as_bytes())).parse()
+                .unwrap_or_else(|err| panic!("Failed to re-parse {}: {err}\n{sdl_text}",
+                                             path.display()));
+        assert_eq!(descriptors, reparsed, "Round trip mismatch for {}", path.display());
+    }
+}
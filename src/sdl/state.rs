@@ -17,13 +17,15 @@
 use std::io::{Cursor, BufRead, Write};
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::warn;
+use serde_json::{json, Value};
 
 use crate::plasma::{Uoid, StreamRead, StreamWrite};
 use crate::plasma::safe_string::{read_safe_str, write_safe_str, StringFormat};
 use super::{DescriptorDb, StateDescriptor, VarType, Variable};
+use super::state_variable::uoid_to_json;
 use super::{HAS_UOID, VAR_LENGTH_IO};
 
 #[derive(Clone, Debug)]
@@ -35,21 +37,50 @@ pub struct State {
     flags: u16,
 }
 
+// Limits how deeply STATEDESC variables may be nested
+const MAX_STATE_DEPTH: usize = 32;
+const DEFAULT_MAX_BLOB_SIZE: usize = 1024 * 1024;
+
+// Limits for reading SDL blobs which may have been crafted by a client (e.g.
+// from the vault), in addition to the limit on elements per variable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SdlLimits {
+    pub max_depth: usize,
+    pub max_blob_size: usize,
+}
+
+impl Default for SdlLimits {
+    fn default() -> Self {
+        Self { max_depth: MAX_STATE_DEPTH, max_blob_size: DEFAULT_MAX_BLOB_SIZE }
+    }
+}
+
 impl State {
     const IO_VERSION: u8 = 6;
 
-    pub fn from_defaults(descriptor: Arc<StateDescriptor>, db: &DescriptorDb) -> Self {
+    pub fn from_defaults(descriptor: Arc<StateDescriptor>, db: &DescriptorDb) -> Result<Self> {
+        Self::from_defaults_nested(descriptor, db, 0)
+    }
+
+    pub(super) fn from_defaults_nested(descriptor: Arc<StateDescriptor>, db: &DescriptorDb,
+                                       depth: usize) -> Result<Self>
+    {
+        if depth > MAX_STATE_DEPTH {
+            return Err(anyhow!("Nested STATEDESC {} exceeds maximum depth of {}",
+                               descriptor.name(), MAX_STATE_DEPTH));
+        }
+
         let mut simple_vars = Vec::with_capacity(descriptor.vars().len());
         let mut statedesc_vars = Vec::with_capacity(descriptor.vars().len());
         for var_desc in descriptor.vars() {
-            let var = Variable::from_defaults(var_desc.clone(), db);
+            let var = Variable::from_defaults_nested(var_desc.clone(), db, depth)?;
             if let VarType::StateDesc(_) = var_desc.var_type() {
                 statedesc_vars.push(var);
             } else {
                 simple_vars.push(var);
             }
         }
-        Self { descriptor, simple_vars, statedesc_vars, object: None, flags: 0 }
+        Ok(Self { descriptor, simple_vars, statedesc_vars, object: None, flags: 0 })
     }
 
     pub fn descriptor(&self) -> &StateDescriptor { &self.descriptor }
@@ -64,9 +95,37 @@ impl State {
             || self.statedesc_vars.iter().any(Variable::is_dirty)
     }
 
+    // Only dirty variables are written, so clearing the dirty flags after a
+    // state is sent lets the next write contain just the changes since then.
+    pub fn clear_dirty(&mut self) { self.set_dirty(false); }
+
+    // Marks every variable (including nested states) dirty, so the next
+    // write contains the full state, e.g. for a newly joined client.
+    pub fn mark_dirty(&mut self) { self.set_dirty(true); }
+
+    pub(super) fn set_dirty(&mut self, dirty: bool) {
+        for var in self.simple_vars.iter_mut().chain(self.statedesc_vars.iter_mut()) {
+            var.set_dirty(dirty);
+        }
+    }
+
     pub fn read<S>(&mut self, stream: &mut S, db: &DescriptorDb) -> Result<()>
         where S: BufRead
     {
+        self.read_nested(stream, db, &SdlLimits::default(), 0)
+            .with_context(|| format!("Failed to read SDL state '{}' version {}",
+                                     self.descriptor.name(), self.descriptor.version()))
+    }
+
+    pub(super) fn read_nested<S>(&mut self, stream: &mut S, db: &DescriptorDb,
+                                 limits: &SdlLimits, depth: usize) -> Result<()>
+        where S: BufRead
+    {
+        if depth > limits.max_depth {
+            return Err(anyhow!("Nested STATEDESC {} exceeds maximum depth of {}",
+                               self.descriptor.name(), limits.max_depth));
+        }
+
         self.flags = stream.read_u16::<LittleEndian>()?;
         let io_version = stream.read_u8()?;
         if io_version != Self::IO_VERSION {
@@ -75,7 +134,7 @@ impl State {
 
         let max_hint = self.descriptor.vars().len();
 
-        let count = read_compressed_size(stream, max_hint)?;
+        let count = read_dirty_count(stream, max_hint, self.simple_vars.len())?;
         let read_indices = count != self.simple_vars.len();
         for idx in 0..count {
             let idx = if read_indices {
@@ -86,10 +145,10 @@ impl State {
             if idx >= self.simple_vars.len() {
                 return Err(anyhow!("Invalid variable index {}", idx));
             }
-            self.simple_vars[idx].read(stream, db)?;
+            self.simple_vars[idx].read_nested(stream, db, limits, depth)?;
         }
 
-        let count = read_compressed_size(stream, max_hint)?;
+        let count = read_dirty_count(stream, max_hint, self.statedesc_vars.len())?;
         let read_indices = count != self.statedesc_vars.len();
         for idx in 0..count {
             let idx = if read_indices {
@@ -100,7 +159,7 @@ impl State {
             if idx >= self.statedesc_vars.len() {
                 return Err(anyhow!("Invalid variable index {}", idx));
             }
-            self.statedesc_vars[idx].read(stream, db)?;
+            self.statedesc_vars[idx].read_nested(stream, db, limits, depth)?;
         }
 
         Ok(())
@@ -140,6 +199,17 @@ impl State {
     }
 
     pub fn from_blob(blob: &Vec<u8>, db: &DescriptorDb) -> Result<Self> {
+        Self::from_blob_limited(blob, db, &SdlLimits::default())
+    }
+
+    pub fn from_blob_limited(blob: &Vec<u8>, db: &DescriptorDb, limits: &SdlLimits)
+        -> Result<Self>
+    {
+        if blob.len() > limits.max_blob_size {
+            return Err(anyhow!("SDL blob is too large ({} > {} bytes)",
+                               blob.len(), limits.max_blob_size));
+        }
+
         let mut stream = Cursor::new(blob);
         let read_flags = stream.read_u16::<LittleEndian>()?;
         if (read_flags & VAR_LENGTH_IO) == 0 {
@@ -149,19 +219,24 @@ impl State {
         let descriptor_name = read_safe_str(&mut stream, StringFormat::Latin1)?;
         let version = stream.read_u16::<LittleEndian>()?;
         if let Some(descriptor) = db.get_version(&descriptor_name, version) {
-            let mut state = State::from_defaults(descriptor, db);
+            let mut state = State::from_defaults(descriptor, db)?;
             if (read_flags & HAS_UOID) != 0 {
                 state.object = Some(Uoid::stream_read(&mut stream)?);
             }
-            state.read(&mut stream, db)?;
+            state.read_nested(&mut stream, db, limits, 0)
+                .with_context(|| format!("Failed to read SDL state '{}' version {}",
+                                         descriptor_name, version))?;
             #[allow(clippy::cast_possible_truncation)]
             if stream.position() as usize != stream.get_ref().len() {
                 warn!("Did not fully parse SDL blob! ({} of {} bytes read)",
                       stream.position(), stream.get_ref().len());
             }
             Ok(state)
+        } else if db.get_latest(&descriptor_name).is_some() {
+            Err(anyhow!("Unknown version {} of state descriptor '{}'",
+                        version, descriptor_name))
         } else {
-            Err(anyhow!("Could not find descriptor {} version {}",
+            Err(anyhow!("Unknown state descriptor '{}' version {}",
                         descriptor_name, version))
         }
     }
@@ -184,6 +259,16 @@ impl State {
         Ok(stream.into_inner())
     }
 
+    // Returns a blob with only the variables changed since the last
+    // clear_dirty(), or None if there is nothing to send.
+    pub fn to_delta_blob(&self) -> Result<Option<Vec<u8>>> {
+        if self.is_dirty() {
+            self.to_blob().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn get_var(&self, var_name: &str) -> Option<&Variable> {
         self.simple_vars.iter().chain(self.statedesc_vars.iter())
                 .find(|var| var.descriptor().name() == var_name)
@@ -194,6 +279,22 @@ impl State {
                 .find(|var| var.descriptor().name() == var_name)
     }
 
+    // Dump the state's variables (in descriptor order) and their current
+    // values for inspection.  Nested STATEDESC variables are included
+    // recursively.
+    pub fn to_json(&self) -> Value {
+        let vars: Vec<Value> = self.descriptor.vars().iter()
+                .filter_map(|var_desc| self.get_var(var_desc.name()))
+                .map(Variable::to_json)
+                .collect();
+        json!({
+            "descriptor": self.descriptor.name(),
+            "version": self.descriptor.version(),
+            "object": self.object.as_ref().map_or(Value::Null, uoid_to_json),
+            "vars": vars,
+        })
+    }
+
     pub fn upgrade(&self, db: &DescriptorDb) -> Option<Self> {
         let Some(new_desc) = db.get_latest(self.descriptor.name()) else {
             // This can't happen unless the descriptor db provided here is
@@ -206,7 +307,13 @@ impl State {
             return None;
         }
 
-        let mut new_state = Self::from_defaults(new_desc, db);
+        let mut new_state = match Self::from_defaults(new_desc, db) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("Failed to upgrade {}: {}", self.descriptor.name(), err);
+                return None;
+            }
+        };
         for old_var in self.simple_vars.iter().chain(self.statedesc_vars.iter()) {
             if let Some(new_var) = new_state.get_var_mut(old_var.descriptor().name()) {
                 new_var.upgrade_from(old_var, db);
@@ -230,6 +337,19 @@ pub(super) fn read_compressed_size<S>(stream: &mut S, max_hint: usize) -> Result
     }
 }
 
+// Reads the number of dirty entries which follow, which can never be more
+// than the total number of entries available.
+pub(super) fn read_dirty_count<S>(stream: &mut S, max_hint: usize, total: usize)
+    -> Result<usize>
+    where S: BufRead
+{
+    let count = read_compressed_size(stream, max_hint)?;
+    if count > total {
+        return Err(anyhow!("Dirty count {} exceeds the total of {} entries", count, total));
+    }
+    Ok(count)
+}
+
 pub(super) fn write_compressed_size(stream: &mut dyn Write, max_hint: usize, value: usize)
     -> Result<()>
 {
@@ -250,7 +370,7 @@ pub(super) fn write_compressed_size(stream: &mut dyn Write, max_hint: usize, val
 #[cfg(test)]
 fn setup_test_state(db: &DescriptorDb) -> Result<State> {
     let desc = db.get_version("Test", 1).expect("Could not get StateDesc Test v1");
-    let mut state = State::from_defaults(desc, db);
+    let mut state = State::from_defaults(desc, db)?;
     assert!(state.is_default());
     assert!(!state.is_dirty());
 
@@ -269,12 +389,7 @@ fn setup_test_state(db: &DescriptorDb) -> Result<State> {
 
 #[test]
 fn test_blob_round_trip() -> Result<()> {
-    use super::descriptor_db::TEST_DESCRIPTORS;
-
-    let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug)
-                .format_timestamp(None).format_target(false).try_init();
-
-    let db = DescriptorDb::from_string(TEST_DESCRIPTORS)?;
+    let db = super::test_fixtures::load_fixtures();
     let orig_state = setup_test_state(&db)?;
     let orig_blob = orig_state.to_blob()?;
     let new_state = State::from_blob(&orig_blob, &db)?;
@@ -286,12 +401,7 @@ fn test_blob_round_trip() -> Result<()> {
 
 #[test]
 fn test_sdl_upgrade() -> Result<()> {
-    use super::descriptor_db::TEST_DESCRIPTORS;
-
-    let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug)
-                .format_timestamp(None).format_target(false).try_init();
-
-    let db = DescriptorDb::from_string(TEST_DESCRIPTORS)?;
+    let db = super::test_fixtures::load_fixtures();
     let orig_state = setup_test_state(&db)?;
     let orig_var1 = orig_state.get_var("bTestVar1").expect("Failed to get bTestVar1 variable");
     let orig_var2 = orig_state.get_var("bTestVar2").expect("Failed to get bTestVar2 variable");
@@ -316,3 +426,243 @@ fn test_sdl_upgrade() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_nested_sdl_upgrade() -> Result<()> {
+    let db = super::test_fixtures::load_fixtures();
+    let desc = db.get_version("Garden", 2).expect("Could not get StateDesc Garden v2");
+    let mut orig_state = State::from_defaults(desc, &db)?;
+    orig_state.get_var_mut("sprinklers")
+            .expect("Could not find variable sprinklers in SDL state")
+            .set_byte(2, 7)?;
+
+    let new_state = orig_state.upgrade(&db).expect("Upgrade didn't find a new version");
+    let sprinklers = new_state.get_var("sprinklers").expect("Failed to get sprinklers variable");
+    assert_eq!(sprinklers.get_byte(2)?, 7);
+    assert!(!sprinklers.is_default());
+
+    // Unchanged variables pick up the new defaults
+    let gate_open = new_state.get_var("gateOpen").expect("Failed to get gateOpen variable");
+    assert!(gate_open.get_bool(0)?);
+    // New variable length vars start out empty
+    let visitors = new_state.get_var("visitors").expect("Failed to get visitors variable");
+    assert!(visitors.get_int(0).is_err());
+
+    // The upgraded state must still round-trip
+    let blob = new_state.to_blob()?;
+    assert_eq!(State::from_blob(&blob, &db)?.to_blob()?, blob);
+
+    Ok(())
+}
+
+#[test]
+fn test_delta_blob() -> Result<()> {
+    let db = super::test_fixtures::load_fixtures();
+    let desc = db.get_version("AllTypes", 1).expect("Could not get StateDesc AllTypes v1");
+    let mut state = State::from_defaults(desc, &db)?;
+    assert_eq!(state.to_delta_blob()?, None);
+
+    state.mark_dirty();
+    let full_blob = state.to_blob()?;
+
+    state.clear_dirty();
+    assert!(!state.is_dirty());
+    assert_eq!(state.to_delta_blob()?, None);
+
+    state.get_var_mut("byteVar")
+            .expect("Could not find variable byteVar in SDL state")
+            .set_byte(0, 7)?;
+    let delta_blob = state.to_delta_blob()?.expect("Expected a delta for a dirty state");
+    assert!(delta_blob.len() * 2 < full_blob.len(),
+            "Delta ({} bytes) is not much smaller than the full state ({} bytes)",
+            delta_blob.len(), full_blob.len());
+
+    // Only the changed variable is included in the delta
+    let delta_state = State::from_blob(&delta_blob, &db)?;
+    let byte_var = delta_state.get_var("byteVar").expect("Failed to get byteVar variable");
+    assert!(byte_var.is_dirty());
+    assert_eq!(byte_var.get_byte(0)?, 7);
+    assert!(!delta_state.get_var("boolVar").expect("Failed to get boolVar variable").is_dirty());
+    assert!(!delta_state.get_var("plantVar").expect("Failed to get plantVar variable")
+                .is_dirty());
+
+    Ok(())
+}
+
+#[test]
+fn test_state_json() -> Result<()> {
+    let db = super::test_fixtures::load_fixtures();
+    let desc = db.get_version("Garden", 2).expect("Could not get StateDesc Garden v2");
+    let mut orig_state = State::from_defaults(desc, &db)?;
+    orig_state.get_var_mut("sprinklers")
+            .expect("Could not find variable sprinklers in SDL state")
+            .set_byte(2, 7)?;
+    let state = State::from_blob(&orig_state.to_blob()?, &db)?;
+
+    let json = state.to_json();
+    assert_eq!(json["descriptor"], "Garden");
+    assert_eq!(json["version"], 2);
+    assert_eq!(json["object"], Value::Null);
+    assert_eq!(json["vars"][0], json!({
+        "name": "gateOpen", "type": "BOOL", "values": [false]
+    }));
+    assert_eq!(json["vars"][2], json!({
+        "name": "sprinklers", "type": "BYTE", "values": [0, 0, 7, 0]
+    }));
+
+    // Nested states are dumped recursively
+    let plants = &json["vars"][1];
+    assert_eq!(plants["type"], "$Plant");
+    assert_eq!(plants["values"].as_array().map(Vec::len), Some(3));
+    assert_eq!(plants["values"][0]["descriptor"], "Plant");
+    assert_eq!(plants["values"][0]["vars"][0], json!({
+        "name": "height", "type": "INT", "values": [1]
+    }));
+
+    Ok(())
+}
+
+#[test]
+fn test_sdl_limits() -> Result<()> {
+    const NESTED_DESCRIPTORS: &str = r"
+        STATEDESC Outer
+        {
+            VERSION 1
+            VAR $Middle     middle[1]
+        }
+
+        STATEDESC Middle
+        {
+            VERSION 1
+            VAR $Inner      inner[1]
+        }
+
+        STATEDESC Inner
+        {
+            VERSION 1
+            VAR INT         value[1]    DEFAULT=0
+        }
+    ";
+
+    let db = DescriptorDb::from_string(NESTED_DESCRIPTORS)?;
+    let desc = db.get_latest("Outer").expect("Could not get StateDesc Outer");
+    let mut state = State::from_defaults(desc, &db)?;
+    state.mark_dirty();
+    let blob = state.to_blob()?;
+
+    let limits = SdlLimits { max_depth: 2, max_blob_size: blob.len() };
+    assert!(State::from_blob_limited(&blob, &db, &limits).is_ok());
+
+    let too_deep = SdlLimits { max_depth: 1, ..limits };
+    let Err(err) = State::from_blob_limited(&blob, &db, &too_deep) else {
+        panic!("Nested state should exceed the maximum depth");
+    };
+    assert!(format!("{err:#}").contains("exceeds maximum depth"), "{err:#}");
+
+    let too_large = SdlLimits { max_blob_size: blob.len() - 1, ..limits };
+    let Err(err) = State::from_blob_limited(&blob, &db, &too_large) else {
+        panic!("Blob should exceed the maximum size");
+    };
+    assert!(format!("{err:#}").contains("too large"), "{err:#}");
+
+    Ok(())
+}
+
+#[test]
+fn test_inflated_dirty_count() -> Result<()> {
+    let db = super::test_fixtures::load_fixtures();
+    let desc = db.get_version("Test", 1).expect("Could not get StateDesc Test v1");
+    let state = State::from_defaults(desc, &db)?;
+    let mut blob = state.to_blob()?;
+
+    // With no dirty variables, the blob ends with the simple and nested
+    // variable counts (one byte each, since there are fewer than 256 vars)
+    assert_eq!(blob[blob.len() - 2..], [0, 0]);
+    let simple_count = blob.len() - 2;
+    blob[simple_count] = 0xFF;
+    let Err(err) = State::from_blob(&blob, &db) else {
+        panic!("Inflated dirty count should be rejected");
+    };
+    assert!(format!("{err:#}").contains("exceeds the total"), "{err:#}");
+
+    Ok(())
+}
+
+#[test]
+fn test_unknown_descriptor_blob() -> Result<()> {
+    const OTHER_DESCRIPTORS: &str = r"
+        STATEDESC Missing
+        {
+            VERSION 3
+            VAR INT     value[1]    DEFAULT=0
+        }
+
+        STATEDESC Test
+        {
+            VERSION 9
+            VAR INT     value[1]    DEFAULT=0
+        }
+    ";
+
+    let db = super::test_fixtures::load_fixtures();
+    let other_db = DescriptorDb::from_string(OTHER_DESCRIPTORS)?;
+    let read_error = |name: &str, version: u16| -> Result<String> {
+        let desc = other_db.get_version(name, version).expect("Missing test descriptor");
+        let blob = State::from_defaults(desc, &other_db)?.to_blob()?;
+        let Err(err) = State::from_blob(&blob, &db) else {
+            panic!("Blob for {name} version {version} should be rejected");
+        };
+        Ok(err.to_string())
+    };
+    assert_eq!(read_error("Missing", 3)?, "Unknown state descriptor 'Missing' version 3");
+    assert_eq!(read_error("Test", 9)?, "Unknown version 9 of state descriptor 'Test'");
+
+    // Errors while reading a known descriptor also name it
+    let desc = db.get_version("Test", 1).expect("Could not get StateDesc Test v1");
+    let mut blob = State::from_defaults(desc, &db)?.to_blob()?;
+    blob.truncate(blob.len() - 1);
+    let Err(err) = State::from_blob(&blob, &db) else {
+        panic!("Truncated blob should be rejected");
+    };
+    assert_eq!(err.to_string(), "Failed to read SDL state 'Test' version 1");
+
+    Ok(())
+}
+
+#[test]
+fn test_want_timestamp() -> Result<()> {
+    use super::{HAS_DIRTY_FLAG, HAS_TIMESTAMP, SAME_AS_DEFAULT, WANT_TIMESTAMP};
+
+    const STAMP_DESCRIPTORS: &str = r"
+        STATEDESC Stamp
+        {
+            VERSION 1
+            VAR INT     value[1]    DEFAULT=0
+        }
+    ";
+
+    let db = DescriptorDb::from_string(STAMP_DESCRIPTORS)?;
+    let desc = db.get_latest("Stamp").expect("Could not get StateDesc Stamp");
+    let mut state = State::from_defaults(desc, &db)?;
+    state.mark_dirty();
+    let plain_blob = state.to_blob()?;
+
+    // The variable's flags come just before the (empty) nested var count
+    let flags_pos = plain_blob.len() - 2;
+    assert_eq!(plain_blob[flags_pos], SAME_AS_DEFAULT | HAS_DIRTY_FLAG);
+    let plain_state = State::from_blob(&plain_blob, &db)?;
+    assert_eq!(plain_state.to_blob()?, plain_blob);
+
+    // A variable which was sent wanting a timestamp gets one when written
+    let mut want_blob = plain_blob.clone();
+    want_blob[flags_pos] = SAME_AS_DEFAULT | WANT_TIMESTAMP;
+    let stamped_blob = State::from_blob(&want_blob, &db)?.to_blob()?;
+    assert_eq!(stamped_blob[flags_pos] & HAS_TIMESTAMP, HAS_TIMESTAMP);
+    assert!(stamped_blob.len() > plain_blob.len());
+
+    // ... and the timestamp can be read back
+    let stamped_state = State::from_blob(&stamped_blob, &db)?;
+    assert_eq!(stamped_state.to_blob()?, stamped_blob);
+
+    Ok(())
+}
@@ -21,16 +21,23 @@ use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use log::warn;
 use paste::paste;
+use serde_json::{json, Value};
 
 use crate::plasma::{Uoid, Creatable, UnifiedTime, Factory, StreamRead, StreamWrite};
 use crate::plasma::color::{Color32, ColorRGBA};
 use crate::plasma::creatable::ClassID;
 use crate::plasma::geometry::{Quaternion, Vector3};
 use crate::plasma::safe_string::{read_safe_str, write_safe_str, StringFormat};
-use super::state::{State, read_compressed_size, write_compressed_size};
+use super::state::{State, SdlLimits, read_compressed_size, read_dirty_count,
+                   write_compressed_size};
 use super::{DescriptorDb, StateDescriptor, VarDescriptor, VarType, VarDefault};
 use super::{HAS_NOTIFICATION_INFO, HAS_TIMESTAMP, SAME_AS_DEFAULT, HAS_DIRTY_FLAG, WANT_TIMESTAMP};
 
+// The maximum number of elements in a single variable.  The client refuses
+// to read any variable with 10000 or more elements, so we use the same limit
+// for both reading and writing.
+const MAX_VAR_COUNT: usize = 9999;
+
 #[derive(Clone, Debug)]
 enum VarValues {
     AgeTimeOfDay(usize),    // No stored value
@@ -59,6 +66,9 @@ pub struct Variable {
     descriptor: Arc<VarDescriptor>,
     values: VarValues,
     timestamp: UnifiedTime,
+    // Set from the stream's WANT_TIMESTAMP flag, to stamp the variable with
+    // the current time when it is written without a timestamp.
+    want_timestamp: bool,
     notification_hint: String,
     dirty: bool,
 }
@@ -128,7 +138,13 @@ macro_rules! var_accessors {
 }
 
 impl Variable {
-    pub fn from_defaults(descriptor: Arc<VarDescriptor>, db: &DescriptorDb) -> Self {
+    pub fn from_defaults(descriptor: Arc<VarDescriptor>, db: &DescriptorDb) -> Result<Self> {
+        Self::from_defaults_nested(descriptor, db, 0)
+    }
+
+    pub(super) fn from_defaults_nested(descriptor: Arc<VarDescriptor>, db: &DescriptorDb,
+                                       depth: usize) -> Result<Self>
+    {
         let count = descriptor.count().unwrap_or(0);
         let values = match descriptor.var_type() {
             VarType::AgeTimeOfDay => VarValues::AgeTimeOfDay(count),
@@ -203,25 +219,25 @@ impl Variable {
                 VarValues::Vector3(vec![default; count])
             }
             VarType::StateDesc(name) => {
-                if let Some(descriptor) = db.get_latest(name) {
-                    let mut states = Vec::with_capacity(count);
-                    for _ in 0..count {
-                        states.push(State::from_defaults(descriptor.clone(), db));
-                    }
-                    VarValues::StateDesc(states)
-                } else {
-                    panic!("Unknown state descriptor '{name}'");
+                let Some(descriptor) = db.get_latest(name) else {
+                    return Err(anyhow!("Unknown state descriptor '{}'", name));
+                };
+                let mut states = Vec::with_capacity(count);
+                for _ in 0..count {
+                    states.push(State::from_defaults_nested(descriptor.clone(), db, depth + 1)?);
                 }
+                VarValues::StateDesc(states)
             }
         };
 
-        Self {
+        Ok(Self {
             descriptor,
             values,
             timestamp: UnifiedTime::default(),
+            want_timestamp: false,
             notification_hint: String::new(),
             dirty: false
-        }
+        })
     }
 
     pub fn is_default(&self) -> bool {
@@ -265,7 +281,7 @@ impl Variable {
             }
             VarType::Point3 => {
                 let default = get_default!(self.descriptor, VarDefault::Vector3, Vector3::default());
-                check_default!(&self.values, VarValues::Vector3, default)
+                check_default!(&self.values, VarValues::Point3, default)
             }
             VarType::Quat => {
                 let default = get_default!(self.descriptor, VarDefault::Quat, Quaternion::default());
@@ -316,12 +332,93 @@ impl Variable {
     pub fn descriptor(&self) -> &VarDescriptor { &self.descriptor }
     pub fn is_dirty(&self) -> bool { self.dirty }
 
+    pub(super) fn set_dirty(&mut self, dirty: bool) {
+        self.dirty = dirty;
+        if let VarValues::StateDesc(values) = &mut self.values {
+            for state in values {
+                state.set_dirty(dirty);
+            }
+        }
+    }
+
     var_accessors!(bool, VarValues::Bool, bool);
     var_accessors!(byte, VarValues::Byte, u8);
     var_accessors!(int, VarValues::Int, i32);
 
+    // Dump the variable's name, type and current values for inspection.
+    pub fn to_json(&self) -> Value {
+        let values: Vec<Value> = match &self.values {
+            VarValues::AgeTimeOfDay(_) => Vec::new(),
+            VarValues::Bool(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Byte(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Creatable(values) => {
+                values.iter().map(|value| match value {
+                    Some(creatable) => json!({
+                        "class_id": format!("0x{:04x}", creatable.class_id())
+                    }),
+                    None => Value::Null,
+                }).collect()
+            }
+            VarValues::Double(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Float(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Int(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Key(values) => values.iter().map(uoid_to_json).collect(),
+            VarValues::Point3(values) | VarValues::Vector3(values) => {
+                values.iter().map(|value| json!({
+                    "x": value.x, "y": value.y, "z": value.z
+                })).collect()
+            }
+            VarValues::Quat(values) => {
+                values.iter().map(|value| json!({
+                    "x": value.x, "y": value.y, "z": value.z, "w": value.w
+                })).collect()
+            }
+            VarValues::Rgb(values) => {
+                values.iter().map(|value| json!({
+                    "r": value.r, "g": value.g, "b": value.b
+                })).collect()
+            }
+            VarValues::Rgb8(values) => {
+                values.iter().map(|value| json!({
+                    "r": value.r, "g": value.g, "b": value.b
+                })).collect()
+            }
+            VarValues::Rgba(values) => {
+                values.iter().map(|value| json!({
+                    "r": value.r, "g": value.g, "b": value.b, "a": value.a
+                })).collect()
+            }
+            VarValues::Rgba8(values) => {
+                values.iter().map(|value| json!({
+                    "r": value.r, "g": value.g, "b": value.b, "a": value.a
+                })).collect()
+            }
+            VarValues::Short(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::String32(values) => values.iter().map(|value| json!(value)).collect(),
+            VarValues::Time(values) => {
+                values.iter().map(|value| json!({
+                    "secs": value.secs(), "micros": value.micros()
+                })).collect()
+            }
+            VarValues::StateDesc(values) => values.iter().map(State::to_json).collect(),
+        };
+        json!({
+            "name": self.descriptor.name(),
+            "type": self.descriptor.var_type().to_string(),
+            "values": values,
+        })
+    }
+
     pub fn read<S>(&mut self, stream: &mut S, db: &DescriptorDb) -> Result<()>
         where S: BufRead
+    {
+        self.read_nested(stream, db, &SdlLimits::default(), 0)
+    }
+
+    // `depth` is the nesting depth of the state containing this variable
+    pub(super) fn read_nested<S>(&mut self, stream: &mut S, db: &DescriptorDb,
+                                 limits: &SdlLimits, depth: usize) -> Result<()>
+        where S: BufRead
     {
         let read_flags = stream.read_u8()?;
         self.notification_hint = if (read_flags & HAS_NOTIFICATION_INFO) != 0 {
@@ -336,7 +433,7 @@ impl Variable {
                 let Some(statedesc) = db.get_latest(name) else {
                     return Err(anyhow!("No such descriptor {name}"));
                 };
-                self.read_statedesc(stream, db, &statedesc)?;
+                self.read_statedesc(stream, db, &statedesc, limits, depth)?;
             }
             _ => self.read_simple(stream)?,
         }
@@ -352,8 +449,8 @@ impl Variable {
             Some(count) => count,
             None => stream.read_u32::<LittleEndian>()? as usize
         };
-        if var_count >= 10000 {
-            Err(anyhow!("Too many elements in SDL variable ({})", var_count))
+        if var_count > MAX_VAR_COUNT {
+            Err(anyhow!("Too many elements in SDL variable ({} > {})", var_count, MAX_VAR_COUNT))
         } else {
             Ok(var_count)
         }
@@ -363,6 +460,7 @@ impl Variable {
         where S: BufRead
     {
         let read_flags = stream.read_u8()?;
+        self.want_timestamp = (read_flags & WANT_TIMESTAMP) != 0;
         if (read_flags & HAS_TIMESTAMP) != 0 {
             self.timestamp = UnifiedTime::stream_read(stream)?;
         } else if (read_flags & HAS_DIRTY_FLAG) != 0 && (read_flags & WANT_TIMESTAMP) != 0 {
@@ -522,17 +620,18 @@ impl Variable {
     }
 
     fn read_statedesc<S>(&mut self, stream: &mut S, db: &DescriptorDb,
-                         statedesc: &Arc<StateDescriptor>) -> Result<()>
+                         statedesc: &Arc<StateDescriptor>, limits: &SdlLimits,
+                         depth: usize) -> Result<()>
         where S: BufRead
     {
         stream.read_u8()?;  // Unused: SD Var read flags
         let total_count = self.read_var_count(stream)?;
         let mut values = Vec::with_capacity(total_count);
         for _ in 0..total_count {
-            values.push(State::from_defaults(statedesc.clone(), db));
+            values.push(State::from_defaults(statedesc.clone(), db)?);
         }
         let max_hint = self.descriptor.count().unwrap_or(0);
-        let dirty_count = read_compressed_size(stream, max_hint)?;
+        let dirty_count = read_dirty_count(stream, max_hint, total_count)?;
         let read_indices = dirty_count != total_count;
         for idx in 0..dirty_count {
             let idx = if read_indices {
@@ -543,7 +642,7 @@ impl Variable {
             if idx >= values.len() {
                 return Err(anyhow!("Invalid value index {}", idx));
             }
-            values[idx].read(stream, db)?;
+            values[idx].read_nested(stream, db, limits, depth + 1)?;
         }
         self.values = VarValues::StateDesc(values);
         Ok(())
@@ -599,7 +698,14 @@ impl Variable {
 
     pub fn write_simple(&self, stream: &mut dyn Write) -> Result<()> {
         let mut write_flags = 0;
-        if self.timestamp != UnifiedTime::default() {
+        let timestamp = if self.timestamp != UnifiedTime::default() {
+            Some(self.timestamp)
+        } else if self.want_timestamp {
+            Some(UnifiedTime::now()?)
+        } else {
+            None
+        };
+        if timestamp.is_some() {
             write_flags |= HAS_TIMESTAMP;
         }
         if self.is_default() {
@@ -609,6 +715,9 @@ impl Variable {
             write_flags |= HAS_DIRTY_FLAG;
         }
         stream.write_u8(write_flags)?;
+        if let Some(timestamp) = timestamp {
+            timestamp.stream_write(stream)?;
+        }
 
         if (write_flags & SAME_AS_DEFAULT) == 0 {
             match &self.values {
@@ -730,8 +839,8 @@ impl Variable {
     }
 
     fn write_var_count(&self, stream: &mut dyn Write, count: usize) -> Result<()> {
-        if count > 10000 {
-            return Err(anyhow!("Too many elements in SDL variable ({})", count));
+        if count > MAX_VAR_COUNT {
+            return Err(anyhow!("Too many elements in SDL variable ({} > {})", count, MAX_VAR_COUNT));
         }
         if self.descriptor.count().is_none() {
             #[allow(clippy::cast_possible_truncation)]
@@ -767,8 +876,9 @@ impl Variable {
                       old_var.descriptor.default(), self.descriptor.default());
             }
             // Automatically use the new default value.  This assumes we were
-            // created in a default state as well.
-            debug_assert!(self.is_default());
+            // created in a default state as well (variable length vars are
+            // created empty, but never report being at default).
+            debug_assert!(self.is_default() || self.descriptor.count().is_none());
             return;
         }
         if self.descriptor.var_type() != old_var.descriptor.var_type() {
@@ -798,3 +908,67 @@ impl Variable {
         }
     }
 }
+
+// Represents a Uoid by its location and object name, rather than the raw
+// stream fields.  Invalid (null) keys are represented as null.
+pub(super) fn uoid_to_json(uoid: &Uoid) -> Value {
+    if uoid.location() == Uoid::invalid().location() {
+        return Value::Null;
+    }
+    let mut value = json!({
+        "location": format!("0x{:08x}", uoid.location().sequence()),
+        "location_flags": uoid.location().flags(),
+        "type": format!("0x{:04x}", uoid.obj_type()),
+        "name": uoid.obj_name(),
+    });
+    if uoid.clone_id() != 0 || uoid.clone_player_id() != 0 {
+        value["clone_id"] = json!(uoid.clone_id());
+        value["clone_player_id"] = json!(uoid.clone_player_id());
+    }
+    value
+}
+
+#[test]
+fn test_var_count_limits() -> Result<()> {
+    let db = DescriptorDb::empty();
+    let desc = Arc::new(VarDescriptor::new("bVarLength".to_string(), VarType::Byte,
+                                           None, None));
+    let mut var = Variable::from_defaults(desc, &db)?;
+
+    let var_length_stream = |count: u32| {
+        let mut stream = Cursor::new(Vec::new());
+        stream.write_u8(0).unwrap();   // Read flags
+        stream.write_u32::<LittleEndian>(count).unwrap();
+        stream.write_all(&vec![0x55; count as usize]).unwrap();
+        stream.set_position(0);
+        stream
+    };
+
+    // Zero-length arrays are valid and round trip
+    let mut stream = var_length_stream(0);
+    var.read_simple(&mut stream)?;
+    assert!(matches!(&var.values, VarValues::Byte(values) if values.is_empty()));
+    assert!(!var.is_default());
+    let mut out_stream = Cursor::new(Vec::new());
+    var.write_simple(&mut out_stream)?;
+    assert_eq!(out_stream.get_ref(), stream.get_ref());
+
+    // Exactly the maximum is allowed in both directions
+    let max_count = u32::try_from(MAX_VAR_COUNT).unwrap();
+    let mut stream = var_length_stream(max_count);
+    var.read_simple(&mut stream)?;
+    assert!(matches!(&var.values, VarValues::Byte(values) if values.len() == MAX_VAR_COUNT));
+    let mut out_stream = Cursor::new(Vec::new());
+    var.write_simple(&mut out_stream)?;
+    assert_eq!(out_stream.get_ref(), stream.get_ref());
+
+    // One more than the maximum is rejected in both directions
+    let mut stream = var_length_stream(max_count + 1);
+    let err = var.read_simple(&mut stream).unwrap_err();
+    assert!(err.to_string().contains("Too many elements"), "Unexpected error: {err}");
+    var.values = VarValues::Byte(vec![0; MAX_VAR_COUNT + 1]);
+    let err = var.write_simple(&mut Cursor::new(Vec::new())).unwrap_err();
+    assert!(err.to_string().contains("Too many elements"), "Unexpected error: {err}");
+
+    Ok(())
+}
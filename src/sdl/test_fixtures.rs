@@ -0,0 +1,54 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+// A shared set of SDL descriptors for tests, loaded from the .sdl files in
+// tests/fixtures/sdl with the same loader used by the server.
+
+use std::path::PathBuf;
+
+use super::{DescriptorDb, VarType};
+
+pub fn fixtures_dir() -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", "sdl"].iter().collect()
+}
+
+pub fn load_fixtures() -> DescriptorDb {
+    let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug)
+                .format_timestamp(None).format_target(false).try_init();
+
+    DescriptorDb::from_dir(&fixtures_dir(), &[0; 4])
+            .expect("Failed to load SDL test fixtures")
+}
+
+#[test]
+fn test_load_fixtures() {
+    let db = load_fixtures();
+    assert!(db.get_version("Test", 1).is_some());
+    assert!(db.get_version("Barney", 1).is_some());
+
+    for version in 1..=3 {
+        assert!(db.get_version("Garden", version).is_some(), "Missing Garden v{version}");
+    }
+    let garden = db.get_latest("Garden").expect("Could not get StateDesc Garden");
+    assert_eq!(garden.version(), 3);
+
+    let plants = garden.get_var("plants").expect("Could not find variable plants in Garden");
+    assert_eq!(plants.var_type(), &VarType::StateDesc("Plant".to_string()));
+    assert_eq!(plants.count(), None);
+    let sprinklers = garden.get_var("sprinklers")
+            .expect("Could not find variable sprinklers in Garden");
+    assert_eq!(sprinklers.count(), Some(4));
+}
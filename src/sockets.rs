@@ -0,0 +1,208 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use log::info;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+
+use crate::config::ServerConfig;
+
+// Bind a listening socket on the first usable address that `address`
+// resolves to, with the configured connection backlog.
+pub async fn bind_listener(address: &str, server_config: &ServerConfig)
+    -> io::Result<TcpListener>
+{
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(address).await? {
+        let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+        // Matches the behavior of TcpListener::bind()
+        #[cfg(not(windows))]
+        socket.set_reuseaddr(true)?;
+
+        // IPv6 listeners only accept IPv6 connections, so they can share a
+        // port with a separate IPv4 listener (e.g. "0.0.0.0" and "::").
+        if addr.is_ipv6() {
+            SockRef::from(&socket).set_only_v6(true)?;
+        }
+
+        if let Err(err) = socket.bind(addr) {
+            last_err = Some(err);
+            continue;
+        }
+        match socket.listen(server_config.listen_backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("Could not resolve any addresses for {address}"))
+    }))
+}
+
+// A set of listening sockets (e.g. for separate IPv4 and IPv6 addresses),
+// whose accepted connections are all funneled into a single queue.
+// Dropping the MultiListener closes all of its sockets.
+pub struct MultiListener {
+    accepted: mpsc::Receiver<io::Result<(TcpStream, SocketAddr)>>,
+    local_addrs: Vec<SocketAddr>,
+    _accept_tasks: JoinSet<()>,
+}
+
+impl MultiListener {
+    // Bind a listener on each of the addresses.  This fails if any of the
+    // addresses cannot be bound.
+    pub async fn bind(service: &str, addresses: &[String], server_config: &ServerConfig)
+        -> io::Result<Self>
+    {
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let listener = bind_listener(address, server_config).await.map_err(|err| {
+                io::Error::new(err.kind(), format!("Failed to bind on address {address}: {err}"))
+            })?;
+            listeners.push(listener);
+        }
+
+        let (accepted_send, accepted) = mpsc::channel(listeners.len().max(1));
+        let mut local_addrs = Vec::with_capacity(listeners.len());
+        let mut accept_tasks = JoinSet::new();
+        for listener in listeners {
+            let local_addr = listener.local_addr()?;
+            info!("{} listening on {}", service, local_addr);
+            local_addrs.push(local_addr);
+
+            let accepted_send = accepted_send.clone();
+            accept_tasks.spawn(async move {
+                loop {
+                    let client = listener.accept().await;
+                    if accepted_send.send(client).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        Ok(Self { accepted, local_addrs, _accept_tasks: accept_tasks })
+    }
+
+    pub async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        match self.accepted.recv().await {
+            Some(client) => client,
+            None => Err(io::Error::new(io::ErrorKind::Other, "All listeners were closed")),
+        }
+    }
+
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+}
+
+// Apply the socket options used for all accepted client and API connections.
+// Nagle's algorithm is disabled, since most of our messages are small and
+// latency sensitive (e.g. pings and vault notifications).
+pub fn configure_stream(stream: &TcpStream, server_config: &ServerConfig) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+
+    if let Some(keepalive_time) = server_config.tcp_keepalive_time {
+        let keepalive = TcpKeepalive::new().with_time(keepalive_time);
+        #[cfg(any(target_os = "linux", target_os = "macos", windows))]
+        let keepalive = keepalive.with_interval(server_config.tcp_keepalive_interval);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
+
+// Returns the kernel's smoothed round trip time estimate for a connection.
+// Clients only echo our replies to their own pings, so this is the only
+// way to see a client's actual latency.  Only supported on Linux.
+#[cfg(target_os = "linux")]
+pub fn tcp_rtt(stream: &TcpStream) -> Option<Duration> {
+    use std::os::fd::AsRawFd;
+
+    let mut info = std::mem::MaybeUninit::<libc::tcp_info>::zeroed();
+    let mut len = libc::socklen_t::try_from(std::mem::size_of::<libc::tcp_info>()).ok()?;
+    // SAFETY: The buffer is large enough for len bytes, and the kernel
+    // won't write more than that.  tcp_info is plain data, so the zeroed
+    // struct is valid even if the kernel only fills part of it.
+    let info = unsafe {
+        if libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+                            info.as_mut_ptr().cast(), &mut len) != 0 {
+            return None;
+        }
+        info.assume_init()
+    };
+    Some(Duration::from_micros(u64::from(info.tcpi_rtt)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tcp_rtt(_stream: &TcpStream) -> Option<Duration> {
+    None
+}
+
+#[tokio::test]
+async fn test_configure_stream() {
+    let server_config = ServerConfig::test_config();
+    let listener = bind_listener("127.0.0.1:0", &server_config).await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    assert!(!stream.nodelay().unwrap());
+    configure_stream(&stream, &server_config).unwrap();
+    assert!(stream.nodelay().unwrap());
+    assert_eq!(SockRef::from(&stream).keepalive().unwrap(),
+               server_config.tcp_keepalive_time.is_some());
+    drop(client);
+}
+
+#[tokio::test]
+async fn test_multi_listener() {
+    let server_config = ServerConfig::test_config();
+    let addresses = ["127.0.0.1:0".to_string(), "127.0.0.1:0".to_string()];
+    let mut listener = MultiListener::bind("Test", &addresses, &server_config).await.unwrap();
+    assert_eq!(listener.local_addrs().len(), 2);
+
+    let local_addrs = listener.local_addrs().to_vec();
+    for local_addr in local_addrs.into_iter().rev() {
+        let client = TcpStream::connect(local_addr).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(stream.local_addr().unwrap(), local_addr);
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+    }
+
+    // All addresses must be bound successfully
+    let addresses = ["127.0.0.1:0".to_string(), "not an address".to_string()];
+    assert!(MultiListener::bind("Test", &addresses, &server_config).await.is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_rtt() {
+    let server_config = ServerConfig::test_config();
+    let listener = bind_listener("127.0.0.1:0", &server_config).await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+
+    // Loopback connections are fast, but should still report a value
+    let rtt = tcp_rtt(&stream).expect("Failed to get TCP round trip time");
+    assert!(rtt < Duration::from_secs(1));
+    drop(client);
+}
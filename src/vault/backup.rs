@@ -0,0 +1,340 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use uuid::Uuid;
+
+use crate::hashes::ShaDigest;
+use crate::plasma::{StreamRead, StreamWrite};
+use crate::plasma::safe_string::{read_safe_str, write_safe_str, StringFormat};
+use super::db_interface::{AccountInfo, PlayerInfo, GameServer};
+use super::{VaultNode, NodeRef};
+
+// A database-independent snapshot of the vault, which can be written to
+// and restored from a portable binary file.
+pub struct VaultBackup {
+    // Accounts are only included when specifically requested, since they
+    // contain password hashes and API/login tokens.
+    pub accounts: Option<Vec<AccountInfo>>,
+    pub players: Vec<(Uuid, PlayerInfo)>,
+    pub game_servers: Vec<GameServer>,
+    pub nodes: Vec<Arc<VaultNode>>,
+    pub refs: Vec<NodeRef>,
+}
+
+const BACKUP_MAGIC: &[u8; 8] = b"MOULArsV";
+const BACKUP_VERSION: u32 = 3;
+// Older backups are still readable.  Version 2 stores nodes inline rather
+// than as length-prefixed blobs, and version 1 also has no login tokens.
+const BACKUP_VERSION_INLINE_NODES: u32 = 2;
+const BACKUP_VERSION_NO_LOGIN_TOKEN: u32 = 1;
+
+const FLAG_ACCOUNTS: u32 = 1 << 0;
+
+impl VaultBackup {
+    pub fn read_file(path: &Path) -> Result<Self> {
+        let mut stream = BufReader::new(File::open(path)?);
+        Self::stream_read(&mut stream)
+    }
+
+    // The backup is written to a temporary file first, so an existing file
+    // at `path` is not lost if writing fails partway through.
+    pub fn write_file(&self, path: &Path) -> Result<()> {
+        let temp_path = path.with_extension("tmp");
+        let mut stream = BufWriter::new(File::create(&temp_path)?);
+        self.stream_write(&mut stream)?;
+        stream.flush()?;
+        drop(stream);
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+}
+
+// Counts are read from the backup file, so they only provide a capacity hint
+// up to this many records.  A corrupt count then fails when the stream runs
+// out, rather than by attempting a huge allocation up front.
+const MAX_PREALLOC_RECORDS: usize = 4096;
+
+fn initial_capacity(count: u32) -> usize {
+    (count as usize).min(MAX_PREALLOC_RECORDS)
+}
+
+fn write_count(stream: &mut dyn Write, count: usize) -> Result<()> {
+    stream.write_u32::<LittleEndian>(u32::try_from(count)
+            .context("Too many records for backup stream")?)?;
+    Ok(())
+}
+
+// Nodes are stored as separate blobs, so backups assembled from another
+// server's export may use the legacy node layout (see from_import_blob).
+fn read_node_blob<S>(stream: &mut S) -> Result<VaultNode>
+    where S: BufRead
+{
+    let size = stream.read_u32::<LittleEndian>()?;
+    let mut blob = Vec::new();
+    stream.take(u64::from(size)).read_to_end(&mut blob)?;
+    if blob.len() != size as usize {
+        return Err(anyhow!("Unexpected end of vault node data"));
+    }
+    VaultNode::from_import_blob(&blob)
+}
+
+impl StreamRead for VaultBackup {
+    fn stream_read<S>(stream: &mut S) -> Result<Self>
+        where S: BufRead
+    {
+        let mut magic = [0u8; 8];
+        stream.read_exact(&mut magic)?;
+        if &magic != BACKUP_MAGIC {
+            return Err(anyhow!("Not a vault backup file"));
+        }
+        let version = stream.read_u32::<LittleEndian>()?;
+        if !(BACKUP_VERSION_NO_LOGIN_TOKEN..=BACKUP_VERSION).contains(&version) {
+            return Err(anyhow!("Unsupported vault backup version {}", version));
+        }
+        let flags = stream.read_u32::<LittleEndian>()?;
+
+        let accounts = if (flags & FLAG_ACCOUNTS) != 0 {
+            let count = stream.read_u32::<LittleEndian>()?;
+            let mut accounts = Vec::with_capacity(initial_capacity(count));
+            for _ in 0..count {
+                accounts.push(AccountInfo {
+                    account_name: read_safe_str(stream, StringFormat::Utf8)?,
+                    pass_hash: ShaDigest::stream_read(stream)?,
+                    account_id: Uuid::stream_read(stream)?,
+                    account_flags: stream.read_u32::<LittleEndian>()?,
+                    billing_type: stream.read_u32::<LittleEndian>()?,
+                    api_token: read_safe_str(stream, StringFormat::Utf8)?,
+                    login_token: if version == BACKUP_VERSION_NO_LOGIN_TOKEN {
+                        String::new()
+                    } else {
+                        read_safe_str(stream, StringFormat::Utf8)?
+                    },
+                });
+            }
+            Some(accounts)
+        } else {
+            None
+        };
+
+        let count = stream.read_u32::<LittleEndian>()?;
+        let mut players = Vec::with_capacity(initial_capacity(count));
+        for _ in 0..count {
+            let account_id = Uuid::stream_read(stream)?;
+            players.push((account_id, PlayerInfo {
+                player_id: stream.read_u32::<LittleEndian>()?,
+                player_name: read_safe_str(stream, StringFormat::Utf8)?,
+                avatar_shape: read_safe_str(stream, StringFormat::Utf8)?,
+                explorer: stream.read_i32::<LittleEndian>()?,
+            }));
+        }
+
+        let count = stream.read_u32::<LittleEndian>()?;
+        let mut game_servers = Vec::with_capacity(initial_capacity(count));
+        for _ in 0..count {
+            game_servers.push(GameServer {
+                instance_id: Uuid::stream_read(stream)?,
+                age_filename: read_safe_str(stream, StringFormat::Utf8)?,
+                display_name: read_safe_str(stream, StringFormat::Utf8)?,
+                age_id: stream.read_u32::<LittleEndian>()?,
+                sdl_id: stream.read_u32::<LittleEndian>()?,
+                temporary: stream.read_u8()? != 0,
+            });
+        }
+
+        let count = stream.read_u32::<LittleEndian>()?;
+        let mut nodes = Vec::with_capacity(initial_capacity(count));
+        for _ in 0..count {
+            let node = if version <= BACKUP_VERSION_INLINE_NODES {
+                VaultNode::stream_read(stream)?
+            } else {
+                read_node_blob(stream)?
+            };
+            nodes.push(Arc::new(node));
+        }
+
+        let count = stream.read_u32::<LittleEndian>()?;
+        let mut refs = Vec::with_capacity(initial_capacity(count));
+        for _ in 0..count {
+            refs.push(NodeRef::stream_read(stream)?);
+        }
+
+        Ok(Self { accounts, players, game_servers, nodes, refs })
+    }
+}
+
+impl StreamWrite for VaultBackup {
+    fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
+        stream.write_all(BACKUP_MAGIC)?;
+        stream.write_u32::<LittleEndian>(BACKUP_VERSION)?;
+        let flags = if self.accounts.is_some() { FLAG_ACCOUNTS } else { 0 };
+        stream.write_u32::<LittleEndian>(flags)?;
+
+        if let Some(accounts) = &self.accounts {
+            write_count(stream, accounts.len())?;
+            for account in accounts {
+                write_safe_str(stream, &account.account_name, StringFormat::Utf8)?;
+                account.pass_hash.stream_write(stream)?;
+                account.account_id.stream_write(stream)?;
+                stream.write_u32::<LittleEndian>(account.account_flags)?;
+                stream.write_u32::<LittleEndian>(account.billing_type)?;
+                write_safe_str(stream, &account.api_token, StringFormat::Utf8)?;
+                write_safe_str(stream, &account.login_token, StringFormat::Utf8)?;
+            }
+        }
+
+        write_count(stream, self.players.len())?;
+        for (account_id, player) in &self.players {
+            account_id.stream_write(stream)?;
+            stream.write_u32::<LittleEndian>(player.player_id)?;
+            write_safe_str(stream, &player.player_name, StringFormat::Utf8)?;
+            write_safe_str(stream, &player.avatar_shape, StringFormat::Utf8)?;
+            stream.write_i32::<LittleEndian>(player.explorer)?;
+        }
+
+        write_count(stream, self.game_servers.len())?;
+        for server in &self.game_servers {
+            server.instance_id.stream_write(stream)?;
+            write_safe_str(stream, &server.age_filename, StringFormat::Utf8)?;
+            write_safe_str(stream, &server.display_name, StringFormat::Utf8)?;
+            stream.write_u32::<LittleEndian>(server.age_id)?;
+            stream.write_u32::<LittleEndian>(server.sdl_id)?;
+            stream.write_u8(u8::from(server.temporary))?;
+        }
+
+        write_count(stream, self.nodes.len())?;
+        for node in &self.nodes {
+            let blob = node.to_blob()?;
+            write_count(stream, blob.len())?;
+            stream.write_all(&blob)?;
+        }
+
+        write_count(stream, self.refs.len())?;
+        for node_ref in &self.refs {
+            node_ref.stream_write(stream)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backup_round_trip() -> Result<()> {
+    use std::io::Cursor;
+
+    use super::db_interface::DbInterface;
+    use super::db_memory::DbMemory;
+    use super::server::init_vault;
+    use super::{VaultPlayerNode, VaultPlayerInfoNode};
+
+    let _ = env_logger::builder().is_test(true).filter_level(log::LevelFilter::Debug)
+                .format_timestamp(None).format_target(false).try_init();
+
+    let orig_db = DbMemory::new();
+    init_vault(&orig_db).expect("Failed to initialize vault");
+    let mut account = orig_db.get_account("Tester").unwrap().unwrap();
+    account.login_token = "0123456789abcdef".to_string();
+    orig_db.update_account(account.clone()).unwrap();
+    let node = VaultPlayerNode::new(&account.account_id, "Test Player", "male", 1);
+    let player_id = orig_db.create_node(node).unwrap();
+    orig_db.create_player(&account.account_id, PlayerInfo {
+        player_id,
+        player_name: "Test Player".to_string(),
+        avatar_shape: "male".to_string(),
+        explorer: 1,
+    }).unwrap();
+    let node = VaultPlayerInfoNode::new(&account.account_id, player_id, "Test Player");
+    let player_info = orig_db.create_node(node).unwrap();
+    orig_db.ref_node(player_id, player_info, 0).unwrap();
+    let all_players = orig_db.get_all_players_node().unwrap();
+    orig_db.ref_node(all_players, player_info, player_id).unwrap();
+
+    let backup = orig_db.export_vault(true).unwrap();
+    let mut stream = Cursor::new(Vec::new());
+    backup.stream_write(&mut stream)?;
+    let orig_blob = stream.into_inner();
+
+    let new_db = DbMemory::new();
+    init_vault(&new_db).expect("Failed to initialize vault");
+    let restored = VaultBackup::stream_read(&mut Cursor::new(&orig_blob))?;
+    new_db.import_vault(restored).unwrap();
+
+    // Node IDs and refs must be preserved exactly
+    assert_eq!(new_db.get_player_info_node(player_id).unwrap().node_id(), player_info);
+    assert_eq!(new_db.get_all_players_node().unwrap(), all_players);
+    assert_eq!(new_db.get_players(&account.account_id).unwrap().len(), 1);
+    assert!(new_db.get_account_for_token(&account.api_token).unwrap().is_some());
+    assert!(new_db.get_account_for_login_token(&account.login_token).unwrap().is_some());
+
+    let mut stream = Cursor::new(Vec::new());
+    new_db.export_vault(true).unwrap().stream_write(&mut stream)?;
+    assert_eq!(orig_blob, stream.into_inner());
+
+    // A vault with players in it is not considered empty
+    let restored = VaultBackup::stream_read(&mut Cursor::new(&orig_blob))?;
+    assert_eq!(new_db.import_vault(restored).err(),
+               Some(crate::netcli::NetResultCode::NetInvalidParameter));
+
+    Ok(())
+}
+
+#[test]
+fn test_backup_legacy_node() -> Result<()> {
+    use std::io::Cursor;
+
+    use super::vault_node::legacy_text_note_blob;
+
+    let node_blob = legacy_text_note_blob()?;
+    let mut blob = BACKUP_MAGIC.to_vec();
+    blob.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+    blob.extend_from_slice(&0_u32.to_le_bytes());   // flags
+    blob.extend_from_slice(&0_u32.to_le_bytes());   // players
+    blob.extend_from_slice(&0_u32.to_le_bytes());   // game servers
+    blob.extend_from_slice(&1_u32.to_le_bytes());   // nodes
+    blob.extend_from_slice(&u32::try_from(node_blob.len())?.to_le_bytes());
+    blob.extend_from_slice(&node_blob);
+    blob.extend_from_slice(&0_u32.to_le_bytes());   // refs
+
+    let backup = VaultBackup::stream_read(&mut Cursor::new(&blob))?;
+    assert_eq!(backup.nodes.len(), 1);
+    assert_eq!(backup.nodes[0].node_id(), 1234);
+    assert_eq!(backup.nodes[0].text_1(), "Some text");
+
+    // Truncated node blobs are rejected
+    blob.truncate(blob.len() - 5);
+    assert!(VaultBackup::stream_read(&mut Cursor::new(&blob)).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_backup_bad_count() {
+    use std::io::Cursor;
+
+    // A corrupt record count must fail once the data runs out, rather than
+    // trying to allocate space for all of the records up front
+    let mut blob = BACKUP_MAGIC.to_vec();
+    blob.extend_from_slice(&BACKUP_VERSION.to_le_bytes());
+    blob.extend_from_slice(&0_u32.to_le_bytes());
+    blob.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(VaultBackup::stream_read(&mut Cursor::new(&blob)).is_err());
+}
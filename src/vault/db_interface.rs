@@ -14,39 +14,82 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::collections::HashSet;
 use std::sync::Arc;
 
 use uuid::Uuid;
 
 use crate::hashes::ShaDigest;
 use crate::netcli::NetResult;
-use super::{VaultNode, NodeRef};
+use super::{VaultNode, NodeRef, VaultBackup};
 
 pub trait DbInterface: Send {
     fn get_account(&self, account_name: &str) -> NetResult<Option<AccountInfo>>;
     fn get_account_for_token(&self, api_token: &str) -> NetResult<Option<AccountInfo>>;
+    fn get_account_for_login_token(&self, login_token: &str)
+        -> NetResult<Option<AccountInfo>>;
+    fn get_account_by_id(&self, account_id: &Uuid) -> NetResult<Option<AccountInfo>>;
+    // Looks up an existing account by name.  Unlike get_account, this never
+    // creates the account in backends which do so on login.
+    fn get_existing_account(&self, account_name: &str) -> NetResult<Option<AccountInfo>>;
+    fn update_account(&self, account: AccountInfo) -> NetResult<()>;
+    // Creates a new account with the specified account flags.  Fails with
+    // NetAccountAlreadyExists if the name is already in use.
+    fn create_account(&self, account_name: &str, pass_hash: ShaDigest, account_flags: u32)
+        -> NetResult<AccountInfo>;
 
-    fn set_all_players_offline(&self) -> NetResult<()>;
+    // Clears the online flag on every PlayerInfo node, returning how many
+    // players were still marked online (e.g. after an unclean shutdown).
+    fn set_all_players_offline(&self) -> NetResult<u64>;
     fn get_players(&self, account_id: &Uuid) -> NetResult<Vec<PlayerInfo>>;
     fn count_players(&self, account_id: &Uuid) -> NetResult<u64>;
     fn player_exists(&self, player_name: &str) -> NetResult<bool>;
     fn create_player(&self, account_id: &Uuid, player: PlayerInfo) -> NetResult<()>;
 
     fn add_game_server(&self, server: GameServer) -> NetResult<()>;
+    fn get_game_server(&self, instance_id: &Uuid) -> NetResult<Option<GameServer>>;
+    fn remove_game_server(&self, instance_id: &Uuid) -> NetResult<()>;
 
+    // Node IDs are allocated monotonically, starting from 1 in an empty
+    // vault.  IDs are never reused, and after an import, allocation resumes
+    // after the highest imported node ID.
     fn create_node(&self, node: VaultNode) -> NetResult<u32>;
     fn fetch_node(&self, node_id: u32) -> NetResult<Arc<VaultNode>>;
     fn update_node(&self, node: VaultNode) -> NetResult<Vec<u32>>;
+    // Blob fields are never set in search templates.
     fn find_nodes(&self, template: VaultNode) -> NetResult<Vec<u32>>;
+    // Pages through the Age Info nodes of the given instances, sorted by
+    // Age filename and then by instance UUID.  Returns the total number of
+    // matching instances, and the nodes in the requested page.
+    fn list_age_instances(&self, age_filename: Option<&str>, instance_ids: &HashSet<Uuid>,
+                          offset: usize, limit: usize)
+        -> NetResult<(usize, Vec<Arc<VaultNode>>)>;
     fn get_system_node(&self) -> NetResult<u32>;
     fn get_all_players_node(&self) -> NetResult<u32>;
+    fn get_ccr_players_node(&self) -> NetResult<u32>;
     fn get_player_info_node(&self, player_id: u32) -> NetResult<Arc<VaultNode>>;
 
     fn ref_node(&self, parent: u32, child: u32, owner: u32) -> NetResult<()>;
     fn fetch_refs(&self, parent: u32, recursive: bool) -> NetResult<Vec<NodeRef>>;
+    // Like a recursive fetch_refs, but stops once it has found more than
+    // max_refs refs, so oversized trees are detected without walking them.
+    fn fetch_ref_tree(&self, parent: u32, max_refs: usize) -> NetResult<Vec<NodeRef>>;
+    // Find every Player whose vault tree contains the node, sorted by
+    // player ID.  Shared nodes (e.g. an Age's nodes) may have several owners.
+    fn find_node_owners(&self, node_id: u32) -> NetResult<Vec<NodeOwner>>;
+
+    fn export_vault(&self, include_accounts: bool) -> NetResult<VaultBackup>;
+    fn import_vault(&self, backup: VaultBackup) -> NetResult<()>;
+
+    // Operations which touch several nodes and refs (e.g. creating a new
+    // player's vault tree) run in a transaction, so a failure partway through
+    // doesn't leave orphaned nodes behind.  Transactions are not nested.
+    fn begin_transaction(&self) -> NetResult<()>;
+    fn commit_transaction(&self) -> NetResult<()>;
+    fn rollback_transaction(&self) -> NetResult<()>;
 }
 
-#[derive(Clone)]
+#[derive(Clone, Eq, PartialEq)]
 pub struct AccountInfo {
     pub account_name: String,
     pub pass_hash: ShaDigest,
@@ -54,20 +97,23 @@ pub struct AccountInfo {
     pub account_flags: u32,
     pub billing_type: u32,
     pub api_token: String,
+    // Token for logging in without a password (e.g. from an external single
+    // sign-on service).  Empty if no token has been issued to the account.
+    pub login_token: String,
 }
 
 impl AccountInfo {
     // Account flags
     pub const ADMIN: u32        = 1 << 0;
     pub const BETA_TESTER: u32  = 1 << 1;
+    pub const CCR: u32          = 1 << 2;
     pub const BANNED: u32       = 1 << 16;
+    pub const DISABLED: u32     = 1 << 17;
 
     pub fn is_admin(&self) -> bool { (self.account_flags & Self::ADMIN) != 0 }
+    pub fn is_ccr(&self) -> bool { (self.account_flags & Self::CCR) != 0 }
     pub fn is_banned(&self) -> bool { (self.account_flags & Self::BANNED) != 0 }
-
-    pub fn can_login_restricted(&self) -> bool {
-        (self.account_flags & (Self::ADMIN | Self::BETA_TESTER)) != 0
-    }
+    pub fn is_disabled(&self) -> bool { (self.account_flags & Self::DISABLED) != 0 }
 }
 
 #[derive(Clone)]
@@ -78,6 +124,12 @@ pub struct PlayerInfo {
     pub explorer: i32,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct NodeOwner {
+    pub player_id: u32,
+    pub account_id: Uuid,
+}
+
 #[derive(Clone)]
 pub struct GameServer {
     pub instance_id: Uuid,
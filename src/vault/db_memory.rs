@@ -25,9 +25,9 @@ use uuid::Uuid;
 use crate::auth_srv::auth_hash::create_pass_hash;
 use crate::hashes::ShaDigest;
 use crate::netcli::{NetResult, NetResultCode};
-use crate::vault::NodeRef;
+use crate::vault::{NodeRef, VaultAgeInfoNode, VaultBackup};
 use crate::vault::vault_node::{VaultNode, StandardNode, NodeType};
-use super::db_interface::{DbInterface, AccountInfo, PlayerInfo, GameServer};
+use super::db_interface::{DbInterface, AccountInfo, PlayerInfo, GameServer, NodeOwner};
 
 // An ephemeral vault backend that vanishes once the server exits.
 pub struct Backend {
@@ -42,6 +42,20 @@ pub struct Backend {
 
 pub struct DbMemory {
     db: RefCell<Backend>,
+    // The changes made by the active transaction, with the previous values
+    // to restore if it is rolled back
+    undo_log: RefCell<Option<Vec<UndoEntry>>>,
+}
+
+// A record's value from before it was changed in a transaction, where None
+// means the record didn't exist yet.  Node refs can't be modified, so they
+// only track whether the ref existed.
+enum UndoEntry {
+    Account(UniCase<String>, Option<AccountInfo>),
+    Players(Uuid, Option<Vec<PlayerInfo>>),
+    GameServer(u32, Option<GameServer>),
+    Node(u32, Option<Arc<VaultNode>>),
+    NodeRef(NodeRef, bool),
 }
 
 impl Backend {
@@ -53,17 +67,64 @@ impl Backend {
             game_index: 1,
             vault: HashMap::new(),
             node_refs: HashSet::new(),
-            node_index: 1000,
+            node_index: 1,
         }
     }
+
+    fn next_free_node_id(&self) -> u32 {
+        self.vault.keys().max().map_or(1, |node_id| node_id + 1)
+    }
 }
 
 impl DbMemory {
     pub fn new() -> Self {
         Self {
-            db: RefCell::new(Backend::new())
+            db: RefCell::new(Backend::new()),
+            undo_log: RefCell::new(None),
+        }
+    }
+
+    // Records how to undo a change, if a transaction is active
+    fn log_undo<F>(&self, entry: F)
+        where F: FnOnce() -> UndoEntry
+    {
+        if let Some(undo_log) = self.undo_log.borrow_mut().as_mut() {
+            undo_log.push(entry());
         }
     }
+
+    // All account lookups return the same stored record, so they always
+    // agree on the account's fields.
+    fn find_account<F>(&self, predicate: F) -> Option<AccountInfo>
+        where F: Fn(&AccountInfo) -> bool
+    {
+        self.db.borrow().accounts.values().find(|account| predicate(account)).cloned()
+    }
+
+    // Appends the refs under parent (depth first) until there are limit refs
+    fn collect_ref_tree(&self, parent: u32, limit: usize, refs: &mut Vec<NodeRef>) {
+        for node_ref in &self.db.borrow().node_refs {
+            if refs.len() >= limit {
+                return;
+            }
+            if node_ref.parent() == parent {
+                refs.push(*node_ref);
+                self.collect_ref_tree(node_ref.child(), limit, refs);
+            }
+        }
+    }
+
+    // Finds one of the global PlayerInfoList folders
+    fn find_player_list(&self, folder_type: StandardNode) -> NetResult<u32> {
+        for (node_id, node) in &self.db.borrow().vault {
+            if node.node_type() == NodeType::PlayerInfoList as i32
+                    && node.int32_1() == folder_type as i32
+            {
+                return Ok(*node_id);
+            }
+        }
+        Err(NetResultCode::NetVaultNodeNotFound)
+    }
 }
 
 impl DbInterface for DbMemory {
@@ -79,8 +140,12 @@ impl DbInterface for DbMemory {
         // of the username as the API token for consistent results
         let api_token = ShaDigest::sha1(account_name.as_bytes()).as_hex();
         info!("API token for '{}' is {}", account_name, api_token);
+        let account_key = UniCase::new(account_name.to_string());
         let mut db = self.db.borrow_mut();
-        let account = db.accounts.entry(UniCase::new(account_name.to_string()))
+        if !db.accounts.contains_key(&account_key) {
+            self.log_undo(|| UndoEntry::Account(account_key.clone(), None));
+        }
+        let account = db.accounts.entry(account_key)
                         .or_insert(AccountInfo {
                             account_name: account_name.to_string(),
                             pass_hash,
@@ -88,26 +153,92 @@ impl DbInterface for DbMemory {
                             account_flags: AccountInfo::ADMIN,
                             billing_type: 1,
                             api_token,
+                            login_token: String::new(),
                         });
         Ok(Some(account.clone()))
     }
 
     fn get_account_for_token(&self, api_token: &str) -> NetResult<Option<AccountInfo>> {
         let api_token = api_token.to_ascii_lowercase();
-        for account in self.db.borrow().accounts.values() {
-            if account.api_token == api_token {
-                return Ok(Some(account.clone()))
-            }
+        Ok(self.find_account(|account| account.api_token == api_token))
+    }
+
+    fn get_account_for_login_token(&self, login_token: &str)
+        -> NetResult<Option<AccountInfo>>
+    {
+        // Accounts without a login token can't be logged into this way
+        if login_token.is_empty() {
+            return Ok(None);
         }
-        Ok(None)
+        let login_token = login_token.to_ascii_lowercase();
+        Ok(self.find_account(|account| account.login_token == login_token))
     }
 
-    fn set_all_players_offline(&self) -> NetResult<()> {
-        // This doesn't have to do anything here -- we always start in a clean
-        // state with all players offline.
+    fn get_account_by_id(&self, account_id: &Uuid) -> NetResult<Option<AccountInfo>> {
+        // Unlike get_account, this never creates a new account
+        Ok(self.find_account(|account| account.account_id == *account_id))
+    }
+
+    fn get_existing_account(&self, account_name: &str) -> NetResult<Option<AccountInfo>> {
+        let db = self.db.borrow();
+        Ok(db.accounts.get(&UniCase::new(account_name.to_string())).cloned())
+    }
+
+    fn update_account(&self, account: AccountInfo) -> NetResult<()> {
+        let mut db = self.db.borrow_mut();
+        let account_key = UniCase::new(account.account_name.clone());
+        let Some(existing) = db.accounts.get_mut(&account_key) else {
+            return Err(NetResultCode::NetAccountNotFound);
+        };
+        if existing.account_id != account.account_id {
+            return Err(NetResultCode::NetInvalidParameter);
+        }
+        self.log_undo(|| UndoEntry::Account(account_key, Some(existing.clone())));
+        *existing = account;
         Ok(())
     }
 
+    fn create_account(&self, account_name: &str, pass_hash: ShaDigest, account_flags: u32)
+        -> NetResult<AccountInfo>
+    {
+        let mut db = self.db.borrow_mut();
+        let account_key = UniCase::new(account_name.to_string());
+        if db.accounts.contains_key(&account_key) {
+            return Err(NetResultCode::NetAccountAlreadyExists);
+        }
+        // Unlike the automatically created accounts above, real accounts
+        // get an API token that can't be derived from the account name
+        let account = AccountInfo {
+            account_name: account_name.to_string(),
+            pass_hash,
+            account_id: Uuid::new_v4(),
+            account_flags,
+            billing_type: 1,
+            api_token: ShaDigest::sha1(&rand::random::<[u8; 32]>()).as_hex(),
+            login_token: String::new(),
+        };
+        self.log_undo(|| UndoEntry::Account(account_key.clone(), None));
+        db.accounts.insert(account_key, account.clone());
+        Ok(account)
+    }
+
+    fn set_all_players_offline(&self) -> NetResult<u64> {
+        // An empty vault starts with all players offline, but an imported
+        // vault may still have players marked online.
+        let mut db = self.db.borrow_mut();
+        let mut count = 0;
+        for (node_id, node) in &mut db.vault {
+            if node.node_type() == NodeType::PlayerInfo as i32 && node.int32_1() != 0 {
+                self.log_undo(|| UndoEntry::Node(*node_id, Some(node.clone())));
+                let mut offline = node.as_ref().clone();
+                offline.set_int32_1(0);
+                *node = Arc::new(offline);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
     fn get_players(&self, account_id: &Uuid) -> NetResult<Vec<PlayerInfo>> {
         if let Some(players) = self.db.borrow().players.get(account_id) {
             Ok(players.clone())
@@ -134,8 +265,9 @@ impl DbInterface for DbMemory {
     }
 
     fn create_player(&self, account_id: &Uuid, player: PlayerInfo) -> NetResult<()> {
-        self.db.borrow_mut().players.entry(*account_id).or_default()
-                .push(player);
+        let mut db = self.db.borrow_mut();
+        self.log_undo(|| UndoEntry::Players(*account_id, db.players.get(account_id).cloned()));
+        db.players.entry(*account_id).or_default().push(player);
         Ok(())
     }
 
@@ -143,6 +275,7 @@ impl DbInterface for DbMemory {
         let mut db = self.db.borrow_mut();
         let server_id = db.game_index;
         db.game_index += 1;
+        self.log_undo(|| UndoEntry::GameServer(server_id, None));
         if db.game_servers.insert(server_id, server).is_some() {
             warn!("Created duplicate game server ID {}!", server_id);
             Err(NetResultCode::NetInternalError)
@@ -151,11 +284,35 @@ impl DbInterface for DbMemory {
         }
     }
 
+    fn get_game_server(&self, instance_id: &Uuid) -> NetResult<Option<GameServer>> {
+        Ok(self.db.borrow().game_servers.values()
+                .find(|server| &server.instance_id == instance_id).cloned())
+    }
+
+    fn remove_game_server(&self, instance_id: &Uuid) -> NetResult<()> {
+        let mut db = self.db.borrow_mut();
+        let count = db.game_servers.len();
+        db.game_servers.retain(|server_id, server| {
+            if &server.instance_id == instance_id {
+                self.log_undo(|| UndoEntry::GameServer(*server_id, Some(server.clone())));
+                false
+            } else {
+                true
+            }
+        });
+        if db.game_servers.len() == count {
+            Err(NetResultCode::NetAgeNotFound)
+        } else {
+            Ok(())
+        }
+    }
+
     fn create_node(&self, mut node: VaultNode) -> NetResult<u32> {
         let mut db = self.db.borrow_mut();
         let node_id = db.node_index;
         db.node_index += 1;
         node.set_node_id(node_id);
+        self.log_undo(|| UndoEntry::Node(node_id, None));
         if db.vault.insert(node_id, Arc::new(node)).is_some() {
             warn!("Created duplicate node ID {}!", node_id);
             Err(NetResultCode::NetInternalError)
@@ -178,6 +335,7 @@ impl DbInterface for DbMemory {
             return Err(NetResultCode::NetVaultNodeNotFound);
         };
         let new_node = update_node(old_node, &node);
+        self.log_undo(|| UndoEntry::Node(node_id, Some(old_node.clone())));
         db.vault.insert(node_id, new_node);
         Ok(vec![node_id])
     }
@@ -192,6 +350,23 @@ impl DbInterface for DbMemory {
         }).collect())
     }
 
+    fn list_age_instances(&self, age_filename: Option<&str>, instance_ids: &HashSet<Uuid>,
+                          offset: usize, limit: usize)
+        -> NetResult<(usize, Vec<Arc<VaultNode>>)>
+    {
+        let template = match age_filename {
+            Some(filename) => VaultAgeInfoNode::new_filename_lookup(filename),
+            None => VaultAgeInfoNode::new_lookup(None),
+        };
+        let db = self.db.borrow();
+        let mut nodes: Vec<&Arc<VaultNode>> = db.vault.values()
+                .filter(|node| node_match(&template, node) && instance_ids.contains(node.uuid_1()))
+                .collect();
+        nodes.sort_by(|a, b| (a.string64_2(), a.uuid_1()).cmp(&(b.string64_2(), b.uuid_1())));
+        let total = nodes.len();
+        Ok((total, nodes.into_iter().skip(offset).take(limit).cloned().collect()))
+    }
+
     fn get_system_node(&self) -> NetResult<u32> {
         for (node_id, node) in &self.db.borrow().vault {
             if node.node_type() == NodeType::System as i32 {
@@ -202,14 +377,11 @@ impl DbInterface for DbMemory {
     }
 
     fn get_all_players_node(&self) -> NetResult<u32> {
-        for (node_id, node) in &self.db.borrow().vault {
-            if node.node_type() == NodeType::PlayerInfoList as i32
-                    && node.int32_1() == StandardNode::AllPlayersFolder as i32
-            {
-                return Ok(*node_id);
-            }
-        }
-        Err(NetResultCode::NetVaultNodeNotFound)
+        self.find_player_list(StandardNode::AllPlayersFolder)
+    }
+
+    fn get_ccr_players_node(&self) -> NetResult<u32> {
+        self.find_player_list(StandardNode::CCRPlayersFolder)
     }
 
     fn get_player_info_node(&self, player_id: u32) -> NetResult<Arc<VaultNode>> {
@@ -227,22 +399,182 @@ impl DbInterface for DbMemory {
     }
 
     fn ref_node(&self, parent: u32, child: u32, owner: u32) -> NetResult<()> {
-        self.db.borrow_mut().node_refs.insert(NodeRef::new(parent, child, owner));
+        let node_ref = NodeRef::new(parent, child, owner);
+        if self.db.borrow_mut().node_refs.insert(node_ref) {
+            self.log_undo(|| UndoEntry::NodeRef(node_ref, false));
+        }
         Ok(())
     }
 
     fn fetch_refs(&self, parent: u32, recursive: bool) -> NetResult<Vec<NodeRef>> {
         let mut refs = Vec::new();
-        for node_ref in &self.db.borrow().node_refs {
-            if node_ref.parent() == parent {
-                refs.push(*node_ref);
-                if recursive {
-                    refs.extend_from_slice(&self.fetch_refs(node_ref.child(), true)?);
-                }
-            }
+        if recursive {
+            self.collect_ref_tree(parent, usize::MAX, &mut refs);
+        } else {
+            refs.extend(self.db.borrow().node_refs.iter()
+                            .filter(|node_ref| node_ref.parent() == parent));
         }
         Ok(refs)
     }
+
+    fn fetch_ref_tree(&self, parent: u32, max_refs: usize) -> NetResult<Vec<NodeRef>> {
+        let mut refs = Vec::new();
+        self.collect_ref_tree(parent, max_refs.saturating_add(1), &mut refs);
+        Ok(refs)
+    }
+
+    fn find_node_owners(&self, node_id: u32) -> NetResult<Vec<NodeOwner>> {
+        let db = self.db.borrow();
+        if !db.vault.contains_key(&node_id) {
+            return Err(NetResultCode::NetVaultNodeNotFound);
+        }
+
+        // Walk up the refs until we reach a Player node.  Nodes above the
+        // Player (e.g. the AllPlayersFolder) don't confer ownership.
+        let mut owners = Vec::new();
+        let mut visited = HashSet::new();
+        let mut pending = vec![node_id];
+        while let Some(current) = pending.pop() {
+            if !visited.insert(current) {
+                continue;
+            }
+            if let Some(player) = db.vault.get(&current).and_then(VaultNode::as_player_node) {
+                owners.push(NodeOwner { player_id: current, account_id: *player.account_id() });
+                continue;
+            }
+            pending.extend(db.node_refs.iter()
+                    .filter(|node_ref| node_ref.child() == current)
+                    .map(NodeRef::parent));
+        }
+        owners.sort_by_key(|owner| owner.player_id);
+        Ok(owners)
+    }
+
+    fn export_vault(&self, include_accounts: bool) -> NetResult<VaultBackup> {
+        let db = self.db.borrow();
+        let accounts = if include_accounts {
+            let mut accounts: Vec<AccountInfo> = db.accounts.values().cloned().collect();
+            accounts.sort_by(|a, b| a.account_name.cmp(&b.account_name));
+            Some(accounts)
+        } else {
+            None
+        };
+        let mut players: Vec<(Uuid, PlayerInfo)> = db.players.iter()
+                .flat_map(|(account_id, player_list)| {
+                    player_list.iter().map(|player| (*account_id, player.clone()))
+                }).collect();
+        players.sort_by_key(|(_, player)| player.player_id);
+        let mut game_servers: Vec<(&u32, &GameServer)> = db.game_servers.iter().collect();
+        game_servers.sort_by_key(|(server_id, _)| **server_id);
+        let mut nodes: Vec<Arc<VaultNode>> = db.vault.values().cloned().collect();
+        nodes.sort_by_key(|node| node.node_id());
+        let mut refs: Vec<NodeRef> = db.node_refs.iter().copied().collect();
+        refs.sort_by_key(|node_ref| (node_ref.parent(), node_ref.child(), node_ref.owner()));
+
+        Ok(VaultBackup {
+            accounts,
+            players,
+            game_servers: game_servers.into_iter().map(|(_, server)| server.clone()).collect(),
+            nodes,
+            refs,
+        })
+    }
+
+    fn import_vault(&self, backup: VaultBackup) -> NetResult<()> {
+        let mut db = self.db.borrow_mut();
+
+        // Only allow restoring into a vault which hasn't been used yet.  The
+        // backup has its own System and global folder nodes, so the ones
+        // created when initializing this vault are replaced.
+        if !db.players.is_empty() || !db.game_servers.is_empty() {
+            warn!("Refusing to import a backup into a non-empty vault");
+            return Err(NetResultCode::NetInvalidParameter);
+        }
+
+        if let Some(accounts) = backup.accounts {
+            for account in accounts {
+                let account_key = UniCase::new(account.account_name.clone());
+                let previous = db.accounts.insert(account_key.clone(), account);
+                self.log_undo(|| UndoEntry::Account(account_key, previous));
+            }
+        }
+        for (account_id, player) in backup.players {
+            self.log_undo(|| UndoEntry::Players(account_id, db.players.get(&account_id).cloned()));
+            db.players.entry(account_id).or_default().push(player);
+        }
+        for server in backup.game_servers {
+            let server_id = db.game_index;
+            db.game_index += 1;
+            self.log_undo(|| UndoEntry::GameServer(server_id, None));
+            db.game_servers.insert(server_id, server);
+        }
+        let new_vault = backup.nodes.into_iter().map(|node| (node.node_id(), node)).collect();
+        for (node_id, node) in std::mem::replace(&mut db.vault, new_vault) {
+            self.log_undo(|| UndoEntry::Node(node_id, Some(node)));
+        }
+        for node_id in db.vault.keys() {
+            self.log_undo(|| UndoEntry::Node(*node_id, None));
+        }
+        let new_refs = backup.refs.into_iter().collect();
+        for node_ref in std::mem::replace(&mut db.node_refs, new_refs) {
+            self.log_undo(|| UndoEntry::NodeRef(node_ref, true));
+        }
+        for node_ref in &db.node_refs {
+            self.log_undo(|| UndoEntry::NodeRef(*node_ref, false));
+        }
+        db.node_index = db.next_free_node_id();
+
+        Ok(())
+    }
+
+    fn begin_transaction(&self) -> NetResult<()> {
+        let mut undo_log = self.undo_log.borrow_mut();
+        if undo_log.is_some() {
+            warn!("Nested vault transactions are not supported");
+            return Err(NetResultCode::NetInternalError);
+        }
+        *undo_log = Some(Vec::new());
+        Ok(())
+    }
+
+    fn commit_transaction(&self) -> NetResult<()> {
+        if self.undo_log.borrow_mut().take().is_none() {
+            warn!("Attempted to commit without an active transaction");
+            return Err(NetResultCode::NetInternalError);
+        }
+        Ok(())
+    }
+
+    fn rollback_transaction(&self) -> NetResult<()> {
+        let Some(undo_log) = self.undo_log.borrow_mut().take() else {
+            warn!("Attempted to roll back without an active transaction");
+            return Err(NetResultCode::NetInternalError);
+        };
+        // IDs allocated during the transaction are not reused, so the node
+        // and game server indices are left alone
+        let mut db = self.db.borrow_mut();
+        for entry in undo_log.into_iter().rev() {
+            match entry {
+                UndoEntry::Account(key, account) => restore(&mut db.accounts, key, account),
+                UndoEntry::Players(key, players) => restore(&mut db.players, key, players),
+                UndoEntry::GameServer(key, server) => restore(&mut db.game_servers, key, server),
+                UndoEntry::Node(key, node) => restore(&mut db.vault, key, node),
+                UndoEntry::NodeRef(node_ref, true) => { db.node_refs.insert(node_ref); }
+                UndoEntry::NodeRef(node_ref, false) => { db.node_refs.remove(&node_ref); }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn restore<K, V>(map: &mut HashMap<K, V>, key: K, value: Option<V>)
+    where K: Eq + std::hash::Hash
+{
+    if let Some(value) = value {
+        map.insert(key, value);
+    } else {
+        map.remove(&key);
+    }
 }
 
 fn node_match(template: &VaultNode, node: &VaultNode) -> bool {
@@ -333,12 +665,6 @@ fn node_match(template: &VaultNode, node: &VaultNode) -> bool {
     if template.has_text_2() && node.text_2() != template.text_2() {
         return false;
     }
-    if template.has_blob_1() && node.blob_1() != template.blob_1() {
-        return false;
-    }
-    if template.has_blob_2() && node.blob_2() != template.blob_2() {
-        return false;
-    }
     true
 }
 
@@ -440,3 +766,90 @@ fn update_node(old_node: &VaultNode, new_node: &VaultNode) -> Arc<VaultNode> {
 
     Arc::new(node)
 }
+
+#[test]
+fn test_node_id_allocation() {
+    use crate::vault::VaultFolderNode;
+
+    let db = DbMemory::new();
+    let folder = || VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::GlobalInboxFolder);
+    assert_eq!(db.create_node(folder()), Ok(1));
+    assert_eq!(db.create_node(folder()), Ok(2));
+    assert_eq!(db.create_node(folder()), Ok(3));
+    assert_eq!(db.fetch_node(2).unwrap().node_id(), 2);
+
+    // Allocation continues after the highest node ID in an imported vault
+    let backup = db.export_vault(false).unwrap();
+    let imported = DbMemory::new();
+    imported.import_vault(backup).unwrap();
+    assert_eq!(imported.create_node(folder()), Ok(4));
+}
+
+#[test]
+fn test_set_all_players_offline() {
+    use crate::vault::VaultPlayerInfoNode;
+
+    let db = DbMemory::new();
+    for (player_id, online) in [(1, 1), (2, 0), (3, 1)] {
+        let node_id = db.create_node(VaultPlayerInfoNode::new(&Uuid::nil(), player_id,
+                                                              "Player")).unwrap();
+        db.update_node(VaultPlayerInfoNode::new_update(node_id, online, "", &Uuid::nil()))
+                .unwrap();
+    }
+
+    assert_eq!(db.set_all_players_offline(), Ok(2));
+    assert_eq!(db.find_nodes(VaultPlayerInfoNode::new_lookup(Some(1))), Ok(Vec::new()));
+    assert_eq!(db.find_nodes(VaultPlayerInfoNode::new_lookup(Some(0))).map(|ids| ids.len()),
+               Ok(3));
+    assert_eq!(db.set_all_players_offline(), Ok(0));
+}
+
+#[test]
+fn test_transaction_rollback() {
+    use crate::vault::VaultFolderNode;
+
+    let db = DbMemory::new();
+    let folder = || VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::GlobalInboxFolder);
+    assert_eq!(db.create_node(folder()), Ok(1));
+
+    db.begin_transaction().unwrap();
+    assert_eq!(db.begin_transaction(), Err(NetResultCode::NetInternalError));
+    assert_eq!(db.create_node(folder()), Ok(2));
+    db.ref_node(1, 2, 0).unwrap();
+    db.rollback_transaction().unwrap();
+    assert_eq!(db.fetch_node(2).err(), Some(NetResultCode::NetVaultNodeNotFound));
+    assert_eq!(db.fetch_refs(1, false), Ok(Vec::new()));
+
+    // Changes to existing records are undone in reverse order
+    let account = db.get_account("Tester").unwrap().unwrap();
+    db.begin_transaction().unwrap();
+    let mut changed = account.clone();
+    changed.login_token = "0123456789abcdef".to_string();
+    db.update_account(changed.clone()).unwrap();
+    changed.account_flags = 0;
+    db.update_account(changed).unwrap();
+    let mut node = VaultNode::default();
+    node.set_node_id(1);
+    node.set_string64_1("Changed");
+    db.update_node(node).unwrap();
+    db.create_player(&account.account_id, PlayerInfo {
+        player_id: 1,
+        player_name: "Test Player".to_string(),
+        avatar_shape: "female".to_string(),
+        explorer: 1,
+    }).unwrap();
+    db.get_account("Someone Else").unwrap();
+    db.rollback_transaction().unwrap();
+    assert!(db.get_existing_account("Tester").unwrap() == Some(account.clone()));
+    assert!(db.get_existing_account("Someone Else").unwrap().is_none());
+    assert!(db.fetch_node(1).unwrap().string64_1().is_empty());
+    assert!(db.get_players(&account.account_id).unwrap().is_empty());
+
+    // Rolled back node IDs are not reused
+    db.begin_transaction().unwrap();
+    assert_eq!(db.create_node(folder()), Ok(3));
+    db.commit_transaction().unwrap();
+    assert!(db.fetch_node(3).is_ok());
+    assert_eq!(db.commit_transaction(), Err(NetResultCode::NetInternalError));
+    assert_eq!(db.rollback_transaction(), Err(NetResultCode::NetInternalError));
+}
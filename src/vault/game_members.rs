@@ -0,0 +1,210 @@
+/* This file is part of moulars.
+ *
+ * moulars is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * moulars is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::netcli::{NetResult, NetResultCode};
+use super::db_interface::DbInterface;
+use super::VaultPlayerInfoNode;
+
+struct Membership {
+    temporary: bool,
+    players: HashSet<u32>,
+    empty_since: Option<Instant>,
+}
+
+// Population counts are cached for a short time, since they are otherwise
+// expensive to look up for every Age in the public Age list.
+struct PopulationCache {
+    entries: HashMap<Uuid, (u32, Instant)>,
+    ttl: Duration,
+}
+
+impl PopulationCache {
+    fn get(&mut self, instance_id: &Uuid, now: Instant, db: &dyn DbInterface)
+        -> NetResult<u32>
+    {
+        if let Some((count, updated)) = self.entries.get(instance_id) {
+            if now.duration_since(*updated) < self.ttl {
+                return Ok(*count);
+            }
+        }
+
+        let mut template = VaultPlayerInfoNode::new_lookup(Some(1));
+        template.set_uuid_1(instance_id);
+        let count = u32::try_from(db.find_nodes(template)?.len()).unwrap_or(u32::MAX);
+        self.entries.insert(*instance_id, (count, now));
+        Ok(count)
+    }
+
+    fn invalidate(&mut self, instance_id: &Uuid) {
+        self.entries.remove(instance_id);
+    }
+
+    fn purge(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, (_, updated)| now.duration_since(*updated) < ttl);
+    }
+}
+
+// Tracks which players are in each running Age instance, so temporary
+// instances can be cleaned up once they have been empty for long enough.
+pub(super) struct GameMembers {
+    instances: HashMap<Uuid, Membership>,
+    grace_period: Duration,
+    populations: PopulationCache,
+}
+
+impl GameMembers {
+    pub fn new(grace_period: Duration, population_ttl: Duration) -> Self {
+        Self {
+            instances: HashMap::new(),
+            grace_period,
+            populations: PopulationCache {
+                entries: HashMap::new(),
+                ttl: population_ttl,
+            },
+        }
+    }
+
+    pub fn population(&mut self, instance_id: &Uuid, now: Instant, db: &dyn DbInterface)
+        -> NetResult<u32>
+    {
+        self.populations.get(instance_id, now, db)
+    }
+
+    // Returns the number of players currently joined to the instance, as
+    // tracked by the membership map (not the cached vault population).
+    pub fn member_count(&self, instance_id: &Uuid) -> u32 {
+        self.instances.get(instance_id).map_or(0, |membership| {
+            u32::try_from(membership.players.len()).unwrap_or(u32::MAX)
+        })
+    }
+
+    // Returns the instances which are currently running (including empty
+    // ones which haven't been reaped yet)
+    pub fn active_instances(&self) -> HashSet<Uuid> {
+        self.instances.keys().copied().collect()
+    }
+
+    pub fn join(&mut self, instance_id: &Uuid, player_id: u32, db: &dyn DbInterface)
+        -> NetResult<()>
+    {
+        self.populations.invalidate(instance_id);
+        if let Some(membership) = self.instances.get_mut(instance_id) {
+            membership.players.insert(player_id);
+            membership.empty_since = None;
+            return Ok(());
+        }
+
+        let Some(server) = db.get_game_server(instance_id)? else {
+            warn!("Player {} joined unknown Age instance {}", player_id, instance_id);
+            return Err(NetResultCode::NetAgeNotFound);
+        };
+        self.instances.insert(*instance_id, Membership {
+            temporary: server.temporary,
+            players: HashSet::from([player_id]),
+            empty_since: None,
+        });
+        Ok(())
+    }
+
+    pub fn leave(&mut self, instance_id: &Uuid, player_id: u32, now: Instant) {
+        self.populations.invalidate(instance_id);
+        if let Some(membership) = self.instances.get_mut(instance_id) {
+            if membership.players.remove(&player_id) && membership.players.is_empty() {
+                membership.empty_since = Some(now);
+            }
+        }
+    }
+
+    // Drops any instances which have been empty for longer than the grace
+    // period.  Temporary instances are also removed from the database.
+    pub fn reap(&mut self, now: Instant, db: &dyn DbInterface) {
+        self.populations.purge(now);
+
+        let expired: Vec<Uuid> = self.instances.iter().filter_map(|(instance_id, membership)| {
+            match membership.empty_since {
+                Some(since) if now.duration_since(since) >= self.grace_period => {
+                    Some(*instance_id)
+                }
+                _ => None,
+            }
+        }).collect();
+
+        for instance_id in expired {
+            let Some(membership) = self.instances.remove(&instance_id) else {
+                continue;
+            };
+            if !membership.temporary {
+                continue;
+            }
+            match db.remove_game_server(&instance_id) {
+                Ok(()) => info!("Removed empty temporary Age instance {}", instance_id),
+                Err(err) => {
+                    warn!("Failed to remove temporary Age instance {}: {:?}",
+                          instance_id, err);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_reap_temporary_instances() {
+    use super::db_memory::DbMemory;
+    use super::GameServer;
+
+    let db = DbMemory::new();
+    let temp_instance = Uuid::new_v4();
+    let persistent_instance = Uuid::new_v4();
+    for (instance_id, temporary) in [(temp_instance, true), (persistent_instance, false)] {
+        db.add_game_server(GameServer {
+            instance_id,
+            age_filename: "Garden".to_string(),
+            display_name: "Eder Kemo".to_string(),
+            age_id: 0,
+            sdl_id: 0,
+            temporary,
+        }).unwrap();
+    }
+
+    let grace_period = Duration::from_secs(60);
+    let mut members = GameMembers::new(grace_period, Duration::from_secs(10));
+    members.join(&temp_instance, 1001, &db).unwrap();
+    members.join(&persistent_instance, 1002, &db).unwrap();
+    assert_eq!(members.join(&Uuid::new_v4(), 1003, &db), Err(NetResultCode::NetAgeNotFound));
+    assert_eq!(members.member_count(&temp_instance), 1);
+
+    let start = Instant::now();
+    members.leave(&temp_instance, 1001, start);
+    members.leave(&persistent_instance, 1002, start);
+    assert_eq!(members.member_count(&temp_instance), 0);
+
+    // Nothing should be removed before the grace period expires
+    members.reap(start + grace_period / 2, &db);
+    assert!(db.get_game_server(&temp_instance).unwrap().is_some());
+    assert!(db.get_game_server(&persistent_instance).unwrap().is_some());
+
+    members.reap(start + grace_period, &db);
+    assert!(db.get_game_server(&temp_instance).unwrap().is_none());
+    assert!(db.get_game_server(&persistent_instance).unwrap().is_some());
+    assert!(members.instances.is_empty());
+}
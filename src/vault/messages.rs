@@ -16,22 +16,45 @@
 
 use std::sync::Arc;
 
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
-use crate::netcli::NetResult;
-use super::db_interface::{AccountInfo, PlayerInfo, GameServer};
-use super::{VaultNode, NodeRef};
+use crate::hashes::ShaDigest;
+use crate::netcli::{KickReason, NetResult};
+use super::db_interface::{AccountInfo, PlayerInfo, GameServer, NodeOwner};
+use super::{VaultNode, NodeRef, VaultBackup};
 
 pub(super) enum VaultMessage {
     GetAccount {
         account_name: String,
         response_send: oneshot::Sender<NetResult<Option<AccountInfo>>>,
     },
+    CreateAccount {
+        account_name: String,
+        pass_hash: ShaDigest,
+        account_flags: u32,
+        response_send: oneshot::Sender<NetResult<AccountInfo>>,
+    },
     GetAccountForToken {
         api_token: String,
         response_send: oneshot::Sender<NetResult<Option<AccountInfo>>>,
     },
+    GetAccountForLoginToken {
+        login_token: String,
+        response_send: oneshot::Sender<NetResult<Option<AccountInfo>>>,
+    },
+    UpdateAccount {
+        account: AccountInfo,
+        response_send: oneshot::Sender<NetResult<()>>,
+    },
+    GetAccountById {
+        account_id: Uuid,
+        response_send: oneshot::Sender<NetResult<Option<AccountInfo>>>,
+    },
+    GetExistingAccount {
+        account_name: String,
+        response_send: oneshot::Sender<NetResult<Option<AccountInfo>>>,
+    },
     GetPlayers {
         account_id: Uuid,
         response_send: oneshot::Sender<NetResult<Vec<PlayerInfo>>>,
@@ -46,6 +69,18 @@ pub(super) enum VaultMessage {
         game_server: GameServer,
         response_send: oneshot::Sender<NetResult<()>>,
     },
+    GetGameServer {
+        instance_id: Uuid,
+        response_send: oneshot::Sender<NetResult<Option<GameServer>>>,
+    },
+    GetMemberCount {
+        instance_id: Uuid,
+        response_send: oneshot::Sender<NetResult<u32>>,
+    },
+    GetPopulations {
+        instance_ids: Vec<Uuid>,
+        response_send: oneshot::Sender<NetResult<Vec<u32>>>,
+    },
     CreateNode {
         node: Box<VaultNode>,
         response_send: oneshot::Sender<NetResult<u32>>,
@@ -54,6 +89,10 @@ pub(super) enum VaultMessage {
         node_id: u32,
         response_send: oneshot::Sender<NetResult<Arc<VaultNode>>>,
     },
+    FetchNodes {
+        node_ids: Vec<u32>,
+        response_send: oneshot::Sender<NetResult<Vec<Arc<VaultNode>>>>,
+    },
     UpdateNode {
         node: Box<VaultNode>,
         response_send: oneshot::Sender<NetResult<()>>,
@@ -62,12 +101,30 @@ pub(super) enum VaultMessage {
         template: Box<VaultNode>,
         response_send: oneshot::Sender<NetResult<Vec<u32>>>,
     },
+    ListAgeInstances {
+        age_filename: Option<String>,
+        offset: usize,
+        limit: usize,
+        response_send: oneshot::Sender<NetResult<(usize, Vec<Arc<VaultNode>>)>>,
+    },
+    FindOrCreateNode {
+        template: Box<VaultNode>,
+        node: Box<VaultNode>,
+        response_send: oneshot::Sender<NetResult<(u32, bool)>>,
+    },
     GetSystemNode {
         response_send: oneshot::Sender<NetResult<u32>>,
     },
     GetAllPlayersNode {
         response_send: oneshot::Sender<NetResult<u32>>,
     },
+    GetCcrPlayersNode {
+        response_send: oneshot::Sender<NetResult<u32>>,
+    },
+    GetNodeOwners {
+        node_id: u32,
+        response_send: oneshot::Sender<NetResult<Vec<NodeOwner>>>,
+    },
     GetPlayerInfoNode {
         player_id: u32,
         response_send: oneshot::Sender<NetResult<Arc<VaultNode>>>,
@@ -83,7 +140,30 @@ pub(super) enum VaultMessage {
         parent: u32,
         recursive: bool,
         response_send: oneshot::Sender<NetResult<Vec<NodeRef>>>,
-    }
+    },
+    FetchRefTree {
+        parent: u32,
+        max_refs: usize,
+        response_send: oneshot::Sender<NetResult<Vec<NodeRef>>>,
+    },
+    ExportVault {
+        include_accounts: bool,
+        response_send: oneshot::Sender<NetResult<VaultBackup>>,
+    },
+    ImportVault {
+        backup: Box<VaultBackup>,
+        response_send: oneshot::Sender<NetResult<()>>,
+    },
+    BeginTransaction {
+        txn_recv: mpsc::Receiver<VaultMessage>,
+        response_send: oneshot::Sender<NetResult<()>>,
+    },
+    CommitTransaction {
+        response_send: oneshot::Sender<NetResult<()>>,
+    },
+    RollbackTransaction {
+        response_send: oneshot::Sender<NetResult<()>>,
+    },
 }
 
 #[derive(Clone, Debug)]
@@ -97,4 +177,11 @@ pub enum VaultBroadcast {
         child_id: u32,
         owner_id: u32,
     },
+    AccountChanged {
+        account_id: Uuid,
+    },
+    KickPlayer {
+        player_id: u32,
+        reason: KickReason,
+    },
 }
@@ -14,26 +14,31 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+mod backup;
+pub use backup::VaultBackup;
+
 mod db_interface;
-pub use db_interface::{PlayerInfo, GameServer};
+pub use db_interface::{AccountInfo, PlayerInfo, GameServer, NodeOwner};
 
 mod db_memory;
 
+mod game_members;
+
 pub mod messages;
 
 mod node_ref;
 pub use node_ref::NodeRef;
 
 mod server;
-pub use server::VaultServer;
+pub use server::{VaultServer, VaultTransaction};
 
 mod vault_node;
-pub use vault_node::{VaultNode, StandardNode};
+pub use vault_node::{VaultNode, NodeType, StandardNode};
 
 mod vnode_access;
 pub use vnode_access::{
     VaultPlayerNode, VaultAgeNode, VaultFolderNode, VaultPlayerInfoNode,
     VaultSystemNode, VaultImageNode, VaultTextNoteNode, VaultSdlNode,
     VaultAgeLinkNode, VaultChronicleNode, VaultPlayerInfoListNode,
-    VaultAgeInfoNode, VaultAgeInfoListNode, VaultMarkerGameNode
+    VaultAgeInfoNode, VaultAgeInfoListNode, VaultMarkerGameNode, SpawnPointInfo
 };
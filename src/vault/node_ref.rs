@@ -14,12 +14,12 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
-use std::io::Write;
+use std::io::{BufRead, Write};
 
 use anyhow::Result;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::plasma::StreamWrite;
+use crate::plasma::{StreamRead, StreamWrite};
 
 #[derive(Eq, PartialEq, Hash, Copy, Clone, Debug)]
 pub struct NodeRef {
@@ -38,6 +38,18 @@ impl NodeRef {
     pub fn owner(&self) -> u32 { self.owner_id }
 }
 
+impl StreamRead for NodeRef {
+    fn stream_read<S>(stream: &mut S) -> Result<Self>
+        where S: BufRead
+    {
+        let parent_id = stream.read_u32::<LittleEndian>()?;
+        let child_id = stream.read_u32::<LittleEndian>()?;
+        let owner_id = stream.read_u32::<LittleEndian>()?;
+        let _ = stream.read_u8()?;  // Seen
+        Ok(Self { parent_id, child_id, owner_id })
+    }
+}
+
 impl StreamWrite for NodeRef {
     fn stream_write(&self, stream: &mut dyn Write) -> Result<()> {
         stream.write_u32::<LittleEndian>(self.parent_id)?;
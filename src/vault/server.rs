@@ -14,30 +14,42 @@
  * along with moulars.  If not, see <http://www.gnu.org/licenses/>.
  */
 
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{info, warn};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
 use tokio::sync::{mpsc, oneshot, broadcast};
 use uuid::Uuid;
 
+use crate::auth_srv::auth_hash::valid_account_name;
 use crate::config::{ServerConfig, VaultDbBackend};
-use crate::netcli::{NetResult, NetResultCode};
+use crate::hashes::ShaDigest;
+use crate::netcli::{KickReason, NetResult, NetResultCode};
 use crate::sdl::DescriptorDb;
-use super::db_interface::{DbInterface, AccountInfo, PlayerInfo, GameServer};
+use super::db_interface::{DbInterface, AccountInfo, PlayerInfo, GameServer, NodeOwner};
 use super::db_memory::DbMemory;
+use super::game_members::GameMembers;
 use super::messages::{VaultMessage, VaultBroadcast};
 use super::{
     VaultNode, VaultPlayerNode, VaultFolderNode, VaultSystemNode,
-    VaultPlayerInfoListNode, StandardNode, NodeRef
+    VaultPlayerInfoNode, VaultPlayerInfoListNode, VaultChronicleNode, VaultAgeLinkNode,
+    VaultAgeInfoNode, VaultBackup, StandardNode, NodeRef
 };
 
 pub struct VaultServer {
     msg_send: mpsc::Sender<VaultMessage>,
     broadcast: broadcast::Sender<VaultBroadcast>,
-    sdl_db: DescriptorDb,
+    sdl_db: Arc<DescriptorDb>,
+    compress_sdl: bool,
+    snapshot_file: Option<PathBuf>,
 }
 
 const MAX_PLAYERS: u64 = 5;
+const REAP_INTERVAL: Duration = Duration::from_secs(30);
+const TXN_BROADCAST_SIZE: usize = 100;
+const TXN_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
 
 fn check_send<T>(sender: oneshot::Sender<NetResult<T>>, reply: NetResult<T>) {
     if sender.send(reply).is_err() {
@@ -51,16 +63,49 @@ fn check_bcast(sender: &broadcast::Sender<VaultBroadcast>, msg: VaultBroadcast)
     }
 }
 
+// Plasma never searches by blob contents, and matching an entire blob is
+// expensive, so don't allow it to reach the backend.
+fn check_search_template(template: &VaultNode) -> NetResult<()> {
+    if template.has_blob_1() || template.has_blob_2() {
+        warn!("Rejecting node search with blob fields in the template");
+        return Err(NetResultCode::NetInvalidParameter);
+    }
+    Ok(())
+}
+
 fn process_vault_message(msg: VaultMessage, bcast_send: &broadcast::Sender<VaultBroadcast>,
-                         db: &dyn DbInterface)
+                         db: &dyn DbInterface, members: &mut GameMembers)
 {
     match msg {
         VaultMessage::GetAccount { account_name, response_send } => {
             check_send(response_send, db.get_account(&account_name));
         }
+        VaultMessage::CreateAccount {
+            account_name, pass_hash, account_flags, response_send
+        } => {
+            check_send(response_send, db.create_account(&account_name, pass_hash,
+                                                        account_flags));
+        }
         VaultMessage::GetAccountForToken { api_token, response_send } => {
             check_send(response_send, db.get_account_for_token(&api_token));
         }
+        VaultMessage::GetAccountForLoginToken { login_token, response_send } => {
+            check_send(response_send, db.get_account_for_login_token(&login_token));
+        }
+        VaultMessage::GetAccountById { account_id, response_send } => {
+            check_send(response_send, db.get_account_by_id(&account_id));
+        }
+        VaultMessage::GetExistingAccount { account_name, response_send } => {
+            check_send(response_send, db.get_existing_account(&account_name));
+        }
+        VaultMessage::UpdateAccount { account, response_send } => {
+            let account_id = account.account_id;
+            if let Err(err) = db.update_account(account) {
+                return check_send(response_send, Err(err));
+            }
+            check_bcast(bcast_send, VaultBroadcast::AccountChanged { account_id });
+            check_send(response_send, Ok(()));
+        }
         VaultMessage::GetPlayers { account_id, response_send } => {
             check_send(response_send, db.get_players(&account_id));
         }
@@ -104,17 +149,40 @@ fn process_vault_message(msg: VaultMessage, bcast_send: &broadcast::Sender<Vault
         VaultMessage::AddGameServer { game_server, response_send } => {
             check_send(response_send, db.add_game_server(game_server));
         }
+        VaultMessage::GetGameServer { instance_id, response_send } => {
+            check_send(response_send, db.get_game_server(&instance_id));
+        }
+        VaultMessage::GetMemberCount { instance_id, response_send } => {
+            check_send(response_send, Ok(members.member_count(&instance_id)));
+        }
+        VaultMessage::GetPopulations { instance_ids, response_send } => {
+            let now = Instant::now();
+            let populations = instance_ids.iter()
+                    .map(|instance_id| members.population(instance_id, now, db))
+                    .collect();
+            check_send(response_send, populations);
+        }
         VaultMessage::CreateNode { node, response_send } => {
             check_send(response_send, db.create_node(*node));
         }
         VaultMessage::FetchNode { node_id, response_send } => {
             check_send(response_send, db.fetch_node(node_id));
         }
+        VaultMessage::FetchNodes { node_ids, response_send } => {
+            let nodes = node_ids.into_iter().map(|node_id| db.fetch_node(node_id)).collect();
+            check_send(response_send, nodes);
+        }
         VaultMessage::UpdateNode { node, response_send } => {
+            let old_location = db.fetch_node(node.node_id()).ok()
+                    .and_then(|node| node.as_player_info_node())
+                    .map(|player_info| game_location(&player_info));
             let updated = match db.update_node(*node) {
                 Ok(nodes) => nodes,
                 Err(err) => return check_send(response_send, Err(err)),
             };
+            if let Some(old_location) = old_location {
+                update_membership(old_location, &updated, db, members);
+            }
             for node_id in updated {
                 check_bcast(bcast_send, VaultBroadcast::NodeChanged {
                     node_id,
@@ -126,12 +194,34 @@ fn process_vault_message(msg: VaultMessage, bcast_send: &broadcast::Sender<Vault
         VaultMessage::FindNodes { template, response_send } => {
             check_send(response_send, db.find_nodes(*template));
         }
+        VaultMessage::ListAgeInstances { age_filename, offset, limit, response_send } => {
+            let active = members.active_instances();
+            check_send(response_send,
+                       db.list_age_instances(age_filename.as_deref(), &active, offset, limit));
+        }
+        VaultMessage::FindOrCreateNode { template, node, response_send } => {
+            // Since the vault processes one message at a time, no other
+            // request can create a matching node in between these.
+            let result = db.find_nodes(*template).and_then(|node_ids| {
+                match node_ids.first() {
+                    Some(node_id) => Ok((*node_id, false)),
+                    None => db.create_node(*node).map(|node_id| (node_id, true)),
+                }
+            });
+            check_send(response_send, result);
+        }
         VaultMessage::GetSystemNode { response_send } => {
             check_send(response_send, db.get_system_node());
         }
         VaultMessage::GetAllPlayersNode { response_send } => {
             check_send(response_send, db.get_all_players_node());
         }
+        VaultMessage::GetCcrPlayersNode { response_send } => {
+            check_send(response_send, db.get_ccr_players_node());
+        }
+        VaultMessage::GetNodeOwners { node_id, response_send } => {
+            check_send(response_send, db.find_node_owners(node_id));
+        }
         VaultMessage::GetPlayerInfoNode { player_id, response_send } => {
             check_send(response_send, db.get_player_info_node(player_id));
         }
@@ -150,6 +240,116 @@ fn process_vault_message(msg: VaultMessage, bcast_send: &broadcast::Sender<Vault
         VaultMessage::FetchRefs { parent, recursive, response_send } => {
             check_send(response_send, db.fetch_refs(parent, recursive));
         }
+        VaultMessage::FetchRefTree { parent, max_refs, response_send } => {
+            check_send(response_send, db.fetch_ref_tree(parent, max_refs));
+        }
+        VaultMessage::ExportVault { include_accounts, response_send } => {
+            check_send(response_send, db.export_vault(include_accounts));
+        }
+        VaultMessage::ImportVault { backup, response_send } => {
+            let account_ids: Vec<Uuid> = backup.accounts.iter().flatten()
+                    .map(|account| account.account_id).collect();
+            if let Err(err) = db.import_vault(*backup) {
+                return check_send(response_send, Err(err));
+            }
+            for account_id in account_ids {
+                check_bcast(bcast_send, VaultBroadcast::AccountChanged { account_id });
+            }
+            check_send(response_send, Ok(()));
+        }
+        VaultMessage::BeginTransaction { response_send, .. } => {
+            // Top-level transactions are handled by run_transaction, so
+            // this can only be reached from inside another transaction.
+            warn!("Nested vault transactions are not supported");
+            check_send(response_send, Err(NetResultCode::NetInternalError));
+        }
+        VaultMessage::CommitTransaction { response_send }
+        | VaultMessage::RollbackTransaction { response_send } => {
+            warn!("No vault transaction is active");
+            check_send(response_send, Err(NetResultCode::NetInternalError));
+        }
+    }
+}
+
+// Processes the requests of a single transaction until it is committed or
+// rolled back.  Everyone else's requests wait in the main queue meanwhile,
+// so a rollback can only undo the transaction's own changes.  To keep that
+// wait short, a transaction which goes idle for TXN_IDLE_TIMEOUT is rolled
+// back, and its remaining requests fail.  Broadcasts are held back until the
+// commit, so clients never hear about rolled back nodes.
+async fn run_transaction(mut txn_recv: mpsc::Receiver<VaultMessage>,
+                         response_send: oneshot::Sender<NetResult<()>>,
+                         bcast_send: &broadcast::Sender<VaultBroadcast>,
+                         db: &mut dyn DbInterface, members: &mut GameMembers)
+{
+    if let Err(err) = db.begin_transaction() {
+        return check_send(response_send, Err(err));
+    }
+    check_send(response_send, Ok(()));
+
+    // Broadcasts are collected after every request, so the channel only
+    // needs to hold those of a single request.  If one request still
+    // overflows it, the transaction can't be committed without losing them.
+    let (txn_bcast, mut txn_bcast_recv) = broadcast::channel(TXN_BROADCAST_SIZE);
+    let mut pending = Vec::new();
+    let mut lost_broadcasts = false;
+    loop {
+        let Ok(msg) = tokio::time::timeout(TXN_IDLE_TIMEOUT, txn_recv.recv()).await else {
+            warn!("Rolling back idle vault transaction");
+            if let Err(err) = db.rollback_transaction() {
+                warn!("Failed to roll back vault transaction: {:?}", err);
+            }
+            return;
+        };
+        match msg {
+            Some(VaultMessage::CommitTransaction { response_send }) => {
+                if lost_broadcasts {
+                    warn!("Rolling back vault transaction with too many broadcasts");
+                    if let Err(err) = db.rollback_transaction() {
+                        warn!("Failed to roll back vault transaction: {:?}", err);
+                    }
+                    return check_send(response_send, Err(NetResultCode::NetInternalError));
+                }
+                let result = db.commit_transaction();
+                if result.is_ok() {
+                    for msg in pending {
+                        check_bcast(bcast_send, msg);
+                    }
+                }
+                return check_send(response_send, result);
+            }
+            Some(VaultMessage::RollbackTransaction { response_send }) => {
+                return check_send(response_send, db.rollback_transaction());
+            }
+            Some(msg) => {
+                process_vault_message(msg, &txn_bcast, db, members);
+                loop {
+                    match txn_bcast_recv.try_recv() {
+                        Ok(msg) => pending.push(msg),
+                        Err(broadcast::error::TryRecvError::Lagged(_)) => lost_broadcasts = true,
+                        Err(_) => break,
+                    }
+                }
+            }
+            None => {
+                // The transaction was dropped without being committed
+                warn!("Rolling back abandoned vault transaction");
+                if let Err(err) = db.rollback_transaction() {
+                    warn!("Failed to roll back vault transaction: {:?}", err);
+                }
+                return;
+            }
+        }
+    }
+}
+
+fn open_backend(server_config: &ServerConfig) -> Result<Box<dyn DbInterface>> {
+    match server_config.db_type {
+        VaultDbBackend::None => Ok(Box::new(DbMemory::new())),
+        VaultDbBackend::Sqlite => Err(anyhow!("The sqlite vault backend is not implemented yet")),
+        VaultDbBackend::Postgres => {
+            Err(anyhow!("The postgres vault backend is not implemented yet"))
+        }
     }
 }
 
@@ -159,40 +359,117 @@ impl VaultServer {
         let (bcast_send, _) = broadcast::channel(100);
 
         let broadcast = bcast_send.clone();
+        let sdl_db = Arc::new(sdl_db);
+        let compress_sdl = server_config.compress_sdl_blobs;
+
+        // Snapshots are only useful for the volatile in-memory backend
+        let snapshot_file = if let VaultDbBackend::None = server_config.db_type {
+            server_config.snapshot_file.clone()
+        } else {
+            if server_config.snapshot_file.is_some() {
+                warn!("Ignoring snapshot_file for a persistent vault backend");
+            }
+            None
+        };
+        let restore_file = snapshot_file.clone();
+
         tokio::spawn(async move {
-            let db: Box<dyn DbInterface> =  match server_config.db_type {
-                VaultDbBackend::None => Box::new(DbMemory::new()),
-                VaultDbBackend::Sqlite => todo!(),
-                VaultDbBackend::Postgres => todo!(),
+            let mut db = match open_backend(&server_config) {
+                Ok(db) => db,
+                Err(err) => panic!("{err:#}"),
             };
 
+            if let Some(path) = restore_file.filter(|path| path.exists()) {
+                if let Err(err) = restore_snapshot(db.as_ref(), &path) {
+                    panic!("Failed to restore vault snapshot {}: {err:#}", path.display());
+                }
+                info!("Restored vault snapshot from {}", path.display());
+            }
+
+            // A restored snapshot may predate some of the standard nodes
             assert!(init_vault(db.as_ref()).is_ok(), "Failed to initialize vault.");
 
-            if db.set_all_players_offline().is_err() {
+            if reset_online_players(db.as_ref()).is_err() {
                 warn!("Failed to set all players offline.");
             }
 
             // TODO: Check and update Global SDL
             // TODO: Check and initialize static ages
 
-            while let Some(msg) = msg_recv.recv().await {
-                process_vault_message(msg, &bcast_send, db.as_ref());
+            let mut members = GameMembers::new(server_config.temp_instance_timeout,
+                                               server_config.age_population_ttl);
+            let mut reap_timer = tokio::time::interval(REAP_INTERVAL);
+            loop {
+                tokio::select! {
+                    msg = msg_recv.recv() => match msg {
+                        Some(VaultMessage::BeginTransaction { txn_recv, response_send }) => {
+                            run_transaction(txn_recv, response_send, &bcast_send,
+                                            db.as_mut(), &mut members).await;
+                        }
+                        Some(msg) => process_vault_message(msg, &bcast_send, db.as_ref(),
+                                                           &mut members),
+                        None => break,
+                    },
+                    _ = reap_timer.tick() => members.reap(Instant::now(), db.as_ref()),
+                }
             }
         });
-        Self { msg_send, broadcast, sdl_db }
+        Self { msg_send, broadcast, sdl_db, compress_sdl, snapshot_file }
+    }
+
+    // Saves a snapshot of the in-memory vault (if configured), so it can be
+    // restored the next time the server starts.  This does nothing for
+    // persistent vault backends.
+    pub async fn shutdown(&self) {
+        let Some(path) = &self.snapshot_file else {
+            return;
+        };
+        let backup = match self.export(true).await {
+            Ok(backup) => backup,
+            Err(err) => {
+                warn!("Failed to export vault snapshot: {:?}", err);
+                return;
+            }
+        };
+        match backup.write_file(path) {
+            Ok(()) => info!("Saved vault snapshot to {}", path.display()),
+            Err(err) => warn!("Failed to save vault snapshot to {}: {err:#}", path.display()),
+        }
     }
 
     pub fn sdl_db(&self) -> &DescriptorDb { &self.sdl_db }
 
+    // Whether new SDL nodes should store their blobs compressed
+    pub fn compress_sdl(&self) -> bool { self.compress_sdl }
+
     pub fn subscribe(&self) -> broadcast::Receiver<VaultBroadcast> {
         self.broadcast.subscribe()
     }
 
+    // Ask whichever auth client has this player active to disconnect.
+    pub fn kick_player(&self, player_id: u32, reason: KickReason) {
+        check_bcast(&self.broadcast, VaultBroadcast::KickPlayer { player_id, reason });
+    }
+
+    // Ask the auth clients of every online player to disconnect.
+    pub async fn kick_online_players(&self, reason: KickReason) -> NetResult<()> {
+        let template = VaultPlayerInfoNode::new_lookup(Some(1));
+        for node in self.fetch_nodes(self.find_nodes(template).await?).await? {
+            if let Some(player_info) = node.as_player_info_node() {
+                self.kick_player(player_info.player_id(), reason);
+            }
+        }
+        Ok(())
+    }
+
     async fn request<T>(&self, msg: VaultMessage, recv: oneshot::Receiver<NetResult<T>>)
         -> NetResult<T>
     {
+        // This can only fail for a transaction which has already been
+        // rolled back, since the main vault task runs until shutdown.
         if let Err(err) = self.msg_send.send(msg).await {
-            panic!("Failed to send message to vault: {err}");
+            warn!("Failed to send message to vault: {err}");
+            return Err(NetResultCode::NetInternalError);
         }
 
         recv.await.unwrap_or_else(|err| {
@@ -210,6 +487,23 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    pub async fn create_account(&self, account_name: &str, pass_hash: ShaDigest,
+                                account_flags: u32) -> NetResult<AccountInfo>
+    {
+        if !valid_account_name(account_name) {
+            return Err(NetResultCode::NetInvalidParameter);
+        }
+
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::CreateAccount {
+            account_name: account_name.to_string(),
+            pass_hash,
+            account_flags,
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
     pub async fn get_account_for_token(&self, api_token: &str)
             -> NetResult<Option<AccountInfo>>
     {
@@ -221,6 +515,48 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    pub async fn get_account_for_login_token(&self, login_token: &str)
+            -> NetResult<Option<AccountInfo>>
+    {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetAccountForLoginToken {
+            login_token: login_token.to_string(),
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    pub async fn get_account_by_id(&self, account_id: &Uuid)
+            -> NetResult<Option<AccountInfo>>
+    {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetAccountById {
+            account_id: *account_id,
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    // Unlike get_account, this never creates a new account
+    pub async fn get_existing_account(&self, account_name: &str)
+            -> NetResult<Option<AccountInfo>>
+    {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetExistingAccount {
+            account_name: account_name.to_string(),
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    // Replaces the stored account info for an existing account, e.g. to
+    // change its flags or ban it.
+    pub async fn update_account(&self, account: AccountInfo) -> NetResult<()> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::UpdateAccount { account, response_send };
+        self.request(request, response_recv).await
+    }
+
     pub async fn get_players(&self, account_id: &Uuid) -> NetResult<Vec<PlayerInfo>> {
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::GetPlayers {
@@ -249,6 +585,31 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    pub async fn get_game_server(&self, instance_id: &Uuid) -> NetResult<Option<GameServer>> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetGameServer { instance_id: *instance_id, response_send };
+        self.request(request, response_recv).await
+    }
+
+    // Returns the number of players joined to the Age instance.  Since joins
+    // and leaves are processed by the vault task, this is always consistent.
+    pub async fn get_member_count(&self, instance_id: &Uuid) -> NetResult<u32> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetMemberCount {
+            instance_id: *instance_id,
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    // Returns the number of players currently in each of the requested
+    // Age instances.  These counts may be slightly out of date.
+    pub async fn get_populations(&self, instance_ids: Vec<Uuid>) -> NetResult<Vec<u32>> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetPopulations { instance_ids, response_send };
+        self.request(request, response_recv).await
+    }
+
     pub async fn create_node(&self, node: VaultNode) -> NetResult<u32> {
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::CreateNode {
@@ -264,6 +625,13 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    // Fetches several nodes with a single vault request
+    pub async fn fetch_nodes(&self, node_ids: Vec<u32>) -> NetResult<Vec<Arc<VaultNode>>> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::FetchNodes { node_ids, response_send };
+        self.request(request, response_recv).await
+    }
+
     pub async fn update_node(&self, node: VaultNode) -> NetResult<()> {
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::UpdateNode {
@@ -274,6 +642,8 @@ impl VaultServer {
     }
 
     pub async fn find_nodes(&self, template: VaultNode) -> NetResult<Vec<u32>> {
+        check_search_template(&template)?;
+
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::FindNodes {
             template: Box::new(template),
@@ -282,6 +652,39 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    // Returns the Age Info nodes for a page of running Age instances, along
+    // with the total number of running instances.
+    pub async fn list_age_instances(&self, age_filename: Option<&str>, offset: usize,
+                                    limit: usize) -> NetResult<(usize, Vec<Arc<VaultNode>>)>
+    {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::ListAgeInstances {
+            age_filename: age_filename.map(str::to_string),
+            offset,
+            limit,
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    // Returns the first node matching the template, or creates `node` if
+    // there are no matches.  The returned flag is true if the node was
+    // created.  Concurrent calls with the same template will not create
+    // duplicate nodes.
+    pub async fn find_or_create_node(&self, template: VaultNode, node: VaultNode)
+        -> NetResult<(u32, bool)>
+    {
+        check_search_template(&template)?;
+
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::FindOrCreateNode {
+            template: Box::new(template),
+            node: Box::new(node),
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
     pub async fn get_system_node(&self) -> NetResult<u32> {
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::GetSystemNode { response_send };
@@ -294,6 +697,19 @@ impl VaultServer {
         self.request(request, response_recv).await
     }
 
+    pub async fn get_ccr_players_node(&self) -> NetResult<u32> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetCcrPlayersNode { response_send };
+        self.request(request, response_recv).await
+    }
+
+    // Returns every Player (and its account) whose vault tree contains the node
+    pub async fn node_owner(&self, node_id: u32) -> NetResult<Vec<NodeOwner>> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::GetNodeOwners { node_id, response_send };
+        self.request(request, response_recv).await
+    }
+
     pub async fn get_player_info_node(&self, player_id: u32) -> NetResult<Arc<VaultNode>> {
         let (response_send, response_recv) = oneshot::channel();
         let request = VaultMessage::GetPlayerInfoNode { player_id, response_send };
@@ -315,9 +731,246 @@ impl VaultServer {
         let request = VaultMessage::FetchRefs { parent, recursive, response_send };
         self.request(request, response_recv).await
     }
+
+    // Fetches the whole ref tree under parent, up to max_refs + 1 refs
+    pub async fn fetch_ref_tree(&self, parent: u32, max_refs: usize) -> NetResult<Vec<NodeRef>> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::FetchRefTree { parent, max_refs, response_send };
+        self.request(request, response_recv).await
+    }
+
+    // Finds one of the player's standard folders (e.g. the ChronicleFolder)
+    pub async fn find_player_folder(&self, player_id: u32, folder_type: StandardNode)
+        -> NetResult<Option<u32>>
+    {
+        let child_ids = self.fetch_refs(player_id, false).await?
+                .iter().map(NodeRef::child).collect();
+        Ok(self.fetch_nodes(child_ids).await?.iter().find_map(|node| {
+            node.as_folder_node().filter(|folder| folder.folder_type() == folder_type as i32)
+                .map(|folder| folder.node_id())
+        }))
+    }
+
+    // Fetches the children of a node, skipping any refs to missing nodes
+    async fn fetch_children(&self, parent: u32) -> NetResult<Vec<Arc<VaultNode>>> {
+        let mut children = Vec::new();
+        for node_ref in self.fetch_refs(parent, false).await? {
+            match self.fetch_node(node_ref.child()).await {
+                Ok(node) => children.push(node),
+                Err(NetResultCode::NetVaultNodeNotFound) => {
+                    warn!("Skipping reference to missing node {}", node_ref.child());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(children)
+    }
+
+    // Returns the links in the player's AgesIOwnFolder (e.g. to their Relto),
+    // along with the Age Info node for each linked Age.
+    pub async fn player_ages(&self, player_id: u32)
+        -> NetResult<Vec<(VaultAgeLinkNode, VaultAgeInfoNode)>>
+    {
+        let Some(folder_id) = self.find_player_folder(player_id,
+                                    StandardNode::AgesIOwnFolder).await? else {
+            return Ok(Vec::new());
+        };
+        let mut ages = Vec::new();
+        for link in self.fetch_children(folder_id).await? {
+            let Some(link) = link.as_age_link_node() else {
+                continue;
+            };
+            let age_info = self.fetch_children(link.node_id()).await?.iter()
+                    .find_map(VaultNode::as_age_info_node);
+            if let Some(age_info) = age_info {
+                ages.push((link, age_info));
+            }
+        }
+        Ok(ages)
+    }
+
+    async fn find_chronicle(&self, folder_id: u32, entry_name: &str)
+        -> NetResult<Option<Arc<VaultNode>>>
+    {
+        let child_ids = self.fetch_refs(folder_id, false).await?
+                .iter().map(NodeRef::child).collect();
+        Ok(self.fetch_nodes(child_ids).await?.into_iter().find(|node| {
+            node.as_chronicle_node().is_some_and(|chron| chron.entry_name() == entry_name)
+        }))
+    }
+
+    // Returns the value of a chronicle entry in the player's ChronicleFolder,
+    // or None if the entry does not exist.
+    pub async fn get_chronicle(&self, player_id: u32, entry_name: &str)
+        -> NetResult<Option<String>>
+    {
+        let Some(folder_id) = self.find_player_folder(player_id,
+                                    StandardNode::ChronicleFolder).await? else {
+            return Ok(None);
+        };
+        Ok(self.find_chronicle(folder_id, entry_name).await?
+                .map(|node| node.text_1().clone()))
+    }
+
+    // Sets the value of a chronicle entry in the player's ChronicleFolder,
+    // creating it if necessary.  Returns the chronicle's node ID.
+    pub async fn set_chronicle(&self, player_id: u32, entry_name: &str, entry_value: &str)
+        -> NetResult<u32>
+    {
+        let Some(folder_id) = self.find_player_folder(player_id,
+                                    StandardNode::ChronicleFolder).await? else {
+            warn!("Player {} has no ChronicleFolder", player_id);
+            return Err(NetResultCode::NetVaultNodeNotFound);
+        };
+
+        if let Some(chronicle) = self.find_chronicle(folder_id, entry_name).await? {
+            let mut node = VaultNode::default();
+            node.set_node_id(chronicle.node_id());
+            node.set_text_1(entry_value);
+            self.update_node(node).await?;
+            return Ok(chronicle.node_id());
+        }
+
+        let Some(player) = self.fetch_node(player_id).await?.as_player_node() else {
+            return Err(NetResultCode::NetPlayerNotFound);
+        };
+        let node = VaultChronicleNode::new(player.account_id(), player_id, 0,
+                                           entry_name, entry_value);
+        let node_id = self.create_node(node).await?;
+        self.ref_node(folder_id, node_id, player_id, true).await?;
+        Ok(node_id)
+    }
+
+    // Exports all vault nodes and refs to a snapshot that can be restored
+    // into another vault, regardless of the database backend.
+    pub async fn export(&self, include_accounts: bool) -> NetResult<VaultBackup> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::ExportVault { include_accounts, response_send };
+        self.request(request, response_recv).await
+    }
+
+    // Restores a snapshot created by export().  This is only allowed on a
+    // freshly initialized vault.
+    pub async fn import(&self, backup: VaultBackup) -> NetResult<()> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::ImportVault {
+            backup: Box::new(backup),
+            response_send
+        };
+        self.request(request, response_recv).await
+    }
+
+    // Starts a transaction, which has exclusive use of the vault until it is
+    // committed or rolled back.  While it is open, requests must be made
+    // through the transaction's own handle, since any made through this one
+    // will wait for the transaction to finish.
+    pub async fn begin_transaction(&self) -> NetResult<VaultTransaction> {
+        let (txn_send, txn_recv) = mpsc::channel(20);
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::BeginTransaction { txn_recv, response_send };
+        self.request(request, response_recv).await?;
+
+        Ok(VaultTransaction {
+            vault: Self {
+                msg_send: txn_send,
+                broadcast: self.broadcast.clone(),
+                sdl_db: self.sdl_db.clone(),
+                compress_sdl: self.compress_sdl,
+                snapshot_file: None,
+            }
+        })
+    }
+}
+
+// A transaction which is dropped without being committed is rolled back.
+pub struct VaultTransaction {
+    vault: VaultServer,
+}
+
+impl VaultTransaction {
+    pub fn vault(&self) -> &VaultServer { &self.vault }
+
+    pub async fn commit(self) -> NetResult<()> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::CommitTransaction { response_send };
+        self.vault.request(request, response_recv).await
+    }
+
+    pub async fn rollback(self) -> NetResult<()> {
+        let (response_send, response_recv) = oneshot::channel();
+        let request = VaultMessage::RollbackTransaction { response_send };
+        self.vault.request(request, response_recv).await
+    }
+
+    // Commits the transaction if the operation succeeded, or rolls it back
+    // and passes on the operation's error.
+    pub async fn finish<T>(self, result: NetResult<T>) -> NetResult<T> {
+        match result {
+            Ok(value) => self.commit().await.map(|()| value),
+            Err(err) => {
+                if let Err(rollback_err) = self.rollback().await {
+                    warn!("Failed to roll back vault transaction: {:?}", rollback_err);
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+// The Age instance a player is currently in, if they are online
+fn game_location(player_info: &VaultPlayerInfoNode) -> Option<Uuid> {
+    let instance_id = *player_info.age_instance_uuid();
+    (player_info.online() != 0 && !instance_id.is_nil()).then_some(instance_id)
+}
+
+// Players join and leave Age instances by updating their Player Info node,
+// so membership is tracked from changes to those nodes.
+fn update_membership(old_location: Option<Uuid>, updated: &[u32], db: &dyn DbInterface,
+                     members: &mut GameMembers)
+{
+    let Some(player_info) = updated.first()
+            .and_then(|node_id| db.fetch_node(*node_id).ok())
+            .and_then(|node| node.as_player_info_node()) else {
+        return;
+    };
+    let new_location = game_location(&player_info);
+    if new_location == old_location {
+        return;
+    }
+    let player_id = player_info.player_id();
+    if let Some(instance_id) = old_location {
+        members.leave(&instance_id, player_id, Instant::now());
+    }
+    if let Some(instance_id) = new_location {
+        // Errors (e.g. an instance without a game server) are logged by join
+        let _ = members.join(&instance_id, player_id, db);
+    }
 }
 
-fn init_vault(db: &dyn DbInterface) -> NetResult<()> {
+// Any players still marked online at startup were left behind by an unclean
+// shutdown of the server.
+fn reset_online_players(db: &dyn DbInterface) -> NetResult<()> {
+    for node_id in db.find_nodes(VaultPlayerInfoNode::new_lookup(None))? {
+        if let Some(player_info) = db.fetch_node(node_id)?.as_player_info_node()
+                .filter(|player_info| player_info.online() != 0)
+        {
+            debug!("Player {} ({}) was still marked online", player_info.player_name_ci(),
+                   player_info.player_id());
+        }
+    }
+    let count = db.set_all_players_offline()?;
+    if count > 0 {
+        info!("Marked {} stale player session(s) offline", count);
+    }
+    Ok(())
+}
+
+fn restore_snapshot(db: &dyn DbInterface, path: &Path) -> anyhow::Result<()> {
+    let backup = VaultBackup::read_file(path)?;
+    db.import_vault(backup).map_err(|err| anyhow!("Import failed: {:?}", err))
+}
+
+pub(super) fn init_vault(db: &dyn DbInterface) -> NetResult<()> {
     if let Err(err) = db.get_system_node() {
         if err != NetResultCode::NetVaultNodeNotFound {
             warn!("Failed to fetch system node");
@@ -338,5 +991,408 @@ fn init_vault(db: &dyn DbInterface) -> NetResult<()> {
         let _ = db.create_node(node)?;
     }
 
+    // Vaults created before CCR presence was supported don't have a
+    // CCRPlayersFolder yet
+    match db.get_ccr_players_node() {
+        Ok(_) => (),
+        Err(NetResultCode::NetVaultNodeNotFound) => {
+            info!("Adding missing CCRPlayersFolder to the Vault");
+            let node = VaultPlayerInfoListNode::new(&Uuid::nil(), 0,
+                                                    StandardNode::CCRPlayersFolder);
+            let _ = db.create_node(node)?;
+        }
+        Err(err) => return Err(err),
+    }
+
     Ok(())
 }
+
+#[test]
+fn test_init_vault_adds_ccr_players() {
+    let db = DbMemory::new();
+    let system_node = db.create_node(VaultSystemNode::new()).unwrap();
+    db.create_node(VaultPlayerInfoListNode::new(&Uuid::nil(), 0,
+                                                StandardNode::AllPlayersFolder)).unwrap();
+    assert_eq!(db.get_ccr_players_node(), Err(NetResultCode::NetVaultNodeNotFound));
+
+    init_vault(&db).unwrap();
+    assert_eq!(db.get_system_node(), Ok(system_node));
+    let ccr_players = db.get_ccr_players_node().unwrap();
+    init_vault(&db).unwrap();
+    assert_eq!(db.get_ccr_players_node(), Ok(ccr_players));
+}
+
+#[tokio::test]
+async fn test_account_lookups_agree() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    // Looking up an unknown ID must not create a new account
+    assert!(vault.get_account_by_id(&Uuid::new_v4()).await.unwrap().is_none());
+
+    let by_name = vault.get_account("Tester").await.unwrap().unwrap();
+    let by_id = vault.get_account_by_id(&by_name.account_id).await.unwrap().unwrap();
+    assert!(by_name == by_id);
+    let by_token = vault.get_account_for_token(&by_name.api_token).await.unwrap().unwrap();
+    assert!(by_name == by_token);
+}
+
+#[tokio::test]
+async fn test_find_nodes_rejects_blobs() {
+    use super::vault_node::NodeType;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let mut template = VaultNode::default();
+    template.set_blob_1(&vec![0x55; 1024 * 1024]);
+    assert_eq!(vault.find_nodes(template).await, Err(NetResultCode::NetInvalidParameter));
+
+    let mut template = VaultNode::default();
+    template.set_node_type(NodeType::System as i32);
+    template.set_blob_2(&[]);
+    assert_eq!(vault.find_nodes(template).await, Err(NetResultCode::NetInvalidParameter));
+
+    // The same template without the blob is fine
+    let mut template = VaultNode::default();
+    template.set_node_type(NodeType::System as i32);
+    assert_eq!(vault.find_nodes(template).await.map(|nodes| nodes.len()), Ok(1));
+}
+
+#[tokio::test]
+async fn test_set_chronicle() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let mut bcast_recv = vault.subscribe();
+
+    let account_id = Uuid::new_v4();
+    let node = VaultPlayerNode::new(&account_id, "Chronicler", "female", 1);
+    let player_id = vault.create_node(node).await.unwrap();
+    let node = VaultFolderNode::new(&account_id, player_id, StandardNode::ChronicleFolder);
+    let folder_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(player_id, folder_id, 0, false).await.unwrap();
+
+    assert_eq!(vault.get_chronicle(player_id, "MarkerGames").await, Ok(None));
+    let chronicle_id = vault.set_chronicle(player_id, "MarkerGames", "1").await.unwrap();
+    assert_eq!(vault.get_chronicle(player_id, "MarkerGames").await.unwrap().as_deref(),
+               Some("1"));
+
+    // Updating the value reuses the existing node
+    while bcast_recv.try_recv().is_ok() {}
+    assert_eq!(vault.set_chronicle(player_id, "MarkerGames", "2").await, Ok(chronicle_id));
+    assert_eq!(vault.get_chronicle(player_id, "MarkerGames").await.unwrap().as_deref(),
+               Some("2"));
+    assert!(matches!(bcast_recv.try_recv(),
+                     Ok(VaultBroadcast::NodeChanged { node_id, .. }) if node_id == chronicle_id));
+
+    let chronicle = vault.fetch_node(chronicle_id).await.unwrap();
+    let chronicle = chronicle.as_chronicle_node().unwrap();
+    assert_eq!(chronicle.entry_name(), "MarkerGames");
+    assert_eq!(vault.fetch_refs(folder_id, false).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_kick_player() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let mut bcast_recv = vault.subscribe();
+
+    vault.kick_player(42, KickReason::LoggedInElsewhere);
+    assert!(matches!(bcast_recv.try_recv(),
+                     Ok(VaultBroadcast::KickPlayer { player_id: 42,
+                                                     reason: KickReason::LoggedInElsewhere })));
+    assert_eq!(KickReason::LoggedInElsewhere.result_code() as i32,
+               NetResultCode::NetLoggedInElsewhere as i32);
+}
+
+#[tokio::test]
+async fn test_kick_online_players() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_id = Uuid::new_v4();
+    let online = VaultPlayerInfoNode::new(&account_id, 100, "Online");
+    let online_id = vault.create_node(online).await.unwrap();
+    let update = VaultPlayerInfoNode::new_update(online_id, 1, "Lobby", &Uuid::nil());
+    vault.update_node(update).await.unwrap();
+    vault.create_node(VaultPlayerInfoNode::new(&account_id, 101, "Offline")).await.unwrap();
+
+    // Only the online player is kicked
+    let mut bcast_recv = vault.subscribe();
+    vault.kick_online_players(KickReason::ServerShutdown).await.unwrap();
+    assert!(matches!(bcast_recv.try_recv(),
+                     Ok(VaultBroadcast::KickPlayer { player_id: 100,
+                                                     reason: KickReason::ServerShutdown })));
+    assert!(bcast_recv.is_empty());
+}
+
+#[tokio::test]
+async fn test_node_owner() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_a = Uuid::new_v4();
+    let node = VaultPlayerNode::new(&account_a, "Alice", "female", 1);
+    let player_a = vault.create_node(node).await.unwrap();
+    let account_b = Uuid::new_v4();
+    let node = VaultPlayerNode::new(&account_b, "Bob", "male", 1);
+    let player_b = vault.create_node(node).await.unwrap();
+
+    // A folder owned only by Alice, with a nested chronicle folder
+    let node = VaultFolderNode::new(&account_a, player_a, StandardNode::AgeJournalsFolder);
+    let private_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(player_a, private_id, 0, false).await.unwrap();
+    let node = VaultFolderNode::new(&account_a, player_a, StandardNode::ChronicleFolder);
+    let nested_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(private_id, nested_id, 0, false).await.unwrap();
+
+    // A folder shared by both players
+    let node = VaultFolderNode::new(&account_a, player_a, StandardNode::InboxFolder);
+    let shared_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(player_a, shared_id, 0, false).await.unwrap();
+    vault.ref_node(player_b, shared_id, 0, false).await.unwrap();
+
+    let alice = NodeOwner { player_id: player_a, account_id: account_a };
+    let bob = NodeOwner { player_id: player_b, account_id: account_b };
+    assert_eq!(vault.node_owner(nested_id).await, Ok(vec![alice]));
+    assert_eq!(vault.node_owner(private_id).await, Ok(vec![alice]));
+    assert_eq!(vault.node_owner(player_b).await, Ok(vec![bob]));
+
+    let mut owners = vec![alice, bob];
+    owners.sort_by_key(|owner| owner.player_id);
+    assert_eq!(vault.node_owner(shared_id).await, Ok(owners));
+
+    // Unreferenced nodes have no owner
+    let node = VaultFolderNode::new(&account_a, player_a, StandardNode::PeopleIKnowAboutFolder);
+    let orphan_id = vault.create_node(node).await.unwrap();
+    assert_eq!(vault.node_owner(orphan_id).await, Ok(Vec::new()));
+    assert_eq!(vault.node_owner(0xDEAD_BEEF).await, Err(NetResultCode::NetVaultNodeNotFound));
+}
+
+#[tokio::test]
+async fn test_create_account() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let pass_hash = ShaDigest::sha1(b"password");
+    let account = vault.create_account("NewAccount", pass_hash, AccountInfo::BETA_TESTER)
+            .await.unwrap();
+    assert_eq!(account.account_flags, AccountInfo::BETA_TESTER);
+    assert!(account.pass_hash == pass_hash);
+    let by_id = vault.get_account_by_id(&account.account_id).await.unwrap().unwrap();
+    assert!(by_id == account);
+    assert_ne!(account.api_token, ShaDigest::sha1(b"NewAccount").as_hex());
+    let by_token = vault.get_account_for_token(&account.api_token).await.unwrap().unwrap();
+    assert!(by_token == account);
+
+    // Names are unique, regardless of case
+    assert_eq!(vault.create_account("newaccount", pass_hash, 0).await.err(),
+               Some(NetResultCode::NetAccountAlreadyExists));
+    assert!(vault.get_account("Tester").await.unwrap().is_some());
+    assert_eq!(vault.create_account("Tester", pass_hash, 0).await.err(),
+               Some(NetResultCode::NetAccountAlreadyExists));
+
+    // Blank and over-long names are rejected
+    let long_name = "a".repeat(64);
+    for account_name in ["", "   ", long_name.as_str()] {
+        assert_eq!(vault.create_account(account_name, pass_hash, 0).await.err(),
+                   Some(NetResultCode::NetInvalidParameter));
+    }
+}
+
+#[tokio::test]
+async fn test_find_or_create_node() {
+    use super::VaultAgeNode;
+
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let instance_id = Uuid::new_v4();
+    let find_or_create = || {
+        let template = VaultAgeNode::new_lookup(Some(&instance_id));
+        let node = VaultAgeNode::new(&instance_id, &Uuid::nil(), "Teledahn");
+        vault.find_or_create_node(template, node)
+    };
+    let (first, second) = tokio::join!(find_or_create(), find_or_create());
+    let (first_id, first_created) = first.unwrap();
+    let (second_id, second_created) = second.unwrap();
+    assert_eq!(first_id, second_id);
+    assert!(first_created != second_created);
+
+    let template = VaultAgeNode::new_lookup(Some(&instance_id));
+    assert_eq!(vault.find_nodes(template).await, Ok(vec![first_id]));
+    assert_eq!(find_or_create().await, Ok((first_id, false)));
+
+    let mut template = VaultNode::default();
+    template.set_blob_1(&[]);
+    assert_eq!(vault.find_or_create_node(template, VaultNode::default()).await,
+               Err(NetResultCode::NetInvalidParameter));
+}
+
+#[tokio::test]
+async fn test_get_game_server() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let instance_id = Uuid::new_v4();
+    assert!(vault.get_game_server(&instance_id).await.unwrap().is_none());
+
+    vault.add_game_server(GameServer {
+        instance_id,
+        age_filename: "Teledahn".to_string(),
+        display_name: "Teledahn".to_string(),
+        age_id: 42,
+        sdl_id: 43,
+        temporary: false,
+    }).await.unwrap();
+    let game_server = vault.get_game_server(&instance_id).await.unwrap().unwrap();
+    assert_eq!(game_server.instance_id, instance_id);
+    assert_eq!(game_server.age_id, 42);
+    assert_eq!(game_server.sdl_id, 43);
+    assert!(vault.get_game_server(&Uuid::new_v4()).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_player_location_membership() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let instance_id = Uuid::new_v4();
+    vault.add_game_server(GameServer {
+        instance_id,
+        age_filename: "Teledahn".to_string(),
+        display_name: "Teledahn".to_string(),
+        age_id: 42,
+        sdl_id: 43,
+        temporary: true,
+    }).await.unwrap();
+
+    let account_id = Uuid::new_v4();
+    let node = VaultPlayerInfoNode::new(&account_id, 100, "Alice");
+    let info_id = vault.create_node(node).await.unwrap();
+    assert_eq!(vault.get_member_count(&instance_id).await, Ok(0));
+
+    let update = VaultPlayerInfoNode::new_update(info_id, 1, "Teledahn", &instance_id);
+    vault.update_node(update).await.unwrap();
+    assert_eq!(vault.get_member_count(&instance_id).await, Ok(1));
+
+    // Linking elsewhere leaves the previous instance
+    let update = VaultPlayerInfoNode::new_update(info_id, 1, "Relto", &Uuid::new_v4());
+    vault.update_node(update).await.unwrap();
+    assert_eq!(vault.get_member_count(&instance_id).await, Ok(0));
+
+    let update = VaultPlayerInfoNode::new_update(info_id, 1, "Teledahn", &instance_id);
+    vault.update_node(update).await.unwrap();
+    assert_eq!(vault.get_member_count(&instance_id).await, Ok(1));
+    let update = VaultPlayerInfoNode::new_update(info_id, 0, "", &Uuid::nil());
+    vault.update_node(update).await.unwrap();
+    assert_eq!(vault.get_member_count(&instance_id).await, Ok(0));
+}
+
+#[tokio::test]
+async fn test_player_ages() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+
+    let account_id = Uuid::new_v4();
+    let node = VaultPlayerNode::new(&account_id, "Alice", "female", 1);
+    let player_id = vault.create_node(node).await.unwrap();
+    assert_eq!(vault.player_ages(player_id).await.map(|ages| ages.len()), Ok(0));
+
+    let node = VaultFolderNode::new(&account_id, player_id, StandardNode::AgesIOwnFolder);
+    let folder_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(player_id, folder_id, 0, false).await.unwrap();
+    assert_eq!(vault.player_ages(player_id).await.map(|ages| ages.len()), Ok(0));
+
+    let mut expected = Vec::new();
+    for age_filename in ["Personal", "Neighborhood"] {
+        let instance_id = Uuid::new_v4();
+        let node = VaultAgeInfoNode::new(&instance_id, 0, 0, false, -1, &Uuid::nil(),
+                                         age_filename, age_filename, "", "");
+        let age_info_id = vault.create_node(node).await.unwrap();
+        let node = VaultAgeLinkNode::new(&account_id, player_id, b"Default:LinkInPointDefault");
+        let link_id = vault.create_node(node).await.unwrap();
+        vault.ref_node(folder_id, link_id, 0, false).await.unwrap();
+        vault.ref_node(link_id, age_info_id, 0, false).await.unwrap();
+        expected.push((link_id, instance_id));
+    }
+
+    // Links without an Age Info node are skipped
+    let node = VaultAgeLinkNode::new(&account_id, player_id, b"");
+    let broken_link_id = vault.create_node(node).await.unwrap();
+    vault.ref_node(folder_id, broken_link_id, 0, false).await.unwrap();
+
+    let mut ages: Vec<(u32, Uuid)> = vault.player_ages(player_id).await.unwrap().iter()
+            .map(|(link, age_info)| (link.node_id(), *age_info.age_instance_uuid()))
+            .collect();
+    ages.sort_unstable();
+    expected.sort_unstable();
+    assert_eq!(ages, expected);
+}
+
+#[tokio::test]
+async fn test_vault_snapshot() {
+    let snapshot_dir = tempfile::tempdir().unwrap();
+    let mut server_config = ServerConfig::test_config();
+    server_config.snapshot_file = Some(snapshot_dir.path().join("vault.snapshot"));
+    let server_config = Arc::new(server_config);
+
+    let vault = VaultServer::start(server_config.clone(), DescriptorDb::empty());
+    let account = vault.create_account("Snapshot", ShaDigest::sha1(b"pass"), 0)
+            .await.unwrap();
+    let node = VaultPlayerNode::new(&account.account_id, "Saved", "female", 1);
+    let player_id = vault.create_node(node).await.unwrap();
+    vault.shutdown().await;
+    assert!(server_config.snapshot_file.as_ref().unwrap().exists());
+
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let restored = vault.get_account("Snapshot").await.unwrap().unwrap();
+    assert_eq!(restored.account_id, account.account_id);
+    let player = vault.fetch_node(player_id).await.unwrap().as_player_node().unwrap();
+    assert_eq!(player.player_name_ci(), "Saved");
+}
+
+#[tokio::test]
+async fn test_transaction_broadcasts() {
+    let server_config = Arc::new(ServerConfig::test_config());
+    let vault = VaultServer::start(server_config, DescriptorDb::empty());
+    let mut bcast_recv = vault.subscribe();
+    let new_folder = || VaultFolderNode::new(&Uuid::nil(), 0, StandardNode::InboxFolder);
+    let parent_id = vault.create_node(new_folder()).await.unwrap();
+
+    // More broadcasts than the transaction channel holds at once
+    let ref_count = TXN_BROADCAST_SIZE + 20;
+    let txn = vault.begin_transaction().await.unwrap();
+    for _ in 0..ref_count {
+        let child_id = txn.vault().create_node(new_folder()).await.unwrap();
+        txn.vault().ref_node(parent_id, child_id, 0, true).await.unwrap();
+    }
+    assert!(bcast_recv.is_empty());
+    txn.commit().await.unwrap();
+
+    // The vault's own broadcast channel can still lag if nobody is reading,
+    // but every broadcast from the transaction is sent to it
+    let mut received = 0;
+    loop {
+        match bcast_recv.try_recv() {
+            Ok(VaultBroadcast::NodeAdded { parent_id: parent, .. }) => {
+                assert_eq!(parent, parent_id);
+                received += 1;
+            }
+            Ok(_) => panic!("Unexpected broadcast"),
+            Err(broadcast::error::TryRecvError::Lagged(count)) => {
+                received += usize::try_from(count).unwrap();
+            }
+            Err(_) => break,
+        }
+    }
+    assert_eq!(received, ref_count);
+
+    // Rolled back changes are never broadcast
+    let txn = vault.begin_transaction().await.unwrap();
+    let child_id = txn.vault().create_node(new_folder()).await.unwrap();
+    txn.vault().ref_node(parent_id, child_id, 0, true).await.unwrap();
+    txn.rollback().await.unwrap();
+    vault.fetch_node(parent_id).await.unwrap();
+    assert!(bcast_recv.is_empty());
+}
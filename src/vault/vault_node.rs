@@ -22,9 +22,12 @@ use std::sync::Arc;
 use anyhow::{anyhow, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use data_encoding::HEXLOWER;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use paste::paste;
 use uuid::Uuid;
 
+use crate::msg_trace::display_as_debug;
 use crate::plasma::{StreamRead, StreamWrite};
 use super::vnode_access::{
     VaultPlayerNode, VaultAgeNode, VaultFolderNode, VaultPlayerInfoNode,
@@ -34,6 +37,7 @@ use super::vnode_access::{
 };
 
 #[repr(i32)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, FromPrimitive)]
 pub enum NodeType {
     _Invalid,
     _VNodeMgrLow, Player, Age,
@@ -43,6 +47,7 @@ pub enum NodeType {
 }
 
 #[repr(i32)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, FromPrimitive)]
 pub enum StandardNode {
     UserDefined, InboxFolder, BuddyListFolder, IgnoreListFolder,
     PeopleIKnowAboutFolder, VaultMgrGlobalDataFolder, ChronicleFolder,
@@ -55,6 +60,28 @@ pub enum StandardNode {
     GlobalInboxFolder, ChildAgesFolder, GameScoresFolder,
 }
 
+display_as_debug!(NodeType, StandardNode);
+
+impl NodeType {
+    // Formats a raw node type for logging, e.g. "PlayerInfo (23)"
+    pub fn describe(node_type: i32) -> String {
+        match Self::from_i32(node_type) {
+            Some(known) => format!("{known} ({node_type})"),
+            None => format!("Unknown ({node_type})"),
+        }
+    }
+
+    // Looks up a node type by its (case-insensitive) name, e.g. "TextNote".
+    // Placeholder types which are never used for real nodes are excluded.
+    pub fn from_name(name: &str) -> Option<Self> {
+        (0..=NodeType::MarkerGame as i32).filter_map(Self::from_i32)
+            .find(|node_type| {
+                let type_name = node_type.to_string();
+                !type_name.starts_with('_') && type_name.eq_ignore_ascii_case(name)
+            })
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct VaultNode {
     fields: u64,
@@ -106,6 +133,10 @@ macro_rules! node_field {
                 self.fields |= [<FIELD_ $field_name:upper>];
                 self.$field_name = value.to_string();
             }
+            pub fn [<clear_ $field_name>](&mut self) {
+                self.fields &= ![<FIELD_ $field_name:upper>];
+                self.$field_name = Default::default();
+            }
         }
     };
     ($field_name:ident, Uuid) => {
@@ -120,6 +151,10 @@ macro_rules! node_field {
                 self.fields |= [<FIELD_ $field_name:upper>];
                 self.$field_name = *value;
             }
+            pub fn [<clear_ $field_name>](&mut self) {
+                self.fields &= ![<FIELD_ $field_name:upper>];
+                self.$field_name = Default::default();
+            }
         }
     };
     ($field_name:ident, Vec<u8>) => {
@@ -134,6 +169,10 @@ macro_rules! node_field {
                 self.fields |= [<FIELD_ $field_name:upper>];
                 self.$field_name = value.to_vec();
             }
+            pub fn [<clear_ $field_name>](&mut self) {
+                self.fields &= ![<FIELD_ $field_name:upper>];
+                self.$field_name = Default::default();
+            }
         }
     };
     ($field_name:ident, $value_type:ty) => {
@@ -148,6 +187,10 @@ macro_rules! node_field {
                 self.fields |= [<FIELD_ $field_name:upper>];
                 self.$field_name = value;
             }
+            pub fn [<clear_ $field_name>](&mut self) {
+                self.fields &= ![<FIELD_ $field_name:upper>];
+                self.$field_name = Default::default();
+            }
         }
     };
 }
@@ -344,6 +387,7 @@ const FIELD_TEXT_1: u64             = 1 << 28;
 const FIELD_TEXT_2: u64             = 1 << 29;
 const FIELD_BLOB_1: u64             = 1 << 30;
 const FIELD_BLOB_2: u64             = 1 << 31;
+const ALL_FIELDS: u64               = (FIELD_BLOB_2 << 1) - 1;
 
 macro_rules! debug_field {
     ($fmt:ident, $fields:ident, $field_name:ident, $value:expr) => {
@@ -373,7 +417,7 @@ impl Debug for VaultNode {
         debug_field!(fmt, fields, create_age_uuid, self.create_age_uuid);
         debug_field!(fmt, fields, creator_uuid, self.creator_uuid);
         debug_field!(fmt, fields, creator_id, self.creator_id);
-        debug_field!(fmt, fields, node_type, self.node_type);
+        debug_field!(fmt, fields, node_type, NodeType::describe(self.node_type));
         debug_field!(fmt, fields, int32_1, self.int32_1);
         debug_field!(fmt, fields, int32_2, self.int32_2);
         debug_field!(fmt, fields, int32_3, self.int32_3);
@@ -484,13 +528,85 @@ macro_rules! f_read_blob {
     }
 }
 
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+macro_rules! clear_default_fields {
+    ($node:ident, $($field_name:ident),+) => {
+        paste! {
+            $(
+                if is_default(&$node.$field_name) {
+                    $node.fields &= ![<FIELD_ $field_name:upper>];
+                }
+            )+
+        }
+    }
+}
+
 impl StreamRead for VaultNode {
     fn stream_read<S>(stream: &mut S) -> Result<Self>
         where S: BufRead
     {
-        #![allow(clippy::similar_names)]
+        let fields = stream.read_u64::<LittleEndian>()?;
+        Self::read_fields(stream, fields)
+    }
+}
 
+impl VaultNode {
+    // Reads a node exported from another server, which may use either the
+    // current layout or the legacy fixed layout (see from_legacy_blob).  This
+    // is only for importing data; clients always use the current layout.
+    pub fn from_import_blob(blob: &[u8]) -> Result<Self> {
+        let mut stream = Cursor::new(blob);
         let fields = stream.read_u64::<LittleEndian>()?;
+        if (fields & !ALL_FIELDS) == 0 {
+            if let Ok(node) = Self::read_fields(&mut stream, fields) {
+                if stream.fill_buf()?.is_empty() {
+                    return Ok(node);
+                }
+            }
+        }
+        Self::from_legacy_blob(blob)
+    }
+
+    // Legacy vault node blobs have no field mask.  Instead, every field is
+    // always stored, in the same order and with the same encoding as the
+    // current layout:
+    //   u32 node_id, u32 create_time, u32 modify_time,
+    //   string create_age_name, uuid create_age_uuid,
+    //   uuid creator_uuid, u32 creator_id, i32 node_type,
+    //   i32 int32_1..4, u32 uint32_1..4, uuid uuid_1..4,
+    //   string string64_1..6, string istring64_1..2, string text_1..2,
+    //   blob blob_1..2
+    // Since the legacy layout begins with the node ID and creation time, the
+    // first 8 bytes of a legacy node rarely look like a valid field mask, and
+    // even then the rest of the node won't match the current layout.
+    // Unset fields can't be told apart from their default values, so fields
+    // which are zero, nil or empty are left unset in the converted node,
+    // except for the node ID and type.
+    fn from_legacy_blob(blob: &[u8]) -> Result<Self> {
+        let mut stream = Cursor::new(blob);
+        let mut node = Self::read_fields(&mut stream, ALL_FIELDS)
+                .context("Node does not match the current or legacy layout")?;
+        if !stream.fill_buf()?.is_empty() {
+            return Err(anyhow!("Unexpected data after legacy vault node"));
+        }
+        clear_default_fields!(node, create_time, modify_time, create_age_name,
+                              create_age_uuid, creator_uuid, creator_id,
+                              int32_1, int32_2, int32_3, int32_4,
+                              uint32_1, uint32_2, uint32_3, uint32_4,
+                              uuid_1, uuid_2, uuid_3, uuid_4,
+                              string64_1, string64_2, string64_3, string64_4,
+                              string64_5, string64_6, istring64_1, istring64_2,
+                              text_1, text_2, blob_1, blob_2);
+        Ok(node)
+    }
+
+    fn read_fields<S>(stream: &mut S, fields: u64) -> Result<Self>
+        where S: BufRead
+    {
+        #![allow(clippy::similar_names)]
 
         let node_id = f_read_u32!(stream, fields, FIELD_NODE_ID);
         let create_time = f_read_u32!(stream, fields, FIELD_CREATE_TIME);
@@ -624,3 +740,110 @@ impl StreamWrite for VaultNode {
         Ok(())
     }
 }
+
+#[test]
+fn test_clear_field() -> Result<()> {
+    let mut node = VaultNode::default();
+    node.set_node_type(NodeType::TextNote as i32);
+    node.set_string64_1("Title");
+    node.set_text_1("Some text");
+
+    let mut with_text = Cursor::new(Vec::new());
+    node.stream_write(&mut with_text)?;
+
+    node.clear_text_1();
+    assert!(!node.has_text_1());
+    assert!(node.text_1().is_empty());
+    assert!(node.has_string64_1());
+
+    // The cleared field is no longer written at all
+    let mut stream = Cursor::new(Vec::new());
+    node.stream_write(&mut stream)?;
+    assert!(stream.get_ref().len() < with_text.get_ref().len());
+    stream.set_position(0);
+    let read_node = VaultNode::stream_read(&mut stream)?;
+    assert!(!read_node.has_text_1());
+    assert_eq!(read_node.string64_1(), "Title");
+    assert_eq!(read_node.fields, FIELD_NODE_TYPE | FIELD_STRING64_1);
+
+    Ok(())
+}
+
+#[test]
+fn test_node_type_names() {
+    assert_eq!(NodeType::from_i32(23), Some(NodeType::PlayerInfo));
+    assert_eq!(NodeType::PlayerInfo.to_string(), "PlayerInfo");
+    assert_eq!(NodeType::describe(NodeType::AgeInfo as i32), "AgeInfo (33)");
+    assert_eq!(NodeType::describe(99), "Unknown (99)");
+    assert_eq!(NodeType::from_name("textnote"), Some(NodeType::TextNote));
+    assert_eq!(NodeType::from_name("MarkerGame"), Some(NodeType::MarkerGame));
+    assert_eq!(NodeType::from_name("_Invalid"), None);
+    assert_eq!(NodeType::from_name("Bogus"), None);
+    assert_eq!(StandardNode::from_i32(12), Some(StandardNode::AllPlayersFolder));
+    assert_eq!(StandardNode::AllPlayersFolder.to_string(), "AllPlayersFolder");
+
+    let mut node = VaultNode::default();
+    node.set_node_id(5);
+    node.set_node_type(NodeType::PlayerInfo as i32);
+    assert_eq!(format!("{node:?}"), "VaultNode { node_id: 5, node_type: PlayerInfo (23) }");
+}
+
+// A legacy TextNote node, with every field present
+#[cfg(test)]
+pub(super) fn legacy_text_note_blob() -> Result<Vec<u8>> {
+    let write_string = |stream: &mut Vec<u8>, value: &str| write_vault_string(stream, value);
+
+    let mut blob = Vec::new();
+    blob.write_u32::<LittleEndian>(1234)?;          // node_id
+    blob.write_u32::<LittleEndian>(1_300_000_000)?; // create_time
+    blob.write_u32::<LittleEndian>(1_300_000_001)?; // modify_time
+    write_string(&mut blob, "Neighborhood")?;       // create_age_name
+    blob.extend_from_slice(&[0; 16]);               // create_age_uuid
+    blob.extend_from_slice(&[0x11; 16]);            // creator_uuid
+    blob.write_u32::<LittleEndian>(42)?;            // creator_id
+    blob.write_i32::<LittleEndian>(NodeType::TextNote as i32)?;
+    for value in [1, 0, 0, 0] {                     // int32_1..4
+        blob.write_i32::<LittleEndian>(value)?;
+    }
+    blob.extend_from_slice(&[0; 4 * 4]);            // uint32_1..4
+    blob.extend_from_slice(&[0; 4 * 16]);           // uuid_1..4
+    for value in ["Title", "", "", "", "", ""] {    // string64_1..6
+        write_string(&mut blob, value)?;
+    }
+    for value in ["", "", "Some text", ""] {        // istring64_1..2, text_1..2
+        write_string(&mut blob, value)?;
+    }
+    blob.write_u32::<LittleEndian>(0)?;             // blob_1
+    blob.write_u32::<LittleEndian>(2)?;             // blob_2
+    blob.extend_from_slice(b"\x01\x02");
+    Ok(blob)
+}
+
+#[test]
+fn test_legacy_node_blob() -> Result<()> {
+    let blob = legacy_text_note_blob()?;
+    let node = VaultNode::from_import_blob(&blob)?;
+    assert_eq!(node.fields, FIELD_NODE_ID | FIELD_CREATE_TIME | FIELD_MODIFY_TIME
+                | FIELD_CREATE_AGE_NAME | FIELD_CREATOR_UUID | FIELD_CREATOR_ID
+                | FIELD_NODE_TYPE | FIELD_INT32_1 | FIELD_STRING64_1 | FIELD_TEXT_1
+                | FIELD_BLOB_2);
+    assert_eq!(node.node_id(), 1234);
+    assert_eq!(node.create_time(), 1_300_000_000);
+    assert_eq!(node.create_age_name(), "Neighborhood");
+    assert_eq!(node.creator_uuid(), &Uuid::from_bytes([0x11; 16]));
+    assert_eq!(node.creator_id(), 42);
+    assert_eq!(node.node_type(), NodeType::TextNote as i32);
+    assert_eq!(node.int32_1(), 1);
+    assert_eq!(node.string64_1(), "Title");
+    assert_eq!(node.text_1(), "Some text");
+    assert_eq!(node.blob_2(), b"\x01\x02");
+
+    // Nodes in the current layout are read as-is
+    let current = node.to_blob()?;
+    assert_eq!(VaultNode::from_import_blob(&current)?.to_blob()?, current);
+
+    // Truncated legacy nodes are rejected
+    assert!(VaultNode::from_import_blob(&blob[..blob.len() - 1]).is_err());
+
+    Ok(())
+}
@@ -19,8 +19,12 @@
 // VaultNode directly for now.
 #![allow(clippy::new_ret_no_self)]
 
+use std::io::{self, Read, Write};
 use std::sync::Arc;
 
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 use uuid::Uuid;
 
 use super::vault_node::{VaultNode, NodeType, StandardNode};
@@ -185,19 +189,81 @@ impl VaultTextNoteNode {
 vnode_access!(VaultSdlNode {
     sdl_name: &String => string64_1,
     sdl_ident: i32 => int32_1,
-    sdl_data: &Vec<u8> => blob_1,
 });
 
+// Compressed SDL blobs are stored as this header byte followed by the gzip
+// stream.  Uncompressed blobs start with the low byte of the SDL read/write
+// flags, which never has these bits set, so both formats can be loaded.
+const COMPRESSED_SDL_HEADER: u8 = 0xFF;
+
+fn compress_sdl_blob(sdl_blob: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = GzEncoder::new(vec![COMPRESSED_SDL_HEADER], Compression::default());
+    stream.write_all(sdl_blob)?;
+    stream.finish()
+}
+
 impl VaultSdlNode {
     pub fn new(creator_uuid: &Uuid, creator_id: u32, sdl_name: &str,
-               sdl_blob: &[u8]) -> VaultNode {
+               sdl_blob: &[u8], compress: bool) -> io::Result<VaultNode>
+    {
         let mut node = VaultNode::default();
         node.set_node_type(NodeType::Sdl as i32);
         node.set_creator_uuid(creator_uuid);
         node.set_creator_id(creator_id);
         node.set_string64_1(sdl_name);
-        node.set_blob_1(sdl_blob);
-        node
+        if compress && !sdl_blob.is_empty() {
+            node.set_blob_1(&compress_sdl_blob(sdl_blob)?);
+        } else {
+            node.set_blob_1(sdl_blob);
+        }
+        Ok(node)
+    }
+
+    // Client-created SDL blobs are stored as they were sent.  A blob which
+    // happens to start with the compressed header is stored compressed
+    // instead, so it isn't mistaken for a compressed blob when it is read.
+    pub fn escape_client_blob(node: &mut VaultNode) -> io::Result<()> {
+        if node.node_type() == NodeType::Sdl as i32
+                && node.blob_1().first() == Some(&COMPRESSED_SDL_HEADER)
+        {
+            let compressed = compress_sdl_blob(node.blob_1())?;
+            node.set_blob_1(&compressed);
+        }
+        Ok(())
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.node.blob_1().first() == Some(&COMPRESSED_SDL_HEADER)
+    }
+
+    // Returns the uncompressed SDL blob.  Blobs which decompress to more
+    // than `max_size` bytes (see SdlLimits) are rejected rather than
+    // exhausting memory.
+    pub fn sdl_data(&self, max_size: usize) -> io::Result<Vec<u8>> {
+        let blob = self.node.blob_1();
+        if self.is_compressed() {
+            let mut sdl_blob = Vec::new();
+            GzDecoder::new(&blob[1..]).take(max_size as u64 + 1)
+                    .read_to_end(&mut sdl_blob)?;
+            if sdl_blob.len() > max_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          "Decompressed SDL blob is too large"));
+            }
+            Ok(sdl_blob)
+        } else {
+            Ok(blob.clone())
+        }
+    }
+
+    // Returns the node with an uncompressed SDL blob, as the client expects
+    // to receive it.
+    pub fn to_uncompressed(&self, max_size: usize) -> io::Result<Arc<VaultNode>> {
+        if !self.is_compressed() {
+            return Ok(self.node.clone());
+        }
+        let mut node = (*self.node).clone();
+        node.set_blob_1(&self.sdl_data(max_size)?);
+        Ok(Arc::new(node))
     }
 }
 
@@ -216,6 +282,62 @@ impl VaultAgeLinkNode {
         node.set_blob_1(link);
         node
     }
+
+    pub fn with_spawn_point(creator_uuid: &Uuid, creator_id: u32,
+                            spawn_point: &SpawnPointInfo) -> VaultNode
+    {
+        Self::new(creator_uuid, creator_id,
+                  &SpawnPointInfo::write_list(std::slice::from_ref(spawn_point)))
+    }
+
+    pub fn spawn_point_list(&self) -> Vec<SpawnPointInfo> {
+        SpawnPointInfo::parse_list(self.spawn_points())
+    }
+
+    // Link flags
+    pub fn is_unlocked(&self) -> bool { self.unlocked() != 0 }
+    pub fn is_volatile(&self) -> bool { self.volatile() != 0 }
+}
+
+// A link-in point within an Age.  AgeLink nodes store a list of these as
+// "Title:SpawnPoint:CameraStack;" entries.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SpawnPointInfo {
+    pub title: String,
+    pub spawn_point: String,
+    pub camera_stack: String,
+}
+
+impl SpawnPointInfo {
+    pub fn new(title: &str, spawn_point: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            spawn_point: spawn_point.to_string(),
+            camera_stack: String::new(),
+        }
+    }
+
+    pub fn parse_list(blob: &[u8]) -> Vec<Self> {
+        String::from_utf8_lossy(blob).split(';').filter_map(|entry| {
+            let mut parts = entry.split(':');
+            let title = parts.next().filter(|title| !title.is_empty())?;
+            Some(Self {
+                title: title.to_string(),
+                spawn_point: parts.next().unwrap_or_default().to_string(),
+                camera_stack: parts.next().unwrap_or_default().to_string(),
+            })
+        }).collect()
+    }
+
+    pub fn write_list(spawn_points: &[Self]) -> Vec<u8> {
+        use std::fmt::Write as _;
+
+        let mut list = String::new();
+        for info in spawn_points {
+            let _ = write!(list, "{}:{}:{};", info.title, info.spawn_point, info.camera_stack);
+        }
+        list.into_bytes()
+    }
 }
 
 vnode_access!(VaultChronicleNode {
@@ -225,7 +347,18 @@ vnode_access!(VaultChronicleNode {
 });
 
 impl VaultChronicleNode {
-    // pub fn new() -> VaultNode { ... }
+    pub fn new(creator_uuid: &Uuid, creator_id: u32, entry_type: i32,
+               entry_name: &str, entry_value: &str) -> VaultNode
+    {
+        let mut node = VaultNode::default();
+        node.set_node_type(NodeType::Chronicle as i32);
+        node.set_creator_uuid(creator_uuid);
+        node.set_creator_id(creator_id);
+        node.set_int32_1(entry_type);
+        node.set_string64_1(entry_name);
+        node.set_text_1(entry_value);
+        node
+    }
 }
 
 vnode_access!(VaultPlayerInfoListNode {
@@ -250,11 +383,10 @@ vnode_access!(VaultAgeInfoNode {
     parent_age_instance_uuid: &Uuid => uuid_2,
     age_description: &String => text_1,
     age_sequence_number: i32 => int32_1,
-    age_language: i32 => int32_2,
+    age_language: i32 => int32_3,
     age_id: u32 => uint32_1,
     age_czar_id: u32 => uint32_2,
     age_info_flags: u32 => uint32_3,
-    is_public: i32 => int32_2,
 });
 
 impl VaultAgeInfoNode {
@@ -267,36 +399,104 @@ impl VaultAgeInfoNode {
         node.set_node_type(NodeType::AgeInfo as i32);
         node.set_creator_uuid(instance_id);
         node.set_creator_id(age_id);
-        node.set_int32_1(seq_number);
-        node.set_int32_2(i32::from(public));
-        node.set_int32_3(language);
-        node.set_uint32_1(age_id);
-        node.set_uint32_2(0);   // Czar ID
-        node.set_uint32_3(0);   // Flags
-        node.set_uuid_1(instance_id);
-        if !parent_uuid.is_nil() {
-            node.set_uuid_2(parent_uuid);
+        Self::set_age_sequence_number(&mut node, seq_number);
+        Self::set_public(&mut node, public);
+        Self::set_age_language(&mut node, language);
+        Self::set_age_id(&mut node, age_id);
+        Self::set_age_czar_id(&mut node, 0);
+        Self::set_age_info_flags(&mut node, 0);
+        Self::set_age_instance_uuid(&mut node, instance_id);
+        Self::set_parent_age_instance_uuid(&mut node, parent_uuid);
+        Self::set_age_filename(&mut node, age_filename);
+        Self::set_age_instance_name(&mut node, instance_name);
+        Self::set_age_user_defined_name(&mut node, user_name);
+        Self::set_age_description(&mut node, description);
+        node
+    }
+
+    pub fn new_lookup(instance_id: Option<&Uuid>) -> VaultNode {
+        let mut node = VaultNode::default();
+        node.set_node_type(NodeType::AgeInfo as i32);
+        if let Some(uuid) = instance_id {
+            Self::set_age_instance_uuid(&mut node, uuid);
         }
+        node
+    }
+
+    pub fn new_filename_lookup(age_filename: &str) -> VaultNode {
+        let mut node = VaultNode::default();
+        node.set_node_type(NodeType::AgeInfo as i32);
+        Self::set_age_filename(&mut node, age_filename);
+        node
+    }
+
+    pub fn new_public_lookup(age_filename: &str) -> VaultNode {
+        let mut node = VaultNode::default();
+        node.set_node_type(NodeType::AgeInfo as i32);
+        Self::set_public(&mut node, true);
+        Self::set_age_filename(&mut node, age_filename);
+        node
+    }
+
+    pub fn is_public(&self) -> bool { self.node.int32_2() != 0 }
+
+    // Setters for building new AgeInfo nodes, update nodes and lookup
+    // templates.  Optional fields are left unset when empty (or nil), so
+    // they won't match anything when used in a lookup template.
+    pub fn set_age_filename(node: &mut VaultNode, age_filename: &str) {
         node.set_string64_2(age_filename);
+    }
+
+    pub fn set_age_instance_name(node: &mut VaultNode, instance_name: &str) {
         if !instance_name.is_empty() {
             node.set_string64_3(instance_name);
         }
+    }
+
+    pub fn set_age_user_defined_name(node: &mut VaultNode, user_name: &str) {
         if !user_name.is_empty() {
             node.set_string64_4(user_name);
         }
+    }
+
+    pub fn set_age_instance_uuid(node: &mut VaultNode, instance_id: &Uuid) {
+        node.set_uuid_1(instance_id);
+    }
+
+    pub fn set_parent_age_instance_uuid(node: &mut VaultNode, parent_uuid: &Uuid) {
+        if !parent_uuid.is_nil() {
+            node.set_uuid_2(parent_uuid);
+        }
+    }
+
+    pub fn set_age_description(node: &mut VaultNode, description: &str) {
         if !description.is_empty() {
             node.set_text_1(description);
         }
-        node
     }
 
-    pub fn new_lookup(instance_id: Option<&Uuid>) -> VaultNode {
-        let mut node = VaultNode::default();
-        node.set_node_type(NodeType::AgeInfo as i32);
-        if let Some(uuid) = instance_id {
-            node.set_uuid_1(uuid);
-        }
-        node
+    pub fn set_age_sequence_number(node: &mut VaultNode, seq_number: i32) {
+        node.set_int32_1(seq_number);
+    }
+
+    pub fn set_age_language(node: &mut VaultNode, language: i32) {
+        node.set_int32_3(language);
+    }
+
+    pub fn set_age_id(node: &mut VaultNode, age_id: u32) {
+        node.set_uint32_1(age_id);
+    }
+
+    pub fn set_age_czar_id(node: &mut VaultNode, czar_id: u32) {
+        node.set_uint32_2(czar_id);
+    }
+
+    pub fn set_age_info_flags(node: &mut VaultNode, flags: u32) {
+        node.set_uint32_3(flags);
+    }
+
+    pub fn set_public(node: &mut VaultNode, public: bool) {
+        node.set_int32_2(i32::from(public));
     }
 }
 
@@ -323,3 +523,123 @@ vnode_access!(VaultMarkerGameNode {
 impl VaultMarkerGameNode {
     // pub fn new() -> VaultNode { ... }
 }
+
+#[test]
+fn test_spawn_point_round_trip() {
+    let spawn_point = SpawnPointInfo::new("Ferry Terminal", "LinkInPointFerry");
+    let node = VaultAgeLinkNode::with_spawn_point(&Uuid::nil(), 1, &spawn_point);
+    assert_eq!(node.blob_1().as_slice(), b"Ferry Terminal:LinkInPointFerry:;");
+
+    let link = Arc::new(node).as_age_link_node().unwrap();
+    assert_eq!(link.spawn_point_list(), vec![spawn_point]);
+    assert!(!link.is_unlocked());
+    assert!(!link.is_volatile());
+
+    let spawn_points = SpawnPointInfo::parse_list(
+            b"Default:LinkInPointDefault:;Kadish:Spawn1:Cam1;");
+    assert_eq!(spawn_points.len(), 2);
+    assert_eq!(spawn_points[1].camera_stack, "Cam1");
+    assert_eq!(SpawnPointInfo::write_list(&spawn_points),
+               b"Default:LinkInPointDefault:;Kadish:Spawn1:Cam1;");
+}
+
+#[cfg(test)]
+const MAX_TEST_BLOB_SIZE: usize = 4096;
+
+#[test]
+fn test_sdl_blob_compression() {
+    // Starts with the SDL read/write flags, like a real SDL blob
+    let sdl_blob: Vec<u8> = [0x00, 0x80].iter().copied()
+            .chain(std::iter::repeat(0x55).take(1024)).collect();
+
+    let node = Arc::new(VaultSdlNode::new(&Uuid::nil(), 1, "Teledahn", &sdl_blob, true)
+                        .unwrap());
+    let sdl_node = node.as_sdl_node().unwrap();
+    assert!(sdl_node.is_compressed());
+    assert!(node.blob_1().len() < sdl_blob.len());
+    assert_eq!(sdl_node.sdl_data(MAX_TEST_BLOB_SIZE).unwrap(), sdl_blob);
+    let uncompressed = sdl_node.to_uncompressed(MAX_TEST_BLOB_SIZE).unwrap();
+    assert_eq!(uncompressed.blob_1(), &sdl_blob);
+    assert_eq!(uncompressed.string64_1(), "Teledahn");
+
+    // Legacy uncompressed blobs are still readable
+    let node = Arc::new(VaultSdlNode::new(&Uuid::nil(), 1, "Teledahn", &sdl_blob, false)
+                        .unwrap());
+    let sdl_node = node.as_sdl_node().unwrap();
+    assert!(!sdl_node.is_compressed());
+    assert_eq!(node.blob_1(), &sdl_blob);
+    assert_eq!(sdl_node.sdl_data(MAX_TEST_BLOB_SIZE).unwrap(), sdl_blob);
+    assert!(Arc::ptr_eq(&sdl_node.to_uncompressed(MAX_TEST_BLOB_SIZE).unwrap(), &node));
+
+    // Empty blobs (e.g. for Ages without an SDL descriptor) are left alone
+    let node = Arc::new(VaultSdlNode::new(&Uuid::nil(), 1, "Empty", &[], true).unwrap());
+    assert!(node.blob_1().is_empty());
+    assert!(node.as_sdl_node().unwrap().sdl_data(MAX_TEST_BLOB_SIZE).unwrap().is_empty());
+}
+
+#[test]
+fn test_client_sdl_blob() {
+    // A client blob which looks like a compressed blob must come back as-is
+    let client_blob = vec![COMPRESSED_SDL_HEADER, 0x1F, 0x8B, 0x08, 0x00];
+    let mut node = VaultSdlNode::new(&Uuid::nil(), 1, "Teledahn", &client_blob, false)
+            .unwrap();
+    VaultSdlNode::escape_client_blob(&mut node).unwrap();
+    let sdl_node = Arc::new(node).as_sdl_node().unwrap();
+    assert_eq!(sdl_node.sdl_data(MAX_TEST_BLOB_SIZE).unwrap(), client_blob);
+    assert_eq!(sdl_node.to_uncompressed(MAX_TEST_BLOB_SIZE).unwrap().blob_1(), &client_blob);
+
+    // Other blobs don't need to be escaped
+    let client_blob = vec![0x00, 0x80, 0x55];
+    let mut node = VaultSdlNode::new(&Uuid::nil(), 1, "Teledahn", &client_blob, false)
+            .unwrap();
+    VaultSdlNode::escape_client_blob(&mut node).unwrap();
+    assert_eq!(node.blob_1(), &client_blob);
+}
+
+#[test]
+fn test_sdl_blob_size_limit() {
+    let too_large = compress_sdl_blob(&[0; MAX_TEST_BLOB_SIZE + 1]).unwrap();
+    let mut node = VaultSdlNode::new(&Uuid::nil(), 1, "Teledahn", &[], false).unwrap();
+    node.set_blob_1(&too_large);
+    let node = Arc::new(node);
+    let sdl_node = node.as_sdl_node().unwrap();
+    assert!(sdl_node.is_compressed());
+    assert_eq!(sdl_node.sdl_data(MAX_TEST_BLOB_SIZE).unwrap_err().kind(),
+               io::ErrorKind::InvalidData);
+    assert_eq!(sdl_node.sdl_data(MAX_TEST_BLOB_SIZE + 1).unwrap().len(),
+               MAX_TEST_BLOB_SIZE + 1);
+}
+
+#[test]
+fn test_age_info_round_trip() {
+    let instance_id = Uuid::new_v4();
+    let parent_id = Uuid::new_v4();
+    let mut node = VaultAgeInfoNode::new(&instance_id, 1234, 7, true, 2, &parent_id,
+                                         "Neighborhood", "Neighborhood", "Test Hood",
+                                         "A neighborhood for testing");
+    VaultAgeInfoNode::set_age_czar_id(&mut node, 5678);
+    VaultAgeInfoNode::set_age_info_flags(&mut node, 0x10);
+
+    let age_info = Arc::new(node).as_age_info_node().unwrap();
+    assert_eq!(age_info.age_filename(), "Neighborhood");
+    assert_eq!(age_info.age_instance_name(), "Neighborhood");
+    assert_eq!(age_info.age_user_defined_name(), "Test Hood");
+    assert_eq!(age_info.age_instance_uuid(), &instance_id);
+    assert_eq!(age_info.parent_age_instance_uuid(), &parent_id);
+    assert_eq!(age_info.age_description(), "A neighborhood for testing");
+    assert_eq!(age_info.age_sequence_number(), 7);
+    assert_eq!(age_info.age_language(), 2);
+    assert_eq!(age_info.age_id(), 1234);
+    assert_eq!(age_info.age_czar_id(), 5678);
+    assert_eq!(age_info.age_info_flags(), 0x10);
+    assert!(age_info.is_public());
+
+    // Empty optional fields are left unset
+    let node = VaultAgeInfoNode::new(&instance_id, 1, 0, false, 0, &Uuid::nil(),
+                                     "Personal", "", "", "");
+    assert!(!node.has_string64_3());
+    assert!(!node.has_string64_4());
+    assert!(!node.has_uuid_2());
+    assert!(!node.has_text_1());
+    assert!(!Arc::new(node).as_age_info_node().unwrap().is_public());
+}